@@ -0,0 +1,54 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[path = "src/module_name.rs"]
+mod module_name;
+
+/// Collects `.lua` files under `dir`, naming each one the way `require` would find it,
+/// see `module_name`.
+fn collect_modules(root: &Path, dir: &Path, modules: &mut Vec<(String, PathBuf)>) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_modules(root, &path, modules);
+        } else if path.extension().is_some_and(|ext| ext == "lua") {
+            let name = module_name::module_name(path.strip_prefix(root).unwrap());
+            modules.push((name, path));
+        }
+    }
+}
+
+fn main() {
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("embedded_lua.rs");
+    let mut modules = vec![];
+    println!("cargo:rerun-if-changed=build.rs");
+
+    if env::var_os("CARGO_FEATURE_EMBED_LUA").is_some() {
+        println!("cargo:rerun-if-env-changed=LUAREPL_EMBED_DIR");
+        let dir = env::var_os("LUAREPL_EMBED_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("lua"));
+        println!("cargo:rerun-if-changed={}", dir.display());
+        if dir.is_dir() {
+            collect_modules(&dir, &dir, &mut modules);
+        }
+    }
+
+    let mut code = String::from("pub static EMBEDDED_MODULES: &[(&str, &str)] = &[\n");
+    for (name, path) in &modules {
+        code.push_str(&format!(
+            "    ({:?}, include_str!({:?})),\n",
+            name,
+            path.canonicalize().unwrap()
+        ));
+    }
+    code.push_str("];\n");
+    fs::write(out, code).unwrap();
+}
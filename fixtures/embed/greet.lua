@@ -0,0 +1 @@
+return { greeting = "hello from an embedded module" }
@@ -0,0 +1,5 @@
+return {
+    area = function(r)
+        return 3 * r * r
+    end,
+}
@@ -0,0 +1,3 @@
+local circle = require("shapes.circle")
+
+return { circle = circle }
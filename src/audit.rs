@@ -0,0 +1,211 @@
+//! An append-only JSONL record of every eval run through a `SessionManager`,
+//! or served by `server::serve` and `jsonrpc::serve`, whose clients are
+//! recorded by their peer address.
+//!
+//! Each line looks like
+//!
+//! ```json
+//! {"time_ms":1700000000000,"session":0,"client":"stdin","source":"x = 1","source_bytes":5,"status":"ok","duration_ms":0.2}
+//! ```
+//!
+//! Rotated files are renamed to `<path>.<time_ms>` and kept.
+
+use crate::manager::SessionId;
+use crate::EvalResponse;
+use serde::Serialize;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// Rotate once the file would grow beyond this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Rotate once the file has been written to for this long.
+    pub max_age: Option<Duration>,
+    /// Record only the length of each source, not the source itself.
+    pub redact_source: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditStatus {
+    Ok,
+    Error,
+    LimitViolation,
+}
+
+impl AuditStatus {
+    pub fn of(response: &EvalResponse) -> Self {
+        if response.limit_violation.is_some() {
+            AuditStatus::LimitViolation
+        } else if response.success {
+            AuditStatus::Ok
+        } else {
+            AuditStatus::Error
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time_ms: u128,
+    session: SessionId,
+    client: Option<&'a str>,
+    source: Option<&'a str>,
+    source_bytes: usize,
+    status: AuditStatus,
+    duration_ms: f64,
+}
+
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditConfig,
+    file: File,
+    written: u64,
+    opened: Instant,
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis())
+}
+
+impl AuditLog {
+    pub fn open(config: AuditConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file,
+            written,
+            opened: Instant::now(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        session: SessionId,
+        client: Option<&str>,
+        source: &str,
+        status: AuditStatus,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let record = AuditRecord {
+            time_ms: now_ms(),
+            session,
+            client,
+            source: (!self.config.redact_source).then_some(source),
+            source_bytes: source.len(),
+            status,
+            duration_ms: duration.as_secs_f64() * 1000.0,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let too_big = self
+            .config
+            .max_bytes
+            .is_some_and(|max| self.written > 0 && self.written + line.len() as u64 > max);
+        let too_old = self
+            .config
+            .max_age
+            .is_some_and(|max| self.written > 0 && self.opened.elapsed() >= max);
+        if too_big || too_old {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.config.path.clone().into_os_string();
+        rotated.push(format!(".{}", now_ms()));
+        std::fs::rename(&self.config.path, rotated)?;
+        *self = Self::open(self.config.clone())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manager::LimitPolicy;
+    use crate::manager::SessionManager;
+    use crate::SessionConfig;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("luarepl-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_audit_trail() {
+        let dir = temp_dir("audit");
+        let path = dir.join("audit.jsonl");
+        let log = AuditLog::open(AuditConfig {
+            path: path.clone(),
+            redact_source: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut manager =
+            SessionManager::new(SessionConfig::default(), LimitPolicy::ErrorAndContinue, 1)
+                .audit(log);
+        let id = manager.open();
+        manager.eval(id, "secret = 1".to_string()).await;
+        manager
+            .eval_as(id, Some("alice"), "error()".to_string())
+            .await;
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["status"], "ok");
+        assert_eq!(lines[0]["source"], serde_json::Value::Null);
+        assert_eq!(lines[0]["source_bytes"], 10);
+        assert_eq!(lines[1]["status"], "error");
+        assert_eq!(lines[1]["client"], "alice");
+        assert_eq!(lines[1]["session"], id);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = temp_dir("rotation");
+        let path = dir.join("audit.jsonl");
+        let mut log = AuditLog::open(AuditConfig {
+            path: path.clone(),
+            max_bytes: Some(200),
+            ..Default::default()
+        })
+        .unwrap();
+        for _ in 0..3 {
+            log.record(0, None, "return 1", AuditStatus::Ok, Duration::ZERO)
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        assert!(files >= 2, "expected rotated files, found {}", files);
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            assert!(entry.unwrap().metadata().unwrap().len() <= 200);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Pre-shared tokens, so that a server reachable over the network does not
+//! run whatever anyone sends it. With a token set, `server` only answers a
+//! client's `hello` once it carries the token, and `jsonrpc` only its
+//! `authenticate` request; everything the client sends before is refused.
+//! A client presenting `MAX_REJECTIONS` wrong tokens is disconnected.
+
+use std::fmt;
+
+/// Where `luarepl serve` looks for the token when `--token` is not given.
+pub const TOKEN_VAR: &str = "LUAREPL_TOKEN";
+
+/// The reply to requests sent before the token was presented.
+pub const REQUIRED: &str = "authenticate first";
+
+/// The reply to a token that does not match.
+pub const REJECTED: &str = "wrong token";
+
+/// How many wrong tokens a connection may present before it is closed, so
+/// that guessing the token takes a connection per few guesses.
+pub const MAX_REJECTIONS: u32 = 3;
+
+/// A token clients must present. Its `Debug` leaves the token out, so that
+/// it stays out of logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Token(String);
+
+impl Token {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    /// Whether `presented` is the token, taking as long to tell for every
+    /// token of the same length.
+    pub fn accepts(&self, presented: Option<&str>) -> bool {
+        let Some(presented) = presented else {
+            return false;
+        };
+        let (expected, presented) = (self.0.as_bytes(), presented.as_bytes());
+        expected.len() == presented.len()
+            && expected
+                .iter()
+                .zip(presented)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accepts() {
+        let token = Token::new("s3cret");
+        assert!(token.accepts(Some("s3cret")));
+        assert!(!token.accepts(Some("s3creT")));
+        assert!(!token.accepts(Some("s3cret ")));
+        assert!(!token.accepts(None));
+        assert_eq!(format!("{:?}", token), "Token(..)");
+    }
+}
@@ -0,0 +1,261 @@
+//! Rust structs exposed to Lua as userdata, registered by embedders with
+//! `SessionBuilder::register_userdata`. Their fields can be read and written
+//! from Lua, their methods called with `value:method(...)`, and results
+//! containing them are serialized into the object graph like tables.
+
+use crate::finalizers;
+use crate::host;
+use crate::host::HostResult;
+use crate::LuaValue;
+use rlua::AnyUserData;
+use rlua::Context;
+use rlua::Error;
+use rlua::MetaMethod;
+use rlua::MultiValue;
+use rlua::UserData;
+use rlua::UserDataMethods;
+use rlua::Value;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Describes a Rust value to Lua and to the object graph.
+pub trait Reflect: Any + Send {
+    fn fields(&self) -> Vec<(&'static str, LuaValue)>;
+
+    /// Assigns `value` to the field `name`; fields are read-only by default.
+    fn set_field(&mut self, name: &str, value: LuaValue) -> Result<(), String> {
+        let _ = value;
+        Err(format!("field {} is read-only", name))
+    }
+}
+
+type Method = Arc<dyn Fn(&mut dyn Reflect, Vec<LuaValue>) -> HostResult + Send + Sync>;
+type Constructor = Arc<dyn Fn(Vec<LuaValue>) -> Result<Box<dyn Reflect>, String> + Send + Sync>;
+
+/// A Rust type as seen from Lua: a global table `name` with a `new`
+/// constructor, and the methods of its values.
+#[derive(Clone)]
+pub struct UserDataType {
+    name: String,
+    constructor: Option<Constructor>,
+    methods: HashMap<String, Method>,
+}
+
+impl fmt::Debug for UserDataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UserDataType({})", self.name)
+    }
+}
+
+impl UserDataType {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            constructor: None,
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Defines `name.new(...)`.
+    pub fn constructor<T: Reflect>(
+        mut self,
+        constructor: impl Fn(Vec<LuaValue>) -> Result<T, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.constructor = Some(Arc::new(move |args| {
+            constructor(args).map(|value| Box::new(value) as Box<dyn Reflect>)
+        }));
+        self
+    }
+
+    /// Defines `value:name(...)` for values of type `T`. Errors are reported
+    /// like those of host functions, see `EvalResponse::host_error`.
+    pub fn method<T: Reflect>(
+        mut self,
+        name: &str,
+        method: impl Fn(&mut T, Vec<LuaValue>) -> HostResult + Send + Sync + 'static,
+    ) -> Self {
+        let method: Method = Arc::new(move |value, args| {
+            let value = (value as &mut dyn Any)
+                .downcast_mut::<T>()
+                .ok_or("method called on a value of another type")?;
+            method(value, args)
+        });
+        self.methods.insert(name.to_string(), method);
+        self
+    }
+}
+
+/// The userdata behind every bridged value.
+struct Bridged {
+    value: Box<dyn Reflect>,
+    kind: Arc<UserDataType>,
+}
+
+impl UserData for Bridged {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Index, |ctx, this, key: String| {
+            if let Some(method) = this.kind.methods.get(&key) {
+                let method = method.clone();
+                let name = format!("{}:{}", this.kind.name, key);
+                let function =
+                    ctx.create_function(move |ctx, (this, args): (AnyUserData, MultiValue)| {
+                        let args = args
+                            .into_iter()
+                            .map(|arg| host::argument(ctx, arg))
+                            .collect();
+                        let mut this = this.borrow_mut::<Bridged>()?;
+                        match method(this.value.as_mut(), args) {
+                            Ok(value) => value.to_lua(ctx),
+                            Err(e) => Err(Error::external(host::failure(&name, e.as_ref()))),
+                        }
+                    })?;
+                return Ok(Value::Function(function));
+            }
+            match this
+                .value
+                .fields()
+                .into_iter()
+                .find(|(name, _)| *name == key)
+            {
+                Some((_, value)) => value.to_lua(ctx),
+                None => Ok(Value::Nil),
+            }
+        });
+        methods.add_meta_method_mut(
+            MetaMethod::NewIndex,
+            |ctx, this, (key, value): (String, Value)| {
+                let value = host::argument(ctx, value);
+                this.value
+                    .set_field(&key, value)
+                    .map_err(Error::RuntimeError)
+            },
+        );
+        methods.add_meta_method(MetaMethod::ToString, |_, this, ()| {
+            Ok(format!("{}: {:p}", this.kind.name, this.value.as_ref()))
+        });
+    }
+}
+
+/// Defines the global tables of `types` in the Lua state behind `ctx`.
+pub(crate) fn install(ctx: Context, types: &[UserDataType]) -> Result<(), Error> {
+    for kind in types {
+        let kind = Arc::new(kind.clone());
+        let table = ctx.create_table()?;
+        if let Some(constructor) = kind.constructor.clone() {
+            let kind = kind.clone();
+            let new = ctx.create_function(move |ctx, args: MultiValue| {
+                let args = args
+                    .into_iter()
+                    .map(|arg| host::argument(ctx, arg))
+                    .collect();
+                let value = constructor(args).map_err(Error::RuntimeError)?;
+                let userdata = ctx.create_userdata(Bridged {
+                    value,
+                    kind: kind.clone(),
+                })?;
+                finalizers::track(ctx, Value::UserData(userdata.clone()), &kind.name);
+                Ok(userdata)
+            })?;
+            table.set("new", new)?;
+        }
+        ctx.globals().set(kind.name.as_str(), table)?;
+    }
+    Ok(())
+}
+
+/// The type name and fields of a bridged value, or `None` for other userdata.
+pub(crate) fn reflect(userdata: &AnyUserData) -> Option<(String, Vec<(&'static str, LuaValue)>)> {
+    let bridged = userdata.borrow::<Bridged>().ok()?;
+    Some((bridged.kind.name.clone(), bridged.value.fields()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    struct Counter {
+        label: String,
+        count: f64,
+    }
+
+    impl Reflect for Counter {
+        fn fields(&self) -> Vec<(&'static str, LuaValue)> {
+            vec![
+                ("label", LuaValue::String(self.label.clone())),
+                ("count", LuaValue::Number(self.count)),
+            ]
+        }
+
+        fn set_field(&mut self, name: &str, value: LuaValue) -> Result<(), String> {
+            match (name, value) {
+                ("label", LuaValue::String(label)) => self.label = label,
+                (name, value) => return Err(format!("cannot set {} to {}", name, value)),
+            }
+            Ok(())
+        }
+    }
+
+    fn session() -> Session {
+        let counter = UserDataType::new("Counter")
+            .constructor(|args| match &args[..] {
+                [LuaValue::String(label)] => Ok(Counter {
+                    label: label.clone(),
+                    count: 0.0,
+                }),
+                _ => Err("Counter.new expects a label".to_string()),
+            })
+            .method("add", |counter: &mut Counter, args| {
+                match args.first().and_then(LuaValue::as_number) {
+                    Some(n) if n >= 0.0 && args.len() == 1 => {
+                        counter.count += n;
+                        Ok(LuaValue::Number(counter.count))
+                    }
+                    _ => Err("expected a non-negative number".into()),
+                }
+            });
+        Session::builder().register_userdata(counter).build()
+    }
+
+    #[tokio::test]
+    async fn test_fields_and_methods() {
+        let mut session = session();
+        let resp = session
+            .eval("c = Counter.new('hits'); c:add(2); c:add(3); c.label = 'clicks'; return c.label, c.count".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::String("clicks".to_string()));
+        let resp = session.eval("return c.count".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Number(5.0));
+
+        assert!(!session.eval("c.count = 1".to_string()).await.success);
+        let resp = session.eval("c:add(-1)".to_string()).await;
+        assert_eq!(
+            resp.host_error.map(|e| e.function),
+            Some("Counter:add".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serialized_into_graph() {
+        let mut session = session();
+        let resp = session
+            .eval("local c = Counter.new('hits'); c:add(1); return { c }".to_string())
+            .await;
+        let LuaValue::ObjectRef(list) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let Some(LuaValue::ObjectRef(counter)) = resp.objects[list].members.first().map(|(_, v)| v)
+        else {
+            panic!("expected a counter in {:?}", resp.objects[list]);
+        };
+        let counter = &resp.objects[counter];
+        assert_eq!(counter.type_name.as_deref(), Some("Counter"));
+        assert_eq!(counter.get("count"), Some(&LuaValue::Number(1.0)));
+        assert_eq!(
+            counter.get("label"),
+            Some(&LuaValue::String("hits".to_string()))
+        );
+    }
+}
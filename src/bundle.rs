@@ -0,0 +1,213 @@
+//! Self-contained executables made of this binary plus a Lua program.
+//!
+//! `luarepl bundle main.lua -o app` copies the running executable and appends
+//! the script and the local modules it requires. At startup the binary looks
+//! for such a payload at its own end and, if present, runs it instead of the
+//! REPL, with the bundled modules preloaded like the `embed-lua` ones.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+const MAGIC: &[u8; 8] = b"LUAREPLB";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bundle {
+    pub main: String,
+    pub modules: Vec<(String, String)>,
+}
+
+impl Bundle {
+    /// Reads `main` and every module it (transitively) requires with a literal
+    /// name that resolves to a file next to it. Other requires are left to
+    /// run time.
+    pub fn from_script(main: &Path) -> io::Result<Self> {
+        let root = main.parent().unwrap_or_else(|| Path::new("."));
+        let source = fs::read_to_string(main)?;
+        let mut modules = vec![];
+        let mut seen = HashSet::new();
+        let mut pending = required_modules(&source);
+
+        while let Some(name) = pending.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            if let Some(path) = resolve_module(root, &name) {
+                let module_source = fs::read_to_string(&path)?;
+                pending.extend(required_modules(&module_source));
+                modules.push((name, module_source));
+            }
+        }
+        modules.sort();
+
+        Ok(Self {
+            main: source,
+            modules,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_str(&mut out, &self.main);
+        for (name, source) in &self.modules {
+            write_str(&mut out, name);
+            write_str(&mut out, source);
+        }
+        out
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let main = read_str(&mut bytes)?;
+        let mut modules = vec![];
+        while !bytes.is_empty() {
+            modules.push((read_str(&mut bytes)?, read_str(&mut bytes)?));
+        }
+        Some(Self { main, modules })
+    }
+
+    /// Writes a copy of `executable` with this bundle appended to `output`.
+    pub fn write_executable(&self, executable: &Path, output: &Path) -> io::Result<()> {
+        let mut image = fs::read(executable)?;
+        let fits = |length: &usize| length.checked_add(16).is_some_and(|end| end <= image.len());
+        if let Some(length) = payload_length(&image).filter(fits) {
+            // Bundling from a bundled binary: replace its payload.
+            image.truncate(image.len() - length - 16);
+        }
+        let payload = self.encode();
+        image.extend_from_slice(&payload);
+        image.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        image.extend_from_slice(MAGIC);
+        fs::write(output, image)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(output, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the bundle appended to the running executable, if any.
+    pub fn from_current_exe() -> Option<Self> {
+        let mut file = File::open(std::env::current_exe().ok()?).ok()?;
+        let mut trailer = [0; 16];
+        let size = file.seek(SeekFrom::End(0)).ok()?;
+        file.seek(SeekFrom::End(-16)).ok()?;
+        file.read_exact(&mut trailer).ok()?;
+        let length = payload_length(&trailer)? as u64;
+        file.seek(SeekFrom::Start(size.checked_sub(length.checked_add(16)?)?))
+            .ok()?;
+        let mut payload = vec![0; length as usize];
+        file.read_exact(&mut payload).ok()?;
+        Self::decode(&payload)
+    }
+}
+
+fn payload_length(image: &[u8]) -> Option<usize> {
+    if image.len() < 16 || &image[image.len() - 8..] != MAGIC {
+        return None;
+    }
+    let mut length = [0; 8];
+    length.copy_from_slice(&image[image.len() - 16..image.len() - 8]);
+    Some(u64::from_le_bytes(length) as usize)
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(bytes: &mut &[u8]) -> Option<String> {
+    let length = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let s = String::from_utf8(bytes.get(4..4 + length)?.to_vec()).ok()?;
+    *bytes = &bytes[4 + length..];
+    Some(s)
+}
+
+/// Finds `require "name"`, `require 'name'` and `require("name")` calls.
+fn required_modules(source: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut rest = source;
+    while let Some(index) = rest.find("require") {
+        let preceded_by_ident = rest[..index]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == ':');
+        rest = &rest[index + "require".len()..];
+        if preceded_by_ident {
+            continue;
+        }
+        let call = rest.trim_start();
+        let call = call.strip_prefix('(').map_or(call, str::trim_start);
+        if let Some(quote) = call.chars().next().filter(|c| *c == '"' || *c == '\'') {
+            if let Some(end) = call[1..].find(quote) {
+                names.push(call[1..1 + end].to_string());
+            }
+        }
+    }
+    names
+}
+
+fn resolve_module(root: &Path, name: &str) -> Option<PathBuf> {
+    let base = root.join(name.replace('.', "/"));
+    vec![base.with_extension("lua"), base.join("init.lua")]
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_required_modules() {
+        assert_eq!(
+            required_modules(
+                "local a = require('a.b')\nrequire \"c\" x.require('d') require(name)"
+            ),
+            vec!["a.b".to_string(), "c".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let dir = std::env::temp_dir().join(format!("luarepl-bundle-{}", std::process::id()));
+        fs::create_dir_all(dir.join("util")).unwrap();
+        fs::write(
+            dir.join("main.lua"),
+            "return require('util').f() + require('string').len('')",
+        )
+        .unwrap();
+        fs::write(dir.join("util/init.lua"), "return require('util.impl')").unwrap();
+        fs::write(
+            dir.join("util/impl.lua"),
+            "return { f = function() return 1 end }",
+        )
+        .unwrap();
+
+        let bundle = Bundle::from_script(&dir.join("main.lua")).unwrap();
+        let names: Vec<_> = bundle
+            .modules
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, vec!["util", "util.impl"]);
+        assert_eq!(Bundle::decode(&bundle.encode()), Some(bundle.clone()));
+
+        let exe = dir.join("exe");
+        fs::write(&exe, b"binary").unwrap();
+        bundle.write_executable(&exe, &dir.join("app")).unwrap();
+        let image = fs::read(dir.join("app")).unwrap();
+        assert!(image.starts_with(b"binary"));
+        assert_eq!(payload_length(&image), Some(bundle.encode().len()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
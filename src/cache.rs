@@ -0,0 +1,210 @@
+//! Memoized results of evals that look free of side effects, for frontends
+//! re-evaluating the same watch expressions after every command.
+//!
+//! Purity is judged from the source alone: no assignments, no `local` or
+//! `function` definitions, no method calls and no calls into the libraries
+//! of `DENIED`. Calls to functions defined by scripts are assumed to be pure.
+
+use crate::EvalResponse;
+use std::collections::HashMap;
+
+/// Library tables and functions whose calls have side effects or do not
+/// return the same result twice.
+const DENIED: &[&str] = &[
+    "collectgarbage",
+    "coroutine",
+    "debug",
+    "dofile",
+    "io",
+    "load",
+    "loadfile",
+    "math.random",
+    "math.randomseed",
+    "os",
+    "package",
+    "print",
+    "rawset",
+    "require",
+    "setmetatable",
+    "table.insert",
+    "table.move",
+    "table.remove",
+    "table.sort",
+];
+
+/// How many results are kept before the cache starts over.
+const CAPACITY: usize = 256;
+
+/// Removes comments and the contents of string literals, which must not be
+/// mistaken for code.
+fn code_only(source: &str) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let long_bracket = |start: usize| -> Option<usize> {
+        // `[[`, `[=[`, ... returns the number of `=`.
+        let mut i = start + 1;
+        while chars.get(i) == Some(&'=') {
+            i += 1;
+        }
+        (chars.get(start) == Some(&'[') && chars.get(i) == Some(&'[')).then_some(i - start - 1)
+    };
+    let skip_long = |from: usize, level: usize| -> usize {
+        let close: Vec<char> = std::iter::once(']')
+            .chain(std::iter::repeat_n('=', level))
+            .chain(std::iter::once(']'))
+            .collect();
+        (from..chars.len())
+            .find(|&i| chars[i..].starts_with(&close))
+            .map_or(chars.len(), |i| i + close.len())
+    };
+
+    let mut code = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '-' if chars.get(i + 1) == Some(&'-') => {
+                i = match long_bracket(i + 2) {
+                    Some(level) => skip_long(i + 2, level),
+                    None => (i..chars.len())
+                        .find(|&j| chars[j] == '\n')
+                        .unwrap_or(chars.len()),
+                };
+            }
+            quote @ ('"' | '\'') => {
+                i += 1;
+                while i < chars.len() && chars[i] != quote {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i += 1;
+                code.push_str("\"\"");
+            }
+            '[' if long_bracket(i).is_some() => {
+                i = skip_long(i, long_bracket(i).unwrap_or_default());
+                code.push_str("\"\"");
+            }
+            c => {
+                code.push(c);
+                i += 1;
+            }
+        }
+    }
+    code
+}
+
+/// Whether caching the result of `source` looks safe.
+pub fn is_pure(source: &str) -> bool {
+    let code = code_only(source);
+    let bytes = code.as_bytes();
+    for (i, &byte) in bytes.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|i| bytes[i]);
+        let next = bytes.get(i + 1).copied();
+        let assignment = byte == b'='
+            && !matches!(previous, Some(b'=' | b'~' | b'<' | b'>'))
+            && next != Some(b'=');
+        let method_call = byte == b':' && previous != Some(b':') && next != Some(b':');
+        if assignment || method_call {
+            return false;
+        }
+    }
+    let names = code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'));
+    !names.filter(|name| !name.is_empty()).any(|name| {
+        let name = name.trim_matches('.');
+        name == "local"
+            || name == "function"
+            || DENIED.iter().any(|denied| {
+                name == *denied
+                    || name
+                        .strip_prefix(denied)
+                        .is_some_and(|rest| rest.starts_with('.'))
+            })
+    })
+}
+
+/// Cached responses keyed by whether the eval was expression-only and its
+/// source.
+#[derive(Debug, Default)]
+pub(crate) struct ResultCache {
+    entries: HashMap<(bool, String), EvalResponse>,
+    pub hits: u64,
+}
+
+impl ResultCache {
+    pub fn get(&mut self, expression_only: bool, source: &str) -> Option<EvalResponse> {
+        let hit = self
+            .entries
+            .get(&(expression_only, source.to_string()))
+            .cloned();
+        if hit.is_some() {
+            self.hits += 1;
+        }
+        hit
+    }
+
+    pub fn insert(&mut self, expression_only: bool, source: String, response: EvalResponse) {
+        if self.entries.len() >= CAPACITY {
+            self.entries.clear();
+        }
+        self.entries.insert((expression_only, source), response);
+    }
+
+    /// Forgets everything, after globals may have changed.
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[test]
+    fn test_is_pure() {
+        assert!(is_pure("return x + 1"));
+        assert!(is_pure("return a == b and c ~= d and e <= f"));
+        assert!(is_pure("return t.items[1].name, #t, string.upper('x = 1')"));
+        assert!(is_pure("return 1 -- x = 2"));
+        assert!(!is_pure("x = 1"));
+        assert!(!is_pure("return os.time()"));
+        assert!(!is_pure("return math.random(6)"));
+        assert!(!is_pure("return stack:pop()"));
+        assert!(!is_pure("return (function() x = 1 end)()"));
+        assert!(!is_pure("print [[ hi ]]"));
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let mut session = Session::builder().cache(true).build();
+        session.eval("x = 1".to_string()).await;
+        session.eval("return x".to_string()).await;
+        let resp = session.eval("return x".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(1));
+        assert_eq!(session.cache_hits(), 1);
+
+        session.eval("x = 2".to_string()).await;
+        let resp = session.eval("return x".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(2));
+        session
+            .set_path("x".to_string(), LuaValue::Number(3.0))
+            .await
+            .unwrap();
+        let resp = session.eval("return x".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Number(3.0));
+        assert_eq!(session.cache_hits(), 1);
+
+        // Work queued through handles runs outside `eval_with`.
+        let handle = session.handle();
+        session
+            .eval("events.on('set', function(v) x = v end)".to_string())
+            .await;
+        session.eval("return x".to_string()).await;
+        assert!(handle.emit("set", LuaValue::Integer(4)));
+        let resp = session.eval("return x".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(4));
+        let set = handle.eval("x = 5".to_string());
+        set.await.unwrap();
+        let resp = session.eval("return x".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(5));
+        assert_eq!(session.cache_hits(), 1);
+    }
+}
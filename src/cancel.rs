@@ -0,0 +1,100 @@
+//! Cancelling the running eval from outside the session, e.g. on Ctrl-C,
+//! see `Session::cancel_token`.
+//!
+//! Lua code notices at its next instruction hook, the same way it runs into
+//! the instruction limit. A pending async host function call is dropped, so
+//! the I/O it was waiting on is abandoned, and the eval sees it fail with
+//! `Cancelled`. Blocking host functions run on the interpreter thread where
+//! the hook cannot interrupt them: to be cancellable, they capture the token
+//! passed to `SessionBuilder::cancel_token`, check it during long operations
+//! and return `Cancelled` once it is set.
+
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often `CancelToken::cancelled` checks the token.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The error of evals and host function calls that were cancelled.
+pub const CANCELLED: &str = "eval cancelled";
+
+/// A clonable flag set to cancel a session's running eval. It is cleared
+/// whenever an eval starts, so cancelling while no eval runs does nothing.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the token is cancelled, for racing against futures.
+    pub async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
+
+/// What host functions return when they stop because of a `CancelToken`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(CANCELLED)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let token = CancelToken::new();
+        let mut session = Session::builder()
+            .cancel_token(token.clone())
+            .register_async_function("fetch", |_| async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(LuaValue::Nil)
+            })
+            .build();
+
+        let cancel = |token: CancelToken| async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            token.cancel();
+        };
+        tokio::spawn(cancel(token.clone()));
+        let resp = session.eval("while true do end".to_string()).await;
+        assert!(resp.error.unwrap().contains(CANCELLED));
+
+        tokio::spawn(cancel(session.cancel_token()));
+        let resp = session.eval("return fetch()".to_string()).await;
+        assert!(resp.error.unwrap().contains(CANCELLED));
+
+        let resp = session.eval("return 1".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(1));
+    }
+}
@@ -0,0 +1,191 @@
+//! What evals print, collected into `EvalResponse::output` and
+//! `EvalResponse::stderr` instead of going to the process's own streams, see
+//! `SessionBuilder::capture_output`.
+//!
+//! `print`, `io.write` and the `write` method of files are replaced. Writes
+//! to `io.stdout` and `io.stderr` are captured, whether through the handles
+//! or through `io.write` after `io.output` selected them; other files are
+//! written as usual.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+
+const TAKE_KEY: &str = "luarepl.capture";
+
+/// How many bytes of each stream one eval keeps; the rest is counted.
+const MAX_OUTPUT: usize = 1 << 20;
+
+/// Replaces the writing functions and returns a function taking the text
+/// written to each stream since it was last called, and how many bytes of
+/// each were left out.
+const INSTALL_SOURCE: &str = r##"
+local max = ...
+local select, tostring, type, error, concat, getmetatable =
+    select, tostring, type, error, table.concat, getmetatable
+local output, stdout, stderr = io.output, io.stdout, io.stderr
+
+local function stream()
+    return { chunks = {}, size = 0, dropped = 0 }
+end
+local out, err = stream(), stream()
+
+local function append(s, text)
+    if s.size + #text <= max then
+        s.chunks[#s.chunks + 1] = text
+        s.size = s.size + #text
+    else
+        s.dropped = s.dropped + #text
+    end
+end
+
+local function capture(s, name, ...)
+    for i = 1, select("#", ...) do
+        local part = select(i, ...)
+        local kind = type(part)
+        if kind ~= "string" and kind ~= "number" then
+            error("bad argument #" .. i .. " to '" .. name .. "' (string expected, got " .. kind .. ")", 3)
+        end
+        append(s, tostring(part))
+    end
+end
+
+local function captured(file)
+    if file == stdout then
+        return out
+    elseif file == stderr then
+        return err
+    end
+end
+
+function print(...)
+    local parts = { ... }
+    for i = 1, select("#", ...) do
+        parts[i] = tostring(parts[i])
+    end
+    append(out, concat(parts, "\t", 1, select("#", ...)) .. "\n")
+end
+
+local write = io.write
+function io.write(...)
+    local file = output()
+    local s = captured(file)
+    if s == nil then
+        return write(...)
+    end
+    capture(s, "write", ...)
+    return file
+end
+
+local methods = getmetatable(stdout).__index
+local file_write = methods.write
+function methods.write(file, ...)
+    local s = captured(file)
+    if s == nil then
+        return file_write(file, ...)
+    end
+    capture(s, "write", ...)
+    return file
+end
+
+local function drain(s)
+    local text, dropped = concat(s.chunks), s.dropped
+    s.chunks, s.size, s.dropped = {}, 0, 0
+    return text, dropped
+end
+
+return function()
+    local out_text, out_dropped = drain(out)
+    local err_text, err_dropped = drain(err)
+    return out_text, out_dropped, err_text, err_dropped
+end
+"##;
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let take: Function = ctx
+        .load(INSTALL_SOURCE)
+        .set_name("=capture")?
+        .call(MAX_OUTPUT)?;
+    ctx.set_named_registry_value(TAKE_KEY, take)
+}
+
+/// The text of one stream as lines; a last line the eval did not end is
+/// included as it is.
+fn lines(text: &str, dropped: usize) -> Vec<String> {
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    if dropped > 0 {
+        lines.push(format!("({} more bytes)", dropped));
+    }
+    lines
+}
+
+/// The lines written to stdout and to stderr since the last call.
+pub(crate) fn take(ctx: Context) -> (Vec<String>, Vec<String>) {
+    let taken: Result<(String, usize, String, usize), Error> = ctx
+        .named_registry_value::<_, Function>(TAKE_KEY)
+        .and_then(|take| take.call(()));
+    match taken {
+        Ok((out, out_dropped, err, err_dropped)) => {
+            (lines(&out, out_dropped), lines(&err, err_dropped))
+        }
+        Err(_) => (vec![], vec![]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_print_and_write() {
+        let mut session = Session::builder().capture_output(true).build();
+        let resp = session
+            .eval("print('a', 1, nil) io.write('b', 2, '\\n', 'c') return 3".to_string())
+            .await;
+        assert_eq!(resp.output, ["a\t1\tnil", "b2", "c"]);
+        assert_eq!(resp.value, LuaValue::Integer(3));
+        let resp = session
+            .eval("return io.write('x') == io.stdout".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Boolean(true));
+        assert_eq!(resp.output, ["x"]);
+        let resp = session.eval("io.write({})".to_string()).await;
+        assert!(!resp.success);
+
+        let resp = Session::new().eval("return 1".to_string()).await;
+        assert!(resp.output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stderr() {
+        let mut session = Session::builder().capture_output(true).build();
+        let resp = session
+            .eval(
+                "io.stderr:write('oops\\n') io.stdout:write('fine\\n')\n\
+                 io.output(io.stderr) io.write('again') io.output(io.stdout)\n\
+                 local f = io.tmpfile() f:write('kept') f:seek('set') return f:read('a')"
+                    .to_string(),
+            )
+            .await;
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(resp.output, ["fine"]);
+        assert_eq!(resp.stderr, ["oops", "again"]);
+        assert_eq!(resp.value, LuaValue::String("kept".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_failed_eval() {
+        let mut session = Session::builder().capture_output(true).build();
+        let resp = session
+            .eval("print('before') error('boom')".to_string())
+            .await;
+        assert!(!resp.success);
+        assert_eq!(resp.output, ["before"]);
+        let resp = session
+            .eval("for i = 1, 200000 do io.write('0123456789') end".to_string())
+            .await;
+        assert_eq!(resp.output.len(), 2);
+        assert_eq!(resp.output[1], "(951430 more bytes)");
+    }
+}
@@ -0,0 +1,169 @@
+//! Errors that scripts catch themselves with `pcall` and `xpcall`, so that
+//! failures a script swallows still show up, see
+//! `SessionBuilder::caught_errors`.
+//!
+//! Both are wrapped to record the error before handing it back, or to the
+//! message handler of `xpcall`. Errors are told apart by their `tostring`,
+//! of which the most recently caught `MESSAGES_KEPT` are kept.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Table;
+
+const CAUGHT_KEY: &str = "luarepl.caught";
+
+/// How many distinct messages a report remembers.
+const MESSAGES_KEPT: usize = 64;
+
+/// Wraps `pcall` and `xpcall`, and returns the function reporting what they
+/// caught.
+const INSTALL_SOURCE: &str = r#"
+local max_kept = ...
+local pcall, xpcall, tostring, type, ipairs, remove =
+    pcall, xpcall, tostring, type, ipairs, table.remove
+local total, entries, by_message = 0, {}, {}
+
+local function record(e)
+    total = total + 1
+    local ok, message = pcall(tostring, e)
+    if not ok then
+        message = "(" .. type(e) .. " error object)"
+    end
+    local entry = by_message[message]
+    if entry == nil then
+        if #entries >= max_kept then
+            local oldest = 1
+            for i = 2, #entries do
+                if entries[i].last < entries[oldest].last then
+                    oldest = i
+                end
+            end
+            by_message[remove(entries, oldest).message] = nil
+        end
+        entry = { message = message, count = 0 }
+        entries[#entries + 1] = entry
+        by_message[message] = entry
+    end
+    entry.count = entry.count + 1
+    entry.last = total
+end
+
+local function check(ok, ...)
+    if not ok then
+        record((...))
+    end
+    return ok, ...
+end
+
+_ENV.pcall = function(f, ...)
+    return check(pcall(f, ...))
+end
+
+_ENV.xpcall = function(f, handler, ...)
+    return xpcall(f, function(e)
+        record(e)
+        return handler(e)
+    end, ...)
+end
+
+return function()
+    local copy = {}
+    for i, entry in ipairs(entries) do
+        copy[i] = { message = entry.message, count = entry.count, last = entry.last }
+    end
+    return { total = total, messages = copy }
+end
+"#;
+
+/// One message `pcall` or `xpcall` caught.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaughtError {
+    pub message: String,
+    pub count: u64,
+    /// Which catch was the last with this message, counting from 1, so the
+    /// latest is `CaughtErrors::total`.
+    pub last: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaughtErrors {
+    /// How many errors were caught.
+    pub total: u64,
+    /// The messages caught, the most recently caught first.
+    pub messages: Vec<CaughtError>,
+}
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let report: Function = ctx
+        .load(INSTALL_SOURCE)
+        .set_name("=caught")?
+        .call(MESSAGES_KEPT)?;
+    ctx.set_named_registry_value(CAUGHT_KEY, report)
+}
+
+/// What was caught since the session started or was last reset, if the
+/// wrappers are installed.
+pub(crate) fn report(ctx: Context) -> Option<CaughtErrors> {
+    let report: Function = ctx.named_registry_value(CAUGHT_KEY).ok()?;
+    let report: Table = report.call(()).ok()?;
+    let messages: Table = report.get("messages").ok()?;
+    let mut messages: Vec<_> = messages
+        .sequence_values::<Table>()
+        .filter_map(Result::ok)
+        .map(|entry| CaughtError {
+            message: entry.get("message").unwrap_or_default(),
+            count: entry.get("count").unwrap_or(0),
+            last: entry.get("last").unwrap_or(0),
+        })
+        .collect();
+    messages.sort_by_key(|caught| std::cmp::Reverse(caught.last));
+    Some(CaughtErrors {
+        total: report.get("total").unwrap_or(0),
+        messages,
+    })
+}
+
+/// The report `:caught` prints.
+pub fn text(report: &CaughtErrors) -> String {
+    let mut text = format!("{} errors caught\n", report.total);
+    for caught in &report.messages {
+        text.push_str(&format!(
+            "{:>6} {:>6}  {}\n",
+            caught.count,
+            format!("#{}", caught.last),
+            caught.message
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_caught_errors() {
+        let mut session = Session::builder().caught_errors(true).build();
+        let resp = session
+            .eval(
+                "for i = 1, 3 do pcall(error, 'retry', 0) end\n\
+                 local ok, e = xpcall(error, function(e) return 'handled ' .. e end, 'bad', 0)\n\
+                 return ok, e, pcall(tostring, 1)"
+                    .to_string(),
+            )
+            .await;
+        assert_eq!(resp.to_string(), "false, \"handled bad\", true, \"1\"");
+
+        let report = session.caught_errors().await.unwrap();
+        assert_eq!(report.total, 4);
+        let messages: Vec<_> = report
+            .messages
+            .iter()
+            .map(|caught| (caught.message.as_str(), caught.count, caught.last))
+            .collect();
+        assert_eq!(messages, vec![("bad", 1, 4), ("retry", 3, 3)]);
+
+        assert!(Session::new().caught_errors().await.is_none());
+    }
+}
@@ -0,0 +1,117 @@
+//! Injected time and randomness, so that embedders can test Lua-scripted
+//! behavior deterministically: `os.time` and `os.date` read the clock set
+//! with `SessionBuilder::with_clock`, and `math.random` starts from the seed
+//! set with `SessionBuilder::with_rng_seed` whenever the state (re)starts.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Table;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Wraps the standard `os.time` and `os.date` so that "now" is `clock()`.
+const CLOCK_SOURCE: &str = r#"
+local clock, time, date = ...
+os.time = function(t)
+    if t == nil then return clock() end
+    return time(t)
+end
+os.date = function(format, t)
+    return date(format, t or clock())
+end
+"#;
+
+/// The wall clock a session reads instead of the system's. It is called on
+/// the interpreter thread, once per `os.time()` or `os.date()`.
+#[derive(Clone)]
+pub struct Clock(Arc<dyn Fn() -> SystemTime + Send + Sync>);
+
+impl Clock {
+    pub fn new(clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        Self(Arc::new(clock))
+    }
+
+    /// Whole seconds since the epoch, negative before it.
+    fn seconds(&self) -> i64 {
+        match (self.0)().duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        }
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Clock")
+    }
+}
+
+/// Points `os.time` and `os.date` at `clock` and seeds `math.random`, each
+/// only if set.
+pub(crate) fn install(ctx: Context, clock: Option<&Clock>, seed: Option<u64>) -> Result<(), Error> {
+    let globals = ctx.globals();
+    if let Some(clock) = clock {
+        let clock = clock.clone();
+        let now = ctx.create_function(move |_, ()| Ok(clock.seconds()))?;
+        let os: Table = globals.get("os")?;
+        let time: Function = os.get("time")?;
+        let date: Function = os.get("date")?;
+        ctx.load(CLOCK_SOURCE)
+            .set_name("=clock")?
+            .call::<_, ()>((now, time, date))?;
+    }
+    if let Some(seed) = seed {
+        let math: Table = globals.get("math")?;
+        let randomseed: Function = math.get("randomseed")?;
+        randomseed.call::<_, ()>(seed as i64)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test]
+    async fn test_clock() {
+        let now = Arc::new(AtomicU64::new(86400));
+        let mut session = {
+            let now = now.clone();
+            Session::builder()
+                .with_clock(move || UNIX_EPOCH + Duration::from_secs(now.load(Ordering::Relaxed)))
+                .build()
+        };
+        let resp = session.eval("return os.time()".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(86400));
+        now.store(86400 * 31, Ordering::Relaxed);
+        let resp = session
+            .eval("return os.date('!%Y-%m-%d')".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::String("1970-02-01".to_string()));
+        let resp = session
+            .eval("return os.time({ year = 2000, month = 1, day = 1 }) > 0".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Boolean(true));
+    }
+
+    #[tokio::test]
+    async fn test_rng_seed() {
+        const ROLLS: &str = "local t = {} for i = 1, 5 do t[i] = math.random(1000) end \
+                             return table.concat(t, ',')";
+        let seeded = || Session::builder().with_rng_seed(42).build();
+        let (mut a, mut b) = (seeded(), seeded());
+        let first = a.eval(ROLLS.to_string()).await.value;
+        assert_eq!(b.eval(ROLLS.to_string()).await.value, first);
+        a.reset().await;
+        assert_eq!(a.eval(ROLLS.to_string()).await.value, first);
+    }
+}
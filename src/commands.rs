@@ -0,0 +1,212 @@
+//! REPL commands starting with `:`, handled by the frontend instead of being
+//! evaluated as Lua.
+
+use crate::help;
+use crate::help::HelpTopic;
+use crate::path;
+use crate::LuaValue;
+
+#[derive(Debug, PartialEq)]
+pub enum MetaCommand {
+    /// `:grep <pattern>` searches keys and string values reachable from `_G`.
+    Grep(String),
+    /// `:refs <ref>` lists the paths from `_G` to an object, by object id or
+    /// path.
+    Refs(String),
+    /// `:help [topic]` shows a reference entry, or lists the commands.
+    Help(Option<String>),
+    /// `:apropos <pattern>` lists reference topics mentioning `pattern`.
+    Apropos(String),
+    /// `:reload` re-reads the settings file, like SIGHUP.
+    Reload,
+    /// `:config` shows the settings in effect.
+    Config,
+    /// `:config set <key> <value>` changes a setting for the running REPL.
+    ConfigSet { key: String, value: String },
+    /// `:config save` writes the settings in effect to the settings file.
+    ConfigSave,
+    /// `:leaks` lists pins that are still held by where they were created.
+    Leaks,
+    /// `:more <id>` prints what a truncation marker in the output elided.
+    More(usize),
+    /// `:memprofile` shows what the recent evals and functions allocated.
+    MemProfile,
+    /// `:gcreport` shows which objects with finalizers were collected.
+    GcReport,
+    /// `:caught` shows the errors `pcall` and `xpcall` caught.
+    Caught,
+    /// `:ui [path]` explores the globals, or the table at `path`, full
+    /// screen.
+    Ui(Option<String>),
+    /// `:history <path>` lists the values a recorded path had.
+    History(String),
+    /// `:export-transcript <file>` writes the inputs so far and what came of
+    /// them as Markdown, or HTML for a `.html` file.
+    ExportTranscript(String),
+    /// `:set <path> = <literal>` stores a value through the path API.
+    Set { path: String, value: LuaValue },
+}
+
+impl MetaCommand {
+    /// Returns `None` if `line` is Lua source rather than a meta-command.
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let line = line.trim().strip_prefix(':')?;
+        let (name, args) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+        Some(match name {
+            "grep" if args.is_empty() => Err("usage: :grep <pattern>".to_string()),
+            "grep" => Ok(MetaCommand::Grep(args.to_string())),
+            "refs" if args.is_empty() => Err("usage: :refs <ref>".to_string()),
+            "refs" => Ok(MetaCommand::Refs(args.to_string())),
+            "help" if args.is_empty() => Ok(MetaCommand::Help(None)),
+            "help" => Ok(MetaCommand::Help(Some(args.to_string()))),
+            "apropos" if args.is_empty() => Err("usage: :apropos <pattern>".to_string()),
+            "apropos" => Ok(MetaCommand::Apropos(args.to_string())),
+            "reload" => Ok(MetaCommand::Reload),
+            "config" => parse_config(args),
+            "leaks" => Ok(MetaCommand::Leaks),
+            "memprofile" => Ok(MetaCommand::MemProfile),
+            "gcreport" => Ok(MetaCommand::GcReport),
+            "caught" => Ok(MetaCommand::Caught),
+            "ui" if args.is_empty() => Ok(MetaCommand::Ui(None)),
+            "ui" => Ok(MetaCommand::Ui(Some(args.to_string()))),
+            "set" => parse_set(args),
+            "history" if args.is_empty() => Err("usage: :history <path>".to_string()),
+            "history" => Ok(MetaCommand::History(args.to_string())),
+            "export-transcript" if args.is_empty() => {
+                Err("usage: :export-transcript <file>".to_string())
+            }
+            "export-transcript" => Ok(MetaCommand::ExportTranscript(args.to_string())),
+            "more" => match args.parse() {
+                Ok(id) => Ok(MetaCommand::More(id)),
+                Err(_) => Err("usage: :more <id>".to_string()),
+            },
+            _ => Err(format!("unknown command :{}", name)),
+        })
+    }
+}
+
+/// The meta-commands as the `:help` reference describes them, in the order
+/// it lists them, for `:help` and generated documentation.
+pub fn commands() -> Vec<&'static HelpTopic> {
+    help::topics()
+        .iter()
+        .filter(|topic| topic.name.starts_with(':'))
+        .collect()
+}
+
+fn parse_config(args: &str) -> Result<MetaCommand, String> {
+    let mut words = args.split_whitespace();
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (None, ..) => Ok(MetaCommand::Config),
+        (Some("save"), None, ..) => Ok(MetaCommand::ConfigSave),
+        (Some("set"), Some(key), Some(value), None) => Ok(MetaCommand::ConfigSet {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+        _ => Err("usage: :config [set <key> <value> | save]".to_string()),
+    }
+}
+
+fn parse_set(args: &str) -> Result<MetaCommand, String> {
+    let usage = || "usage: :set <path> = <value>".to_string();
+    // The path may contain `=` in a quoted key.
+    let mut quote = None;
+    let equals = args.char_indices().find_map(|(i, c)| {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '=') => return Some(i),
+            _ => {}
+        }
+        None
+    });
+    let equals = equals.ok_or_else(usage)?;
+    let path = args[..equals].trim();
+    path::parse_path(path).map_err(|e| format!("bad path: {}", e))?;
+    let value =
+        path::parse_literal(&args[equals + 1..]).map_err(|e| format!("bad value: {}", e))?;
+    Ok(MetaCommand::Set {
+        path: path.to_string(),
+        value,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(MetaCommand::parse("x = 1"), None);
+        assert_eq!(
+            MetaCommand::parse(":grep  host name "),
+            Some(Ok(MetaCommand::Grep("host name".to_string())))
+        );
+        assert!(matches!(MetaCommand::parse(":grep"), Some(Err(_))));
+        assert_eq!(
+            MetaCommand::parse(":refs table: 0x5581c0a3e2f0"),
+            Some(Ok(MetaCommand::Refs("table: 0x5581c0a3e2f0".to_string())))
+        );
+        assert_eq!(MetaCommand::parse(":reload"), Some(Ok(MetaCommand::Reload)));
+        assert_eq!(
+            MetaCommand::parse(":config set limits.memory 1024"),
+            Some(Ok(MetaCommand::ConfigSet {
+                key: "limits.memory".to_string(),
+                value: "1024".to_string(),
+            }))
+        );
+        assert!(matches!(
+            MetaCommand::parse(":config set hardened"),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            MetaCommand::parse(":help string.gsub"),
+            Some(Ok(MetaCommand::Help(Some("string.gsub".to_string()))))
+        );
+        assert!(matches!(MetaCommand::parse(":apropos"), Some(Err(_))));
+        assert_eq!(
+            MetaCommand::parse(":more 4"),
+            Some(Ok(MetaCommand::More(4)))
+        );
+        assert!(matches!(MetaCommand::parse(":more x"), Some(Err(_))));
+        assert!(matches!(MetaCommand::parse(":nope"), Some(Err(_))));
+        assert_eq!(
+            MetaCommand::parse(":set world.players[\"a=b\"].hp = 100"),
+            Some(Ok(MetaCommand::Set {
+                path: "world.players[\"a=b\"].hp".to_string(),
+                value: LuaValue::Integer(100),
+            }))
+        );
+        assert!(matches!(MetaCommand::parse(":set x"), Some(Err(_))));
+        assert_eq!(
+            MetaCommand::parse(":history world.score"),
+            Some(Ok(MetaCommand::History("world.score".to_string())))
+        );
+        assert_eq!(
+            MetaCommand::parse(":export-transcript report.md"),
+            Some(Ok(MetaCommand::ExportTranscript("report.md".to_string())))
+        );
+        assert!(matches!(
+            MetaCommand::parse(":set x = os.exit()"),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_commands() {
+        // Every documented command is one `parse` knows.
+        for topic in commands() {
+            let line = topic.signature.split_whitespace().next().unwrap();
+            assert_ne!(
+                MetaCommand::parse(line),
+                Some(Err(format!("unknown command {}", line))),
+                "{}",
+                topic.name
+            );
+        }
+        assert!(commands().iter().any(|topic| topic.name == ":set"));
+    }
+}
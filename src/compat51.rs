@@ -0,0 +1,177 @@
+//! Shims for scripts written against Lua 5.1, see
+//! `SessionBuilder::compat51`, so they can be explored without porting them
+//! first.
+//!
+//! `setfenv` and `getfenv` are emulated through a function's `_ENV`
+//! upvalue: setting the environment of a function that uses no globals
+//! does nothing, and the global environment itself (level 0) cannot be
+//! replaced.
+
+use rlua::Error;
+use rlua::Lua;
+use rlua::StdLib;
+
+/// Takes the parts of the debug library the emulation needs and defines
+/// the 5.1 globals that later versions removed.
+const INSTALL_SOURCE: &str = r#"
+local getupvalue, upvaluejoin, getinfo = debug.getupvalue, debug.upvaluejoin, debug.getinfo
+debug = nil
+package.loaded.debug = nil
+local type, error, load, pairs, globals = type, error, load, pairs, _G
+
+unpack = table.unpack
+
+function loadstring(source, chunkname)
+    if type(source) ~= "string" then
+        error("bad argument #1 to 'loadstring' (string expected, got " .. type(source) .. ")", 2)
+    end
+    return load(source, chunkname)
+end
+
+function table.getn(t)
+    return #t
+end
+
+function table.maxn(t)
+    local max = 0
+    for k in pairs(t) do
+        if type(k) == "number" and k > max then
+            max = k
+        end
+    end
+    return max
+end
+
+math.pow = math.pow or function(x, y)
+    return x ^ y
+end
+
+math.log10 = math.log10 or function(x)
+    return math.log(x, 10)
+end
+
+local function env_index(f)
+    local i = 1
+    while true do
+        local name, value = getupvalue(f, i)
+        if name == "_ENV" then
+            return i, value
+        elseif name == nil then
+            return nil
+        end
+        i = i + 1
+    end
+end
+
+-- The function at stack `level` of setfenv's or getfenv's caller, or the
+-- function itself.
+local function target(f, name)
+    if type(f) == "function" then
+        return f
+    end
+    local level = f == nil and 1 or f
+    if type(level) ~= "number" or level < 0 then
+        error("bad argument #1 to '" .. name .. "' (number expected, got " .. type(f) .. ")", 3)
+    end
+    if level == 0 then
+        return nil
+    end
+    local info = getinfo(level + 2, "f")
+    if info == nil then
+        error("bad argument #1 to '" .. name .. "' (invalid level)", 3)
+    end
+    return info.func
+end
+
+function getfenv(f)
+    local func = target(f, "getfenv")
+    if func == nil then
+        return globals
+    end
+    local _, env = env_index(func)
+    return env == nil and globals or env
+end
+
+function setfenv(f, env)
+    if type(env) ~= "table" then
+        error("bad argument #2 to 'setfenv' (table expected, got " .. type(env) .. ")", 2)
+    end
+    local func = target(f, "setfenv")
+    if func == nil then
+        error("setfenv cannot change the global environment", 2)
+    end
+    if getinfo(func, "S").what == "C" then
+        error("'setfenv' cannot change environment of given object", 2)
+    end
+    local i = env_index(func)
+    if i ~= nil then
+        -- Joining a fresh upvalue leaves other closures sharing the old
+        -- `_ENV` untouched.
+        upvaluejoin(func, i, function() return env end, 1)
+    end
+    return func
+end
+"#;
+
+/// Installs the shims into a session's Lua state before any script runs.
+pub(crate) fn install(lua: &Lua) -> Result<(), Error> {
+    // Safety: as in `function::install`, the debug library is loaded only to
+    // be captured and is removed from the globals again.
+    unsafe { lua.unsafe_load_from_std_lib(StdLib::DEBUG)? };
+    lua.context(|ctx| ctx.load(INSTALL_SOURCE).set_name("=compat51")?.exec())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_removed_functions() {
+        let mut session = Session::builder().compat51(true).build();
+        let resp = session
+            .eval(
+                "local t = { 1, 2, 3, [10] = 4 }\n\
+                 local a, b = unpack(t)\n\
+                 local f = loadstring('return ...')\n\
+                 return a + b + table.getn({ 1, 2 }) + table.maxn(t) + f(100) + math.pow(2, 3)"
+                    .to_string(),
+            )
+            .await;
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(resp.value, LuaValue::Number(123.0));
+        let resp = session.eval("return debug".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Nil);
+
+        let resp = Session::new().eval("return unpack".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_environments() {
+        let mut session = Session::builder().compat51(true).build();
+        let resp = session
+            .eval(
+                "x = 'global'\n\
+                 local function f() return x end\n\
+                 local function g() return x end\n\
+                 setfenv(f, { x = 'sandboxed' })\n\
+                 return f() .. ' ' .. g() .. ' ' .. getfenv(f).x .. ' ' .. tostring(getfenv(0) == _G)"
+                    .to_string(),
+            )
+            .await;
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(
+            resp.value,
+            LuaValue::String("sandboxed global sandboxed true".to_string())
+        );
+        let resp = session
+            .eval("local function f() setfenv(1, { y = 2 }) return y end return f()".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Integer(2));
+        let resp = session.eval("setfenv(print, {})".to_string()).await;
+        assert!(!resp.success);
+        let resp = session.eval("setfenv(0, {})".to_string()).await;
+        assert!(!resp.success);
+    }
+}
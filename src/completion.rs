@@ -0,0 +1,189 @@
+//! Completion candidates for what has been typed so far, such as
+//! `string.fo` or `player:ta`, from the globals and the tables they reach,
+//! see `Session::complete`.
+//!
+//! Only the name at the end of the input is completed, after the path to
+//! the table it is in; anything before the path is kept as it was typed.
+//! Fields a table inherits through the `__index` table of its metatable are
+//! offered too, and the string library after `name:` for strings, but
+//! nothing is called to find them or the tables along the path, so neither
+//! `__index` functions nor `__pairs` are.
+
+use crate::path;
+use crate::path::is_identifier;
+use crate::path::PathSegment;
+use crate::path::KEYWORDS;
+use rlua::Context;
+use rlua::Table;
+use rlua::Value;
+use std::collections::BTreeSet;
+
+/// How many `__index` tables are followed from a table, so that a loop of
+/// them ends.
+const MAX_INHERITED: usize = 8;
+
+/// The ways `input` can go on, each the whole of `input` with the name at
+/// its end completed, sorted.
+pub(crate) fn candidates(ctx: Context, input: &str) -> Vec<String> {
+    let start = input
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| c.is_alphanumeric() || matches!(c, '_' | '.' | ':'))
+        .last()
+        .map_or(input.len(), |(i, _)| i);
+    let (head, word) = input.split_at(start);
+    if word.starts_with(|c: char| c.is_ascii_digit()) {
+        return vec![];
+    }
+    let mut names = BTreeSet::new();
+    let table_path = match word.rfind(['.', ':']) {
+        None => {
+            collect(ctx.globals(), word, false, &mut names);
+            names.extend(
+                KEYWORDS
+                    .iter()
+                    .filter(|keyword| keyword.starts_with(word))
+                    .map(|keyword| keyword.to_string()),
+            );
+            ""
+        }
+        Some(at) => {
+            let methods = word[at..].starts_with(':');
+            let value = path::parse_path(&word[..at])
+                .ok()
+                .and_then(|segments| resolve(ctx, &segments));
+            let table = match value {
+                Some(Value::Table(table)) => table,
+                Some(Value::String(_)) if methods => match ctx.globals().raw_get("string") {
+                    Ok(table) => table,
+                    Err(_) => return vec![],
+                },
+                _ => return vec![],
+            };
+            collect(table, &word[at + 1..], methods, &mut names);
+            &word[..=at]
+        }
+    };
+    names
+        .into_iter()
+        .map(|name| format!("{}{}{}", head, table_path, name))
+        .collect()
+}
+
+/// The value at the end of `segments` from `_G`, read raw from each table
+/// or the tables it inherits from.
+fn resolve<'l>(ctx: Context<'l>, segments: &[PathSegment]) -> Option<Value<'l>> {
+    let mut current = Value::Table(ctx.globals());
+    for segment in segments {
+        let Value::Table(table) = current else {
+            return None;
+        };
+        current = inherited(table, path::segment_value(ctx, segment).ok()?);
+    }
+    Some(current)
+}
+
+/// `table[key]`, from `table` or the tables it inherits from.
+fn inherited<'l>(table: Table<'l>, key: Value<'l>) -> Value<'l> {
+    let mut table = Some(table);
+    for _ in 0..=MAX_INHERITED {
+        let Some(current) = table.take() else {
+            break;
+        };
+        match current.raw_get(key.clone()) {
+            Ok(Value::Nil) | Err(_) => table = parent(&current),
+            Ok(value) => return value,
+        }
+    }
+    Value::Nil
+}
+
+/// The `__index` table of `table`'s metatable, if it has one.
+fn parent<'l>(table: &Table<'l>) -> Option<Table<'l>> {
+    table
+        .get_metatable()
+        .and_then(|metatable| match metatable.raw_get("__index") {
+            Ok(Value::Table(parent)) => Some(parent),
+            _ => None,
+        })
+}
+
+/// Adds the names in `table` and the tables it inherits from that start
+/// with `partial`, only those of functions if `methods`.
+fn collect(table: Table, partial: &str, methods: bool, names: &mut BTreeSet<String>) {
+    let mut table = Some(table);
+    for _ in 0..=MAX_INHERITED {
+        let Some(current) = table.take() else {
+            break;
+        };
+        for (key, value) in current
+            .clone()
+            .pairs::<Value, Value>()
+            .filter_map(Result::ok)
+        {
+            let Value::String(key) = key else {
+                continue;
+            };
+            let Ok(name) = key.to_str() else {
+                continue;
+            };
+            if name.starts_with(partial)
+                && is_identifier(name)
+                && (!methods || matches!(value, Value::Function(_)))
+            {
+                names.insert(name.to_string());
+            }
+        }
+        table = parent(&current);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_complete() {
+        let mut session = Session::new();
+        session
+            .eval(
+                "Player = { take = function() end, tag = 'p' }\n\
+                 Player.__index = Player\n\
+                 Player.stats = { strength = 3 }\n\
+                 lazy = setmetatable({}, { __index = function() called = true return {} end })\n\
+                 player = setmetatable({ name = 'Ada', next_level = 2 }, Player)\n\
+                 name = 'Ada'"
+                    .to_string(),
+            )
+            .await;
+        assert_eq!(session.complete("string.fo").await, ["string.format"]);
+        assert_eq!(
+            session.complete("player.n").await,
+            ["player.name", "player.next_level"]
+        );
+        assert_eq!(
+            session.complete("player.ta").await,
+            ["player.tag", "player.take"]
+        );
+        assert_eq!(session.complete("x = player:ta").await, ["x = player:take"]);
+        assert_eq!(
+            session.complete("print(name:up").await,
+            ["print(name:upper"]
+        );
+        assert_eq!(session.complete("fun").await, ["function"]);
+        assert_eq!(session.complete("setm").await, ["setmetatable"]);
+        assert!(session.complete("missing.x").await.is_empty());
+        assert!(session.complete("name.x").await.is_empty());
+        assert!(session.complete("1.5").await.is_empty());
+
+        // Tables along the path are inherited too, but never computed.
+        assert_eq!(
+            session.complete("player.stats.st").await,
+            ["player.stats.strength"]
+        );
+        assert!(session.complete("lazy.child.x").await.is_empty());
+        let called = session.eval("return called".to_string()).await;
+        assert_eq!(called.value, LuaValue::Nil);
+    }
+}
@@ -0,0 +1,269 @@
+//! Per-message compression for transports. Serialized object graphs of
+//! large tables are repetitive and shrink to a fraction of their size, but
+//! small messages are not worth the CPU, so only payloads above a threshold
+//! are compressed.
+//!
+//! Peers agree on a codec once, at handshake: the client lists the codecs it
+//! can decode and `negotiate` picks the server's most preferred one among
+//! them. Every message then starts with a byte naming how it was encoded, so
+//! either side can still send small messages uncompressed. `server::serve`
+//! negotiates with `hello`, and wraps the connection in `Compressed`.
+
+use crate::transport::Connection;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+/// First byte of a message that follows uncompressed.
+const RAW: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// The codec's name at handshake.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Codec::Gzip => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Codec::Gzip),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// What a server offers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compression {
+    /// Codecs in order of preference; empty to never compress.
+    pub codecs: Vec<Codec>,
+    /// Smallest payload compressed, in bytes.
+    pub threshold: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            codecs: vec![Codec::Zstd, Codec::Gzip],
+            threshold: 1024,
+        }
+    }
+}
+
+impl Compression {
+    /// The codec to use with a client that can decode `offered`, by name;
+    /// unknown names are ignored.
+    pub fn negotiate<'a>(&self, offered: impl IntoIterator<Item = &'a str>) -> Option<Codec> {
+        let offered: Vec<Codec> = offered.into_iter().filter_map(Codec::from_name).collect();
+        self.codecs
+            .iter()
+            .copied()
+            .find(|codec| offered.contains(codec))
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressionError {
+    /// The message was empty, so it did not even say how it was encoded.
+    Empty,
+    /// The first byte names no known codec.
+    UnknownCodec(u8),
+    /// The codec could not decode the rest.
+    Corrupt(std::io::Error),
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressionError::Empty => write!(f, "empty message"),
+            CompressionError::UnknownCodec(tag) => write!(f, "unknown compression codec {}", tag),
+            CompressionError::Corrupt(e) => write!(f, "corrupt compressed message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {}
+
+/// The encoding of one connection's messages, once negotiated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    /// `None` if the peers share no codec.
+    pub codec: Option<Codec>,
+    pub threshold: usize,
+}
+
+impl Framing {
+    pub fn new(compression: &Compression, codec: Option<Codec>) -> Self {
+        Self {
+            codec,
+            threshold: compression.threshold,
+        }
+    }
+
+    /// `payload` as a message, compressed if it is large enough and that
+    /// makes it smaller.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let compressed = self
+            .codec
+            .filter(|_| payload.len() >= self.threshold)
+            .and_then(|codec| Some((codec, compress(codec, payload).ok()?)))
+            .filter(|(_, compressed)| compressed.len() < payload.len());
+        let (tag, body) = match &compressed {
+            Some((codec, compressed)) => (codec.tag(), compressed.as_slice()),
+            None => (RAW, payload),
+        };
+        let mut message = Vec::with_capacity(body.len() + 1);
+        message.push(tag);
+        message.extend_from_slice(body);
+        message
+    }
+
+    /// The payload of a message from `encode`, with whichever codec it names.
+    pub fn decode(&self, message: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let (&tag, body) = message.split_first().ok_or(CompressionError::Empty)?;
+        if tag == RAW {
+            return Ok(body.to_vec());
+        }
+        let codec = Codec::from_tag(tag).ok_or(CompressionError::UnknownCodec(tag))?;
+        decompress(codec, body).map_err(CompressionError::Corrupt)
+    }
+}
+
+/// A connection whose messages are encoded with a `Framing`, once set.
+#[derive(Debug)]
+pub struct Compressed<C> {
+    connection: C,
+    framing: Option<Framing>,
+}
+
+impl<C: Connection> Compressed<C> {
+    /// `connection`, with its messages as they are until `set_framing`.
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            framing: None,
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.connection
+    }
+
+    /// Encodes and decodes the messages from now on with `framing`.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = Some(framing);
+    }
+}
+
+impl<C: Connection> Connection for Compressed<C> {
+    async fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let message = self.connection.recv().await?;
+        match (message, &self.framing) {
+            (Some(message), Some(framing)) => framing
+                .decode(&message)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            (message, _) => Ok(message),
+        }
+    }
+
+    async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match &self.framing {
+            Some(framing) => self.connection.send(&framing.encode(message)).await,
+            None => self.connection.send(message).await,
+        }
+    }
+
+    fn peer(&self) -> String {
+        self.connection.peer()
+    }
+}
+
+fn compress(codec: Codec, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    match codec {
+        Codec::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(payload)?;
+            encoder.finish()
+        }
+        Codec::Zstd => zstd::encode_all(payload, 0),
+    }
+}
+
+fn decompress(codec: Codec, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match codec {
+        Codec::Gzip => {
+            let mut payload = vec![];
+            flate2::read::GzDecoder::new(body).read_to_end(&mut payload)?;
+            Ok(payload)
+        }
+        Codec::Zstd => zstd::decode_all(body),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let compression = Compression::default();
+        assert_eq!(compression.negotiate(["gzip", "zstd"]), Some(Codec::Zstd));
+        assert_eq!(compression.negotiate(["br", "gzip"]), Some(Codec::Gzip));
+        assert_eq!(compression.negotiate(["br"]), None);
+        let none = Compression {
+            codecs: vec![],
+            ..Default::default()
+        };
+        assert_eq!(none.negotiate(["gzip"]), None);
+    }
+
+    #[test]
+    fn test_framing() {
+        let graph = r#"{"id":"table: 0x1","members":[["hp",100]]},"#.repeat(200);
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            let framing = Framing::new(&Compression::default(), Some(codec));
+            let message = framing.encode(graph.as_bytes());
+            assert_eq!(message[0], codec.tag());
+            assert!(message.len() < graph.len() / 10);
+            assert_eq!(framing.decode(&message).unwrap(), graph.as_bytes());
+
+            let small = framing.encode(b"return 1");
+            assert_eq!(small, b"\0return 1");
+            assert_eq!(framing.decode(&small).unwrap(), b"return 1");
+        }
+        let framing = Framing::new(&Compression::default(), None);
+        assert_eq!(framing.encode(graph.as_bytes())[0], RAW);
+        assert!(matches!(
+            framing.decode(&[9, 1, 2]),
+            Err(CompressionError::UnknownCodec(9))
+        ));
+        assert!(matches!(framing.decode(&[]), Err(CompressionError::Empty)));
+    }
+}
@@ -0,0 +1,136 @@
+//! Coroutines in responses, see `LuaValue::Coroutine`.
+
+use crate::value_id;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Table;
+use rlua::Thread;
+use rlua::ThreadStatus;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+
+const STATUS_KEY: &str = "luarepl.coroutine_status";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoroutineStatus {
+    /// Not started yet, or waiting in `coroutine.yield`.
+    Suspended,
+    /// Running the eval that returned it.
+    Running,
+    /// Waiting for a coroutine it resumed.
+    Normal,
+    /// Returned or failed; it cannot be resumed.
+    Dead,
+}
+
+impl fmt::Display for CoroutineStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Normal => "normal",
+            CoroutineStatus::Dead => "dead",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoroutineRef {
+    /// Identifies the coroutine like the ids of `LuaValue::ObjectRef`, e.g.
+    /// `thread: 0x5581c0a3e2f0`.
+    pub id: String,
+    /// As `coroutine.status` reports it when the response is built.
+    pub status: CoroutineStatus,
+}
+
+impl CoroutineRef {
+    pub(crate) fn new<'l>(ctx: Context<'l>, thread: Thread<'l>) -> Self {
+        let status: Result<String, Error> = ctx
+            .named_registry_value::<_, Function>(STATUS_KEY)
+            .and_then(|status| status.call(thread.clone()));
+        let status = match status.as_deref() {
+            Ok("suspended") => CoroutineStatus::Suspended,
+            Ok("running") => CoroutineStatus::Running,
+            Ok("normal") => CoroutineStatus::Normal,
+            Ok(_) => CoroutineStatus::Dead,
+            // Without `coroutine.status`, e.g. in Lua states not created by a
+            // session, running and normal coroutines pass for dead ones.
+            Err(_) => match thread.status() {
+                ThreadStatus::Resumable => CoroutineStatus::Suspended,
+                _ => CoroutineStatus::Dead,
+            },
+        };
+        Self {
+            id: value_id(ctx, Value::Thread(thread)),
+            status,
+        }
+    }
+}
+
+/// Keeps `coroutine.status` for `CoroutineRef::new`, so that it still works
+/// after scripts replace the `coroutine` library.
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let coroutine: Table = ctx.globals().get("coroutine")?;
+    let status: Function = coroutine.get("status")?;
+    ctx.set_named_registry_value(STATUS_KEY, status)
+}
+
+impl fmt::Display for CoroutineRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.id, self.status)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+
+    fn status(value: &LuaValue) -> CoroutineStatus {
+        match value {
+            LuaValue::Coroutine(coroutine) => coroutine.status,
+            other => panic!("expected a coroutine, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let mut session = Session::new();
+        session
+            .eval("co = coroutine.create(function() coroutine.yield() end)".to_string())
+            .await;
+        let resp = session.eval("return co".to_string()).await;
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(status(&resp.value), CoroutineStatus::Suspended);
+        let resp = session
+            .eval("coroutine.resume(co) coroutine.resume(co) return co".to_string())
+            .await;
+        assert_eq!(status(&resp.value), CoroutineStatus::Dead);
+        let LuaValue::Coroutine(coroutine) = &resp.value else {
+            unreachable!();
+        };
+        assert!(coroutine.id.starts_with("thread: "));
+        assert!(coroutine.to_string().ends_with(" (dead)"));
+    }
+
+    #[tokio::test]
+    async fn test_replaced_library() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("local t = { co = coroutine.create(print) } coroutine = nil return t".to_string())
+            .await;
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        assert_eq!(
+            status(resp.objects[id].get("co").unwrap()),
+            CoroutineStatus::Suspended
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Reports of interpreter crashes, see `SessionBuilder::crash_reports`, for
+//! attaching to bug reports.
+//!
+//! When a command panics the interpreter, a text file named after the time
+//! is written to the configured directory with the panic message and
+//! backtrace, the session's features and limits, how much memory the Lua
+//! state used, and the inputs the session ran last. The panic then carries
+//! on as it would have, so the session recovers or dies as configured.
+
+use crate::info::SessionInfo;
+use crate::Command;
+use crate::SessionConfig;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io;
+use std::panic;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Once;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// How many of the last inputs a report lists.
+const INPUTS_KEPT: usize = 20;
+
+thread_local! {
+    /// The message and backtrace of the panic the thread is unwinding from.
+    static LAST_PANIC: RefCell<Option<(String, Backtrace)>> = const { RefCell::new(None) };
+}
+
+/// Keeps a backtrace of every panic for the thread it happened on, then
+/// reports the panic as before.
+fn install_hook() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::force_capture();
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some((info.to_string(), backtrace)));
+            previous(info);
+        }));
+    });
+}
+
+/// What one interpreter remembers for its crash report.
+#[derive(Debug)]
+pub(crate) struct CrashRecorder {
+    dir: PathBuf,
+    inputs: VecDeque<String>,
+    commands: u64,
+}
+
+/// What the interpreter was like when it crashed.
+pub(crate) struct CrashState<'a> {
+    pub config: &'a SessionConfig,
+    pub used_memory: usize,
+    pub cpu_ticks: u64,
+}
+
+impl CrashRecorder {
+    pub fn new(dir: &Path) -> Self {
+        install_hook();
+        Self {
+            dir: dir.to_path_buf(),
+            inputs: VecDeque::new(),
+            commands: 0,
+        }
+    }
+
+    /// Remembers the source `command` runs, if any.
+    pub fn record(&mut self, command: &Command) {
+        self.commands += 1;
+        let input = match command {
+            Command::Eval(source, ..) => source.clone(),
+            Command::EvalTemplate { source, .. } => source.clone(),
+            Command::SetPath(path, value, _) => format!(":set {} = {}", path, value),
+            _ => return,
+        };
+        if self.inputs.len() == INPUTS_KEPT {
+            self.inputs.pop_front();
+        }
+        self.inputs.push_back(input);
+    }
+
+    /// Writes the report of the panic the thread just caught, returning
+    /// where.
+    pub fn write(&self, state: CrashState) -> io::Result<PathBuf> {
+        let (message, backtrace) = LAST_PANIC
+            .with(|last| last.borrow_mut().take())
+            .unwrap_or_else(|| ("unknown panic".to_string(), Backtrace::disabled()));
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let info = SessionInfo::new(state.config);
+
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "luarepl {} ({}) crash report",
+            info.version, info.lua_version
+        );
+        let _ = writeln!(
+            report,
+            "time: {}.{:03}",
+            time.as_secs(),
+            time.subsec_millis()
+        );
+        let _ = writeln!(report, "{}", message);
+        let _ = writeln!(report, "\n== session ==");
+        let _ = writeln!(report, "features: {}", info.features.join(", "));
+        let _ = writeln!(report, "limits: {:?}", state.config.limits);
+        let _ = writeln!(report, "recovery: {:?}", state.config.recovery);
+        let _ = writeln!(report, "commands handled: {}", self.commands);
+        let _ = writeln!(report, "cpu ticks: {}", state.cpu_ticks);
+        let _ = writeln!(report, "lua memory: {} bytes", state.used_memory);
+        let _ = writeln!(report, "\n== last {} inputs ==", self.inputs.len());
+        for input in &self.inputs {
+            let _ = writeln!(report, "{}\n--", input);
+        }
+        let _ = writeln!(report, "\n== backtrace ==\n{}", backtrace);
+
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!(
+            "luarepl-crash-{}-{:03}.txt",
+            time.as_secs(),
+            time.subsec_millis()
+        ));
+        std::fs::write(&path, report)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Command;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_report() {
+        let dir = std::env::temp_dir().join(format!("luarepl-crash-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut session = Session::builder().crash_reports(&dir).build();
+        session.eval("x = 'before the crash'".to_string()).await;
+        let _ = session.command_sender.send(Command::Crash);
+        assert!(!session.eval("return 1".to_string()).await.success);
+
+        let reports: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(reports.len(), 1);
+        let report = std::fs::read_to_string(reports[0].as_ref().unwrap().path()).unwrap();
+        assert!(report.contains("interpreter crash requested by a test"));
+        assert!(report.contains("x = 'before the crash'\n--"));
+        assert!(report.contains("== backtrace =="));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
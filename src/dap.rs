@@ -0,0 +1,721 @@
+//! `luarepl dap`: a Debug Adapter Protocol server, so that editors such as
+//! VS Code can debug Lua scripts through a session, see `debugger`.
+//!
+//! The editor launches a `program`, optionally with `stopOnEntry`, sets
+//! breakpoints by file and line, and once the script stops asks for its
+//! stack, the scopes and variables of each frame, and for expressions
+//! evaluated in them. Tables in variables and results are expanded from
+//! the `objects` of their responses, one DAP variable reference per table.
+//! The script is the only thread and runs once; afterwards `evaluate` runs
+//! code in the session it left behind. What it printed is sent as `output`
+//! events when it finishes.
+//!
+//! Supported requests are `initialize`, `launch`, `setBreakpoints`,
+//! `configurationDone`, `threads`, `stackTrace`, `scopes`, `variables`,
+//! `evaluate`, `continue`, `next`, `stepIn`, `stepOut`, `pause`,
+//! `terminate` and `disconnect`.
+
+use crate::cancel::CancelToken;
+use crate::debugger::Debugger;
+use crate::debugger::Scope;
+use crate::debugger::Step;
+use crate::debugger::Stop;
+use crate::debugger::StopReason;
+use crate::render;
+use crate::transport::Connection;
+use crate::EvalOptions;
+use crate::EvalResponse;
+use crate::LuaObject;
+use crate::LuaValue;
+use crate::Session;
+use crate::SessionConfig;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+/// The id of the one thread, the script's.
+const THREAD_ID: i64 = 1;
+
+/// Values rendered longer than this show the table's id instead.
+const MAX_VALUE_LEN: usize = 80;
+
+/// Messages behind a `Content-Length` header, as the Debug Adapter Protocol
+/// frames them, over a byte stream.
+#[derive(Debug)]
+pub struct HeaderFramed<R, W> {
+    reader: R,
+    writer: W,
+    peer: String,
+    /// What has been read of the next message, kept across cancelled reads.
+    buffer: Vec<u8>,
+}
+
+impl<R, W> HeaderFramed<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(reader: R, writer: W, peer: String) -> Self {
+        Self {
+            reader,
+            writer,
+            peer,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The next message if the buffer holds all of it.
+    fn take(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(end) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return Ok(None);
+        };
+        let headers = String::from_utf8_lossy(&self.buffer[..end]);
+        let len = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())?
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Length"))?;
+        if len > crate::transport::MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message of {} bytes is too long", len),
+            ));
+        }
+        let start = end + 4;
+        if self.buffer.len() < start + len {
+            return Ok(None);
+        }
+        let message = self.buffer[start..start + len].to_vec();
+        self.buffer.drain(..start + len);
+        Ok(Some(message))
+    }
+}
+
+impl<R, W> Connection for HeaderFramed<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    async fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(message) = self.take()? {
+                return Ok(Some(message));
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+        }
+    }
+
+    async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let header = format!("Content-Length: {}\r\n\r\n", message.len());
+        self.writer.write_all(header.as_bytes()).await?;
+        self.writer.write_all(message).await?;
+        self.writer.flush().await
+    }
+
+    fn peer(&self) -> String {
+        self.peer.clone()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    seq: i64,
+    command: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// What a DAP variable reference expands to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reference {
+    Scope(usize, Scope),
+    /// An index into `Adapter::tables`.
+    Table(usize),
+}
+
+/// The state of one debugging session, apart from the connection.
+struct Adapter {
+    debugger: Debugger,
+    /// Stops the script on `terminate`.
+    cancel: CancelToken,
+    seq: i64,
+    /// The session, unless the script is running in it.
+    session: Option<Session>,
+    running: Option<JoinHandle<(Session, EvalResponse)>>,
+    /// The program launched and its source, until it runs.
+    program: Option<(String, String)>,
+    configured: bool,
+    stop_on_entry: bool,
+    /// Set once the editor disconnected.
+    done: bool,
+    /// Valid until the script carries on; DAP references count from 1.
+    references: Vec<Reference>,
+    tables: Vec<String>,
+    objects: HashMap<String, LuaObject>,
+}
+
+/// Serves one editor on `connection` until it disconnects, debugging the
+/// scripts it launches in a session built from `config`.
+pub async fn serve(mut connection: impl Connection, config: SessionConfig) -> io::Result<()> {
+    let (debugger, mut stops) = Debugger::new();
+    let config = SessionConfig {
+        debugger: Some(debugger.clone()),
+        // Printing to stdout would interleave with the protocol.
+        capture_output: true,
+        ..config
+    };
+    let session = Session::with_config(config);
+    let mut adapter = Adapter {
+        debugger,
+        cancel: session.cancel_token(),
+        seq: 0,
+        session: Some(session),
+        running: None,
+        program: None,
+        configured: false,
+        stop_on_entry: false,
+        done: false,
+        references: vec![],
+        tables: vec![],
+        objects: HashMap::new(),
+    };
+    loop {
+        let messages = tokio::select! {
+            message = connection.recv() => match message? {
+                Some(message) => match serde_json::from_slice::<Request>(&message) {
+                    Ok(request) => adapter.handle(request).await,
+                    Err(e) => vec![adapter.event("output", json!({
+                        "category": "stderr",
+                        "output": format!("cannot parse request: {}\n", e),
+                    }))],
+                },
+                None => return Ok(()),
+            },
+            Some(stop) = stops.recv() => vec![adapter.stopped(stop)],
+            finished = async { adapter.running.as_mut().unwrap().await }, if adapter.running.is_some() => {
+                adapter.running = None;
+                match finished {
+                    Ok((session, response)) => {
+                        adapter.session = Some(session);
+                        adapter.finished(&response)
+                    }
+                    Err(e) => vec![adapter.event("output", json!({
+                        "category": "stderr",
+                        "output": format!("the script crashed: {}\n", e),
+                    }))],
+                }
+            }
+        };
+        for message in messages {
+            connection.send(message.to_string().as_bytes()).await?;
+        }
+        if adapter.done {
+            return Ok(());
+        }
+    }
+}
+
+impl Adapter {
+    fn next_seq(&mut self) -> i64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Value {
+        json!({ "seq": self.next_seq(), "type": "event", "event": event, "body": body })
+    }
+
+    fn response(&mut self, request: &Request, result: Result<Value, String>) -> Value {
+        let mut response = json!({
+            "seq": self.next_seq(),
+            "type": "response",
+            "request_seq": request.seq,
+            "command": request.command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        response
+    }
+
+    /// The messages answering `request`.
+    async fn handle(&mut self, request: Request) -> Vec<Value> {
+        let args = &request.arguments;
+        let mut events = vec![];
+        let result = match request.command.as_str() {
+            "initialize" => {
+                events.push(self.event("initialized", json!({})));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                    "supportsTerminateRequest": true,
+                }))
+            }
+            "launch" => match args["program"].as_str() {
+                Some(program) => match std::fs::read_to_string(program) {
+                    Ok(source) => {
+                        self.program = Some((program.to_string(), source));
+                        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                        self.start();
+                        Ok(json!({}))
+                    }
+                    Err(e) => Err(format!("cannot read {}: {}", program, e)),
+                },
+                None => Err("launch needs a program".to_string()),
+            },
+            "setBreakpoints" => match args["source"]["path"].as_str() {
+                Some(path) => {
+                    let lines: Vec<u32> = match args["breakpoints"].as_array() {
+                        Some(breakpoints) => breakpoints
+                            .iter()
+                            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                            .map(|line| line as u32)
+                            .collect(),
+                        None => vec![],
+                    };
+                    self.debugger.set_breakpoints(path, lines.iter().copied());
+                    let breakpoints: Vec<_> = lines
+                        .iter()
+                        .map(|line| json!({ "verified": true, "line": line }))
+                        .collect();
+                    Ok(json!({ "breakpoints": breakpoints }))
+                }
+                None => Err("breakpoints need a source path".to_string()),
+            },
+            "configurationDone" => {
+                self.configured = true;
+                self.start();
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => match self.debugger.frames().await {
+                Some(frames) => {
+                    let frames: Vec<_> = frames
+                        .iter()
+                        .enumerate()
+                        .map(|(id, frame)| {
+                            let mut json = json!({
+                                "id": id,
+                                "name": frame.name.as_deref().unwrap_or("main chunk"),
+                                "line": frame.line.unwrap_or(0),
+                                "column": 1,
+                            });
+                            if let Some(path) = frame.path() {
+                                json["source"] = json!({ "path": path });
+                            }
+                            json
+                        })
+                        .collect();
+                    Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+                }
+                None => Err("the script is not stopped".to_string()),
+            },
+            "scopes" => {
+                let frame = args["frameId"].as_u64().unwrap_or(0) as usize;
+                let scopes: Vec<_> = [
+                    ("Locals", Scope::Locals),
+                    ("Upvalues", Scope::Upvalues),
+                    ("Globals", Scope::Globals),
+                ]
+                .iter()
+                .map(|&(name, scope)| {
+                    let reference = self.reference(Reference::Scope(frame, scope));
+                    json!({
+                        "name": name,
+                        "variablesReference": reference,
+                        "expensive": scope == Scope::Globals,
+                    })
+                })
+                .collect();
+                Ok(json!({ "scopes": scopes }))
+            }
+            "variables" => self.variables(args["variablesReference"].as_i64()).await,
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or_default().to_string();
+                let frame = args["frameId"].as_u64().unwrap_or(0) as usize;
+                self.evaluate(frame, expression).await
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                self.references.clear();
+                self.debugger.resume(match request.command.as_str() {
+                    "next" => Step::Over,
+                    "stepIn" => Step::Into,
+                    "stepOut" => Step::Out,
+                    _ => Step::Continue,
+                });
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "pause" => {
+                self.debugger.pause();
+                Ok(json!({}))
+            }
+            "terminate" | "disconnect" => {
+                // A running script fails at its next line, which ends the
+                // debugging as if it finished.
+                self.cancel.cancel();
+                self.debugger.detach();
+                if request.command == "disconnect" {
+                    self.done = true;
+                } else if self.running.is_none() {
+                    events.push(self.event("terminated", json!({})));
+                }
+                Ok(json!({}))
+            }
+            command => Err(format!("{} is not supported", command)),
+        };
+        let response = self.response(&request, result);
+        events.insert(0, response);
+        events
+    }
+
+    /// Runs the program once it is launched and the breakpoints are set.
+    fn start(&mut self) {
+        if !self.configured || self.program.is_none() || self.session.is_none() {
+            return;
+        }
+        let (Some((path, source)), Some(mut session)) = (self.program.take(), self.session.take())
+        else {
+            return;
+        };
+        if self.stop_on_entry {
+            self.debugger.pause();
+        }
+        let options = EvalOptions {
+            chunk_name: Some(format!("@{}", path)),
+            ..Default::default()
+        };
+        self.running = Some(tokio::spawn(async move {
+            let response = session.eval_with(source, options).await;
+            (session, response)
+        }));
+    }
+
+    fn stopped(&mut self, stop: Stop) -> Value {
+        self.references.clear();
+        let reason = match stop.reason {
+            StopReason::Pause if std::mem::take(&mut self.stop_on_entry) => "entry",
+            StopReason::Pause => "pause",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step",
+        };
+        let body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        self.event("stopped", body)
+    }
+
+    /// The output of the script, and the events ending the debugging.
+    fn finished(&mut self, response: &EvalResponse) -> Vec<Value> {
+        let mut output: Vec<_> = response
+            .output
+            .iter()
+            .map(|line| ("stdout", line.clone()))
+            .chain(response.stderr.iter().map(|line| ("stderr", line.clone())))
+            .chain(
+                response
+                    .warnings
+                    .iter()
+                    .map(|warning| ("stderr", format!("warning: {}", warning))),
+            )
+            .collect();
+        match &response.error {
+            Some(error) => output.push(("stderr", format!("error: {}", error))),
+            None if response.value != LuaValue::Nil => {
+                output.push(("console", response.to_string()))
+            }
+            None => {}
+        }
+        let mut events: Vec<_> = output
+            .into_iter()
+            .map(|(category, line)| {
+                self.event(
+                    "output",
+                    json!({ "category": category, "output": format!("{}\n", line) }),
+                )
+            })
+            .collect();
+        let code = if response.success { 0 } else { 1 };
+        events.push(self.event("exited", json!({ "exitCode": code })));
+        events.push(self.event("terminated", json!({})));
+        events
+    }
+
+    fn reference(&mut self, reference: Reference) -> usize {
+        match self.references.iter().position(|r| *r == reference) {
+            Some(index) => index + 1,
+            None => {
+                self.references.push(reference);
+                self.references.len()
+            }
+        }
+    }
+
+    /// The reference expanding `value`, or 0 if it is not a table.
+    fn table_reference(&mut self, value: &LuaValue) -> usize {
+        let LuaValue::ObjectRef(id) = value else {
+            return 0;
+        };
+        if !self.objects.contains_key(id) {
+            return 0;
+        }
+        let index = match self.tables.iter().position(|table| table == id) {
+            Some(index) => index,
+            None => {
+                self.tables.push(id.clone());
+                self.tables.len() - 1
+            }
+        };
+        self.reference(Reference::Table(index))
+    }
+
+    fn variable(&mut self, name: String, value: &LuaValue, shown: String) -> Value {
+        let reference = self.table_reference(value);
+        json!({ "name": name, "value": shown, "variablesReference": reference })
+    }
+
+    async fn variables(&mut self, reference: Option<i64>) -> Result<Value, String> {
+        let reference = reference
+            .and_then(|reference| usize::try_from(reference).ok())
+            .and_then(|reference| self.references.get(reference.wrapping_sub(1)).copied())
+            .ok_or_else(|| "no such variables reference".to_string())?;
+        let variables: Vec<_> = match reference {
+            Reference::Scope(frame, scope) => {
+                let variables = self
+                    .debugger
+                    .variables(frame, scope)
+                    .await
+                    .ok_or_else(|| "the script is not stopped".to_string())?;
+                variables
+                    .into_iter()
+                    .map(|(name, response)| {
+                        let shown = shown(&response, &response.value);
+                        self.objects.extend(response.objects);
+                        self.variable(name, &response.value, shown)
+                    })
+                    .collect()
+            }
+            Reference::Table(index) => {
+                let object = self
+                    .objects
+                    .get(&self.tables[index])
+                    .cloned()
+                    .unwrap_or_default();
+                object
+                    .members
+                    .iter()
+                    .map(|(key, value)| {
+                        let name = match key {
+                            LuaValue::String(name) => name.clone(),
+                            key => format!("[{}]", key),
+                        };
+                        let shown = match value {
+                            LuaValue::ObjectRef(id) => {
+                                object.child_previews.get(id).cloned().unwrap_or(id.clone())
+                            }
+                            value => value.to_string(),
+                        };
+                        self.variable(name, value, shown)
+                    })
+                    .collect()
+            }
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    async fn evaluate(&mut self, frame: usize, expression: String) -> Result<Value, String> {
+        let response = if self.debugger.is_paused() {
+            self.debugger.evaluate(frame, expression).await
+        } else if let Some(session) = &mut self.session {
+            Some(session.eval(expression).await)
+        } else {
+            None
+        };
+        let response = response.ok_or_else(|| "the script is running".to_string())?;
+        if !response.success {
+            return Err(response
+                .error
+                .unwrap_or_else(|| "evaluation failed".to_string()));
+        }
+        let result = response.to_string();
+        let reference = match response.values.as_slice() {
+            [value] => {
+                let value = value.clone();
+                self.objects.extend(response.objects);
+                self.table_reference(&value)
+            }
+            _ => 0,
+        };
+        Ok(json!({ "result": result, "variablesReference": reference }))
+    }
+}
+
+/// `value` of `response` on one line, or its id if that is too long.
+fn shown(response: &EvalResponse, value: &LuaValue) -> String {
+    let rendered = render::value(response, value);
+    match value {
+        LuaValue::ObjectRef(id) if rendered.contains('\n') || rendered.len() > MAX_VALUE_LEN => {
+            id.clone()
+        }
+        _ => rendered,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Client<C> {
+        connection: C,
+        seq: i64,
+    }
+
+    impl<C: Connection> Client<C> {
+        async fn request(&mut self, command: &str, arguments: Value) {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            self.connection
+                .send(request.to_string().as_bytes())
+                .await
+                .unwrap();
+        }
+
+        /// Skips messages until the response to `command` or `event`.
+        async fn until(&mut self, kind: &str, name: &str) -> Value {
+            loop {
+                let message = self.connection.recv().await.unwrap().unwrap();
+                let message: Value = serde_json::from_slice(&message).unwrap();
+                let key = if kind == "response" {
+                    "command"
+                } else {
+                    "event"
+                };
+                if message["type"] == kind && message[key] == name {
+                    return message;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debug_session() {
+        let dir = std::env::temp_dir().join(format!("luarepl-dap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("main.lua");
+        std::fs::write(
+            &program,
+            "local player = { name = 'Ada', hp = 10 }\n\
+             player.hp = player.hp - 3\n\
+             print(player.name, player.hp)\n",
+        )
+        .unwrap();
+        let program = program.to_str().unwrap().to_string();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let server = HeaderFramed::new(server_reader, server_writer, "editor".to_string());
+        let serving = tokio::spawn(serve(server, SessionConfig::default()));
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let mut client = Client {
+            connection: HeaderFramed::new(client_reader, client_writer, "adapter".to_string()),
+            seq: 0,
+        };
+
+        client
+            .request("initialize", json!({ "adapterID": "luarepl" }))
+            .await;
+        client.until("event", "initialized").await;
+        client
+            .request("launch", json!({ "program": program }))
+            .await;
+        client
+            .request(
+                "setBreakpoints",
+                json!({ "source": { "path": program }, "breakpoints": [{ "line": 3 }] }),
+            )
+            .await;
+        let response = client.until("response", "setBreakpoints").await;
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], true);
+        client.request("configurationDone", json!({})).await;
+
+        let stopped = client.until("event", "stopped").await;
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+        client.request("stackTrace", json!({ "threadId": 1 })).await;
+        let trace = client.until("response", "stackTrace").await;
+        let frame = &trace["body"]["stackFrames"][0];
+        assert_eq!(
+            (frame["line"].clone(), frame["source"]["path"].clone()),
+            (json!(3), json!(program))
+        );
+
+        client.request("scopes", json!({ "frameId": 0 })).await;
+        let scopes = client.until("response", "scopes").await;
+        let locals = scopes["body"]["scopes"][0]["variablesReference"].clone();
+        client
+            .request("variables", json!({ "variablesReference": locals }))
+            .await;
+        let variables = client.until("response", "variables").await;
+        let player = &variables["body"]["variables"][0];
+        assert_eq!(player["name"], "player");
+        let fields = player["variablesReference"].clone();
+        assert_ne!(fields, json!(0));
+        client
+            .request("variables", json!({ "variablesReference": fields }))
+            .await;
+        let variables = client.until("response", "variables").await;
+        let mut fields: Vec<_> = variables["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| {
+                (
+                    field["name"].as_str().unwrap(),
+                    field["value"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        fields.sort();
+        assert_eq!(fields, [("hp", "7"), ("name", "\"Ada\"")]);
+
+        client
+            .request(
+                "evaluate",
+                json!({ "expression": "player.hp * 2", "frameId": 0 }),
+            )
+            .await;
+        let result = client.until("response", "evaluate").await;
+        assert_eq!(result["body"]["result"], "14");
+
+        client.request("continue", json!({ "threadId": 1 })).await;
+        let output = client.until("event", "output").await;
+        assert_eq!(output["body"]["output"], "Ada\t7\n");
+        let exited = client.until("event", "exited").await;
+        assert_eq!(exited["body"]["exitCode"], 0);
+        client.until("event", "terminated").await;
+
+        client.request("disconnect", json!({})).await;
+        client.until("response", "disconnect").await;
+        serving.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
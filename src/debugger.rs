@@ -0,0 +1,612 @@
+//! Breakpoints and stepping, for debugging the scripts a session runs from
+//! a client such as the `dap` server, see `SessionBuilder::debugger`.
+//!
+//! The session's hook also runs on every line, and stops the script where
+//! the client asked: at a breakpoint, after a step, or anywhere once it
+//! asked to pause. The interpreter thread then waits in the hook, answering
+//! the client's questions about the stack, its variables and expressions
+//! evaluated in its frames, until the client resumes it. Hooks do not run
+//! while it waits, so nothing evaluated then stops at a breakpoint.
+//!
+//! Breakpoints are set by the path of the file, as scripts run with
+//! `EvalOptions::chunk_name` `@path` report it. Frames count from the
+//! innermost, 0, outwards; the chunks a session installs itself, named with
+//! `=`, are never stopped in.
+
+use crate::EvalResponse;
+use rlua::Context;
+use rlua::Debug;
+use rlua::Error;
+use rlua::Function;
+use rlua::Lua;
+use rlua::MultiValue;
+use rlua::StdLib;
+use rlua::Table;
+use rlua::Value;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+const HELPERS_KEY: &str = "luarepl.debugger";
+
+/// Keeps what the helpers need of the debug library, removes it from the
+/// globals and returns the helpers. Rust calls them from the hook, so the
+/// frame being debugged is level 2 to each.
+///
+/// While rlua runs a hook, it keeps a userdata at the bottom of the hooked
+/// function's part of the stack, which moves its locals up a slot; the
+/// locals of frame 0 are read and written one slot further on.
+const INSTALL_SOURCE: &str = r#"
+local getinfo, getlocal, setlocal = debug.getinfo, debug.getlocal, debug.setlocal
+local getupvalue, setupvalue = debug.getupvalue, debug.setupvalue
+debug = nil
+package.loaded.debug = nil
+local load, pcall, pairs, type, rawget, rawset, rawequal, setmetatable, select =
+    load, pcall, pairs, type, rawget, rawset, rawequal, setmetatable, select
+local sub, sort, pack, unpack = string.sub, table.sort, table.pack, table.unpack
+local globals = _ENV
+
+-- The name and value of local `i` of the frame at `level`, frame 0 at 2.
+local function local_at(level, i)
+    local name = getlocal(level + 1, i)
+    if name == nil then
+        return nil
+    end
+    if level == 2 then
+        return name, select(2, getlocal(level + 1, i + 1))
+    end
+    return name, select(2, getlocal(level + 1, i))
+end
+
+local helpers = {}
+
+function helpers.depth()
+    local depth = 0
+    while getinfo(depth + 2, "l") do
+        depth = depth + 1
+    end
+    return depth
+end
+
+function helpers.frames()
+    local frames = {}
+    local level = 2
+    while true do
+        local info = getinfo(level, "nSl")
+        if info == nil then
+            return frames
+        end
+        frames[#frames + 1] = {
+            name = info.name,
+            source = info.source,
+            line = info.currentline >= 0 and info.currentline or nil,
+        }
+        level = level + 1
+    end
+end
+
+-- The variables of a scope as { name, value } pairs, in the order they
+-- were declared, or by name for the globals.
+function helpers.variables(frame, scope)
+    local level, variables = frame + 2, {}
+    if scope == "globals" then
+        for name, value in pairs(globals) do
+            if type(name) == "string" then
+                variables[#variables + 1] = { name, value }
+            end
+        end
+        sort(variables, function(a, b) return a[1] < b[1] end)
+        return variables
+    end
+    local info = getinfo(level, "f")
+    if info == nil then
+        return variables
+    end
+    local i = 1
+    while true do
+        local name, value
+        if scope == "locals" then
+            name, value = local_at(level, i)
+        else
+            name, value = getupvalue(info.func, i)
+        end
+        if name == nil then
+            return variables
+        end
+        if sub(name, 1, 1) ~= "(" and name ~= "_ENV" then
+            variables[#variables + 1] = { name, value }
+        end
+        i = i + 1
+    end
+end
+
+-- Runs `source` with the locals and upvalues of `frame` in scope, writing
+-- back the ones it assigns. Returns whether it succeeded, then its values
+-- or the error.
+function helpers.evaluate(frame, source)
+    local level = frame + 2
+    local info = getinfo(level, "f")
+    if info == nil then
+        return false, "no such frame"
+    end
+    local env, slots = {}, {}
+    local i = 1
+    while true do
+        local name, value = getupvalue(info.func, i)
+        if name == nil then
+            break
+        end
+        if name ~= "_ENV" then
+            slots[name], env[name] = -i, value
+        end
+        i = i + 1
+    end
+    i = 1
+    while true do
+        local name, value = local_at(level, i)
+        if name == nil then
+            break
+        end
+        if sub(name, 1, 1) ~= "(" then
+            slots[name], env[name] = i, value
+        end
+        i = i + 1
+    end
+    setmetatable(env, {
+        __index = globals,
+        __newindex = function(t, name, value)
+            if slots[name] then
+                rawset(t, name, value)
+            else
+                globals[name] = value
+            end
+        end,
+    })
+    local chunk, message = load("return " .. source .. "\n", "=evaluate", "t", env)
+    if chunk == nil then
+        chunk, message = load(source, "=evaluate", "t", env)
+        if chunk == nil then
+            return false, message
+        end
+    end
+    local results = pack(pcall(chunk))
+    for name, slot in pairs(slots) do
+        local value = rawget(env, name)
+        if slot > 0 then
+            local _, old = local_at(level, slot)
+            if not rawequal(old, value) then
+                setlocal(level, level == 2 and slot + 1 or slot, value)
+            end
+        else
+            local _, old = getupvalue(info.func, -slot)
+            if not rawequal(old, value) then
+                setupvalue(info.func, -slot, value)
+            end
+        end
+    end
+    return unpack(results, 1, results.n)
+end
+
+return helpers
+"#;
+
+/// One frame of the stack a script stopped with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// The name the function was called by; `None` for the main chunk and
+    /// anonymous functions.
+    pub name: Option<String>,
+    /// The chunk name, e.g. `@main.lua`, `=[C]` for C functions or the
+    /// source of chunks loaded without a name.
+    pub source: String,
+    /// The line that was running; `None` for C functions.
+    pub line: Option<u32>,
+}
+
+impl Frame {
+    /// The file the frame's function was loaded from, if any.
+    pub fn path(&self) -> Option<&str> {
+        self.source.strip_prefix('@')
+    }
+}
+
+/// The variables `Debugger::variables` lists for a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Locals,
+    Upvalues,
+    /// The same for every frame.
+    Globals,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Locals => "locals",
+            Self::Upvalues => "upvalues",
+            Self::Globals => "globals",
+        }
+    }
+}
+
+/// How a stopped script carries on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Until the next breakpoint.
+    Continue,
+    /// To the next line of the same function, or of one it returns to.
+    Over,
+    /// To the next line run, in whichever function.
+    Into,
+    /// To the next line of a function the current one returns to.
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint,
+    Step,
+    Pause,
+}
+
+/// Where and why a script stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stop {
+    pub reason: StopReason,
+    /// The chunk name, as in `Frame::source`.
+    pub source: String,
+    pub line: u32,
+}
+
+enum Request {
+    Frames(oneshot::Sender<Vec<Frame>>),
+    Variables(usize, Scope, oneshot::Sender<Vec<(String, EvalResponse)>>),
+    Evaluate(usize, String, oneshot::Sender<EvalResponse>),
+    Resume(Step),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Lines by path.
+    breakpoints: HashMap<String, HashSet<u32>>,
+    /// The step being taken, with the depth of the stack it was taken at.
+    stepping: Option<(Step, usize)>,
+    pause: bool,
+    paused: bool,
+    /// Where the hook last saw the script, so that it does not stop twice
+    /// before it moves on to another line.
+    last: Option<(String, u32)>,
+}
+
+/// Controls the scripts of the session it is given to; clones control the
+/// same session.
+#[derive(Clone)]
+pub struct Debugger(Arc<Shared>);
+
+struct Shared {
+    state: Mutex<State>,
+    requests: mpsc::Sender<Request>,
+    /// Read by the interpreter thread while the script is stopped.
+    pending: Mutex<mpsc::Receiver<Request>>,
+    stops: UnboundedSender<Stop>,
+}
+
+impl Debugger {
+    /// A debugger, and where it reports the stops of the script.
+    pub fn new() -> (Self, UnboundedReceiver<Stop>) {
+        let (requests, pending) = mpsc::channel();
+        let (stops, stopped) = tokio::sync::mpsc::unbounded_channel();
+        let debugger = Self(Arc::new(Shared {
+            state: Mutex::default(),
+            requests,
+            pending: Mutex::new(pending),
+            stops,
+        }));
+        (debugger, stopped)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replaces the breakpoints in the file at `path` with `lines`.
+    pub fn set_breakpoints(&self, path: &str, lines: impl IntoIterator<Item = u32>) {
+        let lines: HashSet<_> = lines.into_iter().collect();
+        let mut state = self.state();
+        if lines.is_empty() {
+            state.breakpoints.remove(path);
+        } else {
+            state.breakpoints.insert(path.to_string(), lines);
+        }
+    }
+
+    /// Stops the script at the next line it runs.
+    pub fn pause(&self) {
+        self.state().pause = true;
+    }
+
+    /// Whether the script is stopped.
+    pub fn is_paused(&self) -> bool {
+        self.state().paused
+    }
+
+    /// Carries on with the stopped script; does nothing if it is running.
+    pub fn resume(&self, step: Step) {
+        let mut state = self.state();
+        if std::mem::replace(&mut state.paused, false) {
+            let _ = self.0.requests.send(Request::Resume(step));
+        }
+    }
+
+    /// Clears the breakpoints and lets the script run to its end.
+    pub fn detach(&self) {
+        let mut state = self.state();
+        state.breakpoints.clear();
+        state.pause = false;
+        drop(state);
+        self.resume(Step::Continue);
+    }
+
+    /// The stack of the stopped script, innermost first; `None` if it is
+    /// running.
+    pub async fn frames(&self) -> Option<Vec<Frame>> {
+        self.ask(Request::Frames).await
+    }
+
+    /// The variables of `scope` in `frame` of the stopped script, each as
+    /// the response of an eval returning it.
+    pub async fn variables(
+        &self,
+        frame: usize,
+        scope: Scope,
+    ) -> Option<Vec<(String, EvalResponse)>> {
+        self.ask(|reply| Request::Variables(frame, scope, reply))
+            .await
+    }
+
+    /// Evaluates `source` as an expression or statements with the locals
+    /// and upvalues of `frame` in scope; assigning to them changes them in
+    /// the script.
+    pub async fn evaluate(&self, frame: usize, source: String) -> Option<EvalResponse> {
+        self.ask(|reply| Request::Evaluate(frame, source, reply))
+            .await
+    }
+
+    async fn ask<T>(&self, request: impl FnOnce(oneshot::Sender<T>) -> Request) -> Option<T> {
+        if !self.state().paused {
+            return None;
+        }
+        let (reply, answer) = oneshot::channel();
+        self.0.requests.send(request(reply)).ok()?;
+        answer.await.ok()
+    }
+
+    /// Called by the session's hook, on lines among other events. Stops
+    /// the script if it is where the client asked it to.
+    pub(crate) fn on_hook(&self, ctx: Context, debug: &Debug) -> Result<(), Error> {
+        let Ok(line) = u32::try_from(debug.curr_line()) else {
+            return Ok(());
+        };
+        let mut state = self.state();
+        if state.breakpoints.is_empty() && state.stepping.is_none() && !state.pause {
+            return Ok(());
+        }
+        let source = String::from_utf8_lossy(debug.source().source.unwrap_or_default());
+        if source.starts_with('=') {
+            return Ok(());
+        }
+        let here = Some((source.into_owned(), line));
+        if state.last == here {
+            return Ok(());
+        }
+        let (source, _) = here.clone().unwrap_or_default();
+        state.last = here;
+        let at_breakpoint = source
+            .strip_prefix('@')
+            .and_then(|path| state.breakpoints.get(path))
+            .is_some_and(|lines| lines.contains(&line));
+        let pause = std::mem::take(&mut state.pause);
+        let reason = match state.stepping {
+            _ if pause => StopReason::Pause,
+            _ if at_breakpoint => StopReason::Breakpoint,
+            Some((Step::Into, _)) => StopReason::Step,
+            Some((Step::Over, depth)) if self::depth(ctx)? <= depth => StopReason::Step,
+            Some((Step::Out, depth)) if self::depth(ctx)? < depth => StopReason::Step,
+            _ => return Ok(()),
+        };
+        state.paused = true;
+        state.stepping = None;
+        drop(state);
+        let _ = self.0.stops.send(Stop {
+            reason,
+            source,
+            line,
+        });
+        self.wait(ctx)
+    }
+
+    /// Answers the client's requests until it resumes the script.
+    fn wait(&self, ctx: Context) -> Result<(), Error> {
+        let pending = self.0.pending.lock().unwrap_or_else(|e| e.into_inner());
+        while let Ok(request) = pending.recv() {
+            match request {
+                Request::Frames(reply) => {
+                    let _ = reply.send(frames(ctx)?);
+                }
+                Request::Variables(frame, scope, reply) => {
+                    let _ = reply.send(variables(ctx, frame, scope)?);
+                }
+                Request::Evaluate(frame, source, reply) => {
+                    let _ = reply.send(evaluate(ctx, frame, source)?);
+                }
+                Request::Resume(step) => {
+                    let stepping = match step {
+                        Step::Continue => None,
+                        step => Some((step, depth(ctx)?)),
+                    };
+                    self.state().stepping = stepping;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Debugger")
+            .field("state", &*self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Makes the helpers available to the hook.
+pub(crate) fn install(lua: &Lua) -> Result<(), Error> {
+    // Safety: as in `function::install`, the debug library is loaded only to
+    // be captured and is removed from the globals again.
+    unsafe { lua.unsafe_load_from_std_lib(StdLib::DEBUG)? };
+    lua.context(|ctx| {
+        let helpers: Table = ctx.load(INSTALL_SOURCE).set_name("=debugger")?.eval()?;
+        ctx.set_named_registry_value(HELPERS_KEY, helpers)
+    })
+}
+
+/// Calls one of the helpers directly, so that the frame being debugged is
+/// still level 2 to it.
+fn helper<'l, A: rlua::ToLuaMulti<'l>, R: rlua::FromLuaMulti<'l>>(
+    ctx: Context<'l>,
+    name: &str,
+    args: A,
+) -> Result<R, Error> {
+    let helpers: Table = ctx.named_registry_value(HELPERS_KEY)?;
+    helpers.get::<_, Function>(name)?.call(args)
+}
+
+/// How many frames the stack being debugged has.
+fn depth(ctx: Context) -> Result<usize, Error> {
+    helper(ctx, "depth", ())
+}
+
+fn frames(ctx: Context) -> Result<Vec<Frame>, Error> {
+    let frames: Table = helper(ctx, "frames", ())?;
+    frames
+        .sequence_values::<Table>()
+        .map(|frame| {
+            let frame = frame?;
+            Ok(Frame {
+                name: frame.get("name")?,
+                source: frame.get("source")?,
+                line: frame.get("line")?,
+            })
+        })
+        .collect()
+}
+
+fn variables(
+    ctx: Context,
+    frame: usize,
+    scope: Scope,
+) -> Result<Vec<(String, EvalResponse)>, Error> {
+    let variables: Table = helper(ctx, "variables", (frame, scope.name()))?;
+    variables
+        .sequence_values::<Table>()
+        .map(|variable| {
+            let variable = variable?;
+            let value: Value = variable.get(2)?;
+            Ok((variable.get(1)?, EvalResponse::from_value(ctx, value, None)))
+        })
+        .collect()
+}
+
+fn evaluate(ctx: Context, frame: usize, source: String) -> Result<EvalResponse, Error> {
+    let results: MultiValue = helper(ctx, "evaluate", (frame, source))?;
+    let mut results = results.into_vec().into_iter();
+    match results.next() {
+        Some(Value::Boolean(true)) => Ok(EvalResponse::from_values(ctx, results.collect(), None)),
+        _ => {
+            let mut response = EvalResponse::failure();
+            response.error = match results.next() {
+                Some(Value::String(message)) => Some(message.to_str()?.to_string()),
+                Some(value) => Some(format!("({} error object)", value.type_name())),
+                None => None,
+            };
+            Ok(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EvalOptions;
+    use crate::LuaValue;
+    use crate::Session;
+
+    const SCRIPT: &str = "local function double(n)\n\
+                          \x20 local twice = n * 2\n\
+                          \x20 return twice\n\
+                          end\n\
+                          local total = double(4)\n\
+                          return total + 1\n";
+
+    #[tokio::test]
+    async fn test_breakpoints() {
+        let (debugger, mut stops) = Debugger::new();
+        let mut session = Session::builder().debugger(debugger.clone()).build();
+        debugger.set_breakpoints("main.lua", [3]);
+        let run = tokio::spawn(async move {
+            let options = EvalOptions {
+                chunk_name: Some("@main.lua".to_string()),
+                ..Default::default()
+            };
+            session.eval_with(SCRIPT.to_string(), options).await
+        });
+
+        let stop = stops.recv().await.unwrap();
+        assert_eq!(
+            (stop.reason, stop.source.as_str(), stop.line),
+            (StopReason::Breakpoint, "@main.lua", 3)
+        );
+        let frames = debugger.frames().await.unwrap();
+        let lines: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.name.as_deref(), frame.path(), frame.line))
+            .collect();
+        assert_eq!(
+            lines[..2],
+            [
+                (Some("double"), Some("main.lua"), Some(3)),
+                (None, Some("main.lua"), Some(5)),
+            ]
+        );
+        let locals = debugger.variables(0, Scope::Locals).await.unwrap();
+        let locals: Vec<_> = locals
+            .iter()
+            .map(|(name, response)| (name.as_str(), response.value.clone()))
+            .collect();
+        assert_eq!(
+            locals,
+            [("n", LuaValue::Integer(4)), ("twice", LuaValue::Integer(8))]
+        );
+        let response = debugger.evaluate(0, "twice = n * 10".to_string()).await;
+        assert!(response.unwrap().success);
+        let response = debugger.evaluate(1, "double".to_string()).await.unwrap();
+        assert!(matches!(response.value, LuaValue::FunctionRef(_)));
+
+        debugger.resume(Step::Over);
+        let stop = stops.recv().await.unwrap();
+        assert_eq!((stop.reason, stop.line), (StopReason::Step, 6));
+        assert!(debugger.is_paused());
+        debugger.resume(Step::Continue);
+        assert!(debugger.frames().await.is_none());
+        assert_eq!(run.await.unwrap().value, LuaValue::Integer(41));
+    }
+}
@@ -0,0 +1,310 @@
+//! Delta responses for clients that expand the same objects over and over,
+//! such as UIs polling a large state table: objects the client has already
+//! seen are sent as the members that changed since, or not at all.
+//!
+//! The server keeps one `ClientVersions` per client and passes every
+//! response for it through `ClientVersions::delta`; the client keeps its own
+//! and rebuilds full responses with `ClientVersions::apply`. Each object's
+//! version counts the serializations that changed it, so a client that
+//! missed one notices instead of patching the wrong members.
+//!
+//! `server::serve` keeps one per connection for clients that set `delta`
+//! with `hello`, and sends the objects of their `v2` replies as deltas.
+
+use crate::EvalResponse;
+use crate::LuaObject;
+use crate::LuaValue;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+/// One object of a `DeltaResponse`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectDelta {
+    /// The whole object, as version `version`.
+    Full { version: u64, object: LuaObject },
+    /// The object as of `base`, with members set or removed, as `base + 1`.
+    Changed {
+        base: u64,
+        changed: Vec<(LuaValue, LuaValue)>,
+        removed: Vec<LuaValue>,
+    },
+    /// The object as of `version`.
+    Unchanged { version: u64 },
+}
+
+/// An `EvalResponse` whose objects are replaced by deltas against what the
+/// client has seen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeltaResponse {
+    /// The response without its objects.
+    pub response: EvalResponse,
+    pub objects: HashMap<String, ObjectDelta>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaError {
+    /// A delta is based on a version of `id` the client does not have.
+    Version { id: String, have: Option<u64> },
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeltaError::Version {
+                id,
+                have: Some(have),
+            } => {
+                write!(f, "delta for {} does not apply to version {}", id, have)
+            }
+            DeltaError::Version { id, have: None } => write!(f, "delta for unseen {}", id),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// `LuaValue` as a map key; Lua never uses NaN as a key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Nil,
+    Boolean(bool),
+    Number(u64),
+    Integer(i64),
+    String(String),
+    ObjectRef(String),
+    LightUserData(usize),
+}
+
+impl From<&LuaValue> for Key {
+    fn from(value: &LuaValue) -> Self {
+        match value {
+            LuaValue::Nil => Key::Nil,
+            LuaValue::Boolean(b) => Key::Boolean(*b),
+            LuaValue::Number(n) => Key::Number(n.to_bits()),
+            LuaValue::Integer(i) => Key::Integer(*i),
+            LuaValue::String(s) => Key::String(s.clone()),
+            LuaValue::ObjectRef(id) => Key::ObjectRef(id.clone()),
+            LuaValue::FunctionRef(function) => Key::ObjectRef(function.id.clone()),
+            LuaValue::Coroutine(coroutine) => Key::ObjectRef(coroutine.id.clone()),
+            LuaValue::UserData(userdata) => Key::ObjectRef(userdata.id.clone()),
+            LuaValue::LightUserData(address) => Key::LightUserData(*address),
+        }
+    }
+}
+
+/// The versions of the objects one client has been sent, by object id.
+#[derive(Debug, Default)]
+pub struct ClientVersions {
+    seen: HashMap<String, (u64, LuaObject)>,
+}
+
+impl ClientVersions {
+    /// How many objects the client holds.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Forgets everything, e.g. after the session was reset.
+    pub fn clear(&mut self) {
+        self.seen.clear();
+    }
+
+    /// `response` as deltas against what this client was sent before, which
+    /// then counts as sent.
+    pub fn delta(&mut self, mut response: EvalResponse) -> DeltaResponse {
+        let objects = self.delta_objects(std::mem::take(&mut response.objects));
+        DeltaResponse { response, objects }
+    }
+
+    /// `delta` for the objects of a response alone.
+    pub fn delta_objects(
+        &mut self,
+        objects: HashMap<String, LuaObject>,
+    ) -> HashMap<String, ObjectDelta> {
+        objects
+            .into_iter()
+            .map(|(id, object)| {
+                let delta = self.record(&id, object);
+                (id, delta)
+            })
+            .collect()
+    }
+
+    fn record(&mut self, id: &str, object: LuaObject) -> ObjectDelta {
+        let Some((version, seen)) = self.seen.get_mut(id) else {
+            self.seen.insert(id.to_string(), (0, object.clone()));
+            return ObjectDelta::Full { version: 0, object };
+        };
+        if *seen == object {
+            return ObjectDelta::Unchanged { version: *version };
+        }
+        let base = *version;
+        *version += 1;
+        // Formatter output and previews are small, so any change to them
+        // resends the object.
+        let members_only = LuaObject {
+            members: seen.members.clone(),
+            ..object.clone()
+        };
+        if members_only != *seen {
+            *seen = object.clone();
+            return ObjectDelta::Full {
+                version: *version,
+                object,
+            };
+        }
+        let before: HashMap<Key, &LuaValue> = seen
+            .members
+            .iter()
+            .map(|(key, value)| (Key::from(key), value))
+            .collect();
+        let after: HashMap<Key, &LuaValue> = object
+            .members
+            .iter()
+            .map(|(key, value)| (Key::from(key), value))
+            .collect();
+        let changed = object
+            .members
+            .iter()
+            .filter(|(key, value)| before.get(&Key::from(key)) != Some(&value))
+            .cloned()
+            .collect();
+        let removed = seen
+            .members
+            .iter()
+            .filter(|(key, _)| !after.contains_key(&Key::from(key)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        *seen = object;
+        ObjectDelta::Changed {
+            base,
+            changed,
+            removed,
+        }
+    }
+
+    /// Rebuilds the full response from `delta`, on the client side.
+    pub fn apply(&mut self, delta: DeltaResponse) -> Result<EvalResponse, DeltaError> {
+        let mut response = delta.response;
+        response.objects.extend(self.apply_objects(delta.objects)?);
+        Ok(response)
+    }
+
+    /// `apply` for the objects of a response alone.
+    pub fn apply_objects(
+        &mut self,
+        deltas: HashMap<String, ObjectDelta>,
+    ) -> Result<HashMap<String, LuaObject>, DeltaError> {
+        let mut objects = HashMap::new();
+        for (id, object) in deltas {
+            let have = self.seen.get(&id).map(|(version, _)| *version);
+            let mismatch = || DeltaError::Version {
+                id: id.clone(),
+                have,
+            };
+            let object = match object {
+                ObjectDelta::Full { version, object } => {
+                    self.seen.insert(id.clone(), (version, object.clone()));
+                    object
+                }
+                ObjectDelta::Unchanged { version } if have == Some(version) => {
+                    self.seen[&id].1.clone()
+                }
+                ObjectDelta::Changed {
+                    base,
+                    changed,
+                    removed,
+                } if have == Some(base) => {
+                    let (version, seen) = self.seen.get_mut(&id).ok_or_else(mismatch)?;
+                    let removed: HashSet<Key> = removed.iter().map(Key::from).collect();
+                    seen.members
+                        .retain(|(key, _)| !removed.contains(&Key::from(key)));
+                    for (key, value) in changed {
+                        match seen.members.iter_mut().find(|(k, _)| *k == key) {
+                            Some(member) => member.1 = value,
+                            None => seen.members.push((key, value)),
+                        }
+                    }
+                    *version = base + 1;
+                    seen.clone()
+                }
+                ObjectDelta::Unchanged { .. } | ObjectDelta::Changed { .. } => {
+                    return Err(mismatch())
+                }
+            };
+            objects.insert(id, object);
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_deltas() {
+        let mut session = Session::new();
+        session
+            .eval("state = { tick = 1, players = { { hp = 10 }, { hp = 20 } } }".to_string())
+            .await;
+        let (mut server, mut client) = (ClientVersions::default(), ClientVersions::default());
+        let first = session.get_path("state".to_string()).await.unwrap();
+        let delta = server.delta(first.clone());
+        assert!(delta
+            .objects
+            .values()
+            .all(|object| matches!(object, ObjectDelta::Full { version: 0, .. })));
+        assert_eq!(client.apply(delta).unwrap(), first);
+
+        session
+            .eval("state.tick = 2; state.players[2].hp = nil".to_string())
+            .await;
+        let second = session.get_path("state".to_string()).await.unwrap();
+        let LuaValue::ObjectRef(root) = &second.value else {
+            panic!("expected a table, got {:?}", second.value);
+        };
+        let delta = server.delta(second.clone());
+        assert_eq!(
+            delta.objects[root],
+            ObjectDelta::Changed {
+                base: 0,
+                changed: vec![(LuaValue::String("tick".to_string()), LuaValue::Integer(2))],
+                removed: vec![],
+            }
+        );
+        let unchanged = delta
+            .objects
+            .values()
+            .filter(|object| matches!(object, ObjectDelta::Unchanged { .. }))
+            .count();
+        // Only the first player stays as it was; the list of players is
+        // resent because its preview of the second one changed.
+        assert_eq!(unchanged, 1);
+        assert_eq!(client.apply(delta).unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn test_version_mismatch() {
+        let mut session = Session::new();
+        session.eval("t = { a = 1 }".to_string()).await;
+        let mut server = ClientVersions::default();
+        server.delta(session.get_path("t".to_string()).await.unwrap());
+        session.eval("t.a = 2".to_string()).await;
+        let delta = server.delta(session.get_path("t".to_string()).await.unwrap());
+        let mut fresh = ClientVersions::default();
+        assert!(matches!(
+            fresh.apply(delta),
+            Err(DeltaError::Version { have: None, .. })
+        ));
+    }
+}
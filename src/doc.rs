@@ -0,0 +1,77 @@
+//! The parts of `luarepl doc` that clap does not know about: the REPL's
+//! meta-commands and the keys of the settings file, as extended help text
+//! and as man page sections.
+
+use crate::commands;
+use crate::settings;
+
+/// The meta-commands and settings keys as plain text, for `luarepl doc`.
+pub fn long_help() -> String {
+    let mut text = String::from("REPL commands:\n");
+    for topic in commands::commands() {
+        text.push_str(&format!("  {}\n", topic.signature));
+        text.push_str(&format!("          {}\n", topic.description));
+    }
+    text.push_str(&format!("\nSettings ({}):\n", settings::DEFAULT_PATH));
+    for key in settings::KEYS {
+        text.push_str(&format!("  {} ({})\n", key.key, key.kind));
+        text.push_str(&format!("          {}\n", key.description));
+    }
+    text
+}
+
+/// Escapes `text` for a roff text line.
+fn roff(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    match text.starts_with('.') || text.starts_with('\'') {
+        true => format!("\\&{}", text),
+        false => text,
+    }
+}
+
+/// The meta-commands and settings keys as man page sections, to follow the
+/// ones generated from the command line.
+pub fn man_sections() -> String {
+    let mut page = String::from(".SH \"REPL COMMANDS\"\n");
+    for topic in commands::commands() {
+        page.push_str(&format!(
+            ".TP\n\\fB{}\\fR\n{}\n",
+            roff(topic.signature),
+            roff(topic.description)
+        ));
+    }
+    page.push_str(&format!(
+        ".SH SETTINGS\nThe settings file, {} by default, is TOML with these keys:\n",
+        roff(settings::DEFAULT_PATH)
+    ));
+    for key in settings::KEYS {
+        page.push_str(&format!(
+            ".TP\n\\fB{}\\fR (\\fI{}\\fR)\n{}\n",
+            roff(key.key),
+            key.kind,
+            roff(key.description)
+        ));
+    }
+    page
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_long_help() {
+        let text = long_help();
+        assert!(text.contains("  :set path = value\n"), "{}", text);
+        assert!(text.contains("  limits.memory (integer)\n"));
+    }
+
+    #[test]
+    fn test_man_sections() {
+        let page = man_sections();
+        assert!(page.starts_with(".SH \"REPL COMMANDS\"\n"));
+        assert!(page.contains(".TP\n\\fBlimits.memory\\fR (\\fIinteger\\fR)\n"));
+        assert!(page.contains("like \\-\\-hardened."));
+        assert_eq!(roff(".x"), "\\&.x");
+    }
+}
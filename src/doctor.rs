@@ -0,0 +1,228 @@
+//! `luarepl doctor`: checks that sessions can run here with the settings in
+//! effect, and that the files they would write to are writable, as a report
+//! to paste into support issues.
+
+use crate::limits::LimitViolation;
+use crate::limits::Limits;
+use crate::settings::Settings;
+use crate::workspace::Workspace;
+use crate::LuaValue;
+use crate::Session;
+use crate::SessionConfig;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// The files the REPL was told to use, if any.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Files<'a> {
+    pub settings: Option<&'a Path>,
+    pub crash_reports: Option<&'a Path>,
+    pub audit_log: Option<&'a Path>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    /// Nothing to check, e.g. for a file that was not given.
+    Skip(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (status, detail) = match &self.outcome {
+            Outcome::Pass(detail) => ("ok", detail),
+            Outcome::Fail(detail) => ("FAIL", detail),
+            Outcome::Skip(detail) => ("skip", detail),
+        };
+        write!(f, "{:<4}  {:<20} {}", status, self.name, detail)
+    }
+}
+
+/// Whether every check passed or had nothing to check.
+pub fn passed(checks: &[Check]) -> bool {
+    checks
+        .iter()
+        .all(|check| !matches!(check.outcome, Outcome::Fail(_)))
+}
+
+/// Runs every check, with sessions built from `config`.
+pub async fn run(config: &SessionConfig, files: Files<'_>) -> Vec<Check> {
+    let check = |name, outcome| Check { name, outcome };
+    vec![
+        check("session", session(config).await),
+        check(
+            "instruction limit",
+            limit(
+                config,
+                Limits {
+                    instructions: Some(100_000),
+                    ..config.limits
+                },
+                "while true do end",
+                LimitViolation::Instructions,
+            )
+            .await,
+        ),
+        check(
+            "memory limit",
+            limit(
+                config,
+                Limits {
+                    memory: Some(16 * 1024 * 1024),
+                    ..config.limits
+                },
+                "local t = {} for i = 1, 1e9 do t[i] = i end",
+                LimitViolation::Memory,
+            )
+            .await,
+        ),
+        check(
+            "workspace",
+            match Workspace::create() {
+                Ok(workspace) => Outcome::Pass(format!("created {}", workspace.root().display())),
+                Err(e) => Outcome::Fail(format!("cannot create a temporary directory: {}", e)),
+            },
+        ),
+        check("settings file", settings(files.settings)),
+        check(
+            "crash reports",
+            match files.crash_reports {
+                None => Outcome::Skip("not written, see --crash-reports".to_string()),
+                Some(dir) => writable(dir, writable_dir(dir)),
+            },
+        ),
+        check(
+            "audit log",
+            match files.audit_log {
+                None => Outcome::Skip("not written, see --audit-log".to_string()),
+                Some(path) => writable(path, writable_file(path)),
+            },
+        ),
+    ]
+}
+
+/// The report `luarepl doctor` prints, one line per check.
+pub fn report(checks: &[Check]) -> String {
+    let mut report = String::new();
+    for check in checks {
+        report.push_str(&format!("{}\n", check));
+    }
+    report
+}
+
+async fn session(config: &SessionConfig) -> Outcome {
+    let mut session = Session::with_config(config.clone());
+    let info = session.info();
+    let response = session.eval("return 6 * 7".to_string()).await;
+    session.close().await;
+    match response.value {
+        LuaValue::Integer(42) => Outcome::Pass(format!(
+            "luarepl {}, {}, features: {}",
+            info.version,
+            info.lua_version,
+            match info.features.is_empty() {
+                true => "none".to_string(),
+                false => info.features.join(", "),
+            }
+        )),
+        _ => Outcome::Fail(format!("6 * 7 came out as {}", response)),
+    }
+}
+
+/// Whether `source` runs into `expected` with `limits` in place.
+async fn limit(
+    config: &SessionConfig,
+    limits: Limits,
+    source: &str,
+    expected: LimitViolation,
+) -> Outcome {
+    let mut session = Session::with_config(SessionConfig {
+        limits,
+        ..config.clone()
+    });
+    let response = session.eval(source.to_string()).await;
+    session.close().await;
+    match response.limit_violation {
+        Some(violation) if violation == expected => Outcome::Pass("enforced".to_string()),
+        Some(violation) => Outcome::Fail(format!("stopped by the {:?} limit instead", violation)),
+        None => Outcome::Fail(format!("not enforced: {}", response)),
+    }
+}
+
+fn settings(path: Option<&Path>) -> Outcome {
+    let Some(path) = path else {
+        return Outcome::Skip("none found, see --config".to_string());
+    };
+    if let Err(e) = Settings::load(path) {
+        return Outcome::Fail(format!("{}: {}", path.display(), e));
+    }
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.permissions().readonly() => Outcome::Fail(format!(
+            "{} is read-only, so :config save cannot write it",
+            path.display()
+        )),
+        Ok(_) => Outcome::Pass(path.display().to_string()),
+        Err(e) => Outcome::Fail(format!("{}: {}", path.display(), e)),
+    }
+}
+
+fn writable(path: &Path, result: io::Result<()>) -> Outcome {
+    match result {
+        Ok(()) => Outcome::Pass(format!("{} is writable", path.display())),
+        Err(e) => Outcome::Fail(format!("cannot write to {}: {}", path.display(), e)),
+    }
+}
+
+fn writable_dir(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".luarepl-doctor-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(probe)
+}
+
+/// Opens `path` for appending without changing it, creating it if need be.
+fn writable_file(path: &Path) -> io::Result<()> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(drop)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_doctor() {
+        let checks = run(&SessionConfig::default(), Files::default()).await;
+        assert!(passed(&checks), "{}", report(&checks));
+        assert!(matches!(checks[0].outcome, Outcome::Pass(_)));
+
+        let dir = Workspace::create().unwrap();
+        let broken = dir.root().join("luarepl.toml");
+        std::fs::write(&broken, "limits = 1").unwrap();
+        let files = Files {
+            settings: Some(&broken),
+            crash_reports: Some(dir.root()),
+            ..Default::default()
+        };
+        let checks = run(&SessionConfig::default(), files).await;
+        assert!(!passed(&checks));
+        let outcome = |name| {
+            let check = checks.iter().find(|check| check.name == name).unwrap();
+            check.outcome.clone()
+        };
+        assert!(matches!(outcome("settings file"), Outcome::Fail(_)));
+        assert!(matches!(outcome("crash reports"), Outcome::Pass(_)));
+        assert!(matches!(outcome("audit log"), Outcome::Skip(_)));
+    }
+}
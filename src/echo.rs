@@ -0,0 +1,122 @@
+//! Feedback for evals that only assign, such as `x, cfg.port = f()`: the new
+//! values of the names on the left-hand side, in echo mode, see
+//! `SessionBuilder::echo_assignments`.
+
+use crate::path;
+use crate::EvalResponse;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Value;
+
+/// The targets of `source`'s first statement if it is an assignment and they
+/// are paths, e.g. `["x", "cfg.port"]`. Locals and computed keys such as
+/// `t[i]` are left out.
+pub(crate) fn targets(source: &str) -> Vec<String> {
+    let bytes = source.as_bytes();
+    let mut targets = vec![];
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        match quote {
+            Some(q) if byte == q => quote = None,
+            Some(_) if byte == b'\\' => i += 1,
+            Some(_) => {}
+            None => match byte {
+                b'"' | b'\'' => quote = Some(byte),
+                b'[' | b'(' | b'{' => depth += 1,
+                b']' | b')' | b'}' => depth = depth.saturating_sub(1),
+                b',' if depth == 0 => {
+                    targets.push(&source[start..i]);
+                    start = i + 1;
+                }
+                b'=' if depth == 0 => {
+                    let previous = i.checked_sub(1).map(|i| bytes[i]);
+                    if bytes.get(i + 1) == Some(&b'=')
+                        || matches!(previous, Some(b'<' | b'>' | b'~'))
+                    {
+                        return vec![];
+                    }
+                    targets.push(&source[start..i]);
+                    return targets
+                        .into_iter()
+                        .map(str::trim)
+                        .filter(|target| path::parse_path(target).is_ok())
+                        .map(str::to_string)
+                        .collect();
+                }
+                b'-' if bytes.get(i + 1) == Some(&b'-') => return vec![],
+                _ => {}
+            },
+        }
+        i += 1;
+    }
+    vec![]
+}
+
+/// Adds the values of the names `source` assigned to `response`, if it
+/// succeeded without returning anything.
+pub(crate) fn echo<'l>(
+    ctx: Context<'l>,
+    source: &str,
+    response: &mut EvalResponse,
+    max_depth: Option<usize>,
+) {
+    if !response.success || response.value != LuaValue::Nil {
+        return;
+    }
+    for target in targets(source) {
+        let value = path::parse_path(&target)
+            .and_then(|segments| path::resolve(ctx, &segments))
+            .unwrap_or(Value::Nil);
+        let echoed = EvalResponse::from_value(ctx, value, max_depth);
+        response.objects.extend(echoed.objects);
+        response.assignments.push((target, echoed.value));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output;
+    use crate::output::OutputLevel;
+    use crate::Session;
+
+    #[test]
+    fn test_targets() {
+        assert_eq!(targets("x, y = f()"), ["x", "y"]);
+        assert_eq!(
+            targets("cfg.servers[1].host, t['a'] = 'h', {1, 2}"),
+            ["cfg.servers[1].host", "t['a']"]
+        );
+        assert_eq!(targets("t[i], z = 1, 2"), ["z"]);
+        assert!(targets("local a = 1").is_empty());
+        assert!(targets("print(a == b)").is_empty());
+        assert!(targets("f(x, y)").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let mut session = Session::builder()
+            .echo_assignments(true)
+            .output_level(OutputLevel::Standard)
+            .build();
+        let resp = session
+            .eval("x, y = string.upper('a'), {}".to_string())
+            .await;
+        assert_eq!(
+            resp.assignments[0],
+            ("x".to_string(), LuaValue::String("A".to_string()))
+        );
+        assert!(matches!(resp.assignments[1].1, LuaValue::ObjectRef(_)));
+        assert!(output::render(&resp, OutputLevel::Standard).starts_with("x = \"A\", y = table: "));
+
+        let resp = session.eval("return x".to_string()).await;
+        assert!(resp.assignments.is_empty());
+        let mut session = Session::new();
+        let resp = session.eval("x = 1".to_string()).await;
+        assert!(resp.assignments.is_empty());
+    }
+}
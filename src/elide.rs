@@ -0,0 +1,248 @@
+//! Truncation of long strings and deep tables in rendered output. Whatever is
+//! cut is replaced by a marker such as `…(+3421 bytes, :more 4 to expand)` and
+//! kept, so that `:more 4` can print it later.
+//!
+//! Tables nested deeper than `Hardening::max_depth` are not serialized, so the
+//! interpreter keeps the most recent ones alive for `Session::expand`.
+
+use crate::output;
+use crate::output::OutputLevel;
+use crate::EvalResponse;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Table;
+use rlua::Value;
+use std::collections::BTreeSet;
+
+const KEPT_KEY: &str = "luarepl.elided";
+
+/// How many unserialized tables stay expandable.
+const KEPT_CAPACITY: i64 = 64;
+
+/// Keeps `table`, referenced in a response by `id` but not serialized. Keeping
+/// a table again makes it the most recent.
+pub(crate) fn keep<'l>(ctx: Context<'l>, id: &str, table: Table<'l>) {
+    let _ = (|| -> rlua::Result<()> {
+        let kept: Table = match ctx.named_registry_value(KEPT_KEY)? {
+            Value::Table(kept) => kept,
+            _ => {
+                let kept = ctx.create_table()?;
+                kept.raw_set("values", ctx.create_table()?)?;
+                kept.raw_set("order", ctx.create_table()?)?;
+                kept.raw_set("slots", ctx.create_table()?)?;
+                kept.raw_set("next", 0)?;
+                ctx.set_named_registry_value(KEPT_KEY, kept.clone())?;
+                kept
+            }
+        };
+        let values: Table = kept.raw_get("values")?;
+        let order: Table = kept.raw_get("order")?;
+        let slots: Table = kept.raw_get("slots")?;
+        let next: i64 = kept.raw_get("next")?;
+        // The order is a ring buffer of ids, the oldest sharing the new slot.
+        // An id kept again since only moved to a newer slot.
+        let slot = next % KEPT_CAPACITY;
+        if let Some(oldest) = order.raw_get::<_, Option<String>>(slot)? {
+            if slots.raw_get::<_, Option<i64>>(oldest.as_str())? == Some(slot) {
+                values.raw_set(oldest.as_str(), Value::Nil)?;
+                slots.raw_set(oldest, Value::Nil)?;
+            }
+        }
+        order.raw_set(slot, id)?;
+        slots.raw_set(id, slot)?;
+        values.raw_set(id, table)?;
+        kept.raw_set("next", next + 1)
+    })();
+}
+
+/// A table kept by `keep`, if it is still one of the most recent.
+pub(crate) fn kept<'l>(ctx: Context<'l>, id: &str) -> Option<Table<'l>> {
+    let kept: Table = ctx.named_registry_value(KEPT_KEY).ok()?;
+    let values: Table = kept.raw_get("values").ok()?;
+    values.raw_get(id).ok()
+}
+
+/// Where output is cut and how the cuts are marked. Markers may contain
+/// `{id}`, the argument of `:more`, and for strings `{bytes}`, the length of
+/// the elided part.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncation {
+    /// Longest string shown in full, in bytes.
+    pub max_string_len: usize,
+    pub string_marker: String,
+    pub table_marker: String,
+}
+
+impl Default for Truncation {
+    fn default() -> Self {
+        Self {
+            max_string_len: 200,
+            string_marker: "…(+{bytes} bytes, :more {id} to expand)".to_string(),
+            table_marker: "…(not serialized, :more {id} to expand)".to_string(),
+        }
+    }
+}
+
+/// Something cut from the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Elided {
+    /// The rest of a string.
+    Text(String),
+    /// The id of a table, for `Session::expand`.
+    Table(String),
+}
+
+/// What a frontend has cut so far, numbered from 1.
+#[derive(Debug, Default)]
+pub struct Elisions {
+    pub truncation: Truncation,
+    elided: Vec<Elided>,
+}
+
+impl Elisions {
+    pub fn new(truncation: Truncation) -> Self {
+        Self {
+            truncation,
+            elided: vec![],
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Elided> {
+        self.elided.get(id.checked_sub(1)?)
+    }
+
+    fn add(&mut self, elided: Elided) -> usize {
+        self.elided.push(elided);
+        self.elided.len()
+    }
+
+    fn cut(&mut self, value: &mut LuaValue) {
+        let LuaValue::String(s) = value else {
+            return;
+        };
+        if s.len() <= self.truncation.max_string_len {
+            return;
+        }
+        let mut end = self.truncation.max_string_len;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        let rest = s.split_off(end);
+        let bytes = rest.len();
+        let id = self.add(Elided::Text(rest));
+        s.push_str(
+            &self
+                .truncation
+                .string_marker
+                .replace("{bytes}", &bytes.to_string())
+                .replace("{id}", &id.to_string()),
+        );
+    }
+
+    /// Like `output::render`, with long strings cut and, in full output,
+    /// unserialized tables listed with their markers.
+    pub fn render(&mut self, response: &EvalResponse, level: OutputLevel) -> String {
+        let mut response = response.clone();
+        for value in &mut response.values {
+            self.cut(value);
+        }
+        match response.values.first() {
+            Some(first) => response.value = first.clone(),
+            None => self.cut(&mut response.value),
+        }
+        // Sorted, so that marker numbers do not depend on hashing.
+        let mut ids: Vec<String> = response.objects.keys().cloned().collect();
+        ids.sort();
+        for id in &ids {
+            if let Some(object) = response.objects.get_mut(id) {
+                for (_, value) in &mut object.members {
+                    self.cut(value);
+                }
+            }
+        }
+        let mut text = output::render(&response, level);
+        if level == OutputLevel::Full {
+            for id in unserialized(&response) {
+                let more = self.add(Elided::Table(id.clone()));
+                let marker = self
+                    .truncation
+                    .table_marker
+                    .replace("{id}", &more.to_string());
+                text.push_str(&format!("\n{} {}", id, marker));
+            }
+        }
+        text
+    }
+}
+
+/// Tables referenced by `response` that are missing from its objects.
+fn unserialized(response: &EvalResponse) -> BTreeSet<String> {
+    let referenced = std::iter::once(&response.value)
+        .chain(&response.values)
+        .chain(
+            response
+                .objects
+                .values()
+                .flat_map(|object| object.members.iter().flat_map(|(k, v)| [k, v])),
+        );
+    referenced
+        .filter_map(|value| match value {
+            LuaValue::ObjectRef(id) if !response.objects.contains_key(id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hardening::Hardening;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_long_strings() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("return string.rep('ab', 10) .. 'é'".to_string())
+            .await;
+        let mut elisions = Elisions::new(Truncation {
+            max_string_len: 21,
+            string_marker: "[+{bytes}: {id}]".to_string(),
+            ..Default::default()
+        });
+        let text = elisions.render(&resp, OutputLevel::Minimal);
+        assert_eq!(text, format!("\"{}[+2: 1]\"", "ab".repeat(10)));
+        assert_eq!(elisions.get(1), Some(&Elided::Text("é".to_string())));
+        assert_eq!(elisions.get(2), None);
+    }
+
+    #[tokio::test]
+    async fn test_deep_tables() {
+        let mut session = Session::builder()
+            .hardening(Hardening {
+                max_depth: 2,
+                ..Default::default()
+            })
+            .build();
+        let resp = session
+            .eval("return { a = { b = { c = 'deep' } } }".to_string())
+            .await;
+        let mut elisions = Elisions::default();
+        let text = elisions.render(&resp, OutputLevel::Full);
+        assert!(text.ends_with("…(not serialized, :more 1 to expand)"));
+        let Some(Elided::Table(id)) = elisions.get(1).cloned() else {
+            panic!("expected a table in {}", text);
+        };
+
+        let expanded = session.expand(id.clone()).await.unwrap();
+        let LuaValue::ObjectRef(root) = &expanded.value else {
+            panic!("expected a table, got {:?}", expanded.value);
+        };
+        assert_eq!(
+            expanded.objects[root].get("c"),
+            Some(&LuaValue::String("deep".to_string()))
+        );
+        session.reset().await;
+        assert!(session.expand(id).await.is_none());
+    }
+}
@@ -0,0 +1,64 @@
+//! Lua modules compiled into the binary by `build.rs`.
+//!
+//! With the `embed-lua` feature, every `.lua` file under `$LUAREPL_EMBED_DIR`
+//! (default: `lua/` next to `Cargo.toml`) is embedded and preloaded into each
+//! session, so `require` finds it without touching the filesystem.
+
+include!(concat!(env!("OUT_DIR"), "/embedded_lua.rs"));
+
+#[cfg(test)]
+#[path = "module_name.rs"]
+mod module_name;
+
+#[cfg(test)]
+mod test {
+    use super::module_name::module_name;
+    use std::path::Path;
+
+    #[test]
+    fn test_module_name() {
+        assert_eq!(module_name(Path::new("greet.lua")), "greet");
+        assert_eq!(module_name(Path::new("shapes/circle.lua")), "shapes.circle");
+        assert_eq!(module_name(Path::new("shapes/init.lua")), "shapes");
+        assert_eq!(module_name(Path::new("init.lua")), "init");
+    }
+
+    /// Needs the modules under `fixtures/embed`, as in
+    /// `LUAREPL_EMBED_DIR=fixtures/embed cargo test --features embed-lua`.
+    #[cfg(feature = "embed-lua")]
+    #[tokio::test]
+    async fn test_require_embedded() {
+        use super::EMBEDDED_MODULES;
+        use crate::image::SessionImage;
+        use crate::LuaValue;
+        use crate::Session;
+        use crate::SessionConfig;
+        use std::sync::Arc;
+
+        let mut names: Vec<_> = EMBEDDED_MODULES.iter().map(|(name, _)| *name).collect();
+        names.sort();
+        assert_eq!(names, ["greet", "shapes", "shapes.circle"]);
+
+        let image = SessionImage::builder().preload_embedded().build().unwrap();
+        let mut session = Session::with_config(SessionConfig {
+            image: Some(Arc::new(image)),
+            ..Default::default()
+        });
+        let resp = session
+            .eval("return require('greet').greeting, require('shapes').circle.area(2)".to_string())
+            .await;
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(
+            resp.values,
+            [
+                LuaValue::String("hello from an embedded module".to_string()),
+                LuaValue::Integer(12)
+            ]
+        );
+        // They were preloaded, not found on the filesystem.
+        let resp = session
+            .eval("return package.preload['shapes.circle'] ~= nil".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Boolean(true));
+    }
+}
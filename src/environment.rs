@@ -0,0 +1,215 @@
+//! Tables an eval can run against in place of the globals, see
+//! `EvalOptions::environment`, for dry runs, for evaluating config files
+//! whose assignments should not land in the session, and for giving each of
+//! several clients of one session globals of its own.
+//!
+//! Only the chunk's own global reads and writes are redirected; functions
+//! it calls keep the environment they were defined in, and tables reached
+//! through the globals can still be changed.
+
+use crate::value_id;
+use crate::LuaValue;
+use rlua::Chunk;
+use rlua::Context;
+use rlua::Error;
+use rlua::Table;
+use rlua::Value;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+const NAMESPACES_KEY: &str = "luarepl.namespaces";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Environment {
+    /// A table reachable from the globals, by the object id a response gave
+    /// it. The chunk sees only what the table holds, as after `setfenv`.
+    Object(String),
+    /// A fresh table holding these globals. Other names are read from the
+    /// real globals, and assignments stay in the table.
+    Values(Vec<(String, LuaValue)>),
+    /// A table the session keeps under this name from the first eval using
+    /// it until the Lua state is reset. Like `Values`, it reads what it
+    /// lacks from the real globals and keeps assignments to itself.
+    Namespace(String),
+}
+
+/// The table `environment` stands for in the Lua state behind `ctx`.
+pub(crate) fn resolve<'l>(ctx: Context<'l>, environment: &Environment) -> Result<Table<'l>, Error> {
+    match environment {
+        Environment::Object(id) => find(ctx, id).ok_or_else(|| {
+            Error::RuntimeError(format!("no table {} is reachable from the globals", id))
+        }),
+        Environment::Values(values) => {
+            let table = overlay(ctx)?;
+            for (name, value) in values {
+                table.raw_set(name.as_str(), value.to_lua(ctx)?)?;
+            }
+            Ok(table)
+        }
+        Environment::Namespace(name) => {
+            let namespaces = match ctx.named_registry_value(NAMESPACES_KEY)? {
+                Value::Table(namespaces) => namespaces,
+                _ => {
+                    let namespaces = ctx.create_table()?;
+                    ctx.set_named_registry_value(NAMESPACES_KEY, namespaces.clone())?;
+                    namespaces
+                }
+            };
+            match namespaces.raw_get(name.as_str())? {
+                Value::Table(namespace) => Ok(namespace),
+                _ => {
+                    let namespace = overlay(ctx)?;
+                    namespaces.raw_set(name.as_str(), namespace.clone())?;
+                    Ok(namespace)
+                }
+            }
+        }
+    }
+}
+
+/// An empty table that reads what it lacks from the globals.
+fn overlay(ctx: Context) -> Result<Table, Error> {
+    let table = ctx.create_table()?;
+    let metatable = ctx.create_table()?;
+    metatable.raw_set("__index", ctx.globals())?;
+    table.set_metatable(Some(metatable));
+    Ok(table)
+}
+
+pub(crate) fn resolve_option<'l>(
+    ctx: Context<'l>,
+    environment: &Option<Environment>,
+) -> Result<Option<Table<'l>>, Error> {
+    environment
+        .as_ref()
+        .map(|environment| resolve(ctx, environment))
+        .transpose()
+}
+
+/// Loads `source` to run against `env`, or the globals, named `name` if
+/// given.
+pub(crate) fn load<'l, 'a>(
+    ctx: Context<'l>,
+    source: &'a str,
+    env: &Option<Table<'l>>,
+    name: Option<&str>,
+) -> Result<Chunk<'l, 'a>, Error> {
+    let chunk = match name {
+        Some(name) => ctx.load(source).set_name(name)?,
+        None => ctx.load(source),
+    };
+    match env {
+        Some(env) => chunk.set_environment(env.clone()),
+        None => Ok(chunk),
+    }
+}
+
+/// Searches the tables reachable from the globals for the one named `id`.
+fn find<'l>(ctx: Context<'l>, id: &str) -> Option<Table<'l>> {
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([ctx.globals()]);
+    while let Some(table) = queue.pop_front() {
+        let table_id = value_id(ctx, Value::Table(table.clone()));
+        if table_id == id {
+            return Some(table);
+        }
+        if !seen.insert(table_id) {
+            continue;
+        }
+        for (key, value) in table.pairs::<Value, Value>().filter_map(Result::ok) {
+            for entry in [key, value] {
+                if let Value::Table(t) = entry {
+                    queue.push_back(t);
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EvalOptions;
+    use crate::Session;
+
+    fn within(environment: Environment) -> EvalOptions {
+        EvalOptions {
+            environment: Some(environment),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_values() {
+        let mut session = Session::new();
+        session.eval("port = 80".to_string()).await;
+        let config = within(Environment::Values(vec![(
+            "host".to_string(),
+            LuaValue::String("localhost".to_string()),
+        )]));
+        let resp = session
+            .eval_with(
+                "port = port + 1; name = host .. ':' .. port; return name".to_string(),
+                config,
+            )
+            .await;
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(resp.value, LuaValue::String("localhost:81".to_string()));
+        let resp = session.eval("return port, name".to_string()).await;
+        assert_eq!(resp.values, [LuaValue::Integer(80), LuaValue::Nil]);
+    }
+
+    #[tokio::test]
+    async fn test_object() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("sandbox = { x = 1 } return sandbox".to_string())
+            .await;
+        let LuaValue::ObjectRef(id) = resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let resp = session
+            .eval_with(
+                "x = x + 1 return print".to_string(),
+                within(Environment::Object(id.clone())),
+            )
+            .await;
+        assert_eq!(resp.value, LuaValue::Nil);
+        let resp = session.eval("return sandbox.x, x".to_string()).await;
+        assert_eq!(resp.values, [LuaValue::Integer(2), LuaValue::Nil]);
+
+        let resp = session
+            .eval_with(
+                "return x".to_string(),
+                within(Environment::Object("table: 0x1".to_string())),
+            )
+            .await;
+        assert_eq!(
+            resp.error.as_deref(),
+            Some("no table table: 0x1 is reachable from the globals")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_namespaces() {
+        let mut session = Session::new();
+        session.eval("speed = 1".to_string()).await;
+        let alice = || within(Environment::Namespace("alice".to_string()));
+        let bob = || within(Environment::Namespace("bob".to_string()));
+        session
+            .eval_with("speed = speed * 10".to_string(), alice())
+            .await;
+        session.eval_with("speed = 2".to_string(), bob()).await;
+        let resp = session.eval_with("return speed".to_string(), alice()).await;
+        assert_eq!(resp.value, LuaValue::Integer(10));
+        let resp = session.eval_with("return speed".to_string(), bob()).await;
+        assert_eq!(resp.value, LuaValue::Integer(2));
+        let resp = session.eval("return speed".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(1));
+
+        session.reset().await;
+        let resp = session.eval_with("return speed".to_string(), alice()).await;
+        assert_eq!(resp.value, LuaValue::Nil);
+    }
+}
@@ -0,0 +1,130 @@
+//! Errors raised with something other than a string, e.g.
+//! `error({ code = 404, msg = "no such player" })`, see
+//! `EvalResponse::error_value`.
+//!
+//! By the time rlua hands an error to Rust, only its `tostring` is left, so
+//! `error` and `assert` keep the last such value they raised. If the eval
+//! fails with that value's `tostring` as its message, it is the one that
+//! escaped, rather than one a `pcall` caught.
+//!
+//! A function ending in `return error("...")` still reports the position of
+//! its own caller, since the tail call takes its frame off the stack before
+//! the wrapped `error` runs.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Value;
+
+const TAKE_KEY: &str = "luarepl.error_value";
+
+/// The chunk the wrappers are defined in, whose frames `traceback` leaves
+/// out.
+pub(crate) const SOURCE: &str = "error";
+
+/// Wraps `error` and `assert`, and returns the function handing out the
+/// value they last raised if its `tostring` is the message passed to it.
+/// The wrapped functions stay upvalues named like them, so that tracebacks
+/// still name them, and `error` is passed a level one further out, so that
+/// messages still point at its caller.
+const INSTALL_SOURCE: &str = r##"
+local error, assert = error, assert
+local type, tostring, pcall, select = type, tostring, pcall, select
+local raised
+
+local function record(value)
+    local kind = type(value)
+    if kind ~= "nil" and kind ~= "string" and kind ~= "number" then
+        raised = value
+    end
+end
+
+_ENV.error = function(value, level)
+    record(value)
+    level = level or 1
+    -- Not a tail call, which would take this frame off the stack.
+    error(value, level > 0 and level + 1 or level)
+end
+
+_ENV.assert = function(v, ...)
+    if not v and select("#", ...) > 0 then
+        record((...))
+    end
+    return assert(v, ...)
+end
+
+return function(message)
+    local value = raised
+    raised = nil
+    if value ~= nil and message ~= nil then
+        local ok, text = pcall(tostring, value)
+        if ok and text == message then
+            return value
+        end
+    end
+    return nil
+end
+"##;
+
+/// `source` as a chunk returning its values, tried before running it as
+/// statements. The values pass through a function rather than being
+/// returned by a tail call, which would take the chunk's frame off the
+/// stack, and with it the position `error("...")` reports from the Lua half
+/// of `error`. The newline ends a comment `source` may end with.
+pub(crate) fn expression(source: &str) -> String {
+    format!("return (function(...) return ... end)({}\n)", source)
+}
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let take: Function = ctx
+        .load(INSTALL_SOURCE)
+        .set_name(&format!("={}", SOURCE))?
+        .call(())?;
+    ctx.set_named_registry_value(TAKE_KEY, take)
+}
+
+/// The value the eval that failed with `message` raised, if it raised one
+/// that is not a string. Forgets the value either way, so that evals that
+/// succeed pass `None`.
+pub(crate) fn take<'l>(ctx: Context<'l>, message: Option<&str>) -> Option<Value<'l>> {
+    let take: Function = ctx.named_registry_value(TAKE_KEY).ok()?;
+    match take.call::<_, Value>(message).ok()? {
+        Value::Nil => None,
+        value => Some(value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_error_values() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("error({ code = 404, msg = 'no such player' })".to_string())
+            .await;
+        assert!(!resp.success);
+        let Some(LuaValue::ObjectRef(id)) = &resp.error_value else {
+            panic!("expected a table, got {:?}", resp.error_value);
+        };
+        let object = &resp.objects[id];
+        assert!(object
+            .members
+            .contains(&(LuaValue::String("code".to_string()), LuaValue::Integer(404))));
+        assert!(resp.to_string().contains("msg = \"no such player\""));
+
+        let resp = session.eval("assert(false, true)".to_string()).await;
+        assert_eq!(resp.error_value, Some(LuaValue::Boolean(true)));
+
+        // String errors keep their position; caught values are not reported.
+        let resp = session.eval("error('plain')".to_string()).await;
+        assert_eq!(resp.error.as_deref(), Some("[string \"?\"]:1: plain"));
+        assert_eq!(resp.error_value, None);
+        let resp = session
+            .eval("pcall(error, { 1 }) error('later', 0)".to_string())
+            .await;
+        assert_eq!(resp.error_value, None);
+    }
+}
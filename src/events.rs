@@ -0,0 +1,153 @@
+//! Named events published by the host into a session, handled by Lua code
+//! subscribed with `events.on(name, handler)`.
+//!
+//! Handlers run on the interpreter thread, between evals, and each one is
+//! subject to the session's limits. An error in a handler is passed to the
+//! handlers of the `error` event as a `{ message = ..., event = ... }` table
+//! instead of stopping the others.
+
+use crate::Command;
+use crate::EvalOptions;
+use crate::EvalResponse;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+const DISPATCH_KEY: &str = "luarepl.events";
+
+/// Defines the `events` library and returns the function dispatching to it.
+const EVENTS_SOURCE: &str = r#"
+local handlers = {}
+local pcall, ipairs, remove = pcall, ipairs, table.remove
+
+events = {}
+
+function events.on(name, handler)
+    local list = handlers[name] or {}
+    handlers[name] = list
+    list[#list + 1] = handler
+    return handler
+end
+
+function events.off(name, handler)
+    local list = handlers[name] or {}
+    for i = #list, 1, -1 do
+        if list[i] == handler then
+            remove(list, i)
+        end
+    end
+end
+
+local function dispatch(name, value)
+    local list = { table.unpack(handlers[name] or {}) }
+    for _, handler in ipairs(list) do
+        local ok, message = pcall(handler, value)
+        if not ok and name ~= "error" then
+            dispatch("error", { message = tostring(message), event = name })
+        end
+    end
+end
+
+return dispatch
+"#;
+
+/// A clonable handle to a session for code that does not own it, such as
+/// other threads of the embedding application. Handles stop working once
+/// the session is closed or its interpreter is recovered after a crash.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    pub(crate) commands: UnboundedSender<Command>,
+    /// Bumped for each command queued, which runs Lua code behind the back
+    /// of the session's result cache.
+    pub(crate) mutations: Arc<AtomicU64>,
+}
+
+impl SessionHandle {
+    /// Queues `event` for the handlers subscribed to `name`. Returns false if
+    /// the session is gone.
+    pub fn emit(&self, name: &str, event: LuaValue) -> bool {
+        self.mutations.fetch_add(1, Ordering::Relaxed);
+        self.commands
+            .send(Command::Emit(name.to_string(), event))
+            .is_ok()
+    }
+
+    /// Queues an eval of `expr`. Unlike `Session::eval`, its response is not
+    /// formatted, cached or journaled for recovery, and it fails if it calls
+    /// async host functions. The receiver errs if the session is gone.
+    pub fn eval(&self, expr: String) -> oneshot::Receiver<EvalResponse> {
+        let (reply, response) = oneshot::channel();
+        self.mutations.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .commands
+            .send(Command::Eval(expr, EvalOptions::default(), reply));
+        response
+    }
+}
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let dispatch: Function = ctx.load(EVENTS_SOURCE).set_name("=events")?.eval()?;
+    ctx.set_named_registry_value(DISPATCH_KEY, dispatch)
+}
+
+/// Runs the handlers of `name`.
+pub(crate) fn dispatch(ctx: Context, name: &str, event: &LuaValue) -> Result<(), Error> {
+    let dispatch: Function = ctx.named_registry_value(DISPATCH_KEY)?;
+    dispatch.call((name, event.to_lua(ctx)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_events() {
+        let mut session = Session::new();
+        session
+            .eval(
+                "ticks = {}; timer = events.on('tick', function(n) ticks[#ticks + 1] = n end)"
+                    .to_string(),
+            )
+            .await;
+        let handle = session.handle();
+        assert!(handle.emit("tick", LuaValue::String("a".to_string())));
+        assert!(handle.emit("tick", LuaValue::String("b".to_string())));
+        assert!(handle.emit("ignored", LuaValue::Nil));
+        let resp = session
+            .eval("return table.concat(ticks, ',')".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::String("a,b".to_string()));
+
+        session.eval("events.off('tick', timer)".to_string()).await;
+        handle.emit("tick", LuaValue::String("c".to_string()));
+        let resp = session.eval("return #ticks".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(2));
+    }
+
+    #[tokio::test]
+    async fn test_handler_errors() {
+        let mut session = Session::new();
+        session
+            .eval(
+                "events.on('save', function() error('disk full', 0) end)
+                 events.on('save', function(name) saved = name end)
+                 events.on('error', function(e) failure = e.event .. ': ' .. e.message end)"
+                    .to_string(),
+            )
+            .await;
+        session
+            .handle()
+            .emit("save", LuaValue::String("a.txt".to_string()));
+        let resp = session.eval("return saved, failure".to_string()).await;
+        assert_eq!(resp.value, LuaValue::String("a.txt".to_string()));
+        let resp = session.eval("return failure".to_string()).await;
+        assert_eq!(resp.value, LuaValue::String("save: disk full".to_string()));
+    }
+}
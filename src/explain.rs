@@ -0,0 +1,261 @@
+//! `--explain`: what an eval did and what probably went wrong, for people
+//! learning Lua through the REPL.
+
+use crate::preview;
+use crate::value_id;
+use rlua::Context;
+use rlua::Error;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// `integer` and `float` rather than `number`; `None` if the eval failed.
+    pub result_type: Option<String>,
+    /// Globals that were assigned, in name order.
+    pub changed_globals: Vec<GlobalChange>,
+    pub hints: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlobalChange {
+    pub name: String,
+    /// Previews of the old and new values; `None` for nil.
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(result_type) = &self.result_type {
+            writeln!(f, "result type: {}", result_type)?;
+        }
+        for change in &self.changed_globals {
+            let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "nil".to_string());
+            writeln!(
+                f,
+                "changed: {} = {} (was {})",
+                change.name,
+                show(&change.after),
+                show(&change.before)
+            )?;
+        }
+        for hint in &self.hints {
+            writeln!(f, "hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// String-keyed globals, as an identity to compare and a preview to show.
+pub(crate) struct GlobalsDigest(BTreeMap<String, (String, String)>);
+
+fn describe<'l>(ctx: Context<'l>, value: Value<'l>) -> (String, String) {
+    match value {
+        Value::Boolean(b) => (b.to_string(), b.to_string()),
+        Value::Integer(i) => (format!("integer: {}", i), i.to_string()),
+        Value::Number(n) => (format!("float: {:?}", n), format!("{:?}", n)),
+        Value::String(s) => {
+            let s = format!("{:?}", s.to_str().unwrap_or("?"));
+            (format!("string: {}", s), s)
+        }
+        Value::Table(table) => {
+            let preview = preview::table_preview(table.clone());
+            (value_id(ctx, Value::Table(table)), preview)
+        }
+        other => {
+            let type_name = other.type_name().to_string();
+            (value_id(ctx, other), type_name)
+        }
+    }
+}
+
+pub(crate) fn digest(ctx: Context) -> GlobalsDigest {
+    let globals = ctx
+        .globals()
+        .pairs::<Value, Value>()
+        .filter_map(Result::ok)
+        .filter_map(|(key, value)| match key {
+            Value::String(name) => Some((name.to_str().ok()?.to_string(), describe(ctx, value))),
+            _ => None,
+        })
+        .collect();
+    GlobalsDigest(globals)
+}
+
+fn changes(before: GlobalsDigest, after: GlobalsDigest) -> Vec<GlobalChange> {
+    let (mut before, after) = (before.0, after.0);
+    let mut changes = vec![];
+    for (name, (id, preview)) in after {
+        match before.remove(&name) {
+            Some((old_id, _)) if old_id == id => {}
+            old => changes.push(GlobalChange {
+                name,
+                before: old.map(|(_, preview)| preview),
+                after: Some(preview),
+            }),
+        }
+    }
+    for (name, (_, preview)) in before {
+        changes.push(GlobalChange {
+            name,
+            before: Some(preview),
+            after: None,
+        });
+    }
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    changes
+}
+
+/// Splits `global 'x'` into its kind and name.
+fn variable(description: &str) -> Option<(&str, &str)> {
+    let (kind, rest) = description.split_once(" '")?;
+    Some((kind, rest.split('\'').next()?))
+}
+
+/// Finds out what was nil and what was done with it, from messages like
+/// "attempt to index a nil value (field 'pos')" and, since Lua 5.4.6,
+/// "global 'prnt' is not callable (a nil value)".
+pub(crate) fn nil_value(message: &str) -> Option<(&str, &str, &str)> {
+    if let Some(start) = message.find("attempt to ") {
+        let rest = &message[start + "attempt to ".len()..];
+        let (action, rest) = rest.split_once(" a nil value (")?;
+        let (kind, name) = variable(rest)?;
+        return Some((action, kind, name));
+    }
+    let (callee, _) = message.split_once(" is not callable (a nil value)")?;
+    let callee = callee
+        .rsplit_once(": ")
+        .map_or(callee, |(_, callee)| callee);
+    let (kind, name) = variable(callee)?;
+    Some(("call", kind, name))
+}
+
+fn nil_value_hint(message: &str) -> Option<String> {
+    let (action, kind, name) = nil_value(message)?;
+    let what = match kind {
+        "global" | "field" | "method" => format!("the {} `{}`", kind, name),
+        _ => format!("`{}`", name),
+    };
+    Some(match action {
+        "index" => format!(
+            "{} is nil, so it has no fields to read; check its spelling and that it was assigned first",
+            what
+        ),
+        "call" => format!(
+            "{} is nil, so it cannot be called; check the function name's spelling",
+            what
+        ),
+        _ => format!("{} is nil, but `{}` needs a value", what, action),
+    })
+}
+
+fn hints(source: &str, result: &Result<Value, Error>) -> Vec<String> {
+    let mut hints = vec![];
+    match result {
+        Ok(Value::Number(n)) if n.fract() == 0.0 && source.replace("//", "").contains('/') => {
+            hints.push(
+                "`/` always produces a float (4 / 2 == 2.0); use `//` for integer division"
+                    .to_string(),
+            );
+        }
+        // `if x = 1 then`: the parser wanted `then`, `do` or `)` instead.
+        Err(Error::SyntaxError { message, .. }) if message.contains("expected near '='") => {
+            hints.push("compare with `==` inside a condition, assign with `=`".to_string());
+        }
+        Err(e) => hints.extend(nil_value_hint(&e.to_string())),
+        Ok(_) => {}
+    }
+    hints
+}
+
+pub(crate) fn explain<'l>(
+    ctx: Context<'l>,
+    source: &str,
+    before: GlobalsDigest,
+    result: &Result<Value<'l>, Error>,
+) -> Explanation {
+    let result_type = match result {
+        Ok(Value::Integer(_)) => Some("integer".to_string()),
+        Ok(Value::Number(_)) => Some("float".to_string()),
+        Ok(value) => Some(value.type_name().to_string()),
+        Err(_) => None,
+    };
+    Explanation {
+        result_type,
+        changed_globals: changes(before, digest(ctx)),
+        hints: hints(source, result),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    async fn explained(session: &mut Session, source: &str) -> Explanation {
+        session
+            .eval(source.to_string())
+            .await
+            .explanation
+            .expect("explain mode always explains")
+    }
+
+    #[tokio::test]
+    async fn test_explain_changes() {
+        let mut session = Session::builder().explain(true).build();
+        let explanation = explained(&mut session, "x = 1; t = {1, 2}; return 4 / 2").await;
+        assert_eq!(explanation.result_type.as_deref(), Some("float"));
+        assert_eq!(
+            explanation.changed_globals,
+            vec![
+                GlobalChange {
+                    name: "t".to_string(),
+                    before: None,
+                    after: Some("{1, 2}".to_string()),
+                },
+                GlobalChange {
+                    name: "x".to_string(),
+                    before: None,
+                    after: Some("1".to_string()),
+                },
+            ]
+        );
+        assert!(explanation.hints[0].contains("`//`"));
+
+        let explanation = explained(&mut session, "x = nil; t[3] = 3; return 4 // 2").await;
+        assert_eq!(explanation.result_type.as_deref(), Some("integer"));
+        assert_eq!(
+            explanation.changed_globals,
+            vec![GlobalChange {
+                name: "x".to_string(),
+                before: Some("1".to_string()),
+                after: None,
+            }]
+        );
+        assert!(explanation.hints.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_explain_hints() {
+        let mut session = Session::builder().explain(true).build();
+        let explanation = explained(&mut session, "player = {}; return player.pos.x").await;
+        assert_eq!(explanation.result_type, None);
+        assert!(explanation.hints[0].starts_with("the field `pos` is nil"));
+
+        let explanation = explained(&mut session, "prnt('hi')").await;
+        assert!(explanation.hints[0].starts_with("the global `prnt` is nil"));
+
+        let explanation = explained(&mut session, "if x = 1 then end").await;
+        assert!(explanation.hints[0].contains("`==`"));
+
+        assert!(Session::new()
+            .eval("return 1".to_string())
+            .await
+            .explanation
+            .is_none());
+    }
+}
@@ -0,0 +1,248 @@
+//! Which objects with finalizers the garbage collector freed, for debugging
+//! resource lifetimes in scripts, see `SessionBuilder::gc_diagnostics`.
+//!
+//! Tracked are tables given a metatable with `__gc` through `setmetatable`,
+//! files opened by `io.open`, `io.tmpfile` and `io.popen`, and bridged
+//! userdata. Each gets a sentinel held only through a weak-keyed table, so
+//! the sentinel's own finalizer runs in the cycle that frees the object; for
+//! an object whose `__gc` runs, that is the cycle after it ran. Cycles are
+//! told apart by another sentinel that recreates itself whenever collected.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Table;
+use rlua::Value;
+use std::collections::BTreeMap;
+
+const FINALIZERS_KEY: &str = "luarepl.finalizers";
+
+/// How many cycles that freed tracked objects a report remembers.
+const CYCLES_KEPT: usize = 32;
+
+/// Returns a table with `track(value, name)` and `report()`.
+const INSTALL_SOURCE: &str = r#"
+local max_cycles = ...
+local setmetatable, rawget, type, next, pairs, remove =
+    setmetatable, rawget, type, next, pairs, table.remove
+
+local sentinels = setmetatable({}, { __mode = "k" })
+local cycles, totals, current, completed = {}, {}, {}, 0
+
+local sentinel_mt = {
+    __gc = function(sentinel)
+        local name = sentinel.name
+        current[name] = (current[name] or 0) + 1
+        totals[name] = (totals[name] or 0) + 1
+    end,
+}
+
+local function track(value, name)
+    if sentinels[value] == nil then
+        sentinels[value] = setmetatable({ name = name }, sentinel_mt)
+    end
+    return value
+end
+
+local function count_cycles()
+    setmetatable({}, {
+        __gc = function()
+            completed = completed + 1
+            if next(current) ~= nil then
+                cycles[#cycles + 1] = { cycle = completed, collected = current }
+                if #cycles > max_cycles then
+                    remove(cycles, 1)
+                end
+                current = {}
+            end
+            count_cycles()
+        end,
+    })
+end
+count_cycles()
+
+function _G.setmetatable(t, mt)
+    local result = setmetatable(t, mt)
+    if type(mt) == "table" and rawget(mt, "__gc") ~= nil then
+        local name = rawget(mt, "__name")
+        track(t, type(name) == "string" and name or "table")
+    end
+    return result
+end
+
+for _, name in ipairs({ "open", "tmpfile", "popen" }) do
+    local open = io[name]
+    if open then
+        io[name] = function(...)
+            local file, message, code = open(...)
+            if file then
+                track(file, "FILE*")
+            end
+            return file, message, code
+        end
+    end
+end
+
+local function report()
+    local alive = {}
+    for _, sentinel in pairs(sentinels) do
+        alive[sentinel.name] = (alive[sentinel.name] or 0) + 1
+    end
+    return { cycles = cycles, totals = totals, alive = alive, completed = completed }
+end
+
+return { track = track, report = report }
+"#;
+
+/// Tracked objects freed by one garbage collection cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcCycle {
+    /// The number of the cycle, counting from 1 for the session's first.
+    pub cycle: u64,
+    /// How many objects of each type the cycle freed.
+    pub collected: BTreeMap<String, u64>,
+}
+
+/// Type names are `__name` of the metatable for tables, `FILE*` for files,
+/// and the `UserDataType` name for bridged userdata.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Cycles completed since the session started or was last reset.
+    pub cycles_completed: u64,
+    /// The most recent cycles that freed tracked objects, oldest first.
+    pub cycles: Vec<GcCycle>,
+    /// How many objects of each type were freed in all cycles.
+    pub collected: BTreeMap<String, u64>,
+    /// How many tracked objects of each type are not freed yet.
+    pub alive: BTreeMap<String, u64>,
+}
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let finalizers: Table = ctx
+        .load(INSTALL_SOURCE)
+        .set_name("=finalizers")?
+        .call(CYCLES_KEPT)?;
+    ctx.set_named_registry_value(FINALIZERS_KEY, finalizers)
+}
+
+/// Tracks `value` as an object of type `name`, if the session has GC
+/// diagnostics.
+pub(crate) fn track<'l>(ctx: Context<'l>, value: Value<'l>, name: &str) {
+    if let Ok(Value::Table(finalizers)) = ctx.named_registry_value::<_, Value>(FINALIZERS_KEY) {
+        if let Ok(track) = finalizers.get::<_, Function>("track") {
+            let _ = track.call::<_, ()>((value, name));
+        }
+    }
+}
+
+fn counts(table: Table) -> BTreeMap<String, u64> {
+    table.pairs().filter_map(Result::ok).collect()
+}
+
+/// The report for the Lua state behind `ctx`, or `None` without GC
+/// diagnostics.
+pub(crate) fn report(ctx: Context) -> Option<GcReport> {
+    let finalizers: Table = ctx.named_registry_value(FINALIZERS_KEY).ok()?;
+    let report: Table = finalizers
+        .get::<_, Function>("report")
+        .ok()?
+        .call(())
+        .ok()?;
+    let cycles: Table = report.get("cycles").ok()?;
+    Some(GcReport {
+        cycles_completed: report.get("completed").unwrap_or(0),
+        cycles: cycles
+            .sequence_values::<Table>()
+            .filter_map(Result::ok)
+            .map(|cycle| GcCycle {
+                cycle: cycle.get("cycle").unwrap_or(0),
+                collected: cycle.get("collected").map(counts).unwrap_or_default(),
+            })
+            .collect(),
+        collected: report.get("totals").map(counts).unwrap_or_default(),
+        alive: report.get("alive").map(counts).unwrap_or_default(),
+    })
+}
+
+fn summary(counts: &BTreeMap<String, u64>) -> String {
+    let counts: Vec<_> = counts
+        .iter()
+        .map(|(name, count)| format!("{} {}", count, name))
+        .collect();
+    if counts.is_empty() {
+        "nothing".to_string()
+    } else {
+        counts.join(", ")
+    }
+}
+
+/// `report` as text for `:gcreport`.
+pub fn text(report: &GcReport) -> String {
+    let mut text = format!(
+        "{} cycles completed\ncollected: {}\nalive: {}\n",
+        report.cycles_completed,
+        summary(&report.collected),
+        summary(&report.alive)
+    );
+    if !report.cycles.is_empty() {
+        text.push_str("\nrecent cycles:\n");
+    }
+    for cycle in &report.cycles {
+        text.push_str(&format!(
+            "{:>8}  {}\n",
+            format!("#{}", cycle.cycle),
+            summary(&cycle.collected)
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_collected_tables() {
+        let mut session = Session::builder().gc_diagnostics(true).build();
+        session
+            .eval(
+                "local Conn = { __name = 'Conn', __gc = function() end }\n\
+                 keep = setmetatable({}, Conn)\n\
+                 for i = 1, 3 do setmetatable({}, Conn) end\n\
+                 setmetatable({}, { __gc = function() end })\n\
+                 setmetatable({}, {})"
+                    .to_string(),
+            )
+            .await;
+        session
+            .eval("collectgarbage() collectgarbage() collectgarbage()".to_string())
+            .await;
+        let report = session.gc_report().await.unwrap();
+        assert_eq!(report.collected.get("Conn"), Some(&3));
+        assert_eq!(report.collected.get("table"), Some(&1));
+        assert_eq!(report.alive.get("Conn"), Some(&1));
+        assert!(report.cycles_completed >= 3);
+        let per_cycle: u64 = report
+            .cycles
+            .iter()
+            .flat_map(|cycle| cycle.collected.values())
+            .sum();
+        assert_eq!(per_cycle, 4);
+
+        assert!(Session::new().gc_report().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_files() {
+        let mut session = Session::builder().gc_diagnostics(true).build();
+        session
+            .eval("local f = io.tmpfile() f:write('x')".to_string())
+            .await;
+        session
+            .eval("collectgarbage() collectgarbage()".to_string())
+            .await;
+        let report = session.gc_report().await.unwrap();
+        assert_eq!(report.collected.get("FILE*"), Some(&1));
+        assert!(super::text(&report).contains("collected: 1 FILE*"));
+    }
+}
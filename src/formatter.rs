@@ -0,0 +1,124 @@
+//! Compact renderings of domain objects, registered by embedders with
+//! `SessionBuilder::register_formatter`.
+
+use crate::EvalResponse;
+use crate::LuaObject;
+use crate::LuaValue;
+use std::fmt;
+use std::sync::Arc;
+
+/// Decides which objects a formatter applies to.
+#[derive(Clone)]
+pub struct TypeMatcher(Arc<dyn Fn(&LuaObject) -> bool + Send + Sync>);
+
+impl TypeMatcher {
+    /// Matches tables that have all of the given string keys.
+    pub fn has_fields(fields: &[&str]) -> Self {
+        let fields: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+        Self::custom(move |object| fields.iter().all(|field| object.get(field).is_some()))
+    }
+
+    /// Matches tables whose `field` holds `value`, e.g. `kind = "vec3"`.
+    pub fn field_equals(field: &str, value: LuaValue) -> Self {
+        let field = field.to_string();
+        Self::custom(move |object| object.get(&field) == Some(&value))
+    }
+
+    pub fn custom(matches: impl Fn(&LuaObject) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(matches))
+    }
+
+    pub fn matches(&self, object: &LuaObject) -> bool {
+        (self.0)(object)
+    }
+}
+
+impl fmt::Debug for TypeMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TypeMatcher")
+    }
+}
+
+#[derive(Clone)]
+pub struct Formatter {
+    matcher: TypeMatcher,
+    format: Arc<dyn Fn(&LuaObject) -> String + Send + Sync>,
+}
+
+impl Formatter {
+    pub fn new(
+        matcher: TypeMatcher,
+        format: impl Fn(&LuaObject) -> String + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            matcher,
+            format: Arc::new(format),
+        }
+    }
+}
+
+impl fmt::Debug for Formatter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Formatter")
+            .field("matcher", &self.matcher)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sets `LuaObject::display` on every object matched by one of `formatters`;
+/// the first matching formatter wins.
+pub(crate) fn apply(formatters: &[Formatter], response: &mut EvalResponse) {
+    if formatters.is_empty() {
+        return;
+    }
+    for object in response.objects.values_mut() {
+        object.display = formatters
+            .iter()
+            .find(|formatter| formatter.matcher.matches(object))
+            .map(|formatter| (formatter.format)(object));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_registered_formatter() {
+        let mut session = Session::builder()
+            .register_formatter(TypeMatcher::has_fields(&["x", "y"]), |object| {
+                format!(
+                    "<{}, {}>",
+                    object.get("x").unwrap(),
+                    object.get("y").unwrap()
+                )
+            })
+            .build();
+
+        let resp = session
+            .eval("return { pos = { x = 1, y = 2 }, name = 'p' }".to_string())
+            .await;
+        let mut displays: Vec<_> = resp
+            .objects
+            .values()
+            .map(|object| object.display.clone())
+            .collect();
+        displays.sort();
+        assert_eq!(displays, vec![None, Some("<1, 2>".to_string())]);
+    }
+
+    #[test]
+    fn test_field_equals() {
+        let mut object = LuaObject::new();
+        object.insert(
+            LuaValue::String("kind".to_string()),
+            LuaValue::String("vec3".to_string()),
+        );
+        assert!(
+            TypeMatcher::field_equals("kind", LuaValue::String("vec3".to_string()))
+                .matches(&object)
+        );
+        assert!(!TypeMatcher::field_equals("kind", LuaValue::Nil).matches(&object));
+    }
+}
@@ -0,0 +1,138 @@
+//! Functions in responses, see `LuaValue::FunctionRef`. They cannot be sent
+//! to clients, so responses describe where they were defined instead.
+
+use crate::value_id;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Lua;
+use rlua::StdLib;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+
+const INFO_KEY: &str = "luarepl.function_info";
+
+/// Keeps `debug.getinfo` for `FunctionRef::new` and removes the rest of the
+/// debug library before any script runs.
+const INSTALL_SOURCE: &str = r#"
+local getinfo = debug.getinfo
+debug = nil
+package.loaded.debug = nil
+return function(f)
+    local info = getinfo(f, "S")
+    return info.short_src, info.linedefined, info.lastlinedefined, info.what
+end
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionRef {
+    /// Identifies the function like the ids of `LuaValue::ObjectRef`, e.g.
+    /// `function: 0x5581c0a3e2f0`.
+    pub id: String,
+    /// The chunk the function was defined in, e.g. `[string "?"]`, or `[C]`.
+    pub source: String,
+    /// The lines the definition spans; `None` for C functions, and for Lua
+    /// states not created by a session.
+    pub lines: Option<(u32, u32)>,
+    /// Whether the function is implemented in C or Rust rather than Lua.
+    pub is_c: bool,
+}
+
+impl FunctionRef {
+    pub(crate) fn new<'l>(ctx: Context<'l>, function: Function<'l>) -> Self {
+        let id = value_id(ctx, Value::Function(function.clone()));
+        let info: Result<(String, i64, i64, String), Error> = (|| {
+            let getinfo: Function = ctx.named_registry_value(INFO_KEY)?;
+            getinfo.call(function)
+        })();
+        match info {
+            Ok((source, first, last, what)) => {
+                let is_c = what == "C";
+                let lines = match (u32::try_from(first), u32::try_from(last)) {
+                    (Ok(first), Ok(last)) if !is_c => Some((first, last)),
+                    _ => None,
+                };
+                Self {
+                    id,
+                    source,
+                    lines,
+                    is_c,
+                }
+            }
+            Err(_) => Self {
+                id,
+                source: "?".to_string(),
+                lines: None,
+                is_c: false,
+            },
+        }
+    }
+}
+
+/// Makes `debug.getinfo` available to `FunctionRef::new`. Sessions do not
+/// load the debug library, so it is loaded here and taken out of scripts'
+/// reach again.
+pub(crate) fn install(lua: &Lua) -> Result<(), Error> {
+    // Safety: scripts never see the debug library, and the one function kept
+    // only reads where functions were defined.
+    unsafe { lua.unsafe_load_from_std_lib(StdLib::DEBUG)? };
+    lua.context(|ctx| {
+        let getinfo: Function = ctx
+            .load(INSTALL_SOURCE)
+            .set_name("=function_info")?
+            .eval()?;
+        ctx.set_named_registry_value(INFO_KEY, getinfo)
+    })
+}
+
+impl fmt::Display for FunctionRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.lines {
+            Some((first, last)) => write!(f, "{} ({}:{}-{})", self.id, self.source, first, last),
+            None => write!(f, "{} ({})", self.id, self.source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_c_function() {
+        let mut session = Session::new();
+        let resp = session.eval("return print".to_string()).await;
+        assert!(resp.success, "{:?}", resp.error);
+        let LuaValue::FunctionRef(function) = &resp.value else {
+            panic!("expected a function, got {:?}", resp.value);
+        };
+        assert!(function.is_c);
+        assert_eq!(function.source, "[C]");
+        assert_eq!(function.lines, None);
+        // Scripts still cannot reach the debug library.
+        let resp = session.eval("return debug".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_lua_function() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("local t = {}\nfunction t.f()\n  return 1\nend\nreturn t".to_string())
+            .await;
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let Some(LuaValue::FunctionRef(function)) = resp.objects[id].get("f") else {
+            panic!("expected a function in {:?}", resp.objects[id]);
+        };
+        assert!(!function.is_c);
+        assert_eq!(function.source, "[string \"?\"]");
+        assert_eq!(function.lines, Some((2, 4)));
+        assert!(function.id.starts_with("function: "));
+    }
+}
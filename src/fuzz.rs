@@ -0,0 +1,221 @@
+//! `luarepl fuzz-self`: feeds a hardened session random and malformed input
+//! and checks that every eval finishes, that malformed input is refused
+//! with the reason, and that the session keeps answering.
+
+use crate::hardening::Hardening;
+use crate::limits::Limits;
+use crate::EvalResponse;
+use crate::LuaValue;
+use crate::Session;
+use std::time::Duration;
+
+/// How long a single eval may take before the session counts as hung.
+const EVAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+const FRAGMENTS: &[&str] = &[
+    "local",
+    "x",
+    "y",
+    "=",
+    "==",
+    "{",
+    "}",
+    "(",
+    ")",
+    "[",
+    "]",
+    "[[",
+    "]]",
+    "--",
+    "--[[",
+    ",",
+    ";",
+    ":",
+    ".",
+    "..",
+    "...",
+    "#",
+    "+",
+    "-",
+    "*",
+    "/",
+    "//",
+    "^",
+    "%",
+    "~",
+    "<<",
+    "1",
+    "0.5",
+    "1e308",
+    "-1",
+    "'s'",
+    "\"",
+    "\\",
+    "\0",
+    "nil",
+    "true",
+    "function",
+    "end",
+    "return",
+    "if",
+    "then",
+    "else",
+    "for",
+    "in",
+    "do",
+    "while",
+    "repeat",
+    "until",
+    "goto",
+    "::l::",
+    "_G",
+    "_ENV",
+    "print",
+    "tostring",
+    "string",
+    "setmetatable",
+    "getmetatable",
+    "rawset",
+    "pcall",
+    "error",
+    "load",
+    "coroutine.wrap",
+    "string.rep('x', 1 << 30)",
+    "while true do end",
+    "x = {x}",
+    "__repl_format",
+    "__index",
+    "__tostring",
+];
+
+/// xorshift64*, so runs are reproducible from the seed alone.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn generate(rng: &mut Rng, hardening: &Hardening) -> Vec<u8> {
+    match rng.below(5) {
+        0 => (0..rng.below(64)).map(|_| rng.next() as u8).collect(),
+        1 => {
+            let n = rng.below(2_000);
+            format!("return {}{}", "{".repeat(n), "}".repeat(n)).into_bytes()
+        }
+        2 => format!(
+            "local t = {{}} for i = 1, {} do t = {{t, t}} end return t",
+            rng.below(100_000)
+        )
+        .into_bytes(),
+        3 => vec![b'x'; hardening.max_input_len + rng.below(16)],
+        _ => {
+            let tokens: Vec<_> = (0..rng.below(24))
+                .map(|_| FRAGMENTS[rng.below(FRAGMENTS.len())])
+                .collect();
+            tokens.join(" ").into_bytes()
+        }
+    }
+}
+
+/// Evaluates `source` on a separate task so that a panic or hang in the
+/// session is reported instead of taking the fuzzer down with it.
+async fn timed_eval(
+    session: Session,
+    source: String,
+) -> Result<(Session, EvalResponse), &'static str> {
+    let eval = tokio::spawn(async move {
+        let mut session = session;
+        let response = session.eval(source).await;
+        (session, response)
+    });
+    match tokio::time::timeout(EVAL_TIMEOUT, eval).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(_)) => Err("killed the session"),
+        Err(_) => Err("hung"),
+    }
+}
+
+/// Whether `response` is that of a working `return 1`.
+fn answered(response: &EvalResponse) -> bool {
+    response.success && response.value == LuaValue::Integer(1)
+}
+
+/// Runs `iterations` random inputs, returning a description of the first one
+/// that hung or killed the session.
+pub async fn fuzz_self(iterations: usize, seed: u64) -> Result<(), String> {
+    let hardening = Hardening::default();
+    let mut session = Session::builder()
+        .hardening(hardening)
+        .limits(Limits {
+            memory: Some(64 * 1024 * 1024),
+            instructions: Some(1_000_000),
+        })
+        .build();
+    let mut rng = Rng(seed.max(1));
+
+    for i in 0..iterations {
+        let input = generate(&mut rng, &hardening);
+        let fail = |what| {
+            format!(
+                "input {} {}: {:?}",
+                i,
+                what,
+                String::from_utf8_lossy(&input)
+            )
+        };
+        let (source, refused) = match (
+            hardening.check_input(&input),
+            String::from_utf8(input.clone()),
+        ) {
+            (Ok(source), _) => (source.to_string(), None),
+            // Malformed input, which the session must refuse by itself;
+            // invalid UTF-8 cannot be an eval input at all.
+            (Err(e), Ok(source)) => (source, Some(e.to_string())),
+            (Err(_), Err(_)) => continue,
+        };
+        let (returned, response) = timed_eval(session, source).await.map_err(fail)?;
+        session = returned;
+        if refused.is_some() && (response.success || response.error != refused) {
+            return Err(fail("was not rejected"));
+        }
+
+        // Inputs may legitimately break `return 1`, e.g. by installing a
+        // format hook, so a session only counts as stuck if a reset does
+        // not bring it back.
+        let (returned, response) = timed_eval(session, "return 1".to_string())
+            .await
+            .map_err(fail)?;
+        session = returned;
+        if !answered(&response) {
+            session.reset().await;
+            let (returned, response) = timed_eval(session, "return 1".to_string())
+                .await
+                .map_err(fail)?;
+            if !answered(&response) {
+                return Err(fail("left the session unresponsive"));
+            }
+            session = returned;
+        }
+    }
+    session.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fuzz_self() {
+        assert_eq!(fuzz_self(200, 42).await, Ok(()));
+    }
+}
@@ -0,0 +1,151 @@
+//! Bounds for sessions fed untrusted input, see `SessionBuilder::hardening`.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hardening {
+    /// Longest accepted source, in bytes.
+    pub max_input_len: usize,
+    /// Tables nested deeper than this are referenced in responses but not
+    /// serialized; the result table itself is depth 1.
+    pub max_depth: usize,
+}
+
+impl Default for Hardening {
+    fn default() -> Self {
+        Self {
+            max_input_len: 64 * 1024,
+            max_depth: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputError {
+    TooLong { len: usize, max: usize },
+    NulByte { position: usize },
+    InvalidUtf8 { position: usize },
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputError::TooLong { len, max } => {
+                write!(f, "input is {} bytes, the limit is {}", len, max)
+            }
+            InputError::NulByte { position } => write!(f, "NUL byte at offset {}", position),
+            InputError::InvalidUtf8 { position } => {
+                write!(f, "invalid UTF-8 at offset {}", position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+impl Hardening {
+    /// Returns `source` as text if it is acceptable as an eval input.
+    pub fn check_input<'a>(&self, source: &'a [u8]) -> Result<&'a str, InputError> {
+        if source.len() > self.max_input_len {
+            return Err(InputError::TooLong {
+                len: source.len(),
+                max: self.max_input_len,
+            });
+        }
+        let source = std::str::from_utf8(source).map_err(|e| InputError::InvalidUtf8 {
+            position: e.valid_up_to(),
+        })?;
+        match source.find('\0') {
+            Some(position) => Err(InputError::NulByte { position }),
+            None => Ok(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::local::LocalSession;
+    use crate::EvalRequest;
+    use crate::EvalResponse;
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[test]
+    fn test_check_input() {
+        let hardening = Hardening {
+            max_input_len: 8,
+            ..Default::default()
+        };
+        assert_eq!(hardening.check_input(b"return 1"), Ok("return 1"));
+        assert_eq!(
+            hardening.check_input(b"return 10"),
+            Err(InputError::TooLong { len: 9, max: 8 })
+        );
+        assert_eq!(
+            hardening.check_input(b"x\0"),
+            Err(InputError::NulByte { position: 1 })
+        );
+        assert_eq!(
+            hardening.check_input(b"'\xff'"),
+            Err(InputError::InvalidUtf8 { position: 1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hardened_session() {
+        let mut session = Session::builder()
+            .hardening(Hardening {
+                max_depth: 2,
+                ..Default::default()
+            })
+            .build();
+        let resp = session
+            .eval_request(EvalRequest::new(7, "return '\0'".to_string()))
+            .await;
+        assert!(!resp.success);
+        assert_eq!(resp.error.as_deref(), Some("NUL byte at offset 8"));
+        assert_eq!(resp.request_id, Some(7));
+        let resp = session.eval("x".repeat(64 * 1024 + 1)).await;
+        assert_eq!(
+            resp.error.as_deref(),
+            Some("input is 65537 bytes, the limit is 65536")
+        );
+        // Sessions take text, so only other frontends see invalid UTF-8.
+        let e = Hardening::default().check_input(b"'\xff'").unwrap_err();
+        assert_eq!(
+            EvalResponse::rejected(e).error.as_deref(),
+            Some("invalid UTF-8 at offset 1")
+        );
+
+        let resp = session
+            .eval("local t = {} for i = 1, 100000 do t = {t} end return t".to_string())
+            .await;
+        assert!(resp.success);
+        assert_eq!(resp.objects.len(), 2);
+        assert_eq!(resp.graph_stats.max_depth, 2);
+
+        let resp = session.eval("return io.stdout".to_string()).await;
+        assert!(matches!(resp.value, LuaValue::UserData(_)));
+        let resp = session.eval("return 1".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(1));
+    }
+
+    #[test]
+    fn test_hardened_local_session() {
+        let mut session = LocalSession::with_config(crate::SessionConfig {
+            hardening: Some(Hardening {
+                max_input_len: 8,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let resp = session.eval("x\0".to_string());
+        assert_eq!(resp.error.as_deref(), Some("NUL byte at offset 1"));
+        let resp = session.eval("return 10".to_string());
+        assert_eq!(
+            resp.error.as_deref(),
+            Some("input is 9 bytes, the limit is 8")
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! The offline reference behind `:help` and `:apropos`, embedded from
+//! `help/reference.txt`.
+
+use std::sync::OnceLock;
+
+const REFERENCE: &str = include_str!("../help/reference.txt");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HelpTopic {
+    pub name: &'static str,
+    pub signature: &'static str,
+    pub description: &'static str,
+}
+
+impl HelpTopic {
+    /// The first sentence of the description.
+    pub fn summary(&self) -> &'static str {
+        match self.description.find(". ") {
+            Some(end) => &self.description[..=end],
+            None => self.description,
+        }
+    }
+}
+
+fn parse(reference: &'static str) -> Vec<HelpTopic> {
+    let mut topics = vec![];
+    for section in reference.split("\n## ").skip(1) {
+        let mut lines = section.lines();
+        let name = lines.next().unwrap_or_default().trim();
+        let signature = lines.next().unwrap_or_default().trim();
+        let description = lines.next().unwrap_or_default().trim();
+        topics.push(HelpTopic {
+            name,
+            signature,
+            description,
+        });
+    }
+    topics
+}
+
+pub fn topics() -> &'static [HelpTopic] {
+    static TOPICS: OnceLock<Vec<HelpTopic>> = OnceLock::new();
+    TOPICS.get_or_init(|| parse(REFERENCE))
+}
+
+pub fn lookup(name: &str) -> Option<&'static HelpTopic> {
+    topics().iter().find(|topic| topic.name == name)
+}
+
+/// Topics whose name or description contains `pattern`, ignoring case.
+pub fn apropos(pattern: &str) -> Vec<&'static HelpTopic> {
+    let pattern = pattern.to_lowercase();
+    topics()
+        .iter()
+        .filter(|topic| {
+            topic.name.to_lowercase().contains(&pattern)
+                || topic.description.to_lowercase().contains(&pattern)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let gsub = lookup("string.gsub").unwrap();
+        assert_eq!(
+            gsub.signature,
+            "string.gsub(s, pattern, repl [, n]) -> string, integer"
+        );
+        assert!(gsub.summary().starts_with("Returns a copy of s"));
+        assert!(gsub.summary().ends_with('.'));
+        assert!(lookup("string.nope").is_none());
+        assert!(topics()
+            .iter()
+            .all(|topic| !topic.signature.is_empty() && !topic.description.is_empty()));
+    }
+
+    #[test]
+    fn test_apropos() {
+        let names: Vec<_> = apropos("METATABLE")
+            .iter()
+            .map(|topic| topic.name)
+            .collect();
+        assert!(names.contains(&"setmetatable"));
+        assert!(names.contains(&"metatables"));
+        assert!(apropos("no such text anywhere").is_empty());
+    }
+}
@@ -0,0 +1,414 @@
+//! Timelines of the values stored at configured paths, see
+//! `SessionBuilder::record_history`, for answering "what set this to nil?".
+//!
+//! The key at the end of each path is moved out of its parent table into a
+//! shadow table, so that every assignment to it reaches the `__newindex`
+//! handler installed on the parent, even one replacing an existing value.
+//! Reads, `pairs`, `next`, `rawget` and response graphs still see the key
+//! in the parent, the last two because the globals `next` and `rawget` are
+//! replaced with functions that look in the shadow too; `getmetatable`
+//! does see the metatable installed on the parent.
+//! Parents that already have a metatable are not instrumented; for them,
+//! and for changes `__newindex` cannot see such as `rawset` or replacing a
+//! parent table, the value is compared after every eval instead.
+
+use crate::host;
+use crate::path;
+use crate::path::PathSegment;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Table;
+use rlua::Value;
+use std::collections::VecDeque;
+
+const HISTORY_KEY: &str = "luarepl.history";
+const HIDDEN_KEY: &str = "luarepl.history_hidden";
+
+/// How many changes of one path are kept.
+const MAX_ENTRIES: usize = 1000;
+
+/// Returns `sync`, recording each path's current value if it changed and
+/// instrumenting new parents, and `drain`, handing over the changes recorded
+/// since the last call. Also returns the weak table mapping instrumented
+/// tables to their shadows.
+const INSTALL_SOURCE: &str = r#"
+local globals, paths, max_pending = ...
+local getmetatable, setmetatable, rawget, rawset, rawequal, next, type, remove =
+    getmetatable, setmetatable, rawget, rawset, rawequal, next, type, table.remove
+
+local hidden = setmetatable({}, { __mode = "k" })
+local states = setmetatable({}, { __mode = "k" })
+local parents, seen, last, pending = {}, {}, {}, {}
+
+local function record(i, value)
+    local changes = pending[i]
+    if #changes == max_pending then
+        remove(changes, 1)
+    end
+    changes[#changes + 1] = { value = value }
+    seen[i], last[i] = true, value
+end
+
+-- `next` over the keys of `t`, then those moved into its shadow.
+local function iterate(t, k)
+    local shadow = hidden[t]
+    if shadow == nil then
+        return next(t, k)
+    end
+    if k == nil or shadow[k] == nil then
+        local v
+        k, v = next(t, k)
+        if k ~= nil then
+            return k, v
+        end
+    end
+    return next(shadow, k)
+end
+
+globals.next = iterate
+
+function globals.rawget(t, k)
+    local v = rawget(t, k)
+    local shadow = hidden[t]
+    if v == nil and shadow ~= nil then
+        return rawget(shadow, k)
+    end
+    return v
+end
+
+local function instrument(t)
+    local state = states[t]
+    if state then
+        if getmetatable(t) == state.mt then
+            return state
+        end
+        -- A script replaced the metatable: hand the keys back to the table,
+        -- unless it has since stored them again itself.
+        for k, v in next, state.shadow do
+            if rawget(t, k) == nil then
+                rawset(t, k, v)
+            end
+        end
+        states[t], hidden[t] = nil, nil
+        return nil
+    end
+    if getmetatable(t) ~= nil then
+        return nil
+    end
+    local shadow, keys = {}, {}
+    state = { shadow = shadow, keys = keys }
+    state.mt = {
+        __index = shadow,
+        __newindex = function(self, k, v)
+            local indices = keys[k]
+            if indices == nil then
+                return rawset(self, k, v)
+            end
+            shadow[k] = v
+            for i in next, indices do
+                record(i, v)
+            end
+        end,
+        __pairs = function(self)
+            return iterate, self, nil
+        end,
+    }
+    setmetatable(t, state.mt)
+    states[t], hidden[t] = state, shadow
+    return state
+end
+
+local function release(i, key)
+    local parent = parents[i]
+    local state = parent and states[parent]
+    parents[i] = nil
+    if state == nil or state.keys[key] == nil then
+        return
+    end
+    state.keys[key][i] = nil
+    if next(state.keys[key]) == nil then
+        state.keys[key] = nil
+        rawset(parent, key, state.shadow[key])
+        state.shadow[key] = nil
+    end
+    if next(state.keys) == nil and getmetatable(parent) == state.mt then
+        setmetatable(parent, nil)
+        states[parent], hidden[parent] = nil, nil
+    end
+end
+
+local function parent_of(path)
+    local value = globals
+    for n = 1, #path - 1 do
+        if type(value) ~= "table" then
+            return nil
+        end
+        value = value[path[n]]
+    end
+    if type(value) == "table" then
+        return value
+    end
+end
+
+local function sync()
+    for i, path in ipairs(paths) do
+        local key = path[#path]
+        local parent = parent_of(path)
+        if not rawequal(parents[i], parent) then
+            release(i, key)
+            parents[i] = parent
+        end
+        local state = parent and instrument(parent)
+        if state then
+            state.keys[key] = state.keys[key] or {}
+            state.keys[key][i] = true
+            local raw = rawget(parent, key)
+            if raw ~= nil then
+                state.shadow[key] = raw
+                rawset(parent, key, nil)
+            end
+        end
+        local value
+        if parent then
+            value = parent[key]
+        end
+        if not seen[i] or not rawequal(value, last[i]) then
+            record(i, value)
+        end
+    end
+end
+
+local function drain()
+    local drained = pending
+    pending = {}
+    for i = 1, #paths do
+        pending[i] = {}
+    end
+    return drained
+end
+
+drain()
+return { sync = sync, drain = drain }, hidden
+"#;
+
+/// One change of a path's value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// The eval that made the change, counting from 1; 0 for the value the
+    /// path had when the session started. Calls to `Session::set_path`
+    /// count as evals.
+    pub eval: u64,
+    /// Scalars by value, other values by id as for host function arguments.
+    pub value: LuaValue,
+}
+
+/// The recorded paths and their timelines, which outlive Lua states.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    paths: Vec<(String, Vec<PathSegment>)>,
+    timelines: Vec<VecDeque<HistoryEntry>>,
+    evals: u64,
+}
+
+impl History {
+    /// Records `paths`, skipping those that do not parse.
+    pub fn new(paths: &[String]) -> Self {
+        let paths: Vec<_> = paths
+            .iter()
+            .filter_map(|p| Some((p.clone(), path::parse_path(p).ok()?)))
+            .filter(|(_, segments)| !segments.is_empty())
+            .collect();
+        Self {
+            timelines: vec![VecDeque::new(); paths.len()],
+            paths,
+            evals: 0,
+        }
+    }
+
+    /// Instruments a fresh Lua state and records the values it starts with.
+    pub fn install(&mut self, ctx: Context) -> Result<(), Error> {
+        if self.paths.is_empty() {
+            return Ok(());
+        }
+        let paths = self
+            .paths
+            .iter()
+            .map(|(_, segments)| {
+                segments
+                    .iter()
+                    .map(|segment| path::segment_value(ctx, segment).map_err(Error::external))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (history, hidden): (Table, Table) = ctx
+            .load(INSTALL_SOURCE)
+            .set_name("=history")?
+            .call((ctx.globals(), paths, MAX_ENTRIES))?;
+        ctx.set_named_registry_value(HISTORY_KEY, history)?;
+        ctx.set_named_registry_value(HIDDEN_KEY, hidden)?;
+        self.sync(ctx);
+        Ok(())
+    }
+
+    /// Starts numbering the changes as made by the next eval.
+    pub fn begin_eval(&mut self) {
+        self.evals += 1;
+    }
+
+    /// Takes over the changes recorded in Lua since the last call.
+    pub fn sync(&mut self, ctx: Context) {
+        if self.paths.is_empty() {
+            return;
+        }
+        let drained: Result<Vec<Vec<Table>>, Error> = (|| {
+            let history: Table = ctx.named_registry_value(HISTORY_KEY)?;
+            history.get::<_, Function>("sync")?.call::<_, ()>(())?;
+            history.get::<_, Function>("drain")?.call(())
+        })();
+        for (timeline, changes) in self.timelines.iter_mut().zip(drained.unwrap_or_default()) {
+            for change in changes {
+                let value = change.get("value").unwrap_or(Value::Nil);
+                if timeline.len() == MAX_ENTRIES {
+                    timeline.pop_front();
+                }
+                timeline.push_back(HistoryEntry {
+                    eval: self.evals,
+                    value: host::argument(ctx, value),
+                });
+            }
+        }
+    }
+
+    /// The changes of `path`, oldest first, or `None` if it is not recorded.
+    pub fn timeline(&self, path: &str) -> Option<Vec<HistoryEntry>> {
+        let index = self.paths.iter().position(|(p, _)| p == path)?;
+        Some(self.timelines[index].iter().cloned().collect())
+    }
+}
+
+/// The entries of an instrumented `table` that live in its shadow, for
+/// serializing it with raw iteration.
+pub(crate) fn hidden<'l>(ctx: Context<'l>, table: &Table<'l>) -> Vec<(Value<'l>, Value<'l>)> {
+    let Ok(Value::Table(hidden)) = ctx.named_registry_value::<_, Value>(HIDDEN_KEY) else {
+        return vec![];
+    };
+    match hidden.raw_get::<_, Value>(table.clone()) {
+        Ok(Value::Table(shadow)) => shadow.pairs().filter_map(Result::ok).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    async fn values(session: &mut Session, path: &str) -> Vec<(u64, LuaValue)> {
+        session
+            .history(path)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.eval, entry.value))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_timeline() {
+        let mut session = Session::builder().record_history("world.score").build();
+        session
+            .eval("world = { score = 1, name = 'w' }".to_string())
+            .await;
+        session
+            .eval("world.score = 2; world.score = 3".to_string())
+            .await;
+        session.eval("world.name = 'x'".to_string()).await;
+        session.eval("world.score = nil".to_string()).await;
+        session
+            .set_path("world.score".to_string(), LuaValue::Number(4.0))
+            .await
+            .unwrap();
+        assert_eq!(
+            values(&mut session, "world.score").await,
+            vec![
+                (0, LuaValue::Nil),
+                (1, LuaValue::Integer(1)),
+                (2, LuaValue::Integer(2)),
+                (2, LuaValue::Integer(3)),
+                (4, LuaValue::Nil),
+                (5, LuaValue::Number(4.0)),
+            ]
+        );
+        assert!(session.history("world.name").await.is_none());
+
+        // The instrumented key is still visible to scripts and responses.
+        let resp = session
+            .eval("local n = 0 for k in pairs(world) do n = n + 1 end return n".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Integer(2));
+        let resp = session.eval("return world".to_string()).await;
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        assert_eq!(resp.objects[id].get("score"), Some(&LuaValue::Number(4.0)));
+    }
+
+    #[tokio::test]
+    async fn test_raw_access() {
+        let mut session = Session::builder().record_history("t.x").build();
+        session.eval("t = { x = 1, y = 2 }".to_string()).await;
+        let resp = session
+            .eval(
+                "local keys = {} for k in next, t do keys[#keys + 1] = k end \
+                 table.sort(keys) return table.concat(keys, ','), rawget(t, 'x'), next({}), \
+                 getmetatable(t) ~= nil"
+                    .to_string(),
+            )
+            .await;
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(
+            resp.values,
+            vec![
+                LuaValue::String("x,y".to_string()),
+                LuaValue::Integer(1),
+                LuaValue::Nil,
+                LuaValue::Boolean(true),
+            ]
+        );
+        assert!(
+            !session
+                .eval("return rawget(1, 'x')".to_string())
+                .await
+                .success
+        );
+    }
+
+    #[tokio::test]
+    async fn test_untracked_changes() {
+        let mut session = Session::builder().record_history("t.x").build();
+        session
+            .eval("t = setmetatable({ x = 1 }, {})".to_string())
+            .await;
+        session.eval("t.x = 2".to_string()).await;
+        session.eval("t = { x = 3 }".to_string()).await;
+        session.eval("rawset(t, 'x', 4)".to_string()).await;
+        session
+            .eval("setmetatable(t, {}) t.x = 5".to_string())
+            .await;
+        session.reset().await;
+        assert_eq!(
+            values(&mut session, "t.x").await,
+            vec![
+                (0, LuaValue::Nil),
+                (1, LuaValue::Integer(1)),
+                (2, LuaValue::Integer(2)),
+                (3, LuaValue::Integer(3)),
+                (4, LuaValue::Integer(4)),
+                (5, LuaValue::Integer(5)),
+                (5, LuaValue::Nil),
+            ]
+        );
+    }
+}
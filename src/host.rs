@@ -0,0 +1,466 @@
+//! Rust functions exposed to Lua as globals, registered by embedders with
+//! `SessionBuilder::register_function` and `register_async_function`.
+//!
+//! An eval calling an async function runs as a coroutine that yields to the
+//! interpreter, which parks it and hands the call to the session. The session
+//! awaits the future on its own task, leaving the interpreter thread free,
+//! and then resumes the coroutine with the result.
+
+use crate::explain::GlobalsDigest;
+use crate::output::OutputLevel;
+use crate::value_id;
+use crate::EvalResponse;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::LightUserData;
+use rlua::MultiValue;
+use rlua::RegistryKey;
+use rlua::Thread;
+use rlua::ThreadStatus;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+pub type HostResult = Result<LuaValue, Box<dyn std::error::Error + Send + Sync>>;
+pub type HostFuture = Pin<Box<dyn Future<Output = HostResult> + Send>>;
+
+#[derive(Clone)]
+enum Callable {
+    Blocking(Arc<dyn Fn(Vec<LuaValue>) -> HostResult + Send + Sync>),
+    Async(Arc<dyn Fn(Vec<LuaValue>) -> HostFuture + Send + Sync>),
+}
+
+#[derive(Clone)]
+pub struct HostFunction {
+    name: String,
+    callable: Callable,
+}
+
+impl HostFunction {
+    /// Arguments arrive as plain values; tables and other objects as their
+    /// ids, see `LuaValue::ObjectRef`.
+    pub fn new(
+        name: &str,
+        function: impl Fn(Vec<LuaValue>) -> HostResult + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            callable: Callable::Blocking(Arc::new(function)),
+        }
+    }
+
+    /// Like `new`, but the result is awaited on the session's task. Only
+    /// `Session::eval` and `eval_with` evals can call async functions, and
+    /// not from inside `pcall`, metamethods or functions like `table.sort`:
+    /// Lua cannot yield across those.
+    pub fn new_async<F>(
+        name: &str,
+        function: impl Fn(Vec<LuaValue>) -> F + Send + Sync + 'static,
+    ) -> Self
+    where
+        F: Future<Output = HostResult> + Send + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            callable: Callable::Async(Arc::new(move |args| Box::pin(function(args)))),
+        }
+    }
+
+    pub fn is_async(&self) -> bool {
+        matches!(self.callable, Callable::Async(_))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostFunction({})", self.name)
+    }
+}
+
+/// A host function that returned an error, as opposed to failing Lua code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostError {
+    pub function: String,
+    /// The Rust error's message, including the messages of its sources.
+    pub message: String,
+    /// The host-side stack at the time the error reached the interpreter.
+    pub backtrace: String,
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "host function {} failed: {}",
+            self.function, self.message
+        )
+    }
+}
+
+impl std::error::Error for HostError {}
+
+fn message(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+pub(crate) fn argument<'l>(ctx: Context<'l>, value: Value<'l>) -> LuaValue {
+    match value {
+        Value::Nil => LuaValue::Nil,
+        Value::Boolean(b) => LuaValue::Boolean(b),
+        Value::Integer(i) => LuaValue::Integer(i),
+        Value::Number(n) => LuaValue::Number(n),
+        Value::String(s) => LuaValue::String(s.to_str().unwrap_or_default().to_string()),
+        Value::LightUserData(pointer) => LuaValue::LightUserData(pointer.0 as usize),
+        other => LuaValue::ObjectRef(value_id(ctx, other)),
+    }
+}
+
+pub(crate) fn failure(function: &str, error: &(dyn std::error::Error + 'static)) -> HostError {
+    HostError {
+        function: function.to_string(),
+        message: message(error),
+        backtrace: Backtrace::force_capture().to_string(),
+    }
+}
+
+/// Yielded first by the Lua side of async functions, to tell their calls
+/// apart from other yields.
+static ASYNC_CALL: u8 = 0;
+
+fn async_call_marker() -> LightUserData {
+    LightUserData(&ASYNC_CALL as *const u8 as *mut _)
+}
+
+/// Yields the call to the interpreter, which resumes the coroutine with a
+/// function returning the result or raising the host error.
+const ASYNC_WRAPPER: &str = r#"
+local yield = coroutine.yield
+return function(marker, name)
+    return function(...)
+        return yield(marker, name, ...)()
+    end
+end
+"#;
+
+/// Defines `functions` as globals of the Lua state behind `ctx`.
+pub(crate) fn install(ctx: Context, functions: &[HostFunction]) -> Result<(), Error> {
+    for host in functions {
+        let name = host.name.clone();
+        let function = match host.callable.clone() {
+            Callable::Blocking(function) => ctx.create_function(move |ctx, args: MultiValue| {
+                let args = args.into_iter().map(|arg| argument(ctx, arg)).collect();
+                match function(args) {
+                    Ok(value) => value.to_lua(ctx),
+                    Err(e) => Err(Error::external(failure(&name, e.as_ref()))),
+                }
+            })?,
+            Callable::Async(_) => async_function(ctx, &name)?,
+        };
+        ctx.globals().set(host.name.as_str(), function)?;
+    }
+    Ok(())
+}
+
+/// A Lua function handing its calls to the session as `AsyncCall`s of
+/// `name`.
+pub(crate) fn async_function<'l>(ctx: Context<'l>, name: &str) -> Result<Function<'l>, Error> {
+    let wrapper: Function = ctx.load(ASYNC_WRAPPER).set_name("=async")?.eval()?;
+    wrapper.call((Value::LightUserData(async_call_marker()), name))
+}
+
+/// A call to an async host function, made by a parked eval.
+#[derive(Debug)]
+pub(crate) struct AsyncCall {
+    pub id: u64,
+    pub function: String,
+    pub args: Vec<LuaValue>,
+}
+
+/// Runs `call` on the session's side.
+pub(crate) async fn complete(
+    functions: &[HostFunction],
+    call: &AsyncCall,
+) -> Result<LuaValue, HostError> {
+    let function = functions.iter().find_map(|host| match &host.callable {
+        Callable::Async(function) if host.name == call.function => Some(function.clone()),
+        _ => None,
+    });
+    let function = match function {
+        Some(function) => function,
+        None => {
+            let e: Box<dyn std::error::Error + Send + Sync> =
+                format!("no async host function named {}", call.function).into();
+            return Err(failure(&call.function, e.as_ref()));
+        }
+    };
+    function(call.args.clone())
+        .await
+        .map_err(|e| failure(&call.function, e.as_ref()))
+}
+
+/// What is left of an eval while its coroutine is parked.
+pub(crate) struct PendingEval {
+    pub reply: oneshot::Sender<EvalResponse>,
+    pub source: String,
+    /// The globals from before the eval, in explain mode.
+    pub before: Option<GlobalsDigest>,
+    /// Whether to echo the assignments of `source`, see `echo`.
+    pub echo: bool,
+    pub output_level: Option<OutputLevel>,
+    /// The item limit of an iterator result, see `iterate`.
+    pub iterate: Option<usize>,
+}
+
+struct Parked {
+    call: u64,
+    thread: RegistryKey,
+    eval: PendingEval,
+}
+
+/// Where an eval running as a coroutine got to.
+pub(crate) enum Step<'l> {
+    Done(Result<MultiValue<'l>, Error>, PendingEval),
+    Parked,
+}
+
+/// The interpreter's side: at most one parked eval, and the channel its
+/// calls go out on.
+pub(crate) struct AsyncCalls {
+    calls: UnboundedSender<AsyncCall>,
+    parked: Option<Parked>,
+    next: u64,
+}
+
+impl AsyncCalls {
+    pub fn new(calls: UnboundedSender<AsyncCall>) -> Self {
+        Self {
+            calls,
+            parked: None,
+            next: 0,
+        }
+    }
+
+    /// Forgets a parked eval whose session stopped waiting for it.
+    pub fn abandon(&mut self) {
+        self.parked = None;
+    }
+
+    /// Parks `thread` if it yielded an async call, or finishes `eval`.
+    pub fn advance<'l>(
+        &mut self,
+        ctx: Context<'l>,
+        thread: Thread<'l>,
+        resumed: Result<MultiValue<'l>, Error>,
+        eval: PendingEval,
+    ) -> Step<'l> {
+        let values = match resumed {
+            Ok(values) if thread.status() != ThreadStatus::Resumable => {
+                return Step::Done(Ok(values), eval)
+            }
+            Ok(values) => values,
+            Err(e) => return Step::Done(Err(e), eval),
+        };
+        let mut values = values.into_iter();
+        let first = values.next().unwrap_or(Value::Nil);
+        let function = match (first, values.next()) {
+            (Value::LightUserData(marker), Some(Value::String(name)))
+                if marker == async_call_marker() =>
+            {
+                name.to_str().unwrap_or_default().to_string()
+            }
+            _ => {
+                let e = Error::RuntimeError("attempt to yield from outside a coroutine".into());
+                return Step::Done(Err(e), eval);
+            }
+        };
+        let thread = match ctx.create_registry_value(thread) {
+            Ok(thread) => thread,
+            Err(e) => return Step::Done(Err(e), eval),
+        };
+        let id = self.next;
+        self.next += 1;
+        let args = values.map(|arg| argument(ctx, arg)).collect();
+        self.parked = Some(Parked {
+            call: id,
+            thread,
+            eval,
+        });
+        let _ = self.calls.send(AsyncCall { id, function, args });
+        Step::Parked
+    }
+
+    /// Takes the eval waiting for call `id`, resuming it will continue it.
+    pub fn resume<'l>(
+        &mut self,
+        ctx: Context<'l>,
+        id: u64,
+        result: Result<LuaValue, HostError>,
+    ) -> Option<(Thread<'l>, Function<'l>, PendingEval)> {
+        if self.parked.as_ref()?.call != id {
+            return None;
+        }
+        let parked = self.parked.take()?;
+        let thread: Thread = ctx.registry_value(&parked.thread).ok()?;
+        let _ = ctx.remove_registry_value(parked.thread);
+        let result = ctx
+            .create_function(move |ctx, ()| match &result {
+                Ok(value) => value.to_lua(ctx),
+                Err(e) => Err(Error::external(e.clone())),
+            })
+            .ok()?;
+        Some((thread, result, parked.eval))
+    }
+}
+
+/// Finds the host function failure that made an eval fail, if it was one.
+pub(crate) fn host_error(error: &Error) -> Option<HostError> {
+    match error {
+        Error::CallbackError { cause, .. } => host_error(cause),
+        Error::ExternalError(e) => e.downcast_ref::<HostError>().cloned(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+    use std::io;
+
+    fn session() -> Session {
+        Session::builder()
+            .register_function("add", |args| match args[..] {
+                [LuaValue::Integer(a), LuaValue::Integer(b)] => Ok(LuaValue::Integer(a + b)),
+                [ref a, ref b] => match (a.as_number(), b.as_number()) {
+                    (Some(a), Some(b)) => Ok(LuaValue::Number(a + b)),
+                    _ => Err("expected two numbers".into()),
+                },
+                _ => Err("expected two numbers".into()),
+            })
+            .register_function("read_config", |_| {
+                let cause = io::Error::new(io::ErrorKind::NotFound, "no such file");
+                Err(Box::new(ConfigError(cause)))
+            })
+            .build()
+    }
+
+    #[derive(Debug)]
+    struct ConfigError(io::Error);
+
+    impl fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "cannot read config")
+        }
+    }
+
+    impl std::error::Error for ConfigError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_host_functions() {
+        let mut session = session();
+        let resp = session.eval("return add(1, 2)".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(3));
+        assert_eq!(resp.host_error, None);
+        let resp = session.eval("return add(1, 0.5)".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Number(1.5));
+
+        let resp = session.eval("return add(1)".to_string()).await;
+        assert!(!resp.success);
+        let error = resp.host_error.unwrap();
+        assert_eq!(error.function, "add");
+        assert_eq!(error.message, "expected two numbers");
+        assert!(!error.backtrace.is_empty());
+
+        // Lua errors are not mistaken for host errors.
+        let resp = session.eval("error('add failed')".to_string()).await;
+        assert!(!resp.success);
+        assert_eq!(resp.host_error, None);
+
+        // Host functions survive resets.
+        session.reset().await;
+        let resp = session.eval("return add(2, 2)".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(4));
+    }
+
+    #[tokio::test]
+    async fn test_async_functions() {
+        let mut session = Session::builder()
+            .register_async_function("fetch", |args| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                match &args[..] {
+                    [LuaValue::String(key)] => Ok(LuaValue::String(key.to_uppercase())),
+                    _ => Err("expected a key".into()),
+                }
+            })
+            .build();
+        let resp = session
+            .eval(
+                "local function twice(key) return fetch(key) .. fetch(key) end return fetch('a') .. twice('b')".to_string(),
+            )
+            .await;
+        assert_eq!(resp.value, LuaValue::String("ABB".to_string()));
+
+        // The interpreter stays responsive while a call is awaited.
+        let liveness = session.liveness();
+        {
+            let eval = session.eval("return fetch('c')".to_string());
+            tokio::pin!(eval);
+            tokio::select! {
+                _ = &mut eval => panic!("the call should still be pending"),
+                alive = liveness.ping(std::time::Duration::from_secs(1)) => assert!(alive),
+            }
+            assert_eq!(eval.await.value, LuaValue::String("C".to_string()));
+        }
+
+        let resp = session.eval("return fetch()".to_string()).await;
+        assert_eq!(
+            resp.host_error.map(|e| e.message),
+            Some("expected a key".to_string())
+        );
+        let resp = session.eval("coroutine.yield(1)".to_string()).await;
+        assert!(!resp.success);
+    }
+
+    #[tokio::test]
+    async fn test_nested_host_error() {
+        let mut session = session();
+        let resp = session
+            .eval("local function load() return read_config() end return load()".to_string())
+            .await;
+        assert_eq!(
+            resp.host_error.map(|e| e.message),
+            Some("cannot read config: no such file".to_string())
+        );
+
+        // Errors caught by pcall do not fail the eval.
+        let resp = session
+            .eval("return (pcall(read_config))".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Boolean(false));
+        assert_eq!(resp.host_error, None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Garbage collection while a session is idle, so that long-lived server
+//! sessions give back what their last evals left behind without a pause in
+//! the next one; see `SessionBuilder::idle_gc`.
+//!
+//! Once a session has received no command for `IdleGc::after`, its
+//! interpreter thread runs small incremental steps, checking for commands in
+//! between, until a collection cycle completes. Then it waits for the next
+//! command, so an idle session does not keep collecting. Pooled sessions are
+//! stepped by their worker, cooperative ones in what is left of a
+//! `Session::pump` budget.
+
+use crate::Command;
+use crate::Interpreter;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleGc {
+    /// How long a session must have been idle before collecting.
+    pub after: Duration,
+    /// Work per step, in kilobytes of allocation it accounts for. Smaller
+    /// steps let a command that arrives mid-cycle start sooner.
+    pub step_kbytes: i32,
+}
+
+impl Default for IdleGc {
+    fn default() -> Self {
+        Self {
+            after: Duration::from_secs(5),
+            step_kbytes: 64,
+        }
+    }
+}
+
+/// When an interpreter last handled a command, and whether it has collected
+/// since.
+#[derive(Debug)]
+pub(crate) struct Idleness {
+    active: Instant,
+    collected: bool,
+}
+
+impl Default for Idleness {
+    fn default() -> Self {
+        Self {
+            active: Instant::now(),
+            collected: false,
+        }
+    }
+}
+
+impl Idleness {
+    pub fn touch(&mut self) {
+        self.active = Instant::now();
+        self.collected = false;
+    }
+
+    /// How long to wait for a command before the next step, or `None` to
+    /// wait for as long as it takes.
+    pub fn timeout(&self, idle_gc: Option<&IdleGc>) -> Option<Duration> {
+        let idle_gc = idle_gc.filter(|_| !self.collected)?;
+        Some(idle_gc.after.saturating_sub(self.active.elapsed()))
+    }
+
+    /// Whether `idle_gc` calls for a step now, i.e. collecting is enabled, has
+    /// not finished since the last command, and the session has been idle
+    /// long enough.
+    pub fn due(&self, idle_gc: Option<&IdleGc>) -> bool {
+        self.timeout(idle_gc) == Some(Duration::ZERO)
+    }
+
+    pub fn collected(&mut self) {
+        self.collected = true;
+    }
+}
+
+/// The dedicated interpreter thread's loop.
+pub(crate) fn run(mut interpreter: Interpreter, commands: Receiver<Command>) {
+    loop {
+        let command = match interpreter.idle_timeout() {
+            Some(timeout) => match commands.recv_timeout(timeout) {
+                Ok(command) => command,
+                Err(RecvTimeoutError::Timeout) => {
+                    interpreter.collect_idle();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match commands.recv() {
+                Ok(command) => command,
+                Err(_) => return,
+            },
+        };
+        interpreter.handle(command);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pool::WorkerPool;
+    use crate::LuaValue;
+    use crate::Session;
+    use crate::SessionBuilder;
+    use std::sync::Arc;
+
+    const GARBAGE: &str = "collectgarbage('stop') \
+                           local t = {} for i = 1, 100000 do t[i] = {} end \
+                           return collectgarbage('count')";
+
+    fn idle_gc() -> IdleGc {
+        IdleGc {
+            after: Duration::from_millis(20),
+            step_kbytes: 16,
+        }
+    }
+
+    async fn kilobytes(session: &mut Session, source: &str) -> f64 {
+        match session.eval(source.to_string()).await.value {
+            LuaValue::Number(kilobytes) => kilobytes,
+            value => panic!("expected a number, got {:?}", value),
+        }
+    }
+
+    async fn collects(builder: SessionBuilder) -> bool {
+        let mut session = builder.build();
+        let before = kilobytes(&mut session, GARBAGE).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let after = kilobytes(&mut session, "return collectgarbage('count')").await;
+        after < before / 2.0
+    }
+
+    #[tokio::test]
+    async fn test_idle_collection() {
+        assert!(collects(Session::builder().idle_gc(idle_gc())).await);
+        assert!(!collects(Session::builder()).await);
+    }
+
+    #[tokio::test]
+    async fn test_pooled_idle_collection() {
+        let pool = Arc::new(WorkerPool::new(1));
+        assert!(collects(Session::builder().pool(pool).idle_gc(idle_gc())).await);
+    }
+}
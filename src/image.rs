@@ -0,0 +1,137 @@
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Lua;
+use rlua::Table;
+
+use crate::embedded::EMBEDDED_MODULES;
+
+/// Precompiled startup state that many sessions can be created from.
+///
+/// Lua cannot serialize a live heap, so an image stores the bytecode of its
+/// preloaded modules and init scripts instead: sources are parsed and compiled
+/// once, when the image is built, and every session created from it only has
+/// to load the bytecode and run the init scripts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionImage {
+    modules: Vec<(String, Vec<u8>)>,
+    init_scripts: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+pub struct SessionImageBuilder {
+    modules: Vec<(String, String)>,
+    init_scripts: Vec<String>,
+}
+
+impl SessionImage {
+    pub fn builder() -> SessionImageBuilder {
+        SessionImageBuilder::default()
+    }
+
+    /// Registers the image's modules in `package.preload` and runs its init scripts.
+    pub(crate) fn apply(&self, ctx: Context) -> Result<(), Error> {
+        let preload: Table = ctx.globals().get::<_, Table>("package")?.get("preload")?;
+        for (name, bytecode) in &self.modules {
+            // Safe: the bytecode was produced by `Function::dump` in `build`.
+            let loader = unsafe { ctx.load(bytecode).into_function_allow_binary()? };
+            preload.set(name.as_str(), loader)?;
+        }
+        for bytecode in &self.init_scripts {
+            unsafe { ctx.load(bytecode).into_function_allow_binary()? }.call::<_, ()>(())?;
+        }
+        Ok(())
+    }
+}
+
+impl SessionImageBuilder {
+    /// Makes `source` available to sessions as `require(name)`.
+    pub fn preload(mut self, name: &str, source: &str) -> Self {
+        self.modules.push((name.to_string(), source.to_string()));
+        self
+    }
+
+    /// Preloads every module compiled into the binary with the `embed-lua` feature.
+    pub fn preload_embedded(self) -> Self {
+        EMBEDDED_MODULES
+            .iter()
+            .fold(self, |builder, (name, source)| {
+                builder.preload(name, source)
+            })
+    }
+
+    /// Runs `source` in every session created from the image, after the modules are preloaded.
+    pub fn init_script(mut self, source: &str) -> Self {
+        self.init_scripts.push(source.to_string());
+        self
+    }
+
+    /// Compiles all sources and runs the init scripts once in a scratch state,
+    /// so that broken images are reported here rather than by every session.
+    pub fn build(self) -> Result<SessionImage, Error> {
+        let lua = Lua::new();
+        let image = lua.context(|ctx| -> Result<SessionImage, Error> {
+            let compile = |name: &str, source: &str| -> Result<Vec<u8>, Error> {
+                let function: Function = ctx.load(source).set_name(name)?.into_function()?;
+                function.dump()
+            };
+            Ok(SessionImage {
+                modules: self
+                    .modules
+                    .iter()
+                    .map(|(name, source)| Ok((name.clone(), compile(name, source)?)))
+                    .collect::<Result<_, Error>>()?,
+                init_scripts: self
+                    .init_scripts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, source)| compile(&format!("init{}", i + 1), source))
+                    .collect::<Result<_, Error>>()?,
+            })
+        })?;
+        lua.context(|ctx| image.apply(ctx))?;
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+    use crate::SessionConfig;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_session_from_image() {
+        let image = SessionImage::builder()
+            .preload("greet", "return { name = 'world' }")
+            .init_script("greeting = 'hello ' .. require('greet').name")
+            .build()
+            .unwrap();
+        let mut session = Session::with_config(SessionConfig {
+            image: Some(Arc::new(image)),
+            ..Default::default()
+        });
+
+        let resp = session.eval("return greeting".to_string()).await;
+        assert_eq!(resp.value, LuaValue::String("hello world".to_string()));
+
+        session.eval("greeting = nil".to_string()).await;
+        session.reset().await;
+        let resp = session.eval("return greeting".to_string()).await;
+        assert_eq!(resp.value, LuaValue::String("hello world".to_string()));
+    }
+
+    #[test]
+    fn test_broken_image() {
+        assert!(SessionImage::builder()
+            .init_script("error('boom')")
+            .build()
+            .is_err());
+        assert!(SessionImage::builder()
+            .preload("m", "return (")
+            .build()
+            .is_err());
+    }
+}
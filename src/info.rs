@@ -0,0 +1,100 @@
+//! What a session is running, see `Session::info`, so that clients can tell
+//! whether they can talk to it before a request fails to decode.
+
+use crate::protocol::PROTOCOL_VERSION;
+use crate::SessionConfig;
+use rlua::Lua;
+use rlua::StdLib;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// The version of the luarepl crate, e.g. `0.1.0`.
+    pub version: String,
+    /// `_VERSION` of the embedded Lua, e.g. `Lua 5.4`.
+    pub lua_version: String,
+    /// The version of the frames in `protocol`.
+    pub protocol: u32,
+    /// The Cargo features the crate was built with and the options the
+    /// session has on, e.g. `workspace`, sorted.
+    pub features: Vec<String>,
+}
+
+impl SessionInfo {
+    pub(crate) fn new(config: &SessionConfig) -> Self {
+        let options = [
+            ("embed-lua", cfg!(feature = "embed-lua")),
+            ("test-util", cfg!(feature = "test-util")),
+            ("cache", config.cache),
+            ("capture_output", config.capture_output),
+            ("caught_errors", config.caught_errors),
+            ("compat51", config.compat51),
+            ("cooperative", config.cooperative),
+            ("echo_assignments", config.echo_assignments),
+            ("explain", config.explain),
+            ("gc_diagnostics", config.gc_diagnostics),
+            ("hardening", config.hardening.is_some()),
+            ("leak_diagnostics", config.leak_diagnostics),
+            ("memory_profile", config.memory_profile),
+            ("repl_api", config.repl_api),
+            ("workspace", config.workspace),
+        ];
+        let mut features: Vec<_> = options
+            .iter()
+            .filter(|(_, on)| *on)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        features.sort();
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            lua_version: lua_version().to_string(),
+            protocol: PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    /// Whether a client speaking `PROTOCOL_VERSION` can talk to the server
+    /// that sent this, with a message to show the user if not.
+    pub fn check(&self) -> Result<(), String> {
+        match self.protocol == PROTOCOL_VERSION {
+            true => Ok(()),
+            false => Err(format!(
+                "the server (luarepl {}) speaks protocol version {}, this client only {}",
+                self.version, self.protocol, PROTOCOL_VERSION
+            )),
+        }
+    }
+}
+
+/// Read from a Lua state of its own, as scripts may reassign `_VERSION`.
+fn lua_version() -> &'static str {
+    static VERSION: OnceLock<String> = OnceLock::new();
+    VERSION.get_or_init(|| {
+        Lua::new_with(StdLib::BASE)
+            .context(|ctx| ctx.globals().get("_VERSION"))
+            .unwrap_or_else(|_| "?".to_string())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocol::PROTOCOL_VERSION;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_info() {
+        let session = Session::builder().workspace(true).compat51(true).build();
+        let mut info = session.info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.lua_version, "Lua 5.4");
+        assert!(info.features.contains(&"workspace".to_string()));
+        assert!(info.features.contains(&"compat51".to_string()));
+        assert!(!info.features.contains(&"explain".to_string()));
+        assert!(info.check().is_ok());
+
+        info.protocol = PROTOCOL_VERSION + 1;
+        assert!(info.check().unwrap_err().contains("protocol version"));
+    }
+}
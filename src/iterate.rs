@@ -0,0 +1,177 @@
+//! Iterators returned by evals, driven one item at a time, see
+//! `EvalOptions::iterate` and `Session::next_item`, so that clients can page
+//! through `return pairs(huge)` without the whole table being serialized.
+//!
+//! A function returned first is called like the generic `for` would call it,
+//! with the state and control value returned after it; a coroutine is
+//! resumed. Each call produces one item, until one returns nil, the
+//! coroutine finishes, the limit is reached, or another eval replaces the
+//! iterator.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::MultiValue;
+use rlua::Table;
+use rlua::Value;
+
+const STEP_KEY: &str = "luarepl.iterator";
+
+/// Returns a function producing the next item as a packed table, or nil
+/// once there are no more, if the values it is called with start with an
+/// iterator.
+const START_SOURCE: &str = r#"
+local limit, f, s, control = ...
+local pack, resume, status, type = table.pack, coroutine.resume, coroutine.status, type
+
+local function check(ok, ...)
+    if not ok then
+        error((...), 0)
+    end
+    return ...
+end
+
+if type(f) == "thread" then
+    local co = f
+    f = function()
+        if status(co) == "dead" then
+            return nil
+        end
+        return check(resume(co))
+    end
+elseif type(f) ~= "function" then
+    return nil
+end
+
+local n = 0
+return function()
+    if n >= limit then
+        return nil
+    end
+    local values = pack(f(s, control))
+    if values[1] == nil then
+        n = limit
+        return nil
+    end
+    control = values[1]
+    n = n + 1
+    return values
+end
+"#;
+
+/// Keeps the iterator `result` starts with, producing at most `limit`
+/// items, in place of the previous one; without a limit, only drops the
+/// previous one.
+pub(crate) fn capture<'l>(
+    ctx: Context<'l>,
+    limit: Option<usize>,
+    result: &Result<MultiValue<'l>, Error>,
+) {
+    let step = limit.zip(result.as_ref().ok()).and_then(|(limit, values)| {
+        let mut args = values.clone().into_vec();
+        args.insert(0, Value::Integer(limit as i64));
+        ctx.load(START_SOURCE)
+            .set_name("=iterate")
+            .and_then(|chunk| chunk.call::<_, Option<Function>>(MultiValue::from_vec(args)))
+            .ok()
+            .flatten()
+    });
+    let _ = ctx.set_named_registry_value(STEP_KEY, step);
+}
+
+/// The values of the next item, or `None` if there is no iterator or it is
+/// exhausted. An iterator that fails is dropped after reporting its error.
+pub(crate) fn step(ctx: Context) -> Option<Result<MultiValue, Error>> {
+    let step: Function = ctx.named_registry_value(STEP_KEY).ok()?;
+    let item = step.call::<_, Option<Table>>(()).and_then(|values| {
+        values
+            .map(|values| {
+                let n: i64 = values.raw_get("n")?;
+                (1..=n)
+                    .map(|i| values.raw_get(i))
+                    .collect::<Result<Vec<Value>, _>>()
+                    .map(MultiValue::from_vec)
+            })
+            .transpose()
+    });
+    match item {
+        Ok(Some(values)) => Some(Ok(values)),
+        Ok(None) => {
+            let _ = ctx.unset_named_registry_value(STEP_KEY);
+            None
+        }
+        Err(e) => {
+            let _ = ctx.unset_named_registry_value(STEP_KEY);
+            Some(Err(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::EvalOptions;
+    use crate::LuaValue;
+    use crate::Session;
+
+    fn iterate(limit: usize) -> EvalOptions {
+        EvalOptions {
+            iterate: Some(limit),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_items() {
+        let mut session = Session::new();
+        session
+            .eval("list = { 'a', 'b', 'c', 'd' }".to_string())
+            .await;
+        let resp = session
+            .eval_with("return ipairs(list)".to_string(), iterate(3))
+            .await;
+        assert!(resp.success);
+        let mut items = vec![];
+        while let Some(item) = session.next_item().await {
+            items.push(item.values);
+        }
+        assert_eq!(
+            items,
+            (1..=3)
+                .map(|i| vec![
+                    LuaValue::Integer(i),
+                    LuaValue::String(["a", "b", "c"][i as usize - 1].to_string())
+                ])
+                .collect::<Vec<_>>()
+        );
+
+        let source =
+            "return coroutine.create(function() coroutine.yield(1) coroutine.yield(2) end)";
+        session.eval_with(source.to_string(), iterate(10)).await;
+        assert_eq!(
+            session.next_item().await.unwrap().value,
+            LuaValue::Integer(1)
+        );
+        assert_eq!(
+            session.next_item().await.unwrap().value,
+            LuaValue::Integer(2)
+        );
+        assert!(session.next_item().await.is_none());
+
+        session
+            .eval_with(
+                "return function() error('dry', 0) end".to_string(),
+                iterate(10),
+            )
+            .await;
+        let item = session.next_item().await.unwrap();
+        assert_eq!(item.error.as_deref(), Some("dry"));
+        assert!(session.next_item().await.is_none());
+
+        // Evals without the option drop the iterator.
+        session
+            .eval_with("return ipairs(list)".to_string(), iterate(10))
+            .await;
+        session.eval("return ipairs(list)".to_string()).await;
+        assert!(session.next_item().await.is_none());
+    }
+}
@@ -0,0 +1,602 @@
+//! A JSON-RPC 2.0 interface to sessions over any `Transport`, for clients
+//! written in languages that have a JSON-RPC library but no reason to learn
+//! the frames of `protocol`. Each connection gets one session of its own,
+//! closed when the client goes away.
+//!
+//! ```json
+//! {"jsonrpc":"2.0","id":1,"method":"eval","params":{"source":"return 6 * 7"}}
+//! {"jsonrpc":"2.0","id":1,"result":{"rendered":"42","success":true,"value":{"integer":42},...}}
+//! ```
+//!
+//! The methods are:
+//!
+//! - `eval` `{source}`: the `EvalResponse`, with its value `rendered` at
+//!   the server's output level. Lua errors are results with `success` false.
+//!   `file` or `chunk_name` and `line` say where the source came from, as
+//!   with `protocol` evals, e.g. `{"source":"f()","file":"foo.lua","line":42}`,
+//!   and `output_level` overrides the server's.
+//! - `inspect` `{path}` or `{id, offset, limit}`: the value at a path, or a
+//!   page of a table a response left out, with the `total` number of its
+//!   entries.
+//! - `complete` `{prefix}`: the ways `prefix` can go on, e.g.
+//!   `string.format` for `string.fo`, see `Session::complete`.
+//! - `help` `{name}` and `apropos` `{pattern}`: the entries of the embedded
+//!   reference behind the REPL's `:help` and `:apropos`, the one named
+//!   `name` or those mentioning `pattern`, see `help`. There is no entry
+//!   for an unknown name, which fails with `APPLICATION_ERROR`.
+//! - `reset`: replaces the session's Lua state with a fresh one.
+//! - `authenticate` `{token}`: presents the server's token, see `auth`. A
+//!   server given one refuses every other request with `UNAUTHORIZED` until
+//!   then, and closes the connection after `auth::MAX_REJECTIONS` wrong
+//!   tokens; one without accepts any token. The session is only started
+//!   once the client is in.
+//!
+//! With `ServeOptions::quota` set, evals are run through a `Scheduler`, each
+//! host connecting a tenant; one refused for being over quota fails with
+//! `APPLICATION_ERROR`. With `ServeOptions::audit`, they are recorded as
+//! evals of session 0 by the connection's peer.
+//!
+//! Batches and notifications work as the specification describes; requests
+//! that fail get the standard error codes, or `APPLICATION_ERROR` when the
+//! session could not carry them out, e.g. for a path that leads nowhere.
+
+use crate::audit::AuditLog;
+use crate::audit::AuditStatus;
+use crate::auth;
+use crate::auth::Token;
+use crate::help;
+use crate::output;
+use crate::output::OutputLevel;
+use crate::page::ExpandOptions;
+use crate::protocol::HelpEntry;
+use crate::scheduler;
+use crate::scheduler::Scheduler;
+use crate::server;
+use crate::server::ServeOptions;
+use crate::transport::Connection;
+use crate::transport::Transport;
+use crate::EvalOptions;
+use crate::EvalResponse;
+use crate::Session;
+use crate::SessionConfig;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request was well-formed, but the session could not carry it out.
+pub const APPLICATION_ERROR: i64 = -32000;
+/// The client has yet to `authenticate`, or presented the wrong token.
+pub const UNAUTHORIZED: i64 = -32001;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct EvalParams {
+    source: String,
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default)]
+    chunk_name: Option<String>,
+    #[serde(default)]
+    line: Option<u32>,
+    #[serde(default)]
+    output_level: Option<OutputLevel>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InspectParams {
+    Path {
+        path: String,
+    },
+    Page {
+        id: String,
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+}
+
+#[derive(Deserialize)]
+struct AuthenticateParams {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct CompleteParams {
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+struct HelpParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct AproposParams {
+    pattern: String,
+}
+
+#[derive(Serialize)]
+struct EvalResult {
+    rendered: String,
+    #[serde(flatten)]
+    response: EvalResponse,
+}
+
+#[derive(Serialize)]
+struct InspectResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+    #[serde(flatten)]
+    response: EvalResponse,
+}
+
+/// Accepts clients until `transport` runs out of them, then waits for the
+/// connected ones to disconnect.
+pub async fn serve<T: Transport>(transport: T, config: SessionConfig) -> io::Result<()> {
+    serve_with(transport, config, ServeOptions::default()).await
+}
+
+/// `serve` as `options` say, with clients having to `authenticate` with
+/// its token first. Every client has a session of its own, so
+/// `options.shared` does not apply.
+pub async fn serve_with<T: Transport>(
+    mut transport: T,
+    config: SessionConfig,
+    mut options: ServeOptions,
+) -> io::Result<()> {
+    let mut config = server::served(config);
+    let scheduler = options.quota.map(Scheduler::new);
+    // As in `server::serve`, the channel closes once the last connection
+    // task drops its sender.
+    let (connected, mut done) = mpsc::channel::<()>(1);
+    while let Some(connection) = transport.accept().await? {
+        if let Some(reload) = server::reloaded(&mut options.reloads) {
+            config = server::served(reload.config);
+            options.token = reload.token;
+        }
+        let connected = connected.clone();
+        let config = config.clone();
+        let token = options.token.clone();
+        let scheduler = scheduler.clone();
+        let audit = options.audit.clone();
+        tokio::spawn(async move {
+            handle(connection, config, token, scheduler, audit).await;
+            drop(connected);
+        });
+    }
+    drop(connected);
+    done.recv().await;
+    Ok(())
+}
+
+/// A connection and its session.
+struct Client {
+    /// Started by the first request once the client presented the token.
+    session: Option<Session>,
+    config: SessionConfig,
+    /// The token the client has yet to present.
+    token: Option<Token>,
+    /// How many wrong tokens the client presented.
+    rejections: u32,
+    peer: String,
+    scheduler: Option<Scheduler>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+}
+
+/// Serves one client; `token` is the one it has yet to present.
+async fn handle<C: Connection>(
+    mut connection: C,
+    config: SessionConfig,
+    token: Option<Token>,
+    scheduler: Option<Scheduler>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+) {
+    let mut client = Client {
+        session: None,
+        config,
+        token,
+        rejections: 0,
+        peer: connection.peer(),
+        scheduler,
+        audit,
+    };
+    loop {
+        let message = match connection.recv().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("{}: {}", connection.peer(), e);
+                break;
+            }
+        };
+        let reply = match serde_json::from_slice::<Value>(&message) {
+            Ok(Value::Array(batch)) if !batch.is_empty() => {
+                let mut responses = vec![];
+                for request in batch {
+                    responses.extend(call(&mut client, request).await);
+                }
+                (!responses.is_empty()).then(|| serde_json::to_vec(&responses))
+            }
+            Ok(Value::Array(_)) => Some(serde_json::to_vec(&failure(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, "empty batch"),
+            ))),
+            Ok(request) => call(&mut client, request)
+                .await
+                .map(|response| serde_json::to_vec(&response)),
+            Err(e) => Some(serde_json::to_vec(&failure(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            ))),
+        };
+        let Some(reply) = reply else {
+            continue;
+        };
+        let reply = reply.expect("responses serialize");
+        if let Err(e) = connection.send(&reply).await {
+            eprintln!("{}: {}", connection.peer(), e);
+            break;
+        }
+        if client.rejections >= auth::MAX_REJECTIONS {
+            eprintln!("{}: too many wrong tokens", connection.peer());
+            break;
+        }
+    }
+    if let Some(session) = client.session {
+        session.close().await;
+    }
+}
+
+fn failure(id: Value, error: RpcError) -> RpcResponse {
+    RpcResponse {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(error),
+    }
+}
+
+/// Runs one request, answering it unless it is a notification.
+async fn call(client: &mut Client, request: Value) -> Option<RpcResponse> {
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(Value::as_str);
+    let (Some("2.0"), Some(method)) = (request.get("jsonrpc").and_then(Value::as_str), method)
+    else {
+        let error = RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request");
+        return Some(failure(id.unwrap_or(Value::Null), error));
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let result = match client.token.take() {
+        None => run(client, method, params).await,
+        Some(expected) => {
+            let result = authenticate(&expected, method, params);
+            if let Err(e) = &result {
+                if e.message == auth::REJECTED {
+                    client.rejections += 1;
+                }
+                client.token = Some(expected);
+            }
+            result
+        }
+    };
+    let id = id?;
+    Some(match result {
+        Ok(result) => RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(error) => failure(id, error),
+    })
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// Answers an `authenticate` request carrying `token`, and refuses every
+/// other request.
+fn authenticate(token: &Token, method: &str, params: Value) -> Result<Value, RpcError> {
+    if method != "authenticate" {
+        return Err(RpcError::new(UNAUTHORIZED, auth::REQUIRED));
+    }
+    let AuthenticateParams { token: presented } = self::params(params)?;
+    match token.accepts(Some(&presented)) {
+        true => Ok(Value::Null),
+        false => Err(RpcError::new(UNAUTHORIZED, auth::REJECTED)),
+    }
+}
+
+fn to_value(result: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|e| RpcError::new(APPLICATION_ERROR, e.to_string()))
+}
+
+async fn run(client: &mut Client, method: &str, params: Value) -> Result<Value, RpcError> {
+    let config = &client.config;
+    let session = client
+        .session
+        .get_or_insert_with(|| Session::with_config(config.clone()));
+    let level = session.config.output_level;
+    match method {
+        "eval" => {
+            let EvalParams {
+                source,
+                file,
+                chunk_name,
+                line,
+                output_level,
+            } = self::params(params)?;
+            let options = EvalOptions {
+                output_level,
+                ..Default::default()
+            }
+            .located(file, chunk_name, line);
+            let audited = client.audit.as_ref().map(|audit| (audit, source.clone()));
+            let started = Instant::now();
+            let eval = async |session: &mut Session| session.eval_with(source, options).await;
+            let response = match &client.scheduler {
+                Some(scheduler) => scheduler
+                    .run(scheduler::tenant(&client.peer), session, eval)
+                    .await
+                    .map_err(|e| RpcError::new(APPLICATION_ERROR, e.to_string()))?,
+                None => eval(session).await,
+            };
+            if let Some((audit, source)) = audited {
+                let status = AuditStatus::of(&response);
+                let elapsed = started.elapsed();
+                let recorded =
+                    audit
+                        .lock()
+                        .unwrap()
+                        .record(0, Some(&client.peer), &source, status, elapsed);
+                if let Err(e) = recorded {
+                    eprintln!("{}: cannot write the audit log: {}", client.peer, e);
+                }
+            }
+            to_value(EvalResult {
+                rendered: output::render(&response, output_level.unwrap_or(level)),
+                response,
+            })
+        }
+        "inspect" => match self::params(params)? {
+            InspectParams::Path { path } => match session.get_path(path).await {
+                Ok(response) => to_value(InspectResult {
+                    total: None,
+                    response,
+                }),
+                Err(e) => Err(RpcError::new(APPLICATION_ERROR, e.to_string())),
+            },
+            InspectParams::Page { id, offset, limit } => {
+                let options = ExpandOptions {
+                    offset,
+                    limit,
+                    key_filter: None,
+                };
+                match session.expand_with(id.clone(), options).await {
+                    Some(page) => to_value(InspectResult {
+                        total: Some(page.total),
+                        response: page.response,
+                    }),
+                    None => Err(RpcError::new(
+                        APPLICATION_ERROR,
+                        format!("{} is no longer kept", id),
+                    )),
+                }
+            }
+        },
+        "complete" => {
+            let CompleteParams { prefix } = self::params(params)?;
+            to_value(session.complete(&prefix).await)
+        }
+        "help" => {
+            let HelpParams { name } = self::params(params)?;
+            match help::lookup(&name) {
+                Some(topic) => to_value(HelpEntry::from(topic)),
+                None => Err(RpcError::new(
+                    APPLICATION_ERROR,
+                    format!("no help for {}", name),
+                )),
+            }
+        }
+        "apropos" => {
+            let AproposParams { pattern } = self::params(params)?;
+            let topics: Vec<HelpEntry> = help::apropos(&pattern)
+                .into_iter()
+                .map(Into::into)
+                .collect();
+            to_value(topics)
+        }
+        "authenticate" => Ok(Value::Null),
+        "reset" => {
+            session.reset().await;
+            Ok(Value::Null)
+        }
+        method => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("no method named {}", method),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::Framed;
+    use serde_json::json;
+
+    async fn request(client: &mut impl Connection, message: Value) -> Value {
+        client
+            .send(&serde_json::to_vec(&message).unwrap())
+            .await
+            .unwrap();
+        serde_json::from_slice(&client.recv().await.unwrap().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_methods() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (a_reader, a_writer) = tokio::io::split(a);
+        let (b_reader, b_writer) = tokio::io::split(b);
+        let mut client = Framed::new(a_reader, a_writer, "client".to_string());
+        let server = Framed::new(b_reader, b_writer, "server".to_string());
+        let server = tokio::spawn(handle(server, SessionConfig::default(), None, None, None));
+
+        let reply = request(
+            &mut client,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eval", "params": {"source": "player = { name = 'Ada' } return 6 * 7"}}),
+        )
+        .await;
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["result"]["rendered"], "42");
+        assert_eq!(reply["result"]["value"], json!({"integer": 42}));
+
+        let reply = request(
+            &mut client,
+            json!({"jsonrpc": "2.0", "id": 2, "method": "inspect", "params": {"path": "player.name"}}),
+        )
+        .await;
+        assert_eq!(reply["result"]["value"], json!({"string": "Ada"}));
+
+        let reply = request(
+            &mut client,
+            json!({"jsonrpc": "2.0", "id": 2, "method": "eval", "params": {"source": "return player", "output_level": "minimal"}}),
+        )
+        .await;
+        // The server's full level would expand the table.
+        assert!(reply["result"]["rendered"]
+            .as_str()
+            .unwrap()
+            .starts_with("table: "));
+
+        let reply = request(
+            &mut client,
+            json!([
+                {"jsonrpc": "2.0", "id": 3, "method": "complete", "params": {"prefix": "string.fo"}},
+                {"jsonrpc": "2.0", "method": "reset"},
+                {"jsonrpc": "2.0", "id": 4, "method": "launch"},
+            ]),
+        )
+        .await;
+        assert_eq!(reply[0]["result"], json!(["string.format"]));
+        assert_eq!(reply[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(reply.as_array().unwrap().len(), 2);
+
+        let reply = request(
+            &mut client,
+            json!([
+                {"jsonrpc": "2.0", "id": 7, "method": "help", "params": {"name": "string.rep"}},
+                {"jsonrpc": "2.0", "id": 8, "method": "help", "params": {"name": "string.nope"}},
+                {"jsonrpc": "2.0", "id": 9, "method": "apropos", "params": {"pattern": "metatable"}},
+            ]),
+        )
+        .await;
+        assert_eq!(reply[0]["result"]["name"], "string.rep");
+        assert_eq!(reply[1]["error"]["code"], APPLICATION_ERROR);
+        assert!(reply[2]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|topic| topic["name"] == "setmetatable"));
+
+        let reply = request(
+            &mut client,
+            json!({"jsonrpc": "2.0", "id": 5, "method": "eval", "params": {"source": "return player"}}),
+        )
+        .await;
+        assert_eq!(reply["result"]["value"], "nil");
+        let reply = request(
+            &mut client,
+            json!({"jsonrpc": "2.0", "id": 6, "method": "eval", "params": {}}),
+        )
+        .await;
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+
+        client.send(b"{").await.unwrap();
+        let reply: Value = serde_json::from_slice(&client.recv().await.unwrap().unwrap()).unwrap();
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let connect = || {
+            let (a, b) = tokio::io::duplex(64 * 1024);
+            let (a_reader, a_writer) = tokio::io::split(a);
+            let (b_reader, b_writer) = tokio::io::split(b);
+            let client = Framed::new(a_reader, a_writer, "client".to_string());
+            let server = Framed::new(b_reader, b_writer, "server".to_string());
+            let token = Some(Token::new("s3cret"));
+            let config = SessionConfig::default();
+            (
+                client,
+                tokio::spawn(handle(server, config, token, None, None)),
+            )
+        };
+        let (mut client, server) = connect();
+
+        let authenticate = |id: u64, token: &str| json!({"jsonrpc": "2.0", "id": id, "method": "authenticate", "params": {"token": token}});
+        let reply = request(
+            &mut client,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eval", "params": {"source": "return 1"}}),
+        )
+        .await;
+        assert_eq!(reply["error"]["code"], UNAUTHORIZED);
+        let reply = request(&mut client, authenticate(2, "s3cret")).await;
+        assert_eq!(reply["result"], Value::Null);
+        let reply = request(
+            &mut client,
+            json!({"jsonrpc": "2.0", "id": 3, "method": "eval", "params": {"source": "return 1"}}),
+        )
+        .await;
+        assert_eq!(reply["result"]["value"], json!({"integer": 1}));
+        drop(client);
+        server.await.unwrap();
+
+        // A client that keeps guessing is disconnected.
+        let (mut client, server) = connect();
+        for id in 0..u64::from(auth::MAX_REJECTIONS) {
+            let reply = request(&mut client, authenticate(id, "guess")).await;
+            assert_eq!(reply["error"]["code"], UNAUTHORIZED);
+        }
+        assert!(matches!(client.recv().await, Ok(None) | Err(_)));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+//! `luarepl learn`: tutorials made of steps whose answers are evaluated in
+//! a sandboxed session and checked against the lesson's expectations.
+//!
+//! ```toml
+//! title = "Tables"
+//!
+//! [[step]]
+//! prompt = "Store the numbers 1, 2 and 3 in a global table `t`."
+//! expect_globals = ["t"]
+//! hint = "Table constructors look like {1, 2, 3}."
+//!
+//! [[step]]
+//! prompt = "Return the length of `t`."
+//! expect_value = 3
+//!
+//! [[step]]
+//! prompt = "Print every element of `t`."
+//! expect_output = "1\n2\n3"
+//!
+//! [[step]]
+//! prompt = "Look up `table.concat` with `:help`."
+//! expect_command = "help"
+//! ```
+//!
+//! `luarepl tour` is a lesson too, embedded from `lessons/tour.toml`.
+
+use crate::hardening::Hardening;
+use crate::image::SessionImage;
+use crate::limits::Limits;
+use crate::output;
+use crate::output::OutputLevel;
+use crate::EvalOptions;
+use crate::EvalResponse;
+use crate::LuaValue;
+use crate::Session;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lesson {
+    pub title: String,
+    #[serde(rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub prompt: String,
+    /// Shown after a wrong answer.
+    pub hint: Option<String>,
+    /// A boolean, number or string the answer must return.
+    pub expect_value: Option<toml::Value>,
+    /// Globals the answer must leave non-nil.
+    #[serde(default)]
+    pub expect_globals: Vec<String>,
+    /// Everything the answer prints, lines joined by `\n`.
+    pub expect_output: Option<String>,
+    /// Text the answer's error must contain; the answer must fail.
+    pub expect_error: Option<String>,
+    /// A meta-command such as `grep` the answer must run instead of Lua.
+    /// The frontend runs it, see `Step::accepts_command`.
+    pub expect_command: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum LessonError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    /// `expect_value` of step `step` (counting from 1) is not a boolean,
+    /// number or string.
+    UnsupportedValue {
+        step: usize,
+    },
+}
+
+impl fmt::Display for LessonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LessonError::Io(e) => write!(f, "{}", e),
+            LessonError::Parse(e) => write!(f, "{}", e),
+            LessonError::UnsupportedValue { step } => write!(
+                f,
+                "step {}: expect_value must be a boolean, number or string",
+                step
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LessonError {}
+
+/// Why an answer was not accepted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    /// The answer did not run, e.g. because of a syntax error.
+    Failed,
+    Value {
+        expected: LuaValue,
+        found: LuaValue,
+    },
+    MissingGlobal(String),
+    Output {
+        expected: String,
+        found: String,
+    },
+    /// The answer should have failed with an error containing this.
+    Succeeded(String),
+    Error {
+        expected: String,
+        found: String,
+    },
+    /// The answer should have been this meta-command.
+    Command(String),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Failed => write!(f, "that did not run"),
+            Mismatch::Value { expected, found } => {
+                write!(f, "expected {:?}, got {:?}", expected, found)
+            }
+            Mismatch::MissingGlobal(name) => write!(f, "`{}` is not defined", name),
+            Mismatch::Output { expected, found } => {
+                write!(f, "expected the output {:?}, got {:?}", expected, found)
+            }
+            Mismatch::Succeeded(expected) => {
+                write!(f, "that ran, but should fail with {:?}", expected)
+            }
+            Mismatch::Error { expected, found } => {
+                write!(f, "expected an error with {:?}, got {:?}", expected, found)
+            }
+            Mismatch::Command(name) => write!(f, "run :{} instead of Lua", name),
+        }
+    }
+}
+
+/// Compares like Lua's `==`, so `3` in a lesson matches a float `3.0`.
+fn same_value(expected: &LuaValue, found: &LuaValue) -> bool {
+    match (expected, found) {
+        (LuaValue::Integer(i), LuaValue::Number(n))
+        | (LuaValue::Number(n), LuaValue::Integer(i)) => *i as f64 == *n,
+        _ => expected == found,
+    }
+}
+
+fn expected_value(value: &toml::Value) -> Option<LuaValue> {
+    match value {
+        toml::Value::Boolean(b) => Some(LuaValue::Boolean(*b)),
+        toml::Value::Integer(i) => Some(LuaValue::Integer(*i)),
+        toml::Value::Float(n) => Some(LuaValue::Number(*n)),
+        toml::Value::String(s) => Some(LuaValue::String(s.clone())),
+        _ => None,
+    }
+}
+
+const TOUR: &str = include_str!("../lessons/tour.toml");
+
+/// Collects what `print` writes instead of letting it reach the terminal, so
+/// that it can be compared and then echoed.
+const CAPTURE_PRINT: &str = r#"
+local output = {}
+function print(...)
+    local values = table.pack(...)
+    for i = 1, values.n do
+        values[i] = tostring(values[i])
+    end
+    output[#output + 1] = table.concat(values, "\t")
+end
+function __lesson_output()
+    local text = table.concat(output, "\n")
+    output = {}
+    return text
+end
+"#;
+
+impl Lesson {
+    pub fn parse(source: &str) -> Result<Self, LessonError> {
+        let lesson: Lesson = toml::from_str(source).map_err(LessonError::Parse)?;
+        for (index, step) in lesson.steps.iter().enumerate() {
+            if step
+                .expect_value
+                .as_ref()
+                .is_some_and(|value| expected_value(value).is_none())
+            {
+                return Err(LessonError::UnsupportedValue { step: index + 1 });
+            }
+        }
+        Ok(lesson)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, LessonError> {
+        Self::parse(&std::fs::read_to_string(path).map_err(LessonError::Io)?)
+    }
+
+    /// The walkthrough of the REPL's own features behind `luarepl tour`.
+    pub fn tour() -> Self {
+        Self::parse(TOUR).expect("the tour parses")
+    }
+
+    /// A hardened, limited session whose `print` output can be checked.
+    pub fn session() -> Session {
+        let image = SessionImage::builder()
+            .init_script(CAPTURE_PRINT)
+            .build()
+            .expect("the print capture script compiles");
+        Session::builder()
+            .limits(Limits {
+                memory: Some(16 << 20),
+                instructions: Some(10_000_000),
+            })
+            .hardening(Hardening::default())
+            .image(Arc::new(image))
+            .build()
+    }
+}
+
+/// What an accepted or rejected answer printed and returned, and why it was
+/// rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checked {
+    pub output: String,
+    /// The values the answer returned as the REPL shows them, if it returned
+    /// any.
+    pub result: Option<String>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Checked {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl Step {
+    /// Whether `line` runs the meta-command the step expects.
+    pub fn accepts_command(&self, line: &str) -> bool {
+        let name = line
+            .trim()
+            .strip_prefix(':')
+            .and_then(|line| line.split_whitespace().next());
+        name.is_some() && name == self.expect_command.as_deref()
+    }
+
+    /// Evaluates `answer` in `session`, which must come from `Lesson::session`.
+    pub async fn check(&self, session: &mut Session, answer: String) -> Checked {
+        if let Some(name) = &self.expect_command {
+            return Checked {
+                output: String::new(),
+                result: None,
+                mismatches: vec![Mismatch::Command(name.clone())],
+            };
+        }
+        let response = session.eval(answer).await;
+        let output = session
+            .eval_with(
+                "__lesson_output()".to_string(),
+                EvalOptions {
+                    expression_only: true,
+                    ..Default::default()
+                },
+            )
+            .await;
+        let output = match output.value {
+            LuaValue::String(output) => output,
+            _ => String::new(),
+        };
+        let result = (response.success && !response.values.is_empty())
+            .then(|| output::render(&response, OutputLevel::Full));
+        let mismatches = self.mismatches(session, response, &output).await;
+        Checked {
+            output,
+            result,
+            mismatches,
+        }
+    }
+
+    async fn mismatches(
+        &self,
+        session: &mut Session,
+        response: EvalResponse,
+        output: &str,
+    ) -> Vec<Mismatch> {
+        if let Some(expected) = &self.expect_error {
+            let found = output::render(&response, OutputLevel::Minimal);
+            return match response.success {
+                true => vec![Mismatch::Succeeded(expected.clone())],
+                false if !found.contains(expected.as_str()) => vec![Mismatch::Error {
+                    expected: expected.clone(),
+                    found,
+                }],
+                false => vec![],
+            };
+        }
+        if !response.success {
+            return vec![Mismatch::Failed];
+        }
+
+        let mut mismatches = vec![];
+        if let Some(expected) = self.expect_value.as_ref().and_then(expected_value) {
+            if !same_value(&expected, &response.value) {
+                mismatches.push(Mismatch::Value {
+                    expected,
+                    found: response.value,
+                });
+            }
+        }
+        for name in &self.expect_globals {
+            if session.get_path(name.clone()).await.is_err() {
+                mismatches.push(Mismatch::MissingGlobal(name.clone()));
+            }
+        }
+        if let Some(expected) = &self.expect_output {
+            if output.trim_end() != expected.trim_end() {
+                mismatches.push(Mismatch::Output {
+                    expected: expected.clone(),
+                    found: output.to_string(),
+                });
+            }
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LESSON: &str = include_str!("../lessons/tables.toml");
+
+    #[tokio::test]
+    async fn test_lesson() {
+        let lesson = Lesson::parse(LESSON).unwrap();
+        let mut session = Lesson::session();
+        let [define, length, print] = &lesson.steps[..] else {
+            panic!("expected three steps, got {}", lesson.steps.len());
+        };
+
+        let checked = define
+            .check(&mut session, "s = {1, 2, 3}".to_string())
+            .await;
+        assert_eq!(
+            checked.mismatches,
+            vec![Mismatch::MissingGlobal("t".to_string())]
+        );
+        assert!(define
+            .check(&mut session, "t = {1, 2, 3}".to_string())
+            .await
+            .passed());
+
+        let checked = length
+            .check(&mut session, "return #t - 1".to_string())
+            .await;
+        assert_eq!(
+            checked.mismatches,
+            vec![Mismatch::Value {
+                expected: LuaValue::Integer(3),
+                found: LuaValue::Integer(2),
+            }]
+        );
+        let checked = length.check(&mut session, "return #t +".to_string()).await;
+        assert_eq!(checked.mismatches, vec![Mismatch::Failed]);
+
+        let checked = print
+            .check(
+                &mut session,
+                "for _, v in ipairs(t) do print(v) end".to_string(),
+            )
+            .await;
+        assert!(checked.passed());
+        assert_eq!(checked.output, "1\n2\n3");
+        assert_eq!(checked.result, None);
+        let checked = length.check(&mut session, "return #t".to_string()).await;
+        assert_eq!(checked.result.as_deref(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn test_tour() {
+        let tour = Lesson::tour();
+        let mut session = Lesson::session();
+        let command = tour
+            .steps
+            .iter()
+            .find(|step| step.expect_command.as_deref() == Some("grep"))
+            .unwrap();
+        assert!(command.accepts_command(":grep hero"));
+        assert!(!command.accepts_command(":help"));
+        let checked = command.check(&mut session, "return 1".to_string()).await;
+        assert_eq!(
+            checked.mismatches,
+            vec![Mismatch::Command("grep".to_string())]
+        );
+
+        let limit = tour
+            .steps
+            .iter()
+            .find(|step| step.expect_error.is_some())
+            .unwrap();
+        let checked = limit.check(&mut session, "return 1".to_string()).await;
+        assert!(matches!(&checked.mismatches[..], [Mismatch::Succeeded(_)]));
+        let checked = limit.check(&mut session, "error('x')".to_string()).await;
+        assert!(matches!(&checked.mismatches[..], [Mismatch::Error { .. }]));
+        assert!(limit
+            .check(&mut session, "while true do end".to_string())
+            .await
+            .passed());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            Lesson::parse("title = 'x'\n[[step]]\nprompt = 'p'\nexpect_value = [1]"),
+            Err(LessonError::UnsupportedValue { step: 1 })
+        ));
+        assert!(matches!(
+            Lesson::parse("title = 'x'\n[[step]]\nquestion = 'p'"),
+            Err(LessonError::Parse(_))
+        ));
+    }
+}
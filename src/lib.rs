@@ -0,0 +1,2737 @@
+//! An embeddable Lua REPL session. A `Session` runs Lua 5.4 on an
+//! interpreter thread of its own and answers every eval with an
+//! `EvalResponse`: the result as a `LuaValue`, plus every table reachable
+//! from it as a `LuaObject` keyed by object id, so clients can render and
+//! expand results without holding on to Lua state.
+//!
+//! ```ignore
+//! let mut session = luarepl::Session::new();
+//! session.eval("x = 1".to_string()).await;
+//! let response = session.eval("return { x = x }".to_string()).await;
+//! assert!(response.success);
+//! ```
+//!
+//! The `luarepl` binary is a command-line front end over this library.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::LightUserData;
+use rlua::Lua;
+use rlua::MultiValue;
+use rlua::Table;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+pub mod audit;
+pub mod auth;
+pub mod bridge;
+pub mod bundle;
+pub mod cache;
+pub mod cancel;
+pub mod capture;
+pub mod caught;
+pub mod clock;
+pub mod commands;
+pub mod compat51;
+pub mod completion;
+pub mod compression;
+pub mod coroutine;
+pub mod crash;
+pub mod dap;
+pub mod debugger;
+pub mod delta;
+pub mod doc;
+pub mod doctor;
+pub mod echo;
+pub mod elide;
+mod embedded;
+pub mod environment;
+pub mod error_value;
+pub mod events;
+pub mod explain;
+pub mod finalizers;
+pub mod formatter;
+pub mod function;
+pub mod fuzz;
+pub mod hardening;
+pub mod help;
+pub mod history;
+pub mod host;
+pub mod idle_gc;
+pub mod image;
+pub mod info;
+pub mod iterate;
+pub mod jsonrpc;
+pub mod lesson;
+pub mod limits;
+pub mod liveness;
+pub mod local;
+pub mod manager;
+pub mod memprofile;
+pub mod nil_access;
+pub mod output;
+pub mod page;
+pub mod path;
+pub mod pin;
+pub mod pool;
+pub mod preview;
+pub mod progress;
+pub mod protocol;
+pub mod pump;
+pub mod recovery;
+pub mod render;
+pub mod resources;
+pub mod sandbox;
+pub mod scheduler;
+pub mod schema;
+pub mod scripting;
+pub mod search;
+pub mod server;
+pub mod settings;
+pub mod subscription;
+pub mod template;
+pub mod terminal;
+pub mod traceback;
+pub mod transcript;
+pub mod transport;
+pub mod tui;
+pub mod ui;
+pub mod userdata;
+pub mod warnings;
+pub mod watch;
+pub mod websocket;
+pub mod workspace;
+#[cfg(any(test, feature = "test-util"))]
+#[macro_use]
+pub mod test_util;
+
+use bridge::UserDataType;
+use cache::ResultCache;
+use cancel::CancelToken;
+use cancel::Cancelled;
+use caught::CaughtErrors;
+use clock::Clock;
+use coroutine::CoroutineRef;
+use crash::CrashRecorder;
+use crash::CrashState;
+use debugger::Debugger;
+use environment::Environment;
+use events::SessionHandle;
+use explain::Explanation;
+use finalizers::GcReport;
+use formatter::Formatter;
+use formatter::TypeMatcher;
+use function::FunctionRef;
+use hardening::Hardening;
+use hardening::InputError;
+use history::History;
+use history::HistoryEntry;
+use host::AsyncCall;
+use host::AsyncCalls;
+use host::HostError;
+use host::HostFunction;
+use host::HostResult;
+use host::PendingEval;
+use host::Step;
+use idle_gc::IdleGc;
+use idle_gc::Idleness;
+use image::SessionImage;
+use info::SessionInfo;
+use limits::LimitGuard;
+use limits::LimitViolation;
+use limits::Limits;
+use liveness::Liveness;
+use local::LocalSession;
+use memprofile::MemoryProfile;
+use memprofile::Profiler;
+use nil_access::NilAccess;
+use output::OutputLevel;
+use page::ExpandOptions;
+use page::Page;
+use path::PathError;
+use pin::Pin;
+use pin::PinLeak;
+use pin::PinSites;
+use pin::Pins;
+use pool::WorkerPool;
+use progress::ProgressHandler;
+use pump::Cooperative;
+use recovery::JournalEntry;
+use recovery::Recovery;
+use resources::Resources;
+use schema::Schema;
+use schema::SchemaError;
+use search::SearchMatch;
+use search::SearchOptions;
+use subscription::PathChange;
+use subscription::PathWatch;
+use subscription::Subscriptions;
+use template::TemplateError;
+use traceback::StackFrame;
+use userdata::UserDataRef;
+use watch::Dependencies;
+use watch::Watch;
+use watch::WatchList;
+use workspace::Workspace;
+
+/// The outcome of one eval. Tables in the result appear in `value` and in
+/// each other as `LuaValue::ObjectRef`s naming entries of `objects`.
+///
+/// Responses serialize with their field names as they are here, and enums
+/// externally tagged in snake case, e.g. `{"integer":1}` or `"nil"`.
+/// `protocol` frames carry `ErrorKind` in a shape of their own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EvalResponse {
+    /// Whether the chunk compiled and ran without raising an error.
+    pub success: bool,
+    /// The message of the error that made the eval fail, such as
+    /// `attempt to index a nil value (global 'x')`, or why the session's
+    /// hardening refused the input. `None` on success.
+    pub error: Option<String>,
+    /// What kind of error `error` is.
+    pub error_kind: Option<ErrorKind>,
+    /// What the chunk passed to `error` if it was not a string, e.g. a
+    /// table of details, referencing `objects` like `values`; `error` is
+    /// then its `tostring`. See `error_value`.
+    #[serde(default)]
+    pub error_value: Option<LuaValue>,
+    /// Where the eval was when it failed at runtime, innermost frame first.
+    pub traceback: Vec<StackFrame>,
+    /// The tables reachable from `values`, by object id.
+    pub objects: HashMap<String, LuaObject>,
+    /// The first value the chunk returned; nil if it returned nothing.
+    pub value: LuaValue,
+    /// Every value the chunk returned, starting with `value`.
+    pub values: Vec<LuaValue>,
+    pub limit_violation: Option<LimitViolation>,
+    /// Mismatches against `EvalOptions::schema`, if one was given.
+    pub schema_errors: Vec<SchemaError>,
+    pub graph_stats: GraphStats,
+    /// What the eval did, in explain mode.
+    pub explanation: Option<Explanation>,
+    /// The host function whose error made the eval fail, if any.
+    pub host_error: Option<HostError>,
+    /// New values of the names an eval that returned nothing assigned, in
+    /// echo mode.
+    pub assignments: Vec<(String, LuaValue)>,
+    /// What was nil if the eval failed indexing or calling a nil global or
+    /// field, with spelling suggestions.
+    pub nil_access: Option<NilAccess>,
+    /// Messages the eval passed to `warn`, see `warnings`.
+    pub warnings: Vec<String>,
+    /// The lines the eval printed, if the session captures its output.
+    pub output: Vec<String>,
+    /// The lines the eval wrote to stderr, if the session captures its
+    /// output.
+    pub stderr: Vec<String>,
+    /// `EvalRequest::id` of the eval, if it was made with
+    /// `Session::eval_request`.
+    #[serde(default)]
+    pub request_id: Option<u64>,
+}
+
+/// Why an eval failed, so frontends can tell input that is merely
+/// unfinished from input that is wrong. Serialized like `"runtime"`, or
+/// `{"syntax":{"incomplete":true}}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The chunk did not compile. `incomplete` means it ended too early, as
+    /// a line-based frontend sees after `function f()`, and more input may
+    /// complete it.
+    Syntax {
+        incomplete: bool,
+    },
+    Runtime,
+    /// The memory limit was exceeded or an allocation failed.
+    Memory,
+    /// A host function failed.
+    Callback,
+    /// The instruction limit was exceeded, or the interpreter stopped
+    /// responding.
+    Timeout,
+}
+
+impl ErrorKind {
+    fn of(error: &Error) -> Self {
+        match error {
+            Error::SyntaxError {
+                incomplete_input, ..
+            } => ErrorKind::Syntax {
+                incomplete: *incomplete_input,
+            },
+            Error::MemoryError(_) => ErrorKind::Memory,
+            Error::CallbackError { .. } => ErrorKind::Callback,
+            _ => ErrorKind::Runtime,
+        }
+    }
+}
+
+/// Size of the object graph serialized into a response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphStats {
+    pub tables: usize,
+    pub entries: usize,
+    /// Nesting depth of the deepest table; the result table itself is depth 1.
+    pub max_depth: usize,
+    /// Total length of all string keys and values.
+    pub string_bytes: usize,
+}
+
+/// A Lua value as sent to clients: scalars by value, tables and other
+/// reference types by object id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    /// Lua floats; integers are `Integer`, as Lua 5.4 tells them apart.
+    Number(f64),
+    Integer(i64),
+    String(String),
+    ObjectRef(String),
+    /// Functions are described rather than serialized.
+    FunctionRef(FunctionRef),
+    Coroutine(CoroutineRef),
+    /// Userdata other than bridged values, which are objects.
+    UserData(UserDataRef),
+    /// The address a light userdata holds, e.g. a pointer an embedder passed
+    /// through Lua.
+    LightUserData(usize),
+}
+
+impl LuaValue {
+    /// The value of a float or an integer, for host functions that accept
+    /// either as Lua's arithmetic does.
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            LuaValue::Number(n) => Some(*n),
+            LuaValue::Integer(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    /// Converts a plain value, including a light userdata, back into Lua.
+    /// Object refs cannot be converted because the objects they name are not
+    /// kept alive.
+    fn to_lua<'l>(&self, ctx: Context<'l>) -> Result<Value<'l>, Error> {
+        Ok(match self {
+            LuaValue::Nil => Value::Nil,
+            LuaValue::Boolean(b) => Value::Boolean(*b),
+            LuaValue::Number(n) => Value::Number(*n),
+            LuaValue::Integer(i) => Value::Integer(*i),
+            LuaValue::String(s) => Value::String(ctx.create_string(s)?),
+            LuaValue::LightUserData(address) => {
+                Value::LightUserData(LightUserData(*address as *mut _))
+            }
+            LuaValue::ObjectRef(id) => {
+                return Err(Error::ToLuaConversionError {
+                    from: "ObjectRef",
+                    to: "value",
+                    message: Some(format!("{} is not kept alive by the session", id)),
+                })
+            }
+            LuaValue::FunctionRef(function) => {
+                return Err(Error::ToLuaConversionError {
+                    from: "FunctionRef",
+                    to: "value",
+                    message: Some(format!("{} is not kept alive by the session", function.id)),
+                })
+            }
+            LuaValue::Coroutine(coroutine) => {
+                return Err(Error::ToLuaConversionError {
+                    from: "Coroutine",
+                    to: "value",
+                    message: Some(format!("{} is not kept alive by the session", coroutine.id)),
+                })
+            }
+            LuaValue::UserData(userdata) => {
+                return Err(Error::ToLuaConversionError {
+                    from: "UserData",
+                    to: "value",
+                    message: Some(format!("{} is not kept alive by the session", userdata.id)),
+                })
+            }
+        })
+    }
+}
+
+impl fmt::Display for LuaValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaValue::Nil => write!(f, "nil"),
+            LuaValue::Boolean(b) => write!(f, "{}", b),
+            LuaValue::Number(n) => write!(f, "{}", float_text(*n)),
+            LuaValue::Integer(i) => write!(f, "{}", i),
+            LuaValue::String(s) => write!(f, "{:?}", s),
+            LuaValue::ObjectRef(id) => write!(f, "{}", id),
+            LuaValue::FunctionRef(function) => write!(f, "{}", function),
+            LuaValue::Coroutine(coroutine) => write!(f, "{}", coroutine),
+            LuaValue::UserData(userdata) => write!(f, "{}", userdata),
+            LuaValue::LightUserData(address) => write!(f, "lightuserdata: {:#x}", address),
+        }
+    }
+}
+
+/// A float as `tostring` shows it: integral floats keep a `.0`, so they
+/// cannot be mistaken for integers.
+pub(crate) fn float_text(n: f64) -> String {
+    if n.is_finite() && n.fract() == 0.0 && n.abs() < 1e16 {
+        format!("{:.1}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+/// The entries of one table of a response, in traversal order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LuaObject {
+    pub members: Vec<(LuaValue, LuaValue)>,
+    /// Rendering produced by a registered formatter, if one matched.
+    pub display: Option<String>,
+    /// One-line summaries of the tables referenced by this object's keys and
+    /// values, keyed by their object ref.
+    pub child_previews: HashMap<String, String>,
+    /// The `UserDataType` name of bridged userdata; `None` for tables.
+    pub type_name: Option<String>,
+    /// How often the response's values and objects reference this one.
+    /// More than once means it is shared, e.g. aliased by two fields or
+    /// part of a cycle; `:refs` lists the paths to it.
+    #[serde(default)]
+    pub references: usize,
+}
+
+impl LuaObject {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: LuaValue, value: LuaValue) {
+        self.members.push((key, value));
+    }
+
+    /// Returns the value stored under the string key `name`.
+    pub fn get(&self, name: &str) -> Option<&LuaValue> {
+        self.members
+            .iter()
+            .find(|(key, _)| matches!(key, LuaValue::String(k) if k == name))
+            .map(|(_, value)| value)
+    }
+}
+
+const VALUE_ID_KEY: &str = "luarepl.value_id";
+
+/// Returns a stable identifier for a table, function, thread or userdata,
+/// e.g. `table: 0x5581c0a3e2f0`. Unlike `tostring` this ignores `__tostring`
+/// and keeps working if scripts replace the `string` library.
+pub(crate) fn value_id<'l>(ctx: Context<'l>, value: Value<'l>) -> String {
+    let type_name = value.type_name();
+    let id: Result<String, Error> = (|| {
+        let format: Function = match ctx.named_registry_value(VALUE_ID_KEY)? {
+            Value::Function(format) => format,
+            _ => {
+                let format: Function = ctx
+                    .load("local format = string.format; return function(t, v) return format('%s: %p', t, v) end")
+                    .set_name("=value_id")?
+                    .eval()?;
+                ctx.set_named_registry_value(VALUE_ID_KEY, format.clone())?;
+                format
+            }
+        };
+        format.call((type_name, value))
+    })();
+    id.unwrap_or_else(|_| format!("{}: ?", type_name))
+}
+
+pub(crate) fn table_id<'l>(ctx: Context<'l>, table: &Table<'l>) -> String {
+    value_id(ctx, Value::Table(table.clone()))
+}
+
+/// Serializes Lua values into the object graph of a response.
+#[derive(Default)]
+struct GraphBuilder {
+    objects: HashMap<String, LuaObject>,
+    seen_objs: HashSet<String>,
+    /// How often each object is referenced, see `LuaObject::references`.
+    references: HashMap<String, usize>,
+    stats: GraphStats,
+    /// Tables nested deeper than this are referenced but not serialized.
+    max_depth: Option<usize>,
+    /// Type name of the first value that cannot be serialized, if any.
+    unsupported: Option<&'static str>,
+    /// Which entries of the root table to serialize; taken by the root.
+    page: Option<ExpandOptions>,
+    /// How many entries of the root table matched `page`.
+    matching: usize,
+}
+
+impl GraphBuilder {
+    fn parse_value<'l>(
+        &mut self,
+        ctx: Context<'l>,
+        rlua_value: Value<'l>,
+        depth: usize,
+    ) -> LuaValue {
+        match rlua_value {
+            Value::Table(t) => {
+                let id = self.parse_table(ctx, t, depth + 1);
+                *self.references.entry(id.clone()).or_insert(0) += 1;
+                LuaValue::ObjectRef(id)
+            }
+            Value::Boolean(b) => LuaValue::Boolean(b),
+            Value::String(s) => {
+                self.stats.string_bytes += s.as_bytes().len();
+                LuaValue::String(s.to_str().unwrap_or_default().to_string())
+            }
+            Value::Number(n) => LuaValue::Number(n),
+            Value::Integer(n) => LuaValue::Integer(n),
+            Value::Nil => LuaValue::Nil,
+            Value::Function(function) => LuaValue::FunctionRef(FunctionRef::new(ctx, function)),
+            Value::Thread(thread) => LuaValue::Coroutine(CoroutineRef::new(ctx, thread)),
+            Value::LightUserData(pointer) => LuaValue::LightUserData(pointer.0 as usize),
+            Value::UserData(userdata) => match bridge::reflect(&userdata) {
+                Some((type_name, fields)) => {
+                    let id = value_id(ctx, Value::UserData(userdata));
+                    self.add_reflected(id.clone(), type_name, fields, depth + 1);
+                    *self.references.entry(id.clone()).or_insert(0) += 1;
+                    LuaValue::ObjectRef(id)
+                }
+                None => LuaValue::UserData(UserDataRef::new(ctx, userdata)),
+            },
+            v => {
+                self.unsupported.get_or_insert(v.type_name());
+                LuaValue::Nil
+            }
+        }
+    }
+
+    fn add_reflected(
+        &mut self,
+        id: String,
+        type_name: String,
+        fields: Vec<(&'static str, LuaValue)>,
+        depth: usize,
+    ) {
+        if !self.seen_objs.insert(id.clone()) {
+            return;
+        }
+        self.stats.tables += 1;
+        self.stats.max_depth = self.stats.max_depth.max(depth);
+        let mut object = LuaObject {
+            type_name: Some(type_name),
+            ..LuaObject::new()
+        };
+        for (name, value) in fields {
+            self.stats.entries += 1;
+            object.insert(LuaValue::String(name.to_string()), value);
+        }
+        self.objects.insert(id, object);
+    }
+
+    fn parse_table<'lua>(
+        &mut self,
+        ctx: Context<'lua>,
+        table: Table<'lua>,
+        depth: usize,
+    ) -> String {
+        let id = table_id(ctx, &table);
+        if self.max_depth.is_some_and(|max| depth > max) {
+            elide::keep(ctx, &id, table);
+            return id;
+        }
+
+        if self.seen_objs.insert(id.clone()) {
+            self.stats.tables += 1;
+            self.stats.max_depth = self.stats.max_depth.max(depth);
+            let mut object = LuaObject::new();
+            let page = self.page.take();
+            let hidden = history::hidden(ctx, &table);
+            for (k, v) in table
+                .pairs::<Value, Value>()
+                .filter_map(Result::ok)
+                .chain(hidden)
+            {
+                if let Some(page) = &page {
+                    if !page.matches(&k) {
+                        continue;
+                    }
+                    self.matching += 1;
+                    if !page.includes(self.matching - 1) {
+                        continue;
+                    }
+                }
+                for child in [&k, &v] {
+                    if let Value::Table(child) = child {
+                        let preview = preview::table_preview(child.clone());
+                        object.child_previews.insert(table_id(ctx, child), preview);
+                    }
+                }
+                self.stats.entries += 1;
+                object.insert(
+                    self.parse_value(ctx, k, depth),
+                    self.parse_value(ctx, v, depth),
+                );
+            }
+            self.objects.insert(id.clone(), object);
+        }
+
+        id
+    }
+}
+
+impl EvalResponse {
+    fn failure() -> Self {
+        Self {
+            success: false,
+            error: None,
+            error_kind: None,
+            error_value: None,
+            traceback: vec![],
+            objects: HashMap::new(),
+            value: LuaValue::Nil,
+            values: vec![],
+            limit_violation: None,
+            schema_errors: vec![],
+            graph_stats: GraphStats::default(),
+            explanation: None,
+            host_error: None,
+            assignments: vec![],
+            nil_access: None,
+            warnings: vec![],
+            output: vec![],
+            stderr: vec![],
+            request_id: None,
+        }
+    }
+
+    /// The response to an eval whose interpreter thread died.
+    fn interpreter_died() -> Self {
+        Self {
+            error: Some(INTERPRETER_DIED.to_string()),
+            error_kind: Some(ErrorKind::Runtime),
+            ..Self::failure()
+        }
+    }
+
+    /// The response to an eval that returned a `type_name` value, which
+    /// responses cannot hold.
+    fn unsupported(type_name: &str) -> Self {
+        Self {
+            error: Some(format!("cannot return a {} value", type_name)),
+            error_kind: Some(ErrorKind::Runtime),
+            ..Self::failure()
+        }
+    }
+
+    /// The response to an input the session's hardening refused.
+    fn rejected(error: InputError) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::failure()
+        }
+    }
+
+    fn from_result<'l>(
+        ctx: Context<'l>,
+        eval_result: Result<Vec<Value<'l>>, Error>,
+        max_depth: Option<usize>,
+    ) -> Self {
+        match eval_result {
+            Err(e) => Self {
+                error: Some(error_message(&e)),
+                error_kind: Some(ErrorKind::of(&e)),
+                traceback: traceback::frames(&e),
+                ..Self::failure()
+            },
+            Ok(values) => Self::from_values(ctx, values, max_depth),
+        }
+    }
+
+    fn from_value<'l>(ctx: Context<'l>, value: Value<'l>, max_depth: Option<usize>) -> Self {
+        Self::from_values(ctx, vec![value], max_depth)
+    }
+
+    fn from_values<'l>(ctx: Context<'l>, values: Vec<Value<'l>>, max_depth: Option<usize>) -> Self {
+        let mut graph = GraphBuilder {
+            max_depth,
+            ..Default::default()
+        };
+        let values = values
+            .into_iter()
+            .map(|value| graph.parse_value(ctx, value, 0))
+            .collect();
+        if let Some(type_name) = graph.unsupported {
+            return Self::unsupported(type_name);
+        }
+        Self::from_parsed(values, graph)
+    }
+
+    /// Serializes the entries of `table` that `options` selects.
+    fn page<'l>(
+        ctx: Context<'l>,
+        table: Table<'l>,
+        options: ExpandOptions,
+        max_depth: Option<usize>,
+    ) -> Page {
+        let mut graph = GraphBuilder {
+            max_depth,
+            page: Some(options),
+            ..Default::default()
+        };
+        let value = graph.parse_value(ctx, Value::Table(table), 0);
+        let total = graph.matching;
+        if let Some(type_name) = graph.unsupported {
+            return Page {
+                response: Self::unsupported(type_name),
+                total,
+            };
+        }
+        Page {
+            response: Self::from_parsed(vec![value], graph),
+            total,
+        }
+    }
+
+    fn from_parsed(values: Vec<LuaValue>, mut graph: GraphBuilder) -> Self {
+        for (id, object) in &mut graph.objects {
+            object.references = graph.references.get(id).copied().unwrap_or(0);
+        }
+        Self {
+            success: true,
+            error: None,
+            error_kind: None,
+            error_value: None,
+            traceback: vec![],
+            objects: graph.objects,
+            value: values.first().cloned().unwrap_or(LuaValue::Nil),
+            values,
+            limit_violation: None,
+            schema_errors: vec![],
+            graph_stats: graph.stats,
+            explanation: None,
+            host_error: None,
+            assignments: vec![],
+            nil_access: None,
+            warnings: vec![],
+            output: vec![],
+            stderr: vec![],
+            request_id: None,
+        }
+    }
+}
+
+/// Everything a session is built from; cloned into each interpreter it
+/// starts, including replacements after a crash.
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfig {
+    pub limits: Limits,
+    /// Renderings for domain objects, see `SessionBuilder::register_formatter`.
+    pub formatters: Vec<Formatter>,
+    /// Startup state every (re)started Lua state is initialized from.
+    pub image: Option<Arc<SessionImage>>,
+    /// Input and serialization bounds for untrusted input.
+    pub hardening: Option<Hardening>,
+    /// What happens when the interpreter thread dies.
+    pub recovery: Recovery,
+    /// Threads to run the interpreter on instead of a dedicated one.
+    pub pool: Option<Arc<WorkerPool>>,
+    /// Attach an `Explanation` to every eval response.
+    pub explain: bool,
+    /// Used for evals that do not choose their own `EvalOptions::output_level`.
+    pub output_level: OutputLevel,
+    /// Reuse the results of side-effect-free evals, see `cache::is_pure`.
+    pub cache: bool,
+    /// Remember where pins were created, see `Session::leaks`.
+    pub leak_diagnostics: bool,
+    /// Rust functions defined as globals of every (re)started Lua state.
+    pub host_functions: Vec<HostFunction>,
+    /// Rust types whose values Lua code can create and use.
+    pub userdata_types: Vec<UserDataType>,
+    /// Report the values of assigned names, see `EvalResponse::assignments`.
+    pub echo_assignments: bool,
+    /// Receives the reports of Lua's `progress` function.
+    pub progress: Option<ProgressHandler>,
+    /// Run the interpreter on the caller's thread, see `Session::pump`.
+    /// Takes precedence over `pool`.
+    pub cooperative: bool,
+    /// What `os.time` and `os.date` take as now instead of the system clock.
+    pub clock: Option<Clock>,
+    /// Seed `math.random` with this whenever the Lua state (re)starts.
+    pub rng_seed: Option<u64>,
+    /// Attribute memory growth to evals and functions, see
+    /// `Session::memory_profile`.
+    pub memory_profile: bool,
+    /// Collect garbage in the background once the session is idle.
+    pub idle_gc: Option<IdleGc>,
+    /// Give the session a scratch directory, see `workspace`.
+    pub workspace: bool,
+    /// Paths whose changes are recorded, see `Session::history`.
+    pub history: Vec<String>,
+    /// Count the objects with finalizers that are collected, see
+    /// `Session::gc_report`.
+    pub gc_diagnostics: bool,
+    /// Count the errors `pcall` and `xpcall` catch, see
+    /// `Session::caught_errors`.
+    pub caught_errors: bool,
+    /// Install shims for scripts written against Lua 5.1, see `compat51`.
+    pub compat51: bool,
+    /// Collect what evals print into `EvalResponse::output` and
+    /// `EvalResponse::stderr`, see `capture`.
+    pub capture_output: bool,
+    /// Where reports of interpreter crashes are written, see `crash`.
+    pub crash_reports: Option<PathBuf>,
+    /// Define the `repl` library for Lua code, see `scripting`.
+    pub repl_api: bool,
+    /// Sessions `repl.eval_in` can run code in, by name.
+    pub peers: Vec<(String, SessionHandle)>,
+    /// Cancels the running eval when set, see `cancel`. Sessions without
+    /// one get a token of their own.
+    pub cancel: Option<CancelToken>,
+    /// Stops scripts at breakpoints and steps through them, see `debugger`.
+    pub debugger: Option<Debugger>,
+}
+
+/// Per-eval settings; `Default` matches `Session::eval`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvalOptions {
+    /// Only accept a single expression, evaluated against a read-only view
+    /// of the globals.
+    pub expression_only: bool,
+    /// Expected shape of the result, reported in `EvalResponse::schema_errors`.
+    pub schema: Option<Schema>,
+    /// Overrides `SessionConfig::output_level` for this eval.
+    pub output_level: Option<OutputLevel>,
+    /// Runs the chunk against this table instead of the globals. Such evals
+    /// are neither cached, echoed nor replayed after a crash.
+    pub environment: Option<Environment>,
+    /// If the chunk returns an iterator, a function or a coroutine, keep it
+    /// for `Session::next_item` to produce at most this many items, see
+    /// `iterate`.
+    pub iterate: Option<usize>,
+    /// Names the chunk in error messages, tracebacks and `debugger` frames,
+    /// as `load` does, e.g. `@main.lua` for a file.
+    pub chunk_name: Option<String>,
+    /// The line of its file the chunk starts at, e.g. 42 for a selection an
+    /// editor sent from line 42, so that Lua counts its lines from there.
+    /// Lines past `MAX_FIRST_LINE` count from that.
+    pub first_line: Option<u32>,
+}
+
+impl EvalOptions {
+    /// Places the chunk in the user's buffer: it is named after `file`, as
+    /// `foo.lua`, or else `name` as it is, and starts at `line`. Errors and
+    /// tracebacks then read `foo.lua:42:` instead of `[string "..."]:1:`.
+    pub fn located(self, file: Option<String>, name: Option<String>, line: Option<u32>) -> Self {
+        let chunk_name = match (file, name) {
+            (Some(file), _) => Some(format!("@{}", file)),
+            (None, Some(name)) => Some(format!("={}", name)),
+            (None, None) => self.chunk_name,
+        };
+        Self {
+            chunk_name,
+            first_line: line.or(self.first_line),
+            ..self
+        }
+    }
+}
+
+/// An eval named by the client, so that its response can be told apart from
+/// others when several are in flight.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvalRequest {
+    /// Chosen by the client, and echoed in `EvalResponse::request_id`.
+    pub id: u64,
+    pub code: String,
+    pub options: EvalOptions,
+}
+
+impl EvalRequest {
+    pub fn new(id: u64, code: String) -> Self {
+        Self {
+            id,
+            code,
+            options: EvalOptions::default(),
+        }
+    }
+}
+
+enum Command {
+    Eval(String, EvalOptions, oneshot::Sender<EvalResponse>),
+    Reset(oneshot::Sender<()>),
+    Ping(oneshot::Sender<()>),
+    SetLimits(Limits, oneshot::Sender<()>),
+    Search(String, SearchOptions, oneshot::Sender<Vec<SearchMatch>>),
+    Complete(String, oneshot::Sender<Vec<String>>),
+    References(
+        String,
+        SearchOptions,
+        oneshot::Sender<Result<Vec<String>, PathError>>,
+    ),
+    GetPath(String, oneshot::Sender<Result<EvalResponse, PathError>>),
+    Workspace(oneshot::Sender<Option<PathBuf>>),
+    History(String, oneshot::Sender<Option<Vec<HistoryEntry>>>),
+    ReadPaths(
+        Vec<String>,
+        oneshot::Sender<Vec<Result<EvalResponse, PathError>>>,
+    ),
+    SetPath(String, LuaValue, oneshot::Sender<Result<(), PathError>>),
+    Pin(String, oneshot::Sender<Result<Pin, PathError>>),
+    Pinned(Pin, oneshot::Sender<Option<EvalResponse>>),
+    Unpin(Pin, oneshot::Sender<bool>),
+    Resources(oneshot::Sender<Vec<String>>),
+    Expand(String, ExpandOptions, oneshot::Sender<Option<Page>>),
+    MemoryProfile(oneshot::Sender<Option<MemoryProfile>>),
+    GcReport(oneshot::Sender<Option<GcReport>>),
+    CaughtErrors(oneshot::Sender<Option<CaughtErrors>>),
+    /// Produces the next item of the iterator an eval returned.
+    NextItem(oneshot::Sender<Option<EvalResponse>>),
+    /// Runs the `events.on` handlers of an event.
+    Emit(String, LuaValue),
+    /// Evaluates the watches whose dependencies changed, see `watch`.
+    RefreshWatches(
+        Vec<(Watch, String)>,
+        oneshot::Sender<Vec<(Watch, EvalResponse)>>,
+    ),
+    /// The result of the async host function call a parked eval waits for.
+    Resume(u64, Result<LuaValue, HostError>),
+    EvalTemplate {
+        source: String,
+        prologue: String,
+        params: Vec<LuaValue>,
+        reply: oneshot::Sender<EvalResponse>,
+    },
+    #[cfg(test)]
+    Crash,
+}
+
+/// A Lua state running on an interpreter thread of its own, or on a
+/// `WorkerPool`. Evals run one at a time, in the order they are made;
+/// dropping the session stops the interpreter.
+#[derive(Debug)]
+pub struct Session {
+    command_sender: UnboundedSender<Command>,
+    driver: Driver,
+    formatters: Vec<Formatter>,
+    hardening: Option<Hardening>,
+    heartbeat: Arc<AtomicU64>,
+    /// Kept to start a new interpreter after a crash.
+    config: SessionConfig,
+    journal: Vec<JournalEntry>,
+    recoveries: u64,
+    cache: Option<ResultCache>,
+    /// How much work `SessionHandle`s queued, and how much of it the cache
+    /// was last invalidated for.
+    mutations: Arc<AtomicU64>,
+    seen_mutations: u64,
+    pin_sites: Option<PinSites>,
+    /// Async host function calls of the running eval.
+    calls: UnboundedReceiver<AsyncCall>,
+    watches: WatchList,
+    subscriptions: Subscriptions,
+}
+
+/// Configures a `Session`, starting from `Session::builder`.
+#[derive(Debug, Default)]
+pub struct SessionBuilder {
+    config: SessionConfig,
+}
+
+impl SessionBuilder {
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    pub fn image(mut self, image: Arc<SessionImage>) -> Self {
+        self.config.image = Some(image);
+        self
+    }
+
+    /// Rejects oversized or malformed sources and caps how deeply results
+    /// are serialized.
+    pub fn hardening(mut self, hardening: Hardening) -> Self {
+        self.config.hardening = Some(hardening);
+        self
+    }
+
+    pub fn recovery(mut self, recovery: Recovery) -> Self {
+        self.config.recovery = recovery;
+        self
+    }
+
+    pub fn explain(mut self, explain: bool) -> Self {
+        self.config.explain = explain;
+        self
+    }
+
+    /// Serves repeated side-effect-free evals from a cache that any other
+    /// eval, assignment through `set_path`, iterator item, work queued
+    /// through a `SessionHandle` or reset invalidates.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.config.cache = cache;
+        self
+    }
+
+    pub fn leak_diagnostics(mut self, leak_diagnostics: bool) -> Self {
+        self.config.leak_diagnostics = leak_diagnostics;
+        self
+    }
+
+    /// Profiles memory at the cost of a full garbage collection before and
+    /// after every eval.
+    pub fn memory_profile(mut self, memory_profile: bool) -> Self {
+        self.config.memory_profile = memory_profile;
+        self
+    }
+
+    /// Tracks tables with `__gc`, files and bridged userdata until they are
+    /// collected, see `finalizers`.
+    pub fn gc_diagnostics(mut self, gc_diagnostics: bool) -> Self {
+        self.config.gc_diagnostics = gc_diagnostics;
+        self
+    }
+
+    /// Wraps `pcall` and `xpcall` to count what they catch, see `caught`.
+    pub fn caught_errors(mut self, caught_errors: bool) -> Self {
+        self.config.caught_errors = caught_errors;
+        self
+    }
+
+    /// Defines `unpack`, `loadstring`, `setfenv` and the other globals Lua
+    /// 5.1 scripts expect, see `compat51`.
+    pub fn compat51(mut self, compat51: bool) -> Self {
+        self.config.compat51 = compat51;
+        self
+    }
+
+    /// Collects what `print` and `io.write` write into the responses instead
+    /// of the process's stdout, see `capture`.
+    pub fn capture_output(mut self, capture_output: bool) -> Self {
+        self.config.capture_output = capture_output;
+        self
+    }
+
+    /// Writes a report to `dir` whenever the interpreter panics, see `crash`.
+    pub fn crash_reports(mut self, dir: impl AsRef<Path>) -> Self {
+        self.config.crash_reports = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Lets Lua code drive the REPL through the `repl` library, see
+    /// `scripting`. Only for code trusted as much as the embedder's own.
+    pub fn trust_repl_api(mut self, trusted: bool) -> Self {
+        self.config.repl_api = trusted;
+        self
+    }
+
+    /// Makes the session behind `handle` reachable as `name` from
+    /// `repl.eval_in`.
+    pub fn peer(mut self, name: &str, handle: SessionHandle) -> Self {
+        self.config.peers.push((name.to_string(), handle));
+        self
+    }
+
+    /// Cancels the session's running eval whenever `token` is, see `cancel`.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.config.cancel = Some(token);
+        self
+    }
+
+    /// Lets `debugger` stop the session's scripts, see `debugger`. The hook
+    /// then runs on every line too, which the instruction budget cannot
+    /// tell apart from instructions, so instruction limits are not enforced.
+    pub fn debugger(mut self, debugger: Debugger) -> Self {
+        self.config.debugger = Some(debugger);
+        self
+    }
+
+    pub fn idle_gc(mut self, idle_gc: IdleGc) -> Self {
+        self.config.idle_gc = Some(idle_gc);
+        self
+    }
+
+    /// Gives the session a scratch directory, see `workspace`.
+    pub fn workspace(mut self, workspace: bool) -> Self {
+        self.config.workspace = workspace;
+        self
+    }
+
+    /// Records every value stored at `path`, such as `world.score`, with
+    /// the eval that stored it; see `history`. The key is moved into a
+    /// shadow table behind a metatable on its parent, which `getmetatable`
+    /// returns, as long as the parent had none; the globals `next` and
+    /// `rawget` are replaced to still find it in the parent.
+    pub fn record_history(mut self, path: &str) -> Self {
+        self.config.history.push(path.to_string());
+        self
+    }
+
+    pub fn output_level(mut self, output_level: OutputLevel) -> Self {
+        self.config.output_level = output_level;
+        self
+    }
+
+    pub fn echo_assignments(mut self, echo_assignments: bool) -> Self {
+        self.config.echo_assignments = echo_assignments;
+        self
+    }
+
+    pub fn on_progress(mut self, handler: ProgressHandler) -> Self {
+        self.config.progress = Some(handler);
+        self
+    }
+
+    pub fn cooperative(mut self, cooperative: bool) -> Self {
+        self.config.cooperative = cooperative;
+        self
+    }
+
+    /// Makes `clock` the source of `os.time()` and the default time of
+    /// `os.date`, for tests that need a fixed or controlled "now".
+    pub fn with_clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.config.clock = Some(Clock::new(clock));
+        self
+    }
+
+    /// Makes `math.random` produce the same sequence in every session built
+    /// with `seed`, and again after each reset.
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.config.rng_seed = Some(seed);
+        self
+    }
+
+    pub fn pool(mut self, pool: Arc<WorkerPool>) -> Self {
+        self.config.pool = Some(pool);
+        self
+    }
+
+    /// Defines a global function `name` that runs `function` on the host.
+    /// Errors it returns fail the eval with `EvalResponse::host_error` set,
+    /// unless Lua code catches them.
+    pub fn register_function(
+        mut self,
+        name: &str,
+        function: impl Fn(Vec<LuaValue>) -> HostResult + Send + Sync + 'static,
+    ) -> Self {
+        self.config
+            .host_functions
+            .push(HostFunction::new(name, function));
+        self
+    }
+
+    /// Exposes a Rust type to Lua, see `bridge::Reflect`.
+    pub fn register_userdata(mut self, userdata_type: UserDataType) -> Self {
+        self.config.userdata_types.push(userdata_type);
+        self
+    }
+
+    /// Like `register_function`, but `function` returns a future that the
+    /// session awaits while the interpreter serves other work.
+    pub fn register_async_function<F>(
+        mut self,
+        name: &str,
+        function: impl Fn(Vec<LuaValue>) -> F + Send + Sync + 'static,
+    ) -> Self
+    where
+        F: Future<Output = HostResult> + Send + 'static,
+    {
+        self.config
+            .host_functions
+            .push(HostFunction::new_async(name, function));
+        self
+    }
+
+    /// Renders objects accepted by `matcher` with `format`, exposed as
+    /// `LuaObject::display`. Formatters are tried in registration order.
+    pub fn register_formatter(
+        mut self,
+        matcher: TypeMatcher,
+        format: impl Fn(&LuaObject) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.config.formatters.push(Formatter::new(matcher, format));
+        self
+    }
+
+    /// The configuration built so far, for `Session::with_config` or a
+    /// `SessionManager` that starts sessions of its own.
+    pub fn config(self) -> SessionConfig {
+        self.config
+    }
+
+    /// Builds a session evaluating on the caller's thread, see `LocalSession`.
+    pub fn build_local(self) -> LocalSession {
+        LocalSession::with_config(self.config)
+    }
+
+    pub fn build(self) -> Session {
+        Session::with_config(self.config)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    /// A session with the default configuration: no limits, formatters or
+    /// host functions.
+    pub fn new() -> Self {
+        Self::with_config(SessionConfig::default())
+    }
+
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    pub fn with_config(mut config: SessionConfig) -> Self {
+        config.cancel.get_or_insert_with(CancelToken::new);
+        let heartbeat = Arc::new(AtomicU64::new(0));
+        let (command_sender, driver, calls) = spawn_interpreter(config.clone(), heartbeat.clone());
+        Self {
+            command_sender,
+            driver,
+            calls,
+            formatters: config.formatters.clone(),
+            hardening: config.hardening,
+            heartbeat,
+            journal: vec![],
+            recoveries: 0,
+            cache: config.cache.then(ResultCache::default),
+            mutations: Arc::default(),
+            seen_mutations: 0,
+            pin_sites: config.leak_diagnostics.then(PinSites::default),
+            watches: WatchList::default(),
+            subscriptions: Subscriptions::default(),
+            config,
+        }
+    }
+
+    /// Replaces the interpreter thread, abandoning the current one.
+    fn respawn(&mut self) {
+        let (command_sender, driver, calls) =
+            spawn_interpreter(self.config.clone(), self.heartbeat.clone());
+        self.command_sender = command_sender;
+        self.driver = driver;
+        self.calls = calls;
+        self.forget_state();
+    }
+
+    /// Drops what depended on the Lua state, after it was replaced.
+    fn forget_state(&mut self) {
+        self.invalidate_cache();
+        if let Some(sites) = &mut self.pin_sites {
+            sites.clear();
+        }
+    }
+
+    fn invalidate_cache(&mut self) {
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate();
+        }
+    }
+
+    /// Forgets the cached results if a `SessionHandle` queued work since
+    /// this was last called, which may have changed what they depend on.
+    fn invalidate_cache_if_mutated(&mut self) {
+        let mutations = self.mutations.load(Ordering::Relaxed);
+        if mutations != self.seen_mutations {
+            self.seen_mutations = mutations;
+            self.invalidate_cache();
+        }
+    }
+
+    /// Evals answered from the result cache instead of the interpreter.
+    pub fn cache_hits(&self) -> u64 {
+        self.cache.as_ref().map_or(0, |cache| cache.hits)
+    }
+
+    /// Queues `command`, running it right away in cooperative mode.
+    fn send(&mut self, command: Command) {
+        let _ = self.command_sender.send(command);
+        if let Driver::Cooperative(cooperative) = &mut self.driver {
+            cooperative.run_queued();
+        }
+    }
+
+    /// Runs work queued through `SessionHandle`s for up to `budget`, in
+    /// cooperative mode, and returns how many commands ran. Does nothing for
+    /// sessions with their own interpreter thread.
+    pub fn pump(&mut self, budget: Duration) -> usize {
+        match &mut self.driver {
+            Driver::Cooperative(cooperative) => cooperative.pump(budget),
+            Driver::Thread(_) => 0,
+        }
+    }
+
+    /// Sends the command built by `command` and waits for its reply. Returns
+    /// `None`, after recovering as configured, if the interpreter has died.
+    async fn request<T>(
+        &mut self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> Option<T> {
+        let (reply, response) = oneshot::channel();
+        self.send(command(reply));
+        match response.await {
+            Ok(response) => Some(response),
+            Err(_) => {
+                self.recover().await;
+                None
+            }
+        }
+    }
+
+    /// Like `request` for an eval, completing the async host function calls
+    /// it makes while waiting for its response.
+    async fn request_eval(&mut self, expr: String, options: EvalOptions) -> Option<EvalResponse> {
+        match self.run_eval(expr, options).await {
+            Some(response) => Some(response),
+            None => {
+                self.recover().await;
+                None
+            }
+        }
+    }
+
+    /// Runs an eval without recovering if the interpreter dies.
+    async fn run_eval(&mut self, expr: String, options: EvalOptions) -> Option<EvalResponse> {
+        // Calls of evals whose callers stopped waiting, e.g. after a timeout.
+        while self.calls.try_recv().is_ok() {}
+        let (reply, mut response) = oneshot::channel();
+        self.send(Command::Eval(expr, options, reply));
+        loop {
+            let call = tokio::select! {
+                response = &mut response => return response.ok(),
+                Some(call) = self.calls.recv() => call,
+            };
+            let cancel = self.cancel_token();
+            let complete = async {
+                match scripting::handles(&call) && self.config.repl_api {
+                    true => scripting::complete(&call, &self.config.peers, &mut self.watches).await,
+                    false => host::complete(&self.config.host_functions, &call).await,
+                }
+            };
+            let result = tokio::select! {
+                result = complete => result,
+                _ = cancel.cancelled() => Err(host::failure(&call.function, &Cancelled)),
+            };
+            self.send(Command::Resume(call.id, result));
+        }
+    }
+
+    /// Instruction hook ticks run so far, each `limits::INSTRUCTION_GRANULARITY`
+    /// VM instructions; a measure of the CPU time spent in Lua code.
+    pub fn cpu_ticks(&self) -> u64 {
+        self.heartbeat.load(Ordering::Relaxed)
+    }
+
+    /// How many times the interpreter died and was recovered.
+    pub fn recoveries(&self) -> u64 {
+        self.recoveries
+    }
+
+    /// Runs the chunk `expr` in the session's global environment.
+    pub async fn eval(&mut self, expr: String) -> EvalResponse {
+        self.eval_with(expr, EvalOptions::default()).await
+    }
+
+    /// Runs `request` like `eval_with`, answering with its id.
+    pub async fn eval_request(&mut self, request: EvalRequest) -> EvalResponse {
+        let response = self.eval_with(request.code, request.options).await;
+        EvalResponse {
+            request_id: Some(request.id),
+            ..response
+        }
+    }
+
+    /// Like `eval`, with per-eval overrides from `options`.
+    pub async fn eval_with(&mut self, expr: String, mut options: EvalOptions) -> EvalResponse {
+        if let Err(e) = self.check_input(&expr) {
+            return EvalResponse::rejected(e);
+        }
+        self.invalidate_cache_if_mutated();
+        let output_level = *options.output_level.get_or_insert(self.config.output_level);
+        let schema = options.schema.clone();
+        let expression_only = options.expression_only;
+        let isolated = options.environment.is_some();
+        let journaled = (!expression_only && !isolated).then(|| expr.clone());
+        let pure =
+            self.cache.is_some() && !isolated && options.iterate.is_none() && cache::is_pure(&expr);
+        let cached = self
+            .cache
+            .as_mut()
+            .filter(|_| pure)
+            .and_then(|cache| cache.get(expression_only, &expr));
+        let mut response = match cached {
+            Some(response) => response,
+            None => {
+                let source = pure.then(|| expr.clone());
+                let response = match self.request_eval(expr, options).await {
+                    Some(response) => response,
+                    None => return EvalResponse::interpreter_died(),
+                };
+                match (&mut self.cache, source) {
+                    (Some(cache), Some(source)) if response.success => {
+                        cache.insert(expression_only, source, response.clone())
+                    }
+                    (Some(cache), None) => cache.invalidate(),
+                    _ => {}
+                }
+                response
+            }
+        };
+        if let Some(expr) = journaled.filter(|_| response.success) {
+            self.journal(|| JournalEntry::Eval(expr));
+        }
+        if let Some(schema) = schema.filter(|_| response.success) {
+            response.schema_errors = schema.validate(&response);
+        }
+        formatter::apply(&self.formatters, &mut response);
+        output::trim(&mut response, output_level);
+        self.notify_paths().await;
+        response
+    }
+
+    /// Why `source` is refused as an eval input, if it is.
+    fn check_input(&self, source: &str) -> Result<(), InputError> {
+        match &self.hardening {
+            Some(hardening) => hardening.check_input(source.as_bytes()).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a handle for publishing events to this session from elsewhere.
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            commands: self.command_sender.clone(),
+            mutations: self.mutations.clone(),
+        }
+    }
+
+    /// Returns the token cancelling this session's running eval, see
+    /// `cancel`.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.config.cancel.clone().unwrap_or_default()
+    }
+
+    /// Returns a handle for checking on the interpreter while this session is
+    /// busy evaluating.
+    pub fn liveness(&self) -> Liveness {
+        Liveness {
+            commands: self.command_sender.clone(),
+            heartbeat: self.heartbeat.clone(),
+        }
+    }
+
+    /// Returns whether the interpreter is responsive, see `Liveness::ping`.
+    pub async fn ping(&self, timeout: Duration) -> bool {
+        self.liveness().ping(timeout).await
+    }
+
+    /// Applies `limits` to the running interpreter, starting with the next eval.
+    pub async fn set_limits(&mut self, limits: Limits) {
+        self.config.limits = limits;
+        self.request(|reply| Command::SetLimits(limits, reply))
+            .await;
+    }
+
+    /// Replaces the Lua state with a fresh one, discarding all globals.
+    pub async fn reset(&mut self) {
+        self.request(Command::Reset).await;
+        self.journal.clear();
+        self.forget_state();
+        self.notify_paths().await;
+    }
+
+    /// Finds keys and string values containing `pattern` in the global object graph.
+    pub async fn search(&mut self, pattern: String, opts: SearchOptions) -> Vec<SearchMatch> {
+        self.request(|reply| Command::Search(pattern, opts, reply))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// The ways `prefix` can go on, such as `string.format` for `string.fo`,
+    /// from the globals and the tables they reach, see `completion`.
+    pub async fn complete(&mut self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.to_string();
+        self.request(|reply| Command::Complete(prefix, reply))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// The paths from `_G` to the object `target`, an object id or a path,
+    /// e.g. `party.leader`; see `:refs`.
+    pub async fn references(
+        &mut self,
+        target: String,
+        opts: SearchOptions,
+    ) -> Result<Vec<String>, PathError> {
+        self.request(|reply| Command::References(target, opts, reply))
+            .await
+            .unwrap_or_else(|| Err(PathError::Lua(INTERPRETER_DIED.to_string())))
+    }
+
+    /// Returns the serialized value at `path`, e.g. `config.servers[3].host`.
+    pub async fn get_path(&mut self, path: String) -> Result<EvalResponse, PathError> {
+        let mut response = self
+            .request(|reply| Command::GetPath(path, reply))
+            .await
+            .unwrap_or_else(|| Err(PathError::Lua(INTERPRETER_DIED.to_string())))?;
+        formatter::apply(&self.formatters, &mut response);
+        Ok(response)
+    }
+
+    /// Stores `value` at `path`. Every table along the path must already exist.
+    pub async fn set_path(&mut self, path: String, value: LuaValue) -> Result<(), PathError> {
+        let journaled = (path.clone(), value.clone());
+        self.request(|reply| Command::SetPath(path, value, reply))
+            .await
+            .unwrap_or_else(|| Err(PathError::Lua(INTERPRETER_DIED.to_string())))?;
+        self.invalidate_cache();
+        self.journal(|| JournalEntry::SetPath(journaled.0, journaled.1));
+        self.notify_paths().await;
+        Ok(())
+    }
+
+    /// Keeps the value at `path` alive until `unpin` or a reset, even once
+    /// nothing in Lua refers to it anymore.
+    #[track_caller]
+    pub fn pin(&mut self, path: String) -> impl Future<Output = Result<Pin, PathError>> + '_ {
+        let site = std::panic::Location::caller();
+        async move {
+            let pin = self
+                .request(|reply| Command::Pin(path, reply))
+                .await
+                .unwrap_or_else(|| Err(PathError::Lua(INTERPRETER_DIED.to_string())))?;
+            if let Some(sites) = &mut self.pin_sites {
+                sites.created(pin, site);
+            }
+            Ok(pin)
+        }
+    }
+
+    /// Serializes a pinned value; `None` if it was unpinned or reset away.
+    pub async fn pinned(&mut self, pin: Pin) -> Option<EvalResponse> {
+        let mut response = self.request(|reply| Command::Pinned(pin, reply)).await??;
+        formatter::apply(&self.formatters, &mut response);
+        Some(response)
+    }
+
+    /// Releases `pin`, returning whether it was still held.
+    pub async fn unpin(&mut self, pin: Pin) -> bool {
+        if let Some(sites) = &mut self.pin_sites {
+            sites.released(pin);
+        }
+        self.request(|reply| Command::Unpin(pin, reply))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// The versions and features of the session, as the protocol's `hello`
+    /// reports them.
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo::new(&self.config)
+    }
+
+    /// Pins that are still held, counted by where they were created. Empty
+    /// unless the session was built with `SessionBuilder::leak_diagnostics`.
+    pub fn leaks(&self) -> Vec<PinLeak> {
+        self.pin_sites
+            .as_ref()
+            .map(PinSites::leaks)
+            .unwrap_or_default()
+    }
+
+    /// Evaluates `template`, binding `$1`, `$2`, ... to `params` as locals
+    /// rather than splicing them into the source.
+    pub async fn eval_template(
+        &mut self,
+        template: String,
+        params: Vec<LuaValue>,
+    ) -> Result<EvalResponse, TemplateError> {
+        if let Some(LuaValue::ObjectRef(id)) = params
+            .iter()
+            .find(|param| matches!(param, LuaValue::ObjectRef(_)))
+        {
+            return Err(TemplateError(format!("cannot bind object ref {}", id)));
+        }
+        if let Err(e) = self.check_input(&template) {
+            return Ok(EvalResponse::rejected(e));
+        }
+        let (source, prologue) = template::bind(&template, params.len())?;
+        self.invalidate_cache();
+        let journaled = JournalEntry::Template {
+            source: source.clone(),
+            prologue: prologue.clone(),
+            params: params.clone(),
+        };
+        let mut response = match self
+            .request(|reply| Command::EvalTemplate {
+                source,
+                prologue,
+                params,
+                reply,
+            })
+            .await
+        {
+            Some(response) => response,
+            None => return Ok(EvalResponse::interpreter_died()),
+        };
+        if response.success {
+            self.journal(|| journaled);
+        }
+        formatter::apply(&self.formatters, &mut response);
+        Ok(response)
+    }
+
+    /// Serializes a table that a recent response referenced by `id` but left
+    /// out for being nested too deeply, see `Hardening::max_depth`.
+    pub async fn expand(&mut self, id: String) -> Option<EvalResponse> {
+        let page = self.expand_with(id, ExpandOptions::default()).await?;
+        Some(page.response)
+    }
+
+    /// Like `expand`, serializing only the entries `options` selects.
+    pub async fn expand_with(&mut self, id: String, options: ExpandOptions) -> Option<Page> {
+        let mut page = self
+            .request(|reply| Command::Expand(id, options, reply))
+            .await??;
+        formatter::apply(&self.formatters, &mut page.response);
+        Some(page)
+    }
+
+    /// Names of the resources host functions opened and have not released,
+    /// oldest first, see `resources::track`.
+    pub async fn open_resources(&mut self) -> Vec<String> {
+        self.request(Command::Resources).await.unwrap_or_default()
+    }
+
+    /// How much memory the recent evals retained and which functions
+    /// allocated the most. `None` unless the session was built with
+    /// `SessionBuilder::memory_profile`.
+    pub async fn memory_profile(&mut self) -> Option<MemoryProfile> {
+        self.request(Command::MemoryProfile).await.flatten()
+    }
+
+    /// Which objects with finalizers were collected since the session started
+    /// or was last reset, if it was built with
+    /// `SessionBuilder::gc_diagnostics`.
+    pub async fn gc_report(&mut self) -> Option<GcReport> {
+        self.request(Command::GcReport).await.flatten()
+    }
+
+    /// How many errors scripts caught with `pcall` and `xpcall` since the
+    /// session started or was last reset, and which, if it was built with
+    /// `SessionBuilder::caught_errors`.
+    pub async fn caught_errors(&mut self) -> Option<CaughtErrors> {
+        self.request(Command::CaughtErrors).await.flatten()
+    }
+
+    /// The session's scratch directory, if it was built with
+    /// `SessionBuilder::workspace`. It is removed when the session closes;
+    /// an interpreter replaced after a crash starts with an empty one.
+    pub async fn workspace(&mut self) -> Option<Workspace> {
+        self.request(Command::Workspace)
+            .await
+            .flatten()
+            .map(Workspace::at)
+    }
+
+    /// The values `path` had, oldest first, if it was configured with
+    /// `SessionBuilder::record_history`. Only the most recent changes are
+    /// kept, and history recorded before a crash is lost.
+    pub async fn history(&mut self, path: &str) -> Option<Vec<HistoryEntry>> {
+        let path = path.to_string();
+        self.request(|reply| Command::History(path, reply))
+            .await
+            .flatten()
+    }
+
+    /// Adds a watch expression, evaluated by `refresh_watches`.
+    pub fn watch(&mut self, expr: String) -> Watch {
+        self.watches.add(expr)
+    }
+
+    pub fn unwatch(&mut self, watch: Watch) -> bool {
+        self.watches.remove(watch)
+    }
+
+    /// The next item of the iterator returned by the last eval with
+    /// `EvalOptions::iterate`, or `None` once it is exhausted, replaced or
+    /// there is none.
+    pub async fn next_item(&mut self) -> Option<EvalResponse> {
+        // Iterators run Lua code, which may have side effects.
+        self.invalidate_cache();
+        let mut response = self.request(Command::NextItem).await??;
+        formatter::apply(&self.formatters, &mut response);
+        output::trim(&mut response, self.config.output_level);
+        Some(response)
+    }
+
+    /// Evaluates the watches that never ran or read something that changed
+    /// since they last ran, in the order they were added, and returns their
+    /// responses.
+    pub async fn refresh_watches(&mut self) -> Vec<(Watch, EvalResponse)> {
+        let sources = self.watches.sources();
+        self.invalidate_cache();
+        let mut responses = self
+            .request(|reply| Command::RefreshWatches(sources, reply))
+            .await
+            .unwrap_or_default();
+        for (_, response) in &mut responses {
+            formatter::apply(&self.formatters, response);
+            output::trim(response, self.config.output_level);
+        }
+        responses
+    }
+
+    /// Subscribes to the value at `path`, which need not exist yet. Changes
+    /// go to the receiver from `path_changes`.
+    pub async fn watch_path(&mut self, path: String) -> Result<PathWatch, PathError> {
+        path::parse_path(&path)?;
+        let current = self.read_paths(vec![path.clone()]).await.pop();
+        let current = current.unwrap_or_else(|| Err(PathError::Lua(INTERPRETER_DIED.to_string())));
+        Ok(self.subscriptions.add(path, current))
+    }
+
+    pub fn unwatch_path(&mut self, watch: PathWatch) -> bool {
+        self.subscriptions.remove(watch)
+    }
+
+    /// Receives a `PathChange` whenever an eval, `set_path` or reset changed
+    /// a subscribed path. Only the latest receiver gets them.
+    pub fn path_changes(&mut self) -> UnboundedReceiver<PathChange> {
+        self.subscriptions.changes()
+    }
+
+    async fn read_paths(&mut self, paths: Vec<String>) -> Vec<Result<EvalResponse, PathError>> {
+        let mut values = self
+            .request(|reply| Command::ReadPaths(paths, reply))
+            .await
+            .unwrap_or_default();
+        for response in values.iter_mut().flatten() {
+            formatter::apply(&self.formatters, response);
+        }
+        values
+    }
+
+    async fn notify_paths(&mut self) {
+        if self.subscriptions.is_empty() {
+            return;
+        }
+        let values = self.read_paths(self.subscriptions.paths()).await;
+        self.subscriptions.update(values);
+    }
+
+    /// Shuts the interpreter down and waits for it to exit.
+    pub async fn close(self) {
+        drop(self.command_sender);
+        if let Driver::Thread(eval_thread) = self.driver {
+            let _ = eval_thread.await;
+        }
+    }
+}
+
+const INTERPRETER_DIED: &str = "the interpreter thread died";
+
+/// What runs a session's interpreter.
+#[derive(Debug)]
+enum Driver {
+    /// The task forwarding commands to a thread, dedicated or pooled.
+    Thread(JoinHandle<()>),
+    Cooperative(Box<Cooperative>),
+}
+
+fn spawn_interpreter(
+    config: SessionConfig,
+    heartbeat: Arc<AtomicU64>,
+) -> (
+    UnboundedSender<Command>,
+    Driver,
+    UnboundedReceiver<AsyncCall>,
+) {
+    let (call_sender, calls) = tokio::sync::mpsc::unbounded_channel();
+    if config.cooperative {
+        let (command_sender, cooperative) = Cooperative::new(config, heartbeat, call_sender);
+        return (
+            command_sender,
+            Driver::Cooperative(Box::new(cooperative)),
+            calls,
+        );
+    }
+    if let Some(pool) = config.pool.clone() {
+        let (command_sender, task) = pool.attach(config, heartbeat, call_sender);
+        return (command_sender, Driver::Thread(task), calls);
+    }
+    let (command_sender, mut command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let eval_thread = tokio::spawn(async move {
+        let (inner_sender, inner_receiver) = std::sync::mpsc::channel::<Command>();
+        let eval_thread =
+            thread::spawn(move || run_interpreter(config, heartbeat, call_sender, inner_receiver));
+
+        while let Some(command) = command_receiver.recv().await {
+            let _ = inner_sender.send(command);
+        }
+        drop(inner_sender);
+        let _ = tokio::task::spawn_blocking(move || eval_thread.join()).await;
+    });
+    (command_sender, Driver::Thread(eval_thread), calls)
+}
+
+/// Global that, when set to a function, is called with every non-nil result
+/// and returns what gets serialized in its place.
+const FORMAT_HOOK: &str = "__repl_format";
+
+fn post_process<'l>(ctx: Context<'l>, value: Value<'l>) -> Result<Value<'l>, Error> {
+    match (&value, ctx.globals().raw_get::<_, Value>(FORMAT_HOOK)?) {
+        (Value::Nil, _) => Ok(value),
+        (_, Value::Function(format)) => format.call(value),
+        _ => Ok(value),
+    }
+}
+
+/// The results of an eval that can only produce one value.
+fn single(value: Value) -> MultiValue {
+    MultiValue::from_vec(vec![value])
+}
+
+/// Builds the response to an eval of `source`; `before` holds the globals
+/// from before it ran, in explain mode.
+fn respond<'l>(
+    ctx: Context<'l>,
+    config: &SessionConfig,
+    guard: &LimitGuard,
+    source: &str,
+    result: Result<MultiValue<'l>, Error>,
+    before: Option<explain::GlobalsDigest>,
+) -> EvalResponse {
+    let result = result.and_then(|values| {
+        values
+            .into_iter()
+            .map(|value| post_process(ctx, value))
+            .collect::<Result<Vec<_>, _>>()
+    });
+    guard.finish(ctx, source);
+    let first = match &result {
+        Ok(values) => Ok(values.first().cloned().unwrap_or(Value::Nil)),
+        Err(e) => Err(e.clone()),
+    };
+    let limit_violation = guard.violation(&first);
+    let host_error = result.as_ref().err().and_then(host::host_error);
+    let nil_access = result
+        .as_ref()
+        .err()
+        .and_then(|e| nil_access::diagnose(ctx, source, e));
+    let explanation = before.map(|before| explain::explain(ctx, source, before, &first));
+    let warnings = warnings::take(ctx);
+    let (output, stderr) = capture::take(ctx);
+    let message = result.as_ref().err().map(error_message);
+    let error_value = error_value::take(ctx, message.as_deref());
+    let mut response = EvalResponse::from_result(ctx, result, max_depth(config));
+    let error_value = error_value.map(|value| {
+        let raised = EvalResponse::from_value(ctx, value, max_depth(config));
+        response.objects.extend(raised.objects);
+        raised.value
+    });
+    let error_kind = match limit_violation {
+        Some(LimitViolation::Instructions) => Some(ErrorKind::Timeout),
+        Some(LimitViolation::Memory) => Some(ErrorKind::Memory),
+        None => response.error_kind,
+    };
+    EvalResponse {
+        error_value,
+        limit_violation,
+        explanation,
+        host_error,
+        nil_access,
+        error_kind,
+        warnings,
+        output,
+        stderr,
+        ..response
+    }
+}
+
+/// The message Lua raised with, without the traceback rlua appends to it or
+/// its description of the callbacks the error passed through.
+fn error_message(error: &Error) -> String {
+    match error {
+        Error::RuntimeError(message) => match message.find("\nstack traceback:") {
+            Some(end) => message[..end].to_string(),
+            None => message.clone(),
+        },
+        Error::SyntaxError { message, .. } => message.clone(),
+        Error::CallbackError { cause, .. } => error_message(cause),
+        e => e.to_string(),
+    }
+}
+
+fn read_path(ctx: Context, config: &SessionConfig, path: &str) -> Result<EvalResponse, PathError> {
+    let value = path::parse_path(path).and_then(|segments| path::resolve(ctx, &segments))?;
+    Ok(EvalResponse::from_value(ctx, value, max_depth(config)))
+}
+
+fn max_depth(config: &SessionConfig) -> Option<usize> {
+    config.hardening.map(|hardening| hardening.max_depth)
+}
+
+/// The furthest `EvalOptions::first_line` moves a chunk, which costs a byte
+/// per line.
+pub const MAX_FIRST_LINE: u32 = 1 << 20;
+
+/// `source` moved down to start at `first_line`, by blank lines ahead of
+/// it, so that Lua numbers its lines as the file they came from does.
+fn positioned(source: &str, first_line: Option<u32>) -> Cow<'_, str> {
+    match first_line {
+        Some(line) if line > 1 => {
+            let blank = line.min(MAX_FIRST_LINE) as usize - 1;
+            Cow::Owned("\n".repeat(blank) + source)
+        }
+        _ => Cow::Borrowed(source),
+    }
+}
+
+fn with_output_level<'l, R>(
+    ctx: Context<'l>,
+    output_level: Option<OutputLevel>,
+    eval: impl FnOnce() -> R,
+) -> R {
+    match output_level {
+        Some(OutputLevel::Minimal) => output::silenced(ctx, eval),
+        _ => eval(),
+    }
+}
+
+/// Replies to an eval run as a coroutine once it returned or failed.
+fn finish<'l>(ctx: Context<'l>, config: &SessionConfig, guard: &LimitGuard, step: Step<'l>) {
+    if let Step::Done(result, eval) = step {
+        iterate::capture(ctx, eval.iterate, &result);
+        let mut response = respond(ctx, config, guard, &eval.source, result, eval.before);
+        if eval.echo {
+            echo::echo(ctx, &eval.source, &mut response, max_depth(config));
+        }
+        let _ = eval.reply.send(response);
+    }
+}
+
+/// The Lua state of one session and the commands that operate on it. Runs on
+/// a dedicated thread, or on a `WorkerPool` thread shared with other sessions.
+struct Interpreter {
+    config: SessionConfig,
+    heartbeat: Arc<AtomicU64>,
+    lua: Lua,
+    guard: LimitGuard,
+    pins: Pins,
+    async_calls: AsyncCalls,
+    dependencies: Dependencies,
+    /// Closed when the Lua state is replaced or the interpreter goes away.
+    resources: Arc<Mutex<Resources>>,
+    /// Kept across resets, in memory profiling mode.
+    profiler: Option<Arc<Mutex<Profiler>>>,
+    idleness: Idleness,
+    /// Kept across resets and removed with the interpreter; `None` if the
+    /// session has none or it could not be created.
+    workspace: Option<Workspace>,
+    /// Kept across resets.
+    history: History,
+    crash: Option<CrashRecorder>,
+}
+
+impl Interpreter {
+    fn new(
+        config: SessionConfig,
+        heartbeat: Arc<AtomicU64>,
+        calls: UnboundedSender<AsyncCall>,
+    ) -> Self {
+        let profiler = config.memory_profile.then(Arc::default);
+        let workspace = config
+            .workspace
+            .then(Workspace::create)
+            .and_then(Result::ok);
+        let (lua, guard) = Self::start(&config, &heartbeat, &profiler, &workspace);
+        let mut history = History::new(&config.history);
+        let _ = lua.context(|ctx| history.install(ctx));
+        let crash = config.crash_reports.as_deref().map(CrashRecorder::new);
+        Self {
+            config,
+            heartbeat,
+            lua,
+            guard,
+            pins: Pins::default(),
+            async_calls: AsyncCalls::new(calls),
+            dependencies: Dependencies::default(),
+            resources: Arc::default(),
+            profiler,
+            idleness: Idleness::default(),
+            workspace,
+            history,
+            crash,
+        }
+    }
+
+    fn start(
+        config: &SessionConfig,
+        heartbeat: &Arc<AtomicU64>,
+        profiler: &Option<Arc<Mutex<Profiler>>>,
+        workspace: &Option<Workspace>,
+    ) -> (Lua, LimitGuard) {
+        let lua = Lua::new();
+        let _ = function::install(&lua);
+        if profiler.is_some() {
+            let _ = lua.context(memprofile::install);
+        }
+        let _ = lua.context(coroutine::install);
+        let _ = lua.context(userdata::install);
+        let _ = lua.context(warnings::install);
+        let _ = lua.context(error_value::install);
+        if config.capture_output {
+            let _ = lua.context(capture::install);
+        }
+        if config.compat51 {
+            let _ = compat51::install(&lua);
+        }
+        let _ = lua.context(events::install);
+        if let Some(image) = &config.image {
+            // The image already ran successfully when it was built, so this
+            // only fails if the session's own environment is broken.
+            let _ = lua.context(|ctx| image.apply(ctx));
+        }
+        let _ = lua.context(|ctx| host::install(ctx, &config.host_functions));
+        let _ = lua.context(|ctx| bridge::install(ctx, &config.userdata_types));
+        if config.repl_api {
+            let _ = lua.context(scripting::install);
+        }
+        let _ = lua.context(|ctx| progress::install(ctx, config.progress.as_ref()));
+        let _ = lua.context(|ctx| clock::install(ctx, config.clock.as_ref(), config.rng_seed));
+        if let Some(workspace) = workspace {
+            let hardened = config.hardening.is_some();
+            let _ = lua.context(|ctx| workspace::install(ctx, workspace.root(), hardened));
+        }
+        if config.gc_diagnostics {
+            let _ = lua.context(finalizers::install);
+        }
+        if config.caught_errors {
+            let _ = lua.context(caught::install);
+        }
+        if config.debugger.is_some() {
+            let _ = debugger::install(&lua);
+        }
+        let guard = LimitGuard::install(
+            &lua,
+            &config.limits,
+            heartbeat.clone(),
+            config.cancel.clone().unwrap_or_default(),
+            profiler.clone(),
+            config.debugger.clone(),
+        );
+        (lua, guard)
+    }
+
+    fn close_resources(&self) {
+        if let Ok(mut resources) = self.resources.lock() {
+            resources.close_all();
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        if let Some(crash) = &mut self.crash {
+            crash.record(&command);
+        }
+        let resources = self.resources.clone();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            resources::with_current(&resources, || self.run(command))
+        }));
+        if let Err(panic) = result {
+            self.report_crash();
+            std::panic::resume_unwind(panic);
+        }
+        self.idleness.touch();
+    }
+
+    fn report_crash(&self) {
+        let Some(crash) = &self.crash else {
+            return;
+        };
+        let state = CrashState {
+            config: &self.config,
+            used_memory: self.lua.used_memory(),
+            cpu_ticks: self.heartbeat.load(Ordering::Relaxed),
+        };
+        match crash.write(state) {
+            Ok(path) => eprintln!("interpreter crashed, report written to {}", path.display()),
+            Err(e) => eprintln!("interpreter crashed, cannot write report: {}", e),
+        }
+    }
+
+    /// How long to wait for the next command before `collect_idle`, if at
+    /// all.
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.idleness.timeout(self.config.idle_gc.as_ref())
+    }
+
+    /// Runs one incremental garbage collection step if the session has been
+    /// idle long enough, returning whether it did.
+    fn collect_idle(&mut self) -> bool {
+        let idle_gc = self.config.idle_gc.as_ref();
+        if !self.idleness.due(idle_gc) {
+            return false;
+        }
+        let step_kbytes = idle_gc.map_or(0, |idle_gc| idle_gc.step_kbytes);
+        // An erroring finalizer must not make the step repeat forever.
+        if self.lua.gc_step_kbytes(step_kbytes).unwrap_or(true) {
+            self.idleness.collected();
+        }
+        true
+    }
+
+    fn run(&mut self, command: Command) {
+        if let Command::Reset(reply) = command {
+            self.close_resources();
+            let (lua, guard) = Self::start(
+                &self.config,
+                &self.heartbeat,
+                &self.profiler,
+                &self.workspace,
+            );
+            self.lua = lua;
+            self.guard = guard;
+            let history = &mut self.history;
+            let _ = self.lua.context(|ctx| history.install(ctx));
+            self.pins = Pins::default();
+            self.dependencies = Dependencies::default();
+            self.async_calls.abandon();
+            let _ = reply.send(());
+            return;
+        }
+        if let Command::Resources(reply) = command {
+            let names = self.resources.lock().map(|r| r.names()).unwrap_or_default();
+            let _ = reply.send(names);
+            return;
+        }
+        if let Command::Workspace(reply) = command {
+            let root = self.workspace.as_ref().map(|w| w.root().to_path_buf());
+            let _ = reply.send(root);
+            return;
+        }
+        if let Command::History(path, reply) = command {
+            let _ = reply.send(self.history.timeline(&path));
+            return;
+        }
+        if let Command::SetLimits(limits, reply) = command {
+            self.config.limits = limits;
+            self.guard = LimitGuard::install(
+                &self.lua,
+                &limits,
+                self.heartbeat.clone(),
+                self.config.cancel.clone().unwrap_or_default(),
+                self.profiler.clone(),
+                self.config.debugger.clone(),
+            );
+            let _ = reply.send(());
+            return;
+        }
+        if matches!(
+            command,
+            Command::Eval(..) | Command::EvalTemplate { .. } | Command::SetPath(..)
+        ) {
+            self.history.begin_eval();
+        }
+        if let Command::Eval(expr, options, _) = &command {
+            if self.config.repl_api && !options.expression_only {
+                let _ = self.lua.context(|ctx| scripting::record(ctx, expr));
+            }
+        }
+        let config = &self.config;
+        let guard = &self.guard;
+        let pins = &mut self.pins;
+        let async_calls = &mut self.async_calls;
+        let dependencies = &mut self.dependencies;
+        let profiler = &self.profiler;
+        self.lua.context(|ctx| match command {
+            Command::Eval(expr, options, reply)
+                if !options.expression_only
+                    && (config.repl_api
+                        || config.host_functions.iter().any(HostFunction::is_async)) =>
+            {
+                async_calls.abandon();
+                guard.rearm(ctx);
+                let eval = PendingEval {
+                    reply,
+                    before: config.explain.then(|| explain::digest(ctx)),
+                    echo: config.echo_assignments && options.environment.is_none(),
+                    output_level: options.output_level,
+                    iterate: options.iterate,
+                    source: expr.clone(),
+                };
+                let thread =
+                    environment::resolve_option(ctx, &options.environment).and_then(|env| {
+                        let name = options.chunk_name.as_deref();
+                        let load = |source: &str| {
+                            let source = positioned(source, options.first_line);
+                            environment::load(ctx, &source, &env, name)?.into_function()
+                        };
+                        let function =
+                            load(&error_value::expression(&expr)).or_else(|_| load(&expr))?;
+                        ctx.create_thread(function)
+                    });
+                match thread {
+                    Ok(thread) => {
+                        let resumed =
+                            with_output_level(ctx, eval.output_level, || thread.resume(()));
+                        let step = async_calls.advance(ctx, thread, resumed, eval);
+                        finish(ctx, config, guard, step);
+                    }
+                    Err(e) => finish(ctx, config, guard, Step::Done(Err(e), eval)),
+                }
+            }
+            Command::Resume(id, result) => {
+                if let Some((thread, result, eval)) = async_calls.resume(ctx, id, result) {
+                    let resumed =
+                        with_output_level(ctx, eval.output_level, || thread.resume(result));
+                    let step = async_calls.advance(ctx, thread, resumed, eval);
+                    finish(ctx, config, guard, step);
+                }
+            }
+            Command::Eval(expr, options, reply) => {
+                guard.rearm(ctx);
+                let before = config.explain.then(|| explain::digest(ctx));
+                let run = || {
+                    let env = environment::resolve_option(ctx, &options.environment)?;
+                    if options.expression_only {
+                        let globals = env.unwrap_or_else(|| ctx.globals());
+                        sandbox::eval_expression(ctx, &expr, globals).map(single)
+                    } else {
+                        let name = options.chunk_name.as_deref();
+                        let expression = error_value::expression(&expr);
+                        let load = |source: &str| {
+                            let source = positioned(source, options.first_line);
+                            environment::load(ctx, &source, &env, name)?.into_function()
+                        };
+                        match load(&expression) {
+                            Ok(function) => function.call::<_, MultiValue>(()),
+                            Err(_) => load(&expr)?.call::<_, MultiValue>(()),
+                        }
+                    }
+                };
+                let result = with_output_level(ctx, options.output_level, run);
+                iterate::capture(ctx, options.iterate, &result);
+                let mut response = respond(ctx, config, guard, &expr, result, before);
+                if config.echo_assignments
+                    && !options.expression_only
+                    && options.environment.is_none()
+                {
+                    echo::echo(ctx, &expr, &mut response, max_depth(config));
+                }
+                let _ = reply.send(response);
+            }
+            Command::EvalTemplate {
+                source,
+                prologue,
+                params,
+                reply,
+            } => {
+                guard.rearm(ctx);
+                let result = ctx
+                    .load(&format!("{}{}", prologue, error_value::expression(&source)))
+                    .into_function()
+                    .or_else(|_| ctx.load(&format!("{}{}", prologue, source)).into_function())
+                    .and_then(|function| {
+                        let args = params
+                            .iter()
+                            .map(|param| param.to_lua(ctx))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        function.call::<_, MultiValue>(MultiValue::from_vec(args))
+                    });
+                let _ = reply.send(respond(ctx, config, guard, &source, result, None));
+            }
+            Command::RefreshWatches(watches, reply) => {
+                dependencies.retain(ctx, &watches);
+                let mut responses = vec![];
+                for (watch, source) in watches {
+                    if dependencies.stale(ctx, watch) {
+                        guard.rearm(ctx);
+                        let result = dependencies.eval(ctx, watch, &source).map(single);
+                        let response = respond(ctx, config, guard, &source, result, None);
+                        responses.push((watch, response));
+                    }
+                }
+                let _ = reply.send(responses);
+            }
+            Command::Ping(reply) => {
+                let _ = reply.send(());
+            }
+            Command::Emit(name, event) => {
+                guard.rearm(ctx);
+                let _ = events::dispatch(ctx, &name, &event);
+            }
+            #[cfg(test)]
+            Command::Crash => panic!("interpreter crash requested by a test"),
+            Command::Reset(_)
+            | Command::SetLimits(..)
+            | Command::Resources(_)
+            | Command::Workspace(_)
+            | Command::History(..) => unreachable!(),
+            Command::Search(pattern, opts, reply) => {
+                let _ = reply.send(search::search_globals(ctx, &pattern, &opts));
+            }
+            Command::Complete(input, reply) => {
+                let _ = reply.send(completion::candidates(ctx, &input));
+            }
+            Command::References(target, opts, reply) => {
+                let _ = reply.send(search::references(ctx, &target, &opts));
+            }
+            Command::GetPath(path, reply) => {
+                let _ = reply.send(read_path(ctx, config, &path));
+            }
+            Command::ReadPaths(paths, reply) => {
+                let values = paths
+                    .iter()
+                    .map(|path| read_path(ctx, config, path))
+                    .collect();
+                let _ = reply.send(values);
+            }
+            Command::SetPath(path, value, reply) => {
+                let result = path::parse_path(&path).and_then(|segments| {
+                    let value = value.to_lua(ctx)?;
+                    path::assign(ctx, &segments, value)
+                });
+                let _ = reply.send(result);
+            }
+            Command::Pin(path, reply) => {
+                let _ = reply.send(pins.pin(ctx, &path));
+            }
+            Command::Pinned(pin, reply) => {
+                let response = pins
+                    .get(ctx, pin)
+                    .map(|value| EvalResponse::from_value(ctx, value, max_depth(config)));
+                let _ = reply.send(response);
+            }
+            Command::MemoryProfile(reply) => {
+                let profile = profiler.as_ref().map(|profiler| {
+                    let profiler = profiler.lock().unwrap_or_else(|e| e.into_inner());
+                    profiler.profile(ctx)
+                });
+                let _ = reply.send(profile);
+            }
+            Command::NextItem(reply) => {
+                guard.rearm(ctx);
+                let item = iterate::step(ctx)
+                    .map(|result| respond(ctx, config, guard, "iterator", result, None));
+                let _ = reply.send(item);
+            }
+            Command::GcReport(reply) => {
+                let _ = reply.send(finalizers::report(ctx));
+            }
+            Command::CaughtErrors(reply) => {
+                let _ = reply.send(caught::report(ctx));
+            }
+            Command::Expand(id, options, reply) => {
+                let page = elide::kept(ctx, &id).map(|table| {
+                    let page = EvalResponse::page(ctx, table.clone(), options, max_depth(config));
+                    // Keep paging from evicting the table it pages through.
+                    elide::keep(ctx, &id, table);
+                    page
+                });
+                let _ = reply.send(page);
+            }
+            Command::Unpin(pin, reply) => {
+                let _ = reply.send(pins.unpin(ctx, pin));
+            }
+        });
+        let history = &mut self.history;
+        self.lua.context(|ctx| history.sync(ctx));
+    }
+}
+
+fn run_interpreter(
+    config: SessionConfig,
+    heartbeat: Arc<AtomicU64>,
+    calls: UnboundedSender<AsyncCall>,
+    commands: std::sync::mpsc::Receiver<Command>,
+) {
+    idle_gc::run(Interpreter::new(config, heartbeat, calls), commands);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_simple() {
+        let mut session = Session::new();
+
+        assert_eq!(
+            session.eval("x = 1".to_string()).await,
+            EvalResponse {
+                success: true,
+                error: None,
+                error_kind: None,
+                error_value: None,
+                traceback: vec![],
+                objects: HashMap::new(),
+                value: LuaValue::Nil,
+                values: vec![],
+                limit_violation: None,
+                schema_errors: vec![],
+                graph_stats: GraphStats::default(),
+                explanation: None,
+                host_error: None,
+                assignments: vec![],
+                nil_access: None,
+                warnings: vec![],
+                output: vec![],
+                stderr: vec![],
+                request_id: None,
+            }
+        );
+
+        assert_eq!(
+            session.eval("return x".to_string()).await,
+            EvalResponse {
+                success: true,
+                error: None,
+                error_kind: None,
+                error_value: None,
+                traceback: vec![],
+                objects: HashMap::new(),
+                value: LuaValue::Integer(1),
+                values: vec![LuaValue::Integer(1)],
+                limit_violation: None,
+                schema_errors: vec![],
+                graph_stats: GraphStats::default(),
+                explanation: None,
+                host_error: None,
+                assignments: vec![],
+                nil_access: None,
+                warnings: vec![],
+                output: vec![],
+                stderr: vec![],
+                request_id: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multiple_values() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("local function f() return 1, nil, { 2 } end return f()".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Integer(1));
+        assert_eq!(resp.values.len(), 3);
+        assert_eq!(resp.values[1], LuaValue::Nil);
+        let LuaValue::ObjectRef(id) = &resp.values[2] else {
+            panic!("expected a table, got {:?}", resp.values[2]);
+        };
+        assert_eq!(resp.objects[id].members[0].1, LuaValue::Integer(2));
+        assert_eq!(
+            output::render(&resp, OutputLevel::Standard),
+            format!("1, nil, {}", id)
+        );
+
+        let resp = session.eval("return".to_string()).await;
+        assert!(resp.values.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_serde() {
+        let mut session = Session::builder().explain(true).build();
+        let resp = session
+            .eval("return 1, 2.5, 'x', { t = true }, print".to_string())
+            .await;
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(json["value"], serde_json::json!({ "integer": 1 }));
+        assert_eq!(json["values"][1], serde_json::json!({ "number": 2.5 }));
+        assert_eq!(json["explanation"]["result_type"], "integer");
+        let decoded: EvalResponse = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, resp);
+
+        let resp = session.eval("missing.field = 1".to_string()).await;
+        let json = serde_json::to_string(&resp).unwrap();
+        assert_eq!(serde_json::from_str::<EvalResponse>(&json).unwrap(), resp);
+        assert!(json.contains(r#""value":"nil""#), "{}", json);
+        assert!(json.contains(r#""error_kind":"runtime""#), "{}", json);
+
+        let resp = session.eval("function f(".to_string()).await;
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(
+            json["error_kind"],
+            serde_json::json!({ "syntax": { "incomplete": true } })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_syntax_error() {
+        let mut session = Session::new();
+
+        assert_eq!(
+            session.eval("syntax error".to_string()).await,
+            EvalResponse {
+                success: false,
+                error: Some("[string \"?\"]:1: syntax error near 'error'".to_string()),
+                error_kind: Some(ErrorKind::Syntax { incomplete: false }),
+                error_value: None,
+                traceback: vec![],
+                objects: HashMap::new(),
+                value: LuaValue::Nil,
+                values: vec![],
+                limit_violation: None,
+                schema_errors: vec![],
+                graph_stats: GraphStats::default(),
+                explanation: None,
+                host_error: None,
+                assignments: vec![],
+                nil_access: None,
+                warnings: vec![],
+                output: vec![],
+                stderr: vec![],
+                request_id: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runtime_error() {
+        let mut session = Session::new();
+        let resp = session.eval("error('boom')".to_string()).await;
+        assert_eq!(resp.error.as_deref(), Some("[string \"?\"]:1: boom"));
+        let resp = session.eval("return missing.field".to_string()).await;
+        assert_eq!(
+            resp.error.as_deref(),
+            Some("[string \"?\"]:1: attempt to index a nil value (global 'missing')")
+        );
+        assert_eq!(resp.error_kind, Some(ErrorKind::Runtime));
+        let resp = session.eval("return 1".to_string()).await;
+        assert_eq!(resp.error, None);
+        assert_eq!(resp.error_kind, None);
+    }
+
+    #[tokio::test]
+    async fn test_located_eval() {
+        let mut session = Session::new();
+        let located = |name: Option<&str>| {
+            EvalOptions::default().located(
+                name.map(|_| "foo.lua".to_string()),
+                name.map(str::to_string),
+                Some(42),
+            )
+        };
+        let resp = session
+            .eval_with(
+                "local function f()\n  error('boom')\nend\nf()".to_string(),
+                located(Some("buffer")),
+            )
+            .await;
+        assert_eq!(resp.error.as_deref(), Some("foo.lua:43: boom"));
+        let lines: Vec<_> = resp
+            .traceback
+            .iter()
+            .filter(|frame| frame.source != "[C]")
+            .map(|frame| (frame.source.as_str(), frame.line))
+            .collect();
+        assert_eq!(lines, [("foo.lua", Some(43)), ("foo.lua", Some(45))]);
+
+        let resp = session
+            .eval_with(
+                "return 1 +".to_string(),
+                EvalOptions::default().located(None, Some("scratch".to_string()), None),
+            )
+            .await;
+        assert!(
+            resp.error.as_deref().unwrap().starts_with("scratch:1:"),
+            "{:?}",
+            resp.error
+        );
+        let resp = session
+            .eval_with("return missing.field".to_string(), located(None))
+            .await;
+        assert!(resp.error.unwrap().starts_with("[string \"?\"]:42:"));
+    }
+
+    #[tokio::test]
+    async fn test_error_kinds() {
+        let mut session = Session::builder()
+            .limits(Limits {
+                instructions: Some(100_000),
+                ..Default::default()
+            })
+            .register_function("fail", |_| Err("unavailable".into()))
+            .build();
+        let kind = |resp: EvalResponse| resp.error_kind;
+        let incomplete = Some(ErrorKind::Syntax { incomplete: true });
+        let eval = |source: &str| source.to_string();
+        assert_eq!(kind(session.eval(eval("function f()")).await), incomplete);
+        assert_eq!(
+            kind(session.eval(eval("x = = 1")).await),
+            Some(ErrorKind::Syntax { incomplete: false })
+        );
+        assert_eq!(
+            kind(session.eval(eval("fail()")).await),
+            Some(ErrorKind::Callback)
+        );
+        assert_eq!(
+            kind(session.eval(eval("while true do end")).await),
+            Some(ErrorKind::Timeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_graph_stats() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("local t = { a = { b = {} }, 'xy' }; t.self = t; return t".to_string())
+            .await;
+        assert_eq!(
+            resp.graph_stats,
+            GraphStats {
+                tables: 3,
+                entries: 4,
+                max_depth: 3,
+                string_bytes: 8,
+            }
+        );
+        let LuaValue::ObjectRef(root) = &resp.value else {
+            panic!("{:?}", resp.value);
+        };
+        let mut references: Vec<_> = resp.objects.values().map(|o| o.references).collect();
+        references.sort();
+        assert_eq!(references, [1, 1, 2]);
+        assert_eq!(resp.objects[root].references, 2);
+    }
+
+    #[tokio::test]
+    async fn test_format_hook() {
+        let mut session = Session::new();
+        session
+            .eval(
+                "function __repl_format(v) return type(v) == 'table' and v.name or v end"
+                    .to_string(),
+            )
+            .await;
+
+        let resp = session.eval("return { name = 'point' }".to_string()).await;
+        assert_eq!(resp.value, LuaValue::String("point".to_string()));
+        assert!(resp.objects.is_empty());
+        let resp = session.eval("return 2".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(2));
+
+        session
+            .eval("function __repl_format() error('broken') end".to_string())
+            .await;
+        assert!(!session.eval("return 1".to_string()).await.success);
+    }
+
+    #[tokio::test]
+    async fn test_simple_table() {
+        let mut session = Session::new();
+        let resp = session.eval("x = {}; return x".to_string()).await;
+
+        assert!(resp.success);
+        let table_id = if let LuaValue::ObjectRef(id) = &resp.value {
+            id.to_string()
+        } else {
+            panic!("Expected an object ref got {:?}!", resp.value);
+        };
+
+        let resp = session.eval("x['a'] = 1 ; return x".to_string()).await;
+        assert!(resp.success);
+        assert_eq!(
+            resp.objects,
+            vec![(
+                table_id,
+                LuaObject {
+                    members: vec![(LuaValue::String("a".to_string()), LuaValue::Integer(1))],
+                    display: None,
+                    child_previews: HashMap::new(),
+                    type_name: None,
+                    references: 1,
+                }
+            )]
+            .into_iter()
+            .collect(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_light_userdata() {
+        let mut session = Session::builder()
+            .register_function("handle", |args| match args.as_slice() {
+                [LuaValue::LightUserData(address)] => Ok(LuaValue::LightUserData(address + 1)),
+                _ => Ok(LuaValue::LightUserData(0x1000)),
+            })
+            .build();
+        let resp = session.eval("return handle()".to_string()).await;
+        assert_eq!(resp.value, LuaValue::LightUserData(0x1000));
+        assert_eq!(resp.value.to_string(), "lightuserdata: 0x1000");
+        let resp = session
+            .eval("local h = handle() return { h, handle(h) }".to_string())
+            .await;
+        assert!(resp.success, "{:?}", resp.error);
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let values: Vec<_> = resp.objects[id].members.iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            values,
+            [
+                &LuaValue::LightUserData(0x1000),
+                &LuaValue::LightUserData(0x1001)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_integers() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("return { big = math.maxinteger, float = 2.0, [3] = 0.5 }".to_string())
+            .await;
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let object = &resp.objects[id];
+        assert_eq!(object.get("big"), Some(&LuaValue::Integer(i64::MAX)));
+        assert_eq!(object.get("float"), Some(&LuaValue::Number(2.0)));
+        assert!(object
+            .members
+            .iter()
+            .any(|(key, value)| *key == LuaValue::Integer(3) && *value == LuaValue::Number(0.5)));
+        assert_eq!(LuaValue::Number(2.0).to_string(), "2.0");
+        assert_eq!(LuaValue::Integer(2).to_string(), "2");
+
+        session
+            .set_path("n".to_string(), LuaValue::Integer(i64::MIN))
+            .await
+            .unwrap();
+        let resp = session
+            .eval("return math.type(n) .. ' ' .. tostring(n == math.mininteger)".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::String("integer true".to_string()));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::cancel::CancelToken;
+use crate::cancel::CANCELLED;
+use crate::debugger::Debugger;
+use crate::memprofile::Profiler;
+use rlua::Context;
+use rlua::Error;
+use rlua::HookTriggers;
+use rlua::Lua;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// How many VM instructions run between two checks of the instruction budget.
+pub const INSTRUCTION_GRANULARITY: u32 = 1000;
+
+/// Resource limits applied to every eval of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Maximum number of bytes the Lua state may have allocated at any time.
+    pub memory: Option<usize>,
+    /// Maximum number of VM instructions a single eval may execute.
+    pub instructions: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitViolation {
+    Memory,
+    Instructions,
+}
+
+/// Tracks limit usage for one Lua state. Must be re-armed before each eval.
+pub(crate) struct LimitGuard {
+    executed: Arc<AtomicU64>,
+    tripped: Arc<AtomicBool>,
+    cancel: CancelToken,
+    /// Sampled by the same hook, in memory profiling mode.
+    profiler: Option<Arc<Mutex<Profiler>>>,
+}
+
+impl LimitGuard {
+    /// Also bumps `heartbeat` while Lua code runs, see `Liveness`, stops
+    /// evals once `cancel` is set, feeds `profiler` its samples and lets
+    /// `debugger` stop scripts.
+    pub fn install(
+        lua: &Lua,
+        limits: &Limits,
+        heartbeat: Arc<AtomicU64>,
+        cancel: CancelToken,
+        profiler: Option<Arc<Mutex<Profiler>>>,
+        debugger: Option<Debugger>,
+    ) -> Self {
+        let executed = Arc::new(AtomicU64::new(0));
+        let tripped = Arc::new(AtomicBool::new(false));
+
+        lua.set_memory_limit(limits.memory);
+        let max_instructions = limits.instructions;
+        {
+            let executed = executed.clone();
+            let tripped = tripped.clone();
+            let profiler = profiler.clone();
+            let cancel = cancel.clone();
+            lua.set_hook(
+                HookTriggers {
+                    every_nth_instruction: Some(INSTRUCTION_GRANULARITY),
+                    every_line: debugger.is_some(),
+                    ..Default::default()
+                },
+                move |ctx, debug| {
+                    heartbeat.fetch_add(1, Ordering::Relaxed);
+                    if let Some(profiler) = &profiler {
+                        if let Ok(mut profiler) = profiler.lock() {
+                            profiler.sample(ctx, &debug);
+                        }
+                    }
+                    if cancel.is_cancelled() {
+                        return Err(Error::RuntimeError(CANCELLED.to_string()));
+                    }
+                    if let Some(debugger) = &debugger {
+                        // Lines and instructions look the same from here.
+                        return debugger.on_hook(ctx, &debug);
+                    }
+                    let count = executed
+                        .fetch_add(INSTRUCTION_GRANULARITY as u64, Ordering::Relaxed)
+                        + INSTRUCTION_GRANULARITY as u64;
+                    if max_instructions.is_some_and(|max| count > max) {
+                        tripped.store(true, Ordering::Relaxed);
+                        Err(Error::RuntimeError(
+                            "instruction limit exceeded".to_string(),
+                        ))
+                    } else {
+                        Ok(())
+                    }
+                },
+            );
+        }
+
+        Self {
+            executed,
+            tripped,
+            cancel,
+            profiler,
+        }
+    }
+
+    /// Also clears the cancel token and starts measuring the eval's memory
+    /// growth when profiling.
+    pub fn rearm(&self, ctx: Context) {
+        self.executed.store(0, Ordering::Relaxed);
+        self.tripped.store(false, Ordering::Relaxed);
+        self.cancel.reset();
+        if let Some(profiler) = &self.profiler {
+            if let Ok(mut profiler) = profiler.lock() {
+                profiler.begin(ctx);
+            }
+        }
+    }
+
+    /// Attributes the memory growth since `rearm` to `source`.
+    pub fn finish(&self, ctx: Context, source: &str) {
+        if let Some(profiler) = &self.profiler {
+            if let Ok(mut profiler) = profiler.lock() {
+                profiler.end(ctx, source);
+            }
+        }
+    }
+
+    /// Returns which limit, if any, caused `result` to fail.
+    pub fn violation(&self, result: &Result<Value, Error>) -> Option<LimitViolation> {
+        if self.tripped.load(Ordering::Relaxed) {
+            return Some(LimitViolation::Instructions);
+        }
+        match result {
+            Err(Error::MemoryError(_)) => Some(LimitViolation::Memory),
+            _ => None,
+        }
+    }
+}
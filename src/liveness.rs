@@ -0,0 +1,68 @@
+//! Detection of interpreter threads that have stopped making progress, e.g.
+//! because they are stuck in a blocking host call.
+
+use crate::Command;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+/// How often `ping` checks whether a busy interpreter has made progress.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A handle for checking on a session's interpreter thread while the session
+/// itself is busy, see `Session::liveness`.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    pub(crate) commands: UnboundedSender<Command>,
+    /// Bumped by the instruction hook while Lua code runs.
+    pub(crate) heartbeat: Arc<AtomicU64>,
+}
+
+impl Liveness {
+    /// Returns whether the interpreter either answers a ping or executes Lua
+    /// instructions within `timeout`.
+    pub async fn ping(&self, timeout: Duration) -> bool {
+        let beats = self.heartbeat.load(Ordering::Relaxed);
+        let (reply, pong) = oneshot::channel();
+        if self.commands.send(Command::Ping(reply)).is_err() {
+            return false;
+        }
+        let progress = async {
+            while self.heartbeat.load(Ordering::Relaxed) == beats {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        };
+        let alive = async {
+            tokio::select! {
+                pong = pong => pong.is_ok(),
+                _ = progress => true,
+            }
+        };
+        tokio::time::timeout(timeout, alive).await.unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Session;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_ping() {
+        let mut session = Session::new();
+        assert!(session.ping(Duration::from_secs(1)).await);
+
+        // A busy interpreter is alive as long as it keeps running Lua code.
+        let liveness = session.liveness();
+        let eval = session.eval("for i = 1, 2e7 do end return 1".to_string());
+        tokio::pin!(eval);
+        assert!(tokio::time::timeout(Duration::from_millis(1), &mut eval)
+            .await
+            .is_err());
+        assert!(liveness.ping(Duration::from_secs(1)).await);
+        assert!(eval.await.success);
+    }
+}
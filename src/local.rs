@@ -0,0 +1,173 @@
+//! A session that owns its Lua state and evaluates synchronously on the
+//! caller's thread, without tokio tasks or threads of its own, for embedders
+//! that need strict control over threading.
+
+use crate::formatter;
+use crate::host;
+use crate::host::AsyncCall;
+use crate::output;
+use crate::path::PathError;
+use crate::Command;
+use crate::EvalOptions;
+use crate::EvalResponse;
+use crate::Interpreter;
+use crate::Limits;
+use crate::LuaValue;
+use crate::SessionConfig;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::oneshot;
+
+/// Like `Session`, minus what needs a runtime: there is no recovery, cache,
+/// liveness or `SessionHandle`, and async host functions fail when called.
+pub struct LocalSession {
+    interpreter: Interpreter,
+    calls: UnboundedReceiver<AsyncCall>,
+    heartbeat: Arc<AtomicU64>,
+}
+
+impl Default for LocalSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalSession {
+    pub fn new() -> Self {
+        Self::with_config(SessionConfig::default())
+    }
+
+    /// Settings that need a `Session`, such as `pool`, `recovery` and `cache`,
+    /// are ignored.
+    pub fn with_config(config: SessionConfig) -> Self {
+        let heartbeat = Arc::new(AtomicU64::new(0));
+        let (call_sender, calls) = tokio::sync::mpsc::unbounded_channel();
+        Self {
+            interpreter: Interpreter::new(config, heartbeat.clone(), call_sender),
+            calls,
+            heartbeat,
+        }
+    }
+
+    fn config(&self) -> &SessionConfig {
+        &self.interpreter.config
+    }
+
+    /// Runs `command` and returns its reply, if it sent one.
+    fn request<T>(&mut self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Option<T> {
+        let (reply, mut response) = oneshot::channel();
+        self.interpreter.handle(command(reply));
+        loop {
+            if let Ok(response) = response.try_recv() {
+                return Some(response);
+            }
+            let call = self.calls.try_recv().ok()?;
+            let e: Box<dyn std::error::Error + Send + Sync> =
+                "async host functions need a Session".into();
+            let result = Err(host::failure(&call.function, e.as_ref()));
+            self.interpreter.handle(Command::Resume(call.id, result));
+        }
+    }
+
+    pub fn eval(&mut self, expr: String) -> EvalResponse {
+        self.eval_with(expr, EvalOptions::default())
+    }
+
+    pub fn eval_with(&mut self, expr: String, mut options: EvalOptions) -> EvalResponse {
+        if let Some(Err(e)) = self
+            .config()
+            .hardening
+            .map(|hardening| hardening.check_input(expr.as_bytes()))
+        {
+            return EvalResponse::rejected(e);
+        }
+        let output_level = *options
+            .output_level
+            .get_or_insert(self.config().output_level);
+        let schema = options.schema.clone();
+        let mut response = match self.request(|reply| Command::Eval(expr, options, reply)) {
+            Some(response) => response,
+            None => return EvalResponse::interpreter_died(),
+        };
+        if let Some(schema) = schema.filter(|_| response.success) {
+            response.schema_errors = schema.validate(&response);
+        }
+        formatter::apply(&self.config().formatters, &mut response);
+        output::trim(&mut response, output_level);
+        response
+    }
+
+    /// See `Session::cpu_ticks`.
+    pub fn cpu_ticks(&self) -> u64 {
+        self.heartbeat.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.request(|reply| Command::SetLimits(limits, reply));
+    }
+
+    /// Replaces the Lua state with a fresh one, discarding all globals.
+    pub fn reset(&mut self) {
+        self.request(Command::Reset);
+    }
+
+    /// See `Session::get_path`.
+    pub fn get_path(&mut self, path: String) -> Result<EvalResponse, PathError> {
+        let mut response = self
+            .request(|reply| Command::GetPath(path, reply))
+            .unwrap_or_else(|| Err(PathError::Lua("the path was not resolved".to_string())))?;
+        formatter::apply(&self.config().formatters, &mut response);
+        Ok(response)
+    }
+
+    pub fn set_path(&mut self, path: String, value: LuaValue) -> Result<(), PathError> {
+        self.request(|reply| Command::SetPath(path, value, reply))
+            .unwrap_or_else(|| Err(PathError::Lua("the path was not assigned".to_string())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[test]
+    fn test_local_session() {
+        let mut session = LocalSession::new();
+        session.eval("t = { name = 'a' }".to_string());
+        session
+            .set_path("t.name".to_string(), LuaValue::String("b".to_string()))
+            .unwrap();
+        let resp = session.eval("return t.name".to_string());
+        assert_eq!(resp.value, LuaValue::String("b".to_string()));
+        assert!(session.get_path("t.missing.x".to_string()).is_err());
+
+        session.reset();
+        assert_eq!(session.eval("return t".to_string()).value, LuaValue::Nil);
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let mut session = Session::builder()
+            .register_function("twice", |args| match args[..] {
+                [ref n] => match n.as_number() {
+                    Some(n) => Ok(LuaValue::Number(n * 2.0)),
+                    None => Err("expected a number".into()),
+                },
+                _ => Err("expected a number".into()),
+            })
+            .register_async_function("later", |_| async { Ok(LuaValue::Nil) })
+            .build_local();
+        let resp = session.eval("return twice(21)".to_string());
+        assert_eq!(resp.value, LuaValue::Number(42.0));
+
+        let resp = session.eval("later()".to_string());
+        assert!(!resp.success);
+        assert_eq!(
+            resp.host_error.map(|e| e.function),
+            Some("later".to_string())
+        );
+    }
+}
@@ -1,266 +1,1124 @@
-use rlua::Context;
-use rlua::Error;
-use rlua::Function;
-use rlua::Lua;
-use rlua::Table;
-use rlua::Value;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use clap::parser::ValueSource;
+use clap::CommandFactory;
+use clap::FromArgMatches;
+use clap::Parser;
+use clap::ValueEnum;
+use clap_complete::Shell;
+use luarepl::audit::AuditConfig;
+use luarepl::audit::AuditLog;
+use luarepl::auth;
+use luarepl::auth::Token;
+use luarepl::bundle::Bundle;
+use luarepl::cancel::CancelToken;
+use luarepl::caught;
+use luarepl::commands;
+use luarepl::commands::MetaCommand;
+use luarepl::dap;
+use luarepl::dap::HeaderFramed;
+use luarepl::doc;
+use luarepl::doctor;
+use luarepl::elide::Elided;
+use luarepl::elide::Elisions;
+use luarepl::elide::Truncation;
+use luarepl::finalizers;
+use luarepl::fuzz;
+use luarepl::hardening::Hardening;
+use luarepl::help;
+use luarepl::idle_gc::IdleGc;
+use luarepl::image::SessionImage;
+use luarepl::jsonrpc;
+use luarepl::lesson::Lesson;
+use luarepl::limits::Limits;
+use luarepl::manager::LimitPolicy;
+use luarepl::manager::SessionManager;
+use luarepl::memprofile;
+use luarepl::output;
+use luarepl::output::OutputLevel;
+use luarepl::path;
+use luarepl::progress;
+use luarepl::recovery::Recovery;
+use luarepl::scheduler::OverQuota;
+use luarepl::scheduler::Quota;
+use luarepl::search::SearchOptions;
+use luarepl::server;
+use luarepl::server::Reload;
+use luarepl::server::ServeOptions;
+use luarepl::settings;
+use luarepl::settings::Settings;
+use luarepl::transcript::Transcript;
+use luarepl::transport::StdioTransport;
+use luarepl::transport::TcpTransport;
+use luarepl::transport::Transport;
+#[cfg(unix)]
+use luarepl::transport::UnixTransport;
+use luarepl::tui;
+use luarepl::ui;
+use luarepl::websocket::WsTransport;
+use luarepl::LuaValue;
+use luarepl::Session;
+use luarepl::SessionConfig;
 use std::io::BufRead;
-use std::thread;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::task::JoinHandle;
-
-#[derive(Debug, PartialEq)]
-struct EvalResponse {
-    success: bool,
-    objects: HashMap<String, LuaObject>,
-    value: LuaValue,
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+/// The flags of the REPL's `SessionManager`, which `serve` and `dap` have
+/// none of.
+const MANAGER_FLAGS: [&str; 3] = ["limit_policy", "max_violations", "liveness_timeout"];
+
+/// A Lua REPL reading one chunk per line from stdin.
+#[derive(Parser, Debug, Clone)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+    /// Maximum bytes the Lua state may allocate.
+    #[arg(long)]
+    memory_limit: Option<usize>,
+    /// Maximum VM instructions a single eval may execute.
+    #[arg(long)]
+    instruction_limit: Option<u64>,
+    /// What to do once limits have been violated `--max-violations` times,
+    /// in the REPL.
+    #[arg(long, value_enum, default_value = "continue")]
+    limit_policy: PolicyArg,
+    #[arg(long, default_value_t = 3)]
+    max_violations: u32,
+    /// Treat evals that make no progress for this many milliseconds as wedged
+    /// and apply `--limit-policy` to them.
+    #[arg(long, value_name = "MS")]
+    liveness_timeout: Option<u64>,
+    /// Settings file, reloaded on SIGHUP or `:reload` [default: luarepl.toml if present].
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Append a JSONL record of every eval to this file, the evals of
+    /// `serve` clients included.
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+    /// Rotate the audit log once it reaches this size.
+    #[arg(long, value_name = "BYTES", requires = "audit_log")]
+    audit_max_bytes: Option<u64>,
+    /// Rotate the audit log after this many seconds.
+    #[arg(long, value_name = "SECONDS", requires = "audit_log")]
+    audit_max_age: Option<u64>,
+    /// Leave eval sources out of the audit log.
+    #[arg(long, requires = "audit_log")]
+    audit_redact: bool,
+    /// After each eval, explain the result type, changed globals and likely mistakes.
+    #[arg(long)]
+    explain: bool,
+    /// How much of each eval to print: the value, the value after its
+    /// output, or the whole response.
+    #[arg(long, value_enum, default_value = "full")]
+    output_level: OutputLevelArg,
+    /// Print each eval as one line of JSON with its success, value, objects,
+    /// error and printed output, instead of at --output-level.
+    #[arg(long)]
+    json: bool,
+    /// Cut strings in the output after this many bytes, see `:more`
+    /// [default: 200].
+    #[arg(long, value_name = "BYTES")]
+    max_string_len: Option<usize>,
+    /// After evals that only assign, print the new values of the assigned names.
+    #[arg(long)]
+    echo_assignments: bool,
+    /// Record where objects are pinned, for `:leaks`.
+    #[arg(long)]
+    leak_diagnostics: bool,
+    /// Attribute memory growth to evals and functions, for `:memprofile`.
+    #[arg(long)]
+    memory_profile: bool,
+    /// Count objects with finalizers as they are collected, for `:gcreport`.
+    #[arg(long)]
+    gc_diagnostics: bool,
+    /// Count the errors `pcall` and `xpcall` catch, for `:caught`.
+    #[arg(long)]
+    caught_errors: bool,
+    /// Define `unpack`, `loadstring`, `setfenv` and other Lua 5.1 globals.
+    #[arg(long)]
+    compat51: bool,
+    /// Write a report to this directory whenever the interpreter crashes.
+    #[arg(long, value_name = "DIR")]
+    crash_reports: Option<PathBuf>,
+    /// Collect garbage once the session has been idle for this many
+    /// milliseconds.
+    #[arg(long, value_name = "MS")]
+    idle_gc: Option<u64>,
+    /// Let Lua code drive the REPL through the `repl` library: run code in
+    /// other sessions, read the input history and add watches.
+    #[arg(long)]
+    trust_repl_api: bool,
+    /// Give the session a scratch directory in `WORKSPACE`, removed on exit.
+    #[arg(long)]
+    workspace: bool,
+    /// Record every value stored at this path, for `:history`.
+    #[arg(long = "history", value_name = "PATH")]
+    history_paths: Vec<String>,
+    /// What to do if the interpreter crashes.
+    #[arg(long, value_enum, default_value = "none")]
+    recovery: RecoveryArg,
+    /// Lua files run at startup and after every reset.
+    #[arg(long = "init", value_name = "FILE")]
+    init_scripts: Vec<PathBuf>,
+    /// Reject oversized, NUL-containing or non-UTF-8 input and cap result nesting.
+    #[arg(long)]
+    hardened: bool,
+    /// Run the REPL as a full-screen dashboard, with watches, new globals
+    /// and session stats beside the scrollback.
+    #[arg(long)]
+    tui: bool,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum CliCommand {
+    /// Package a script and the local modules it requires into a standalone executable.
+    Bundle {
+        script: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Print a completion script for the shell, generated from these options.
+    Completions { shell: Shell },
+    /// Debug scripts from an editor speaking the Debug Adapter Protocol
+    /// over stdin and stdout.
+    Dap,
+    /// Check that sessions run here with these options and settings, and
+    /// that the files they write to are writable.
+    Doctor,
+    /// Print every option, REPL command and settings key.
+    Doc {
+        /// Print a roff man page instead.
+        #[arg(long)]
+        man: bool,
+    },
+    /// Throw random and malformed input at a hardened session.
+    FuzzSelf {
+        #[arg(long, default_value_t = 10_000)]
+        iterations: usize,
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+    },
+    /// Work through a lesson file step by step; `:skip` moves on without an answer.
+    Learn { lesson: PathBuf },
+    /// Serve sessions to clients speaking the JSON frames of `luarepl::protocol`.
+    Serve {
+        /// Listen on this TCP address instead of serving stdin and stdout.
+        #[arg(long)]
+        tcp: Option<String>,
+        /// Listen on a Unix domain socket at this path instead.
+        #[arg(long, value_name = "PATH", conflicts_with = "tcp")]
+        socket: Option<PathBuf>,
+        /// Accept WebSocket connections on this address instead, for
+        /// browser-based frontends.
+        #[arg(long, value_name = "ADDR", conflicts_with_all = ["tcp", "socket"])]
+        ws: Option<String>,
+        /// Speak JSON-RPC 2.0 (`eval`, `inspect`, `complete` and `reset`,
+        /// one session per client) instead of the protocol frames.
+        #[arg(long)]
+        jsonrpc: bool,
+        /// Have every client work in one session, with each eval's reply
+        /// broadcast to the others, for pair-debugging.
+        #[arg(long, conflicts_with = "jsonrpc")]
+        shared: bool,
+        /// With `--shared`, keep the globals each client assigns apart from
+        /// the other clients'.
+        #[arg(long, requires = "shared")]
+        namespaced: bool,
+        /// Require clients to present this token before anything else, in
+        /// `hello` or JSON-RPC `authenticate` [default: $LUAREPL_TOKEN].
+        #[arg(long)]
+        token: Option<String>,
+        /// Read the token from this file instead, again on SIGHUP.
+        #[arg(long, value_name = "PATH", conflicts_with = "token")]
+        token_file: Option<PathBuf>,
+        /// CPU budget of each client host per `--quota-window`, in
+        /// instruction hook ticks, shared by its connections and sessions.
+        #[arg(long, value_name = "TICKS")]
+        quota_ticks: Option<u64>,
+        /// How often the budgets are replenished.
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = 60,
+            requires = "quota_ticks"
+        )]
+        quota_window: u64,
+        /// What happens to the evals of a client over its budget.
+        #[arg(
+            long,
+            value_enum,
+            default_value = "deprioritize",
+            requires = "quota_ticks"
+        )]
+        over_quota: OverQuotaArg,
+    },
+    /// Walk through the REPL's own features, step by step.
+    Tour,
 }
 
-#[derive(Debug, PartialEq)]
-enum LuaValue {
-    Nil,
-    Boolean(bool),
-    Number(f64),
-    String(String),
-    ObjectRef(String),
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PolicyArg {
+    Continue,
+    Reset,
+    Terminate,
 }
 
-#[derive(Debug, PartialEq)]
-struct LuaObject {
-    members: Vec<(LuaValue, LuaValue)>,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OverQuotaArg {
+    Reject,
+    Deprioritize,
 }
 
-impl LuaObject {
-    pub fn new() -> Self {
-        Self { members: vec![] }
+impl From<OverQuotaArg> for OverQuota {
+    fn from(arg: OverQuotaArg) -> Self {
+        match arg {
+            OverQuotaArg::Reject => OverQuota::Reject,
+            OverQuotaArg::Deprioritize => OverQuota::Deprioritize,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputLevelArg {
+    Minimal,
+    Standard,
+    Full,
+}
+
+impl From<OutputLevelArg> for OutputLevel {
+    fn from(arg: OutputLevelArg) -> Self {
+        match arg {
+            OutputLevelArg::Minimal => OutputLevel::Minimal,
+            OutputLevelArg::Standard => OutputLevel::Standard,
+            OutputLevelArg::Full => OutputLevel::Full,
+        }
     }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RecoveryArg {
+    None,
+    Restart,
+    Replay,
+}
 
-    pub fn insert(&mut self, key: LuaValue, value: LuaValue) {
-        self.members.push((key, value));
+impl From<RecoveryArg> for Recovery {
+    fn from(arg: RecoveryArg) -> Self {
+        match arg {
+            RecoveryArg::None => Recovery::None,
+            RecoveryArg::Restart => Recovery::Restart,
+            RecoveryArg::Replay => Recovery::Replay,
+        }
     }
 }
 
-fn parse_value<'l>(
-    ctx: Context<'l>,
-    rlua_value: Value<'l>,
-    objects: &mut HashMap<String, LuaObject>,
-    seen_objs: &mut HashSet<String>,
-) -> LuaValue {
-    match rlua_value {
-        Value::Table(t) => {
-            let to_string: Function = ctx.globals().get("tostring").unwrap();
-            parse_table(ctx, t.clone(), objects, seen_objs);
-            LuaValue::ObjectRef(to_string.call::<_, String>(t).unwrap())
-        }
-        Value::Boolean(b) => LuaValue::Boolean(b),
-        Value::String(s) => LuaValue::String(s.to_str().unwrap_or_default().to_string()),
-        Value::Number(n) => LuaValue::Number(n),
-        Value::Integer(n) => LuaValue::Number(n as f64),
-        Value::Nil => LuaValue::Nil,
-        v => panic!("Error: Not yet supported {:?}", v),
+impl From<PolicyArg> for LimitPolicy {
+    fn from(arg: PolicyArg) -> Self {
+        match arg {
+            PolicyArg::Continue => LimitPolicy::ErrorAndContinue,
+            PolicyArg::Reset => LimitPolicy::ResetState,
+            PolicyArg::Terminate => LimitPolicy::Terminate,
+        }
     }
 }
 
-fn parse_table<'lua>(
-    ctx: Context<'lua>,
-    table: Table<'lua>,
-    objects: &mut HashMap<String, LuaObject>,
-    seen_objs: &mut HashSet<String>,
-) -> String {
-    let to_string: Function = ctx.globals().get("tostring").unwrap();
-    let table_id = to_string.call::<_, String>(table.clone()).unwrap();
-
-    if seen_objs.insert(table_id.clone()) {
-        let mut object = LuaObject::new();
-        for (k, v) in table
-            .pairs::<Value, Value>()
-            .into_iter()
-            .map(|r| r.unwrap())
-        {
-            object.insert(
-                parse_value(ctx, k, objects, seen_objs),
-                parse_value(ctx, v, objects, seen_objs),
-            );
+/// Runs the program appended to this executable by `luarepl bundle`.
+async fn run_bundle(bundle: Bundle) -> i32 {
+    let image = bundle.modules.iter().fold(
+        SessionImage::builder().preload_embedded(),
+        |image, (name, source)| image.preload(name, source),
+    );
+    let image = match image.build() {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
         }
-        objects.insert(table_id.clone(), object);
+    };
+    let mut session = Session::with_config(SessionConfig {
+        image: Some(Arc::new(image)),
+        ..Default::default()
+    });
+    let response = session.eval(bundle.main).await;
+    if response.success {
+        0
+    } else {
+        eprintln!("{}", output::render(&response, OutputLevel::Full));
+        1
     }
+}
 
-    table_id
+/// The help for every option followed by the REPL commands and settings keys,
+/// as text or as a man page.
+fn print_doc(man: bool) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    if man {
+        clap_mangen::Man::new(Cli::command()).render(&mut stdout)?;
+        write!(stdout, "{}", doc::man_sections())
+    } else {
+        writeln!(stdout, "{}", Cli::command().render_long_help())?;
+        write!(stdout, "{}", doc::long_help())
+    }
 }
 
-impl EvalResponse {
-    fn from_result<'l>(ctx: Context<'l>, eval_result: Result<Value<'l>, Error>) -> Self {
-        match eval_result {
-            Err(_e) => Self {
-                success: false,
-                objects: HashMap::new(),
-                value: LuaValue::Nil,
-            },
-            Ok(v) => Self::from_value(ctx, v),
+fn bundle_script(script: &Path, output: &Path) -> std::io::Result<()> {
+    let bundle = Bundle::from_script(script)?;
+    bundle.write_executable(&std::env::current_exe()?, output)?;
+    for (name, _) in &bundle.modules {
+        println!("bundled module {}", name);
+    }
+    Ok(())
+}
+
+/// Presents each step of `lesson` until it is answered correctly or skipped.
+async fn learn(lesson: Lesson) {
+    println!("{}", lesson.title);
+    let mut session = Lesson::session();
+    let mut elisions = Elisions::new(Truncation::default());
+    let mut stdin = std::io::stdin().lock();
+    for (index, step) in lesson.steps.iter().enumerate() {
+        println!("\n[{}/{}] {}", index + 1, lesson.steps.len(), step.prompt);
+        loop {
+            let mut line = String::new();
+            match stdin.read_line(&mut line) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("cannot read input: {}", e);
+                    return;
+                }
+            }
+            let answer = line.trim_end();
+            if answer == ":skip" {
+                break;
+            }
+            let checked = match MetaCommand::parse(answer) {
+                Some(Ok(command)) => {
+                    run_meta_command(&mut session, &mut elisions, command).await;
+                    if step.accepts_command(answer) {
+                        println!("correct!");
+                        break;
+                    }
+                    continue;
+                }
+                Some(Err(message)) => {
+                    eprintln!("{}", message);
+                    continue;
+                }
+                None => step.check(&mut session, answer.to_string()).await,
+            };
+            if !checked.output.is_empty() {
+                println!("{}", checked.output);
+            }
+            if let Some(result) = &checked.result {
+                println!("{}", result);
+            }
+            if checked.passed() {
+                println!("correct!");
+                break;
+            }
+            for mismatch in &checked.mismatches {
+                println!("not yet: {}", mismatch);
+            }
+            if let Some(hint) = &step.hint {
+                println!("hint: {}", hint);
+            }
         }
     }
+    println!("\nlesson complete");
+}
 
-    fn from_value<'l>(ctx: Context<'l>, value: Value<'l>) -> Self {
-        match value {
-            Value::Boolean(b) => Self {
-                success: true,
-                objects: HashMap::new(),
-                value: LuaValue::Boolean(b),
-            },
-            Value::String(s) => Self {
-                success: true,
-                objects: HashMap::new(),
-                value: LuaValue::String(s.to_str().unwrap_or_default().to_string()),
-            },
-            Value::Number(n) => Self {
-                success: true,
-                objects: HashMap::new(),
-                value: LuaValue::Number(n),
-            },
-            Value::Integer(n) => Self {
-                success: true,
-                objects: HashMap::new(),
-                value: LuaValue::Number(n as f64),
-            },
-            Value::Nil => Self {
-                success: true,
-                objects: HashMap::new(),
-                value: LuaValue::Nil,
+async fn run_meta_command(session: &mut Session, elisions: &mut Elisions, command: MetaCommand) {
+    match command {
+        MetaCommand::More(id) => match elisions.get(id).cloned() {
+            Some(Elided::Text(rest)) => println!("{}", rest),
+            Some(Elided::Table(table)) => match session.expand(table).await {
+                Some(response) => println!("{}", elisions.render(&response, OutputLevel::Full)),
+                None => eprintln!("{} is no longer available", id),
             },
-            Value::Table(t) => {
-                let mut objects = HashMap::new();
-                let mut seen_objs = HashSet::new();
-                let table_id = parse_table(ctx, t, &mut objects, &mut seen_objs);
-                Self {
-                    success: true,
-                    objects,
-                    value: LuaValue::ObjectRef(table_id),
+            None => eprintln!("nothing to expand as {}", id),
+        },
+        MetaCommand::Grep(pattern) => {
+            for found in session.search(pattern, SearchOptions::default()).await {
+                println!("{} = {}", found.path, found.value);
+            }
+        }
+        MetaCommand::Refs(target) => {
+            match session
+                .references(target.clone(), SearchOptions::default())
+                .await
+            {
+                Ok(paths) if paths.is_empty() => println!("no paths from _G reach {}", target),
+                Ok(paths) => {
+                    for path in &paths {
+                        println!("{}", path);
+                    }
+                    if paths.len() > 1 {
+                        println!("{} paths share {}", paths.len(), target);
+                    }
                 }
+                Err(e) => eprintln!("cannot find {}: {}", target, e),
             }
-            v => panic!("Value not yet supported {:?}", v),
         }
+        MetaCommand::Help(None) => {
+            for topic in commands::commands() {
+                println!("{:<24} {}", topic.signature, topic.summary());
+            }
+        }
+        MetaCommand::Help(Some(name)) => match help::lookup(&name) {
+            Some(topic) => println!("{}\n\n{}", topic.signature, topic.description),
+            None => eprintln!("no help for {}, try :apropos {}", name, name),
+        },
+        MetaCommand::Apropos(pattern) => {
+            for topic in help::apropos(&pattern) {
+                println!("{:<24} {}", topic.name, topic.summary());
+            }
+        }
+        MetaCommand::Leaks if !session.config().leak_diagnostics => {
+            eprintln!("leak diagnostics are off, see --leak-diagnostics");
+        }
+        MetaCommand::Leaks => {
+            for leak in session.leaks() {
+                println!("{:>6} {}", leak.count, leak.site);
+            }
+        }
+        MetaCommand::MemProfile => match session.memory_profile().await {
+            Some(profile) => print!("{}", memprofile::report(&profile)),
+            None => eprintln!("memory profiling is off, see --memory-profile"),
+        },
+        MetaCommand::GcReport => match session.gc_report().await {
+            Some(report) => print!("{}", finalizers::text(&report)),
+            None => eprintln!("GC diagnostics are off, see --gc-diagnostics"),
+        },
+        MetaCommand::Ui(path) => {
+            if let Err(e) = ui::run(session, path).await {
+                eprintln!("cannot explore: {}", e);
+            }
+        }
+        MetaCommand::Caught => match session.caught_errors().await {
+            Some(report) => print!("{}", caught::text(&report)),
+            None => eprintln!("caught errors are not counted, see --caught-errors"),
+        },
+        MetaCommand::Set { path, value } => {
+            let old = session
+                .get_path(path.clone())
+                .await
+                .map_or(LuaValue::Nil, |response| response.value);
+            match session.set_path(path.clone(), value.clone()).await {
+                Ok(()) => println!("{}: {} -> {}", path, old, value),
+                Err(e) => eprintln!("cannot set {}: {}", path, e),
+            }
+        }
+        MetaCommand::History(path) => match session.history(&path).await {
+            Some(entries) => {
+                for entry in entries {
+                    println!("{:>6}  {}", format!("#{}", entry.eval), entry.value);
+                }
+            }
+            None => eprintln!("{} is not recorded, see --history", path),
+        },
+        MetaCommand::Reload
+        | MetaCommand::Config
+        | MetaCommand::ConfigSet { .. }
+        | MetaCommand::ConfigSave
+        | MetaCommand::ExportTranscript(_) => unreachable!("handled by the main loop"),
     }
 }
 
-#[derive(Debug)]
-struct Session {
-    expr_sender: UnboundedSender<String>,
-    result_receiver: UnboundedReceiver<EvalResponse>,
-    eval_thread: JoinHandle<()>,
+fn settings_path(cli: &Cli) -> Option<PathBuf> {
+    cli.config.clone().or_else(|| {
+        let default = PathBuf::from(settings::DEFAULT_PATH);
+        default.exists().then_some(default)
+    })
 }
 
-impl Session {
-    pub fn new() -> Self {
-        let (expr_sender, mut expr_receiver) = tokio::sync::mpsc::unbounded_channel();
-        let (result_sender, result_receiver) = tokio::sync::mpsc::unbounded_channel();
-        let eval_thread = tokio::spawn(async move {
-            let lua = Lua::new();
-            let (inner_sender, inner_receiver) = std::sync::mpsc::channel::<String>();
-            let eval_thread = thread::spawn(move || {
-                lua.context(|ctx| {
-                    inner_receiver
-                        .into_iter()
-                        .map(|expr| ctx.load(&expr).eval::<Value>())
-                        .for_each(|result| {
-                            // TODO: handle this
-                            let _ = result_sender.send(EvalResponse::from_result(ctx, result));
-                        });
-                });
-            });
+/// Combines the settings file with the command line, which takes precedence.
+fn session_config(cli: &Cli, settings: &Settings, image: &Arc<SessionImage>) -> SessionConfig {
+    let mut builder = Session::builder()
+        .limits(Limits {
+            memory: cli.memory_limit.or(settings.limits.memory),
+            instructions: cli.instruction_limit.or(settings.limits.instructions),
+        })
+        .image(image.clone())
+        .recovery(cli.recovery.into())
+        .explain(cli.explain)
+        .leak_diagnostics(cli.leak_diagnostics)
+        .memory_profile(cli.memory_profile)
+        .gc_diagnostics(cli.gc_diagnostics)
+        .caught_errors(cli.caught_errors)
+        .compat51(cli.compat51)
+        .workspace(cli.workspace)
+        .echo_assignments(cli.echo_assignments)
+        .trust_repl_api(cli.trust_repl_api)
+        .output_level(match cli.json {
+            true => OutputLevel::Full,
+            false => cli.output_level.into(),
+        })
+        .capture_output(cli.json || cli.tui)
+        .on_progress(progress::terminal_bar());
+    if let Some(dir) = &cli.crash_reports {
+        builder = builder.crash_reports(dir);
+    }
+    if cli.hardened || settings.hardened {
+        builder = builder.hardening(Hardening::default());
+    }
+    for path in &cli.history_paths {
+        match path::parse_path(path) {
+            Ok(_) => builder = builder.record_history(path),
+            Err(e) => eprintln!("not recording {}: {}", path, e),
+        }
+    }
+    if let Some(ms) = cli.idle_gc {
+        builder = builder.idle_gc(IdleGc {
+            after: Duration::from_millis(ms),
+            ..Default::default()
+        });
+    }
+    builder.config()
+}
 
-            while let Some(expr) = expr_receiver.recv().await {
-                let _ = inner_sender.send(expr);
+/// Longest string shown in full, from the command line or the settings.
+fn max_string_len(cli: &Cli, settings: &Settings) -> usize {
+    cli.max_string_len
+        .or(settings.output.max_string_len)
+        .unwrap_or(Truncation::default().max_string_len)
+}
+
+/// Shows, changes or saves the settings in effect for `:config`.
+async fn config_command(
+    cli: &Cli,
+    manager: &mut SessionManager,
+    elisions: &mut Elisions,
+    command: MetaCommand,
+) {
+    let mut settings = Settings::effective(manager.config(), &elisions.truncation);
+    match command {
+        MetaCommand::Config => {
+            for key in settings::KEYS {
+                let value = settings.get(key.key).unwrap_or_default();
+                println!("{:<24} {}", key.key, value);
             }
-            let _ = eval_thread.join();
-        });
+            let config = manager.config();
+            println!("{:<24} {:?}", "output level", config.output_level);
+            println!("{:<24} {}", "formatters", config.formatters.len());
+            if let Some(hardening) = config.hardening {
+                println!("{:<24} {} bytes", "max input", hardening.max_input_len);
+                println!("{:<24} {}", "max depth", hardening.max_depth);
+            }
+        }
+        MetaCommand::ConfigSet { key, value } => {
+            if let Err(e) = settings.set(&key, &value) {
+                eprintln!("{}", e);
+                return;
+            }
+            let config = settings.apply(manager.config());
+            manager.reconfigure(config).await;
+            elisions.truncation.max_string_len = max_string_len(cli, &settings);
+            println!("{} = {}", key, settings.get(&key).unwrap_or_default());
+        }
+        MetaCommand::ConfigSave => {
+            let path = settings_path(cli).unwrap_or_else(|| PathBuf::from(settings::DEFAULT_PATH));
+            match settings.save(&path) {
+                Ok(()) => println!("saved {}", path.display()),
+                Err(e) => eprintln!("cannot save {}: {}", path.display(), e),
+            }
+        }
+        _ => unreachable!("not a :config command"),
+    }
+}
 
-        Self {
-            result_receiver,
-            expr_sender,
-            eval_thread,
+/// Re-reads the settings file, keeping the current settings if it is broken.
+async fn reload_settings(
+    cli: &Cli,
+    image: &Arc<SessionImage>,
+    manager: &mut SessionManager,
+    elisions: &mut Elisions,
+) {
+    let path = match settings_path(cli) {
+        Some(path) => path,
+        None => {
+            eprintln!("no settings file to reload");
+            return;
+        }
+    };
+    match Settings::load(&path) {
+        Ok(settings) => {
+            let config = SessionConfig {
+                cancel: manager.config().cancel.clone(),
+                ..session_config(cli, &settings, image)
+            };
+            manager.reconfigure(config).await;
+            elisions.truncation.max_string_len = max_string_len(cli, &settings);
+            eprintln!("reloaded {}", path.display());
         }
+        Err(e) => eprintln!("cannot reload {}: {}", path.display(), e),
     }
+}
 
-    pub async fn eval(&mut self, expr: String) -> EvalResponse {
-        let _ = self.expr_sender.send(expr);
-        self.result_receiver.recv().await.unwrap()
+/// The `--audit-log`, if given; exits if it cannot be opened.
+fn audit_log(cli: &Cli) -> Option<AuditLog> {
+    let path = cli.audit_log.as_ref()?;
+    let log = AuditLog::open(AuditConfig {
+        path: path.clone(),
+        max_bytes: cli.audit_max_bytes,
+        max_age: cli.audit_max_age.map(Duration::from_secs),
+        redact_source: cli.audit_redact,
+    });
+    match log {
+        Ok(log) => Some(log),
+        Err(e) => {
+            eprintln!("cannot open {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let mut session = Session::new();
-    for line in std::io::stdin().lock().lines() {
-        println!("{:#?}", session.eval(line.unwrap()).await);
+/// A message for each SIGHUP from now on, which would otherwise end the
+/// process.
+fn hangups() -> mpsc::UnboundedReceiver<()> {
+    let (sender, hangups) = mpsc::unbounded_channel();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::signal;
+        use tokio::signal::unix::SignalKind;
+        match signal(SignalKind::hangup()) {
+            Ok(mut signals) => {
+                tokio::spawn(async move {
+                    while signals.recv().await.is_some() && sender.send(()).is_ok() {}
+                });
+            }
+            Err(e) => eprintln!("cannot handle SIGHUP: {}", e),
+        }
     }
+    #[cfg(not(unix))]
+    drop(sender);
+    hangups
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// The token `serve` was given, read from `token_file` if it was given that.
+fn serve_token(
+    token: &Option<String>,
+    token_file: &Option<PathBuf>,
+) -> std::io::Result<Option<Token>> {
+    let token = match token_file {
+        Some(path) => Some(std::fs::read_to_string(path)?.trim().to_string()),
+        None => token
+            .clone()
+            .or_else(|| std::env::var(auth::TOKEN_VAR).ok()),
+    };
+    Ok(token.map(Token::new))
+}
 
-    #[tokio::test]
-    async fn test_simple() {
-        let mut session = Session::new();
-
-        assert_eq!(
-            session.eval("x = 1".to_string()).await,
-            EvalResponse {
-                success: true,
-                objects: HashMap::new(),
-                value: LuaValue::Nil,
-            }
-        );
-
-        assert_eq!(
-            session.eval("return x".to_string()).await,
-            EvalResponse {
-                success: true,
-                objects: HashMap::new(),
-                value: LuaValue::Number(1.0),
-            }
-        );
+/// Re-reads the settings file and the token file for `serve`, or `None` if
+/// either is broken, so that the server keeps what it serves with.
+fn reload_served(
+    cli: &Cli,
+    image: &Arc<SessionImage>,
+    token: &Option<String>,
+    token_file: &Option<PathBuf>,
+) -> Option<Reload> {
+    let settings = match settings_path(cli) {
+        Some(path) => match Settings::load(&path) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("cannot reload {}: {}", path.display(), e);
+                return None;
+            }
+        },
+        None => Settings::default(),
+    };
+    let token = match serve_token(token, token_file) {
+        Ok(token) => token,
+        Err(e) => {
+            let path = token_file.as_deref().unwrap_or(Path::new(""));
+            eprintln!("cannot reload {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    eprintln!("reloaded the settings");
+    Some(Reload {
+        config: session_config(cli, &settings, image),
+        token,
+    })
+}
+
+/// How `serve` serves its clients.
+#[derive(Clone, Copy, Debug)]
+enum ServeMode {
+    Sessions,
+    Shared,
+    JsonRpc,
+}
+
+/// Serves clients on `transport` the way `serve` was asked to.
+async fn serve<T: Transport>(
+    transport: T,
+    config: SessionConfig,
+    mode: ServeMode,
+    options: ServeOptions,
+) -> std::io::Result<()> {
+    let options = ServeOptions {
+        shared: matches!(mode, ServeMode::Shared),
+        ..options
+    };
+    match mode {
+        ServeMode::JsonRpc => jsonrpc::serve_with(transport, config, options).await,
+        _ => server::serve_with(transport, config, options).await,
     }
+}
 
-    #[tokio::test]
-    async fn test_syntax_error() {
-        let mut session = Session::new();
+#[tokio::main]
+async fn main() {
+    if let Some(bundle) = Bundle::from_current_exe() {
+        std::process::exit(run_bundle(bundle).await);
+    }
 
-        assert_eq!(
-            session.eval("syntax error".to_string()).await,
-            EvalResponse {
-                success: false,
-                objects: HashMap::new(),
-                value: LuaValue::Nil,
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(command @ (CliCommand::Serve { .. } | CliCommand::Dap)) = &cli.command {
+        let given = |id: &&&str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if let Some(id) = MANAGER_FLAGS.iter().find(given) {
+            let name = match command {
+                CliCommand::Dap => "dap",
+                _ => "serve",
+            };
+            let message = format!("--{} does not apply to {}", id.replace('_', "-"), name);
+            Cli::command()
+                .error(clap::error::ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+    }
+    match &cli.command {
+        Some(CliCommand::Bundle { script, output }) => {
+            if let Err(e) = bundle_script(script, output) {
+                eprintln!("cannot bundle {}: {}", script.display(), e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(CliCommand::Completions { shell }) => {
+            clap_complete::generate(
+                *shell,
+                &mut Cli::command(),
+                "luarepl",
+                &mut std::io::stdout(),
+            );
+            return;
+        }
+        Some(CliCommand::Doc { man }) => {
+            if let Err(e) = print_doc(*man) {
+                eprintln!("cannot write documentation: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(CliCommand::FuzzSelf { iterations, seed }) => {
+            if let Err(failure) = fuzz::fuzz_self(*iterations, *seed).await {
+                eprintln!("{}", failure);
+                std::process::exit(1);
+            }
+            println!("{} inputs, no hangs or crashes", iterations);
+            return;
+        }
+        Some(CliCommand::Learn { lesson }) => {
+            match Lesson::load(lesson) {
+                Ok(loaded) => learn(loaded).await,
+                Err(e) => {
+                    eprintln!("cannot load {}: {}", lesson.display(), e);
+                    std::process::exit(1);
+                }
             }
-        );
+            return;
+        }
+        Some(CliCommand::Tour) => {
+            learn(Lesson::tour()).await;
+            return;
+        }
+        Some(CliCommand::Serve { .. })
+        | Some(CliCommand::Dap)
+        | Some(CliCommand::Doctor)
+        | None => {}
     }
-
-    #[tokio::test]
-    async fn test_simple_table() {
-        let mut session = Session::new();
-        let resp = session.eval("x = {}; return x".to_string()).await;
-
-        assert!(resp.success);
-        let table_id = if let LuaValue::ObjectRef(id) = &resp.value {
-            id.to_string()
-        } else {
-            panic!("Expected an object ref got {:?}!", resp.value);
+    let mut image = SessionImage::builder().preload_embedded();
+    for path in &cli.init_scripts {
+        match std::fs::read_to_string(path) {
+            Ok(source) => image = image.init_script(&source),
+            Err(e) => {
+                eprintln!("cannot read {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let image = match image.build() {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("init scripts failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let image = Arc::new(image);
+    let settings =
+        match settings_path(&cli).map(|path| Settings::load(&path).map_err(|e| (path, e))) {
+            None => Settings::default(),
+            Some(Ok(settings)) => settings,
+            // The doctor reports it along with everything else.
+            Some(Err(_)) if matches!(cli.command, Some(CliCommand::Doctor)) => Settings::default(),
+            Some(Err((path, e))) => {
+                eprintln!("cannot read {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+    let config = session_config(&cli, &settings, &image);
+    if let Some(CliCommand::Doctor) = &cli.command {
+        let settings = settings_path(&cli);
+        let files = doctor::Files {
+            settings: settings.as_deref(),
+            crash_reports: cli.crash_reports.as_deref(),
+            audit_log: cli.audit_log.as_deref(),
+        };
+        let checks = doctor::run(&config, files).await;
+        print!("{}", doctor::report(&checks));
+        std::process::exit(if doctor::passed(&checks) { 0 } else { 1 });
+    }
+    // Installed before serving too, or SIGHUP would end the server.
+    let mut hangups = hangups();
+    if let Some(CliCommand::Serve {
+        tcp,
+        socket,
+        ws,
+        jsonrpc,
+        shared,
+        namespaced,
+        token,
+        token_file,
+        quota_ticks,
+        quota_window,
+        over_quota,
+    }) = &cli.command
+    {
+        let (token, token_file) = (token.clone(), token_file.clone());
+        let initial = match serve_token(&token, &token_file) {
+            Ok(token) => token,
+            Err(e) => {
+                let path = token_file.as_deref().unwrap_or(Path::new(""));
+                eprintln!("cannot read {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        let (reload, reloads) = watch::channel(Reload {
+            config: config.clone(),
+            token: initial.clone(),
+        });
+        {
+            let (cli, image) = (cli.clone(), image.clone());
+            tokio::spawn(async move {
+                while hangups.recv().await.is_some() {
+                    if let Some(reloaded) = reload_served(&cli, &image, &token, &token_file) {
+                        let _ = reload.send(reloaded);
+                    }
+                }
+            });
+        }
+        let options = ServeOptions {
+            namespaced: *namespaced,
+            token: initial,
+            reloads: Some(reloads),
+            quota: quota_ticks.map(|ticks| Quota {
+                ticks,
+                window: Duration::from_secs(*quota_window),
+                over_quota: (*over_quota).into(),
+            }),
+            audit: audit_log(&cli).map(|log| Arc::new(Mutex::new(log))),
+            ..Default::default()
+        };
+        let mode = match (jsonrpc, shared) {
+            (true, _) => ServeMode::JsonRpc,
+            (false, true) => ServeMode::Shared,
+            (false, false) => ServeMode::Sessions,
+        };
+        let served = match (tcp, socket, ws) {
+            (_, _, Some(addr)) => match WsTransport::bind(addr).await {
+                Ok(transport) => serve(transport, config, mode, options).await,
+                Err(e) => Err(e),
+            },
+            (Some(addr), _, None) => match TcpTransport::bind(addr).await {
+                Ok(transport) => serve(transport, config, mode, options).await,
+                Err(e) => Err(e),
+            },
+            #[cfg(unix)]
+            (None, Some(path), None) => match UnixTransport::bind(path).await {
+                Ok(transport) => serve(transport, config, mode, options).await,
+                Err(e) => Err(e),
+            },
+            #[cfg(not(unix))]
+            (None, Some(_), None) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform",
+            )),
+            (None, None, None) => serve(StdioTransport::default(), config, mode, options).await,
         };
+        if let Err(e) = served {
+            eprintln!("cannot serve: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(CliCommand::Dap) = &cli.command {
+        let connection =
+            HeaderFramed::new(tokio::io::stdin(), tokio::io::stdout(), "stdio".to_string());
+        if let Err(e) = dap::serve(connection, config).await {
+            eprintln!("cannot debug: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    // Ctrl-C cancels the running eval instead of ending the REPL.
+    let cancel = CancelToken::new();
+    {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
+            }
+        });
+    }
+    let config = SessionConfig {
+        cancel: Some(cancel),
+        ..config
+    };
+    let mut manager = SessionManager::new(config, cli.limit_policy.into(), cli.max_violations);
+    if let Some(ms) = cli.liveness_timeout {
+        manager = manager.liveness_timeout(Duration::from_millis(ms));
+    }
+    if let Some(log) = audit_log(&cli) {
+        manager = manager.audit(log);
+    }
 
-        let resp = session.eval("x['a'] = 1 ; return x".to_string()).await;
-        assert!(resp.success);
-        assert_eq!(
-            resp.objects,
-            vec![(
-                table_id,
-                LuaObject {
-                    members: vec![(LuaValue::String("a".to_string()), LuaValue::Number(1.0))]
+    let session = manager.open();
+    if cli.tui {
+        if let Err(e) = tui::run(&mut manager, session).await {
+            eprintln!("cannot start the dashboard: {}", e);
+        }
+        manager.shutdown().await;
+        return;
+    }
+    let mut elisions = Elisions::new(Truncation {
+        max_string_len: max_string_len(&cli, &settings),
+        ..Default::default()
+    });
+    let mut transcript = Transcript::new();
+    let mut stdin = std::io::stdin().lock();
+    let mut buffer = vec![];
+    loop {
+        buffer.clear();
+        match stdin.read_until(b'\n', &mut buffer) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("cannot read input: {}", e);
+                break;
+            }
+        }
+        let input = buffer.strip_suffix(b"\n").unwrap_or(&buffer);
+        let input = input.strip_suffix(b"\r").unwrap_or(input);
+        let mut hung_up = false;
+        while hangups.try_recv().is_ok() {
+            hung_up = true;
+        }
+        if hung_up {
+            reload_settings(&cli, &image, &mut manager, &mut elisions).await;
+        }
+        let line = match manager.config().hardening {
+            Some(hardening) => match hardening.check_input(input) {
+                Ok(line) => line.to_string(),
+                Err(e) => {
+                    eprintln!("rejected input: {}", e);
+                    continue;
                 }
-            )].into_iter().collect(),
-       );
+            },
+            None => String::from_utf8_lossy(input).into_owned(),
+        };
+        match MetaCommand::parse(&line) {
+            Some(Ok(MetaCommand::Reload)) => {
+                reload_settings(&cli, &image, &mut manager, &mut elisions).await;
+                continue;
+            }
+            Some(Ok(
+                command @ (MetaCommand::Config
+                | MetaCommand::ConfigSet { .. }
+                | MetaCommand::ConfigSave),
+            )) => {
+                config_command(&cli, &mut manager, &mut elisions, command).await;
+                continue;
+            }
+            Some(Ok(MetaCommand::ExportTranscript(path))) => {
+                match transcript.write(Path::new(&path)) {
+                    Ok(()) => println!("wrote {} inputs to {}", transcript.entries().len(), path),
+                    Err(e) => eprintln!("cannot write {}: {}", path, e),
+                }
+                continue;
+            }
+            Some(Ok(command)) => {
+                let session = manager.session_mut(session).unwrap();
+                run_meta_command(session, &mut elisions, command).await;
+                continue;
+            }
+            Some(Err(message)) => {
+                eprintln!("{}", message);
+                continue;
+            }
+            None => {}
+        }
+        let response = manager.eval_as(session, Some("stdin"), line.clone()).await;
+        progress::clear_terminal_bar();
+        match response {
+            Some(response) => {
+                transcript.record(&line, &response, manager.config().output_level);
+                if cli.json {
+                    println!("{}", output::json(&response));
+                } else {
+                    println!(
+                        "{}",
+                        elisions.render(&response, manager.config().output_level)
+                    );
+                }
+                for warning in &response.warnings {
+                    eprintln!("warning: {}", warning);
+                }
+            }
+            None => break,
+        }
+        if let Some(session) = manager.session_mut(session).filter(|_| cli.trust_repl_api) {
+            for (watch, response) in session.refresh_watches().await {
+                println!("watch {}: {}", watch.id(), response);
+            }
+        }
+        if !manager.contains(session) {
+            eprintln!("session terminated after repeated limit violations");
+            break;
+        }
+    }
+    manager.shutdown().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hangups() {
+        // Without a handler, SIGHUP would end the test, as it did `serve`.
+        let mut hangups = hangups();
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGHUP);
+        }
+        let hung_up = tokio::time::timeout(Duration::from_secs(5), hangups.recv()).await;
+        assert_eq!(hung_up.unwrap(), Some(()));
     }
 }
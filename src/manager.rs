@@ -0,0 +1,341 @@
+use crate::audit::AuditLog;
+use crate::audit::AuditStatus;
+use crate::ErrorKind;
+use crate::EvalResponse;
+use crate::Session;
+use crate::SessionConfig;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+pub type SessionId = u64;
+
+/// What to do with a session once it has violated its limits `max_violations`
+/// times, or at once if its interpreter stops responding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Report the failed eval and keep the session as is.
+    ErrorAndContinue,
+    /// Throw away the Lua state and start the session over with a fresh one.
+    ResetState,
+    /// Close the session; further evals against it fail.
+    Terminate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    pub evals: u64,
+    /// Violations since the session was created or last reset.
+    pub limit_violations: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ManagerStats {
+    pub open_sessions: usize,
+    pub evals: u64,
+    pub limit_violations: u64,
+    pub resets: u64,
+    pub terminations: u64,
+    /// Evals abandoned because the interpreter stopped making progress.
+    pub wedged: u64,
+    /// Evals that could not be written to the audit log.
+    pub audit_failures: u64,
+}
+
+struct ManagedSession {
+    session: Session,
+    stats: SessionStats,
+}
+
+pub struct SessionManager {
+    config: SessionConfig,
+    policy: LimitPolicy,
+    max_violations: u32,
+    liveness_timeout: Option<Duration>,
+    audit: Option<AuditLog>,
+    sessions: HashMap<SessionId, ManagedSession>,
+    next_id: SessionId,
+    stats: ManagerStats,
+}
+
+impl SessionManager {
+    /// Creates a manager whose sessions all use `config`. `policy` is applied
+    /// once a session has violated its limits `max_violations` times.
+    pub fn new(config: SessionConfig, policy: LimitPolicy, max_violations: u32) -> Self {
+        Self {
+            config,
+            policy,
+            max_violations: max_violations.max(1),
+            liveness_timeout: None,
+            audit: None,
+            sessions: HashMap::new(),
+            next_id: 0,
+            stats: ManagerStats::default(),
+        }
+    }
+
+    /// Pings sessions whose evals take longer than `timeout`, treating one
+    /// that fails to answer within another `timeout` as wedged.
+    pub fn liveness_timeout(mut self, timeout: Duration) -> Self {
+        self.liveness_timeout = Some(timeout);
+        self
+    }
+
+    /// Records every eval in `log`.
+    pub fn audit(mut self, log: AuditLog) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// Uses `config` for sessions opened from now on. Open sessions keep
+    /// their state and only pick up the new limits.
+    pub async fn reconfigure(&mut self, config: SessionConfig) {
+        for managed in self.sessions.values_mut() {
+            managed.session.set_limits(config.limits).await;
+        }
+        self.config = config;
+    }
+
+    pub fn open(&mut self) -> SessionId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(
+            id,
+            ManagedSession {
+                session: Session::with_config(self.config.clone()),
+                stats: SessionStats::default(),
+            },
+        );
+        id
+    }
+
+    pub fn close(&mut self, id: SessionId) -> bool {
+        self.sessions.remove(&id).is_some()
+    }
+
+    /// Closes every session and waits for their interpreters to exit, so that
+    /// their workspaces are gone before the process is.
+    pub async fn shutdown(self) {
+        for (_, managed) in self.sessions {
+            managed.session.close().await;
+        }
+    }
+
+    pub fn contains(&self, id: SessionId) -> bool {
+        self.sessions.contains_key(&id)
+    }
+
+    pub fn session_mut(&mut self, id: SessionId) -> Option<&mut Session> {
+        self.sessions
+            .get_mut(&id)
+            .map(|managed| &mut managed.session)
+    }
+
+    /// Evaluates `expr` in session `id`, or returns `None` if there is no such session.
+    pub async fn eval(&mut self, id: SessionId, expr: String) -> Option<EvalResponse> {
+        self.eval_as(id, None, expr).await
+    }
+
+    /// Like `eval`, recording `client` as the origin of `expr` in the audit log.
+    pub async fn eval_as(
+        &mut self,
+        id: SessionId,
+        client: Option<&str>,
+        expr: String,
+    ) -> Option<EvalResponse> {
+        let source = self.audit.as_ref().map(|_| expr.clone());
+        let started = Instant::now();
+        let response = self.run_eval(id, expr).await?;
+        if let (Some(audit), Some(source)) = (&mut self.audit, source) {
+            let status = AuditStatus::of(&response);
+            if audit
+                .record(id, client, &source, status, started.elapsed())
+                .is_err()
+            {
+                self.stats.audit_failures += 1;
+            }
+        }
+        Some(response)
+    }
+
+    async fn run_eval(&mut self, id: SessionId, expr: String) -> Option<EvalResponse> {
+        let managed = self.sessions.get_mut(&id)?;
+        let response = match self.liveness_timeout {
+            None => Some(managed.session.eval(expr).await),
+            Some(timeout) => {
+                let liveness = managed.session.liveness();
+                let eval = managed.session.eval(expr);
+                tokio::pin!(eval);
+                loop {
+                    if let Ok(response) = tokio::time::timeout(timeout, &mut eval).await {
+                        break Some(response);
+                    }
+                    if !liveness.ping(timeout).await {
+                        break None;
+                    }
+                }
+            }
+        };
+        managed.stats.evals += 1;
+        self.stats.evals += 1;
+
+        let response = match response {
+            Some(response) => response,
+            None => {
+                self.stats.wedged += 1;
+                match self.policy {
+                    LimitPolicy::ErrorAndContinue => {}
+                    LimitPolicy::ResetState => {
+                        // A reset would queue behind the stuck eval, so the
+                        // session gets a new interpreter instead.
+                        managed.session = Session::with_config(self.config.clone());
+                        managed.stats.limit_violations = 0;
+                        self.stats.resets += 1;
+                    }
+                    LimitPolicy::Terminate => {
+                        self.sessions.remove(&id);
+                        self.stats.terminations += 1;
+                    }
+                }
+                return Some(EvalResponse {
+                    error_kind: Some(ErrorKind::Timeout),
+                    ..EvalResponse::failure()
+                });
+            }
+        };
+
+        if response.limit_violation.is_some() {
+            managed.stats.limit_violations += 1;
+            self.stats.limit_violations += 1;
+            if managed.stats.limit_violations >= self.max_violations {
+                match self.policy {
+                    LimitPolicy::ErrorAndContinue => {}
+                    LimitPolicy::ResetState => {
+                        managed.session.reset().await;
+                        managed.stats.limit_violations = 0;
+                        self.stats.resets += 1;
+                    }
+                    LimitPolicy::Terminate => {
+                        self.sessions.remove(&id);
+                        self.stats.terminations += 1;
+                    }
+                }
+            }
+        }
+
+        Some(response)
+    }
+
+    pub fn session_stats(&self, id: SessionId) -> Option<SessionStats> {
+        self.sessions.get(&id).map(|managed| managed.stats)
+    }
+
+    pub fn stats(&self) -> ManagerStats {
+        ManagerStats {
+            open_sessions: self.sessions.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::limits::LimitViolation;
+    use crate::limits::Limits;
+    use crate::LuaValue;
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            limits: Limits {
+                instructions: Some(100_000),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reset_policy() {
+        let mut manager = SessionManager::new(config(), LimitPolicy::ResetState, 2);
+        let id = manager.open();
+        manager.eval(id, "x = 1".to_string()).await;
+
+        for _ in 0..2 {
+            let resp = manager
+                .eval(id, "while true do end".to_string())
+                .await
+                .unwrap();
+            assert_eq!(resp.limit_violation, Some(LimitViolation::Instructions));
+        }
+
+        let resp = manager.eval(id, "return x".to_string()).await.unwrap();
+        assert_eq!(resp.value, LuaValue::Nil);
+        assert_eq!(manager.stats().resets, 1);
+        assert_eq!(manager.stats().limit_violations, 2);
+        assert_eq!(manager.session_stats(id).unwrap().limit_violations, 0);
+    }
+
+    #[tokio::test]
+    async fn test_wedged_session() {
+        let mut manager = SessionManager::new(config(), LimitPolicy::ResetState, 2)
+            .liveness_timeout(Duration::from_millis(100));
+        let id = manager.open();
+        manager.eval(id, "x = 1".to_string()).await;
+
+        // Blocking in C code runs no instructions, so nothing answers pings.
+        let resp = manager
+            .eval(id, "os.execute('sleep 2')".to_string())
+            .await
+            .unwrap();
+        assert!(!resp.success);
+        assert_eq!(resp.error_kind, Some(ErrorKind::Timeout));
+        assert_eq!(manager.stats().wedged, 1);
+        assert_eq!(manager.stats().resets, 1);
+
+        let resp = manager.eval(id, "return x".to_string()).await.unwrap();
+        assert!(resp.success);
+        assert_eq!(resp.value, LuaValue::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure() {
+        let mut manager = SessionManager::new(config(), LimitPolicy::ErrorAndContinue, 1);
+        let id = manager.open();
+        manager.eval(id, "x = 1".to_string()).await;
+
+        manager
+            .reconfigure(SessionConfig {
+                limits: Limits {
+                    instructions: Some(1_000_000_000),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .await;
+        let resp = manager
+            .eval(id, "for i = 1, 1000000 do end return x".to_string())
+            .await
+            .unwrap();
+        assert_eq!(resp.value, LuaValue::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn test_terminate_policy() {
+        let mut manager = SessionManager::new(config(), LimitPolicy::Terminate, 1);
+        let id = manager.open();
+
+        assert!(manager
+            .eval(id, "while true do end".to_string())
+            .await
+            .is_some());
+        assert!(!manager.contains(id));
+        assert!(manager.eval(id, "return 1".to_string()).await.is_none());
+        assert_eq!(manager.stats().terminations, 1);
+        assert_eq!(manager.stats().open_sessions, 0);
+    }
+}
@@ -0,0 +1,211 @@
+//! Memory profiling for hunting leaks in long-running sessions: how much each
+//! eval grew the Lua heap by, and approximately which functions allocated.
+//!
+//! rlua owns the allocator and only exposes its running total, which Lua
+//! reports through `collectgarbage("count")`. The instruction hook samples
+//! that total, so allocations are attributed to whichever function was
+//! running at the sample after them, every `INSTRUCTION_GRANULARITY`
+//! instructions. Growth per eval is measured after full collections on both
+//! sides, so it counts only what the eval left reachable.
+
+use rlua::Context;
+use rlua::Debug;
+use rlua::Function;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+const COLLECTGARBAGE_KEY: &str = "luarepl.collectgarbage";
+
+/// How many evals a profile remembers.
+const EVALS_KEPT: usize = 32;
+
+/// Longest eval source kept in a profile, in characters.
+const SOURCE_LEN: usize = 60;
+
+/// What one eval left allocated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalMemory {
+    /// The eval's first line, shortened.
+    pub source: String,
+    /// Bytes still reachable afterwards minus before; negative if it freed.
+    pub growth: i64,
+}
+
+/// Bytes allocated while a function ran, garbage included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionMemory {
+    /// Its name if the caller knew one, and where it was defined.
+    pub function: String,
+    pub allocated: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryProfile {
+    /// Bytes the Lua state has allocated now.
+    pub used: usize,
+    /// The most recent evals, oldest first.
+    pub evals: Vec<EvalMemory>,
+    /// Every function seen allocating, most allocated first.
+    pub functions: Vec<FunctionMemory>,
+}
+
+/// The samples of one session, kept across resets.
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    evals: VecDeque<EvalMemory>,
+    functions: HashMap<String, usize>,
+    /// The total at the last sample.
+    last: usize,
+    /// The total after the collection that started the running eval.
+    baseline: Option<usize>,
+}
+
+/// Keeps the standard `collectgarbage`, which Lua code may replace.
+pub(crate) fn install(ctx: Context) -> rlua::Result<()> {
+    let collectgarbage: Function = ctx.globals().get("collectgarbage")?;
+    ctx.set_named_registry_value(COLLECTGARBAGE_KEY, collectgarbage)
+}
+
+fn collectgarbage(ctx: Context, option: &str) -> Option<f64> {
+    let collectgarbage: Function = ctx.named_registry_value(COLLECTGARBAGE_KEY).ok()?;
+    collectgarbage.call(option).ok()
+}
+
+/// Bytes in use, or 0 if `install` did not run.
+fn used(ctx: Context) -> usize {
+    collectgarbage(ctx, "count").map_or(0, |kilobytes| (kilobytes * 1024.0) as usize)
+}
+
+fn function_name(debug: &Debug) -> String {
+    let source = debug.source();
+    let text =
+        |bytes: Option<&[u8]>| String::from_utf8_lossy(bytes.unwrap_or_default()).into_owned();
+    let location = match source.what {
+        Some(b"main") => format!("main chunk of {}", text(source.short_src)),
+        _ => format!("{}:{}", text(source.short_src), source.line_defined),
+    };
+    match debug.names().name {
+        Some(name) => format!("{} ({})", String::from_utf8_lossy(name), location),
+        None => location,
+    }
+}
+
+impl Profiler {
+    pub fn profile(&self, ctx: Context) -> MemoryProfile {
+        let mut functions: Vec<FunctionMemory> = self
+            .functions
+            .iter()
+            .map(|(function, allocated)| FunctionMemory {
+                function: function.clone(),
+                allocated: *allocated,
+            })
+            .collect();
+        functions.sort_by(|a, b| (b.allocated, &a.function).cmp(&(a.allocated, &b.function)));
+        MemoryProfile {
+            used: used(ctx),
+            evals: self.evals.iter().cloned().collect(),
+            functions,
+        }
+    }
+
+    /// Called from the instruction hook while Lua code runs.
+    pub fn sample(&mut self, ctx: Context, debug: &Debug) {
+        let now = used(ctx);
+        if now > self.last {
+            *self.functions.entry(function_name(debug)).or_default() += now - self.last;
+        }
+        self.last = now;
+    }
+
+    /// Starts measuring an eval.
+    pub fn begin(&mut self, ctx: Context) {
+        collectgarbage(ctx, "collect");
+        self.last = used(ctx);
+        self.baseline = Some(self.last);
+    }
+
+    /// Records the growth since `begin` as that of `source`.
+    pub fn end(&mut self, ctx: Context, source: &str) {
+        let Some(baseline) = self.baseline.take() else {
+            return;
+        };
+        collectgarbage(ctx, "collect");
+        let after = used(ctx);
+        let line = source.lines().next().unwrap_or_default();
+        let mut shortened: String = line.chars().take(SOURCE_LEN).collect();
+        if shortened.len() < source.trim_end().len() {
+            shortened.push('…');
+        }
+        if self.evals.len() == EVALS_KEPT {
+            self.evals.pop_front();
+        }
+        self.evals.push_back(EvalMemory {
+            source: shortened,
+            growth: after as i64 - baseline as i64,
+        });
+        self.last = after;
+    }
+}
+
+/// `profile` as text for `:memprofile`.
+pub fn report(profile: &MemoryProfile) -> String {
+    let mut report = format!(
+        "{} bytes in use\n\nretained by recent evals:\n",
+        profile.used
+    );
+    for eval in &profile.evals {
+        report.push_str(&format!("{:>+10}  {}\n", eval.growth, eval.source));
+    }
+    report.push_str("\nallocated by function:\n");
+    for function in profile.functions.iter().take(10) {
+        report.push_str(&format!(
+            "{:>10}  {}\n",
+            function.allocated, function.function
+        ));
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_eval_growth() {
+        let mut session = Session::builder().memory_profile(true).build();
+        session
+            .eval("leak = {} for i = 1, 1000 do leak[i] = { i } end".to_string())
+            .await;
+        session
+            .eval("local t = {} for i = 1, 1000 do t[i] = { i } end".to_string())
+            .await;
+        let profile = session.memory_profile().await.unwrap();
+        assert_eq!(profile.evals.len(), 2);
+        assert!(profile.evals[0].source.starts_with("leak = {}"));
+        assert!(profile.evals[0].growth > 32_000, "{:?}", profile.evals);
+        assert!(profile.evals[1].growth < 1_000, "{:?}", profile.evals);
+        assert!(profile.used > 0);
+
+        assert!(Session::new().memory_profile().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_function_attribution() {
+        let mut session = Session::builder().memory_profile(true).build();
+        session
+            .eval(
+                "function build(n) local t = {} for i = 1, n do t[i] = { i } end return t end"
+                    .to_string(),
+            )
+            .await;
+        session.eval("kept = build(20000)".to_string()).await;
+        let profile = session.memory_profile().await.unwrap();
+        let top = &profile.functions[0];
+        assert!(
+            top.function.starts_with("build ("),
+            "{:?}",
+            profile.functions
+        );
+        assert!(top.allocated > 100_000);
+    }
+}
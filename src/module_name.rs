@@ -0,0 +1,17 @@
+use std::path::Path;
+
+/// The name `require` finds the module at `relative`, a `.lua` path under
+/// the embedded directory, by: `foo/bar.lua` is `foo.bar` and
+/// `foo/init.lua` is `foo`. Shared by `build.rs` and the tests of
+/// `embedded`.
+pub fn module_name(relative: &Path) -> String {
+    let mut parts: Vec<_> = relative
+        .with_extension("")
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if parts.len() > 1 && parts.last().map(String::as_str) == Some("init") {
+        parts.pop();
+    }
+    parts.join(".")
+}
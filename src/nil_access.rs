@@ -0,0 +1,236 @@
+//! What was nil when an eval failed with "attempt to index a nil value" or
+//! "attempt to call a nil value", and what was there instead, so that
+//! frontends can suggest the name that was probably meant.
+
+use crate::explain;
+use crate::path;
+use rlua::Context;
+use rlua::Error;
+use rlua::Table;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+
+/// How many suggestions a diagnosis offers at most.
+const MAX_SUGGESTIONS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NilAccess {
+    /// The expression that was nil, e.g. `config.servr`.
+    pub path: String,
+    /// The nearest enclosing value that exists, `_G` for globals.
+    pub ancestor: String,
+    /// The string keys of `ancestor`, sorted.
+    pub keys: Vec<String>,
+    /// Keys of `ancestor` close enough to the missing one to be typos of it,
+    /// closest first, as paths. Only functions are suggested for calls.
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for NilAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` is nil", self.path)?;
+        if !self.suggestions.is_empty() {
+            write!(f, "; did you mean: {}?", self.suggestions.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Levenshtein distance between `a` and `b`, by characters.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidates close enough to `name` to be typos of it, closest first
+/// and at most `MAX_SUGGESTIONS` of them.
+pub(crate) fn similar<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a String>,
+) -> Vec<String> {
+    let max = name.chars().count().div_ceil(3);
+    let mut similar: Vec<(usize, &String)> = candidates
+        .into_iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max)
+        .collect();
+    similar.sort();
+    similar
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate.clone())
+        .collect()
+}
+
+/// The string keys of `table`, sorted, and those of them holding functions.
+fn string_keys(table: Table) -> (Vec<String>, Vec<String>) {
+    let mut keys = vec![];
+    let mut functions = vec![];
+    for (key, value) in table.pairs::<Value, Value>().filter_map(Result::ok) {
+        let Value::String(key) = key else {
+            continue;
+        };
+        let Ok(key) = key.to_str() else {
+            continue;
+        };
+        if matches!(value, Value::Function(_)) {
+            functions.push(key.to_string());
+        }
+        keys.push(key.to_string());
+    }
+    keys.sort();
+    functions.sort();
+    (keys, functions)
+}
+
+/// Paths in `source` that are followed by `.name` and then indexed again,
+/// such as `config` in `config.name.host`, or called if `call` is set, such
+/// as `string` in `string.name(s)`.
+fn prefixes<'s>(source: &'s str, name: &str, call: bool) -> Vec<&'s str> {
+    let field = format!(".{}", name);
+    let bytes = source.as_bytes();
+    let mut prefixes = vec![];
+    for (at, _) in source.match_indices(&field) {
+        let after = source[at + field.len()..].trim_start();
+        let next = after.chars().next();
+        let ends_name = source[at + field.len()..]
+            .chars()
+            .next()
+            .is_none_or(|c| !(c.is_ascii_alphanumeric() || c == '_'));
+        let followed = match call {
+            true => matches!(next, Some('(' | '"' | '\'' | '{')),
+            false => matches!(next, Some('.' | '[' | ':')),
+        };
+        if !ends_name || !followed {
+            continue;
+        }
+        let mut start = at;
+        while start > 0
+            && (bytes[start - 1].is_ascii_alphanumeric() || matches!(bytes[start - 1], b'_' | b'.'))
+        {
+            start -= 1;
+        }
+        prefixes.push(&source[start..at]);
+    }
+    prefixes
+}
+
+/// Explains `error` if it came from indexing or calling a nil global or
+/// field that `source` reaches through a path.
+pub(crate) fn diagnose(ctx: Context, source: &str, error: &Error) -> Option<NilAccess> {
+    let message = error.to_string();
+    let (action, kind, name) = explain::nil_value(&message)?;
+    let call = match action {
+        "index" => false,
+        "call" => true,
+        _ => return None,
+    };
+    let name = name.to_string();
+    // Calls can only have meant a function.
+    let suggest = |(keys, functions): &(Vec<String>, Vec<String>)| match call {
+        true => similar(&name, functions),
+        false => similar(&name, keys),
+    };
+    match kind {
+        "global" => {
+            let keys = string_keys(ctx.globals());
+            Some(NilAccess {
+                suggestions: suggest(&keys),
+                path: name.clone(),
+                ancestor: "_G".to_string(),
+                keys: keys.0,
+            })
+        }
+        "field" => prefixes(source, &name, call)
+            .into_iter()
+            .find_map(|prefix| {
+                let segments = path::parse_path(prefix).ok()?;
+                let Value::Table(table) = path::resolve(ctx, &segments).ok()? else {
+                    return None;
+                };
+                if !matches!(table.get::<_, Value>(name.as_str()).ok()?, Value::Nil) {
+                    return None;
+                }
+                let keys = string_keys(table);
+                Some(NilAccess {
+                    path: format!("{}.{}", prefix, name),
+                    suggestions: suggest(&keys)
+                        .into_iter()
+                        .map(|key| format!("{}.{}", prefix, key))
+                        .collect(),
+                    ancestor: prefix.to_string(),
+                    keys: keys.0,
+                })
+            }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[test]
+    fn test_similar() {
+        let names = ["servers", "ports", "timeout", "server"].map(String::from);
+        assert_eq!(similar("servr", &names), ["server", "servers"]);
+        assert_eq!(similar("timeuot", &names), ["timeout"]);
+        assert!(similar("xyz", &names).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_nil_access() {
+        let mut session = Session::new();
+        session
+            .eval("config = { servers = { { host = 'a' } }, port = 1 }".to_string())
+            .await;
+        let resp = session
+            .eval("return #config.servr[1].host".to_string())
+            .await;
+        let access = resp.nil_access.expect("the nil field is diagnosed");
+        assert_eq!(access.path, "config.servr");
+        assert_eq!(access.ancestor, "config");
+        assert_eq!(access.keys, ["port", "servers"]);
+        assert_eq!(access.suggestions, ["config.servers"]);
+        assert_eq!(
+            access.to_string(),
+            "`config.servr` is nil; did you mean: config.servers?"
+        );
+
+        let resp = session.eval("return confg.port".to_string()).await;
+        let access = resp.nil_access.expect("the nil global is diagnosed");
+        assert_eq!(access.ancestor, "_G");
+        assert_eq!(access.suggestions, ["config"]);
+
+        let resp = session.eval("local t; return t.x".to_string()).await;
+        assert_eq!(resp.nil_access, None);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_functions() {
+        let mut session = Session::new();
+        session.eval("prin = 1".to_string()).await;
+        let resp = session.eval("prnt('hi')".to_string()).await;
+        let access = resp.nil_access.expect("the nil call is diagnosed");
+        assert_eq!(access.suggestions, ["print"]);
+        assert_eq!(access.to_string(), "`prnt` is nil; did you mean: print?");
+
+        let resp = session.eval("return string.uper('a')".to_string()).await;
+        let access = resp.nil_access.expect("the nil method is diagnosed");
+        assert_eq!(access.path, "string.uper");
+        assert_eq!(access.suggestions, ["string.upper"]);
+    }
+}
@@ -0,0 +1,235 @@
+//! How much of an eval the frontend shows, from just the value to the whole
+//! response. Scripts consuming luarepl pick the least they need to parse.
+
+use crate::limits::LimitViolation;
+use crate::value_id;
+use crate::EvalResponse;
+use crate::GraphStats;
+use crate::LuaObject;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::MultiValue;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLevel {
+    /// One line with the value; `print` is silenced while the eval runs.
+    Minimal,
+    /// One line with the value, after whatever the eval printed.
+    Standard,
+    /// The value with the tables it references expanded, and any traceback
+    /// and explanation, see `render`.
+    #[default]
+    Full,
+}
+
+/// Drops what `level` does not show, so that it is not sent around either.
+pub(crate) fn trim(response: &mut EvalResponse, level: OutputLevel) {
+    if level != OutputLevel::Full {
+        response.objects.clear();
+        response.graph_stats = GraphStats::default();
+        response.explanation = None;
+    }
+}
+
+/// Runs `eval` with `print` replaced by a function that does nothing.
+pub(crate) fn silenced<'l, R>(ctx: Context<'l>, eval: impl FnOnce() -> R) -> R {
+    let globals = ctx.globals();
+    let print: Value = globals.get("print").unwrap_or(Value::Nil);
+    let silent = match ctx.create_function(|_, _: MultiValue| Ok(())) {
+        Ok(silent) => silent,
+        Err(_) => return eval(),
+    };
+    let silent_id = value_id(ctx, Value::Function(silent.clone()));
+    let _ = globals.set("print", silent);
+    let result = eval();
+    // Keep whatever the eval itself assigned to `print`.
+    let current: Value = globals.get("print").unwrap_or(Value::Nil);
+    if value_id(ctx, current) == silent_id {
+        let _ = globals.set("print", print);
+    }
+    result
+}
+
+fn value_text(value: &LuaValue) -> String {
+    match value {
+        LuaValue::Nil => "nil".to_string(),
+        LuaValue::Boolean(b) => b.to_string(),
+        LuaValue::Number(_) | LuaValue::Integer(_) => value.to_string(),
+        LuaValue::String(s) => format!("{:?}", s),
+        LuaValue::ObjectRef(id) => id.clone(),
+        LuaValue::FunctionRef(function) => function.to_string(),
+        LuaValue::Coroutine(coroutine) => coroutine.to_string(),
+        LuaValue::UserData(userdata) => userdata.to_string(),
+        LuaValue::LightUserData(_) => value.to_string(),
+    }
+}
+
+/// The text a frontend prints for `response`. Minimal and standard output is
+/// a single line: the value, or `error` followed by the violated limit or the
+/// error message.
+pub fn render(response: &EvalResponse, level: OutputLevel) -> String {
+    if level == OutputLevel::Full {
+        return response.to_string();
+    }
+    match response.limit_violation {
+        _ if response.success && !response.assignments.is_empty() => response
+            .assignments
+            .iter()
+            .map(|(name, value)| format!("{} = {}", name, value_text(value)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        _ if response.success && response.values.len() > 1 => response
+            .values
+            .iter()
+            .map(value_text)
+            .collect::<Vec<_>>()
+            .join(", "),
+        _ if response.success => value_text(&response.value),
+        _ => match error_text(response) {
+            Some(text) => format!("error: {}", text),
+            None => "error".to_string(),
+        },
+    }
+}
+
+/// Why a failed eval failed: the violated limit, what was nil, or the error
+/// message.
+pub(crate) fn error_text(response: &EvalResponse) -> Option<String> {
+    match (
+        &response.limit_violation,
+        &response.nil_access,
+        &response.error,
+    ) {
+        (Some(LimitViolation::Memory), _, _) => Some("memory limit exceeded".to_string()),
+        (Some(LimitViolation::Instructions), _, _) => {
+            Some("instruction limit exceeded".to_string())
+        }
+        (None, Some(access), _) => Some(access.to_string()),
+        (None, None, message) => message.clone(),
+    }
+}
+
+/// One line of `--json` output.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    success: bool,
+    value: &'a LuaValue,
+    objects: &'a HashMap<String, LuaObject>,
+    /// Also set for limit violations, which have no error message.
+    error: Option<String>,
+    /// What a failed eval raised, if not a string.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_value: Option<&'a LuaValue>,
+    /// What the eval printed, if the session captures its output.
+    output: &'a [String],
+}
+
+/// `response` as a single line of JSON, for editors and scripts driving the
+/// binary. Objects are only included if the response has them, i.e. at
+/// `OutputLevel::Full`.
+pub fn json(response: &EvalResponse) -> String {
+    let line = JsonLine {
+        success: response.success,
+        value: &response.value,
+        objects: &response.objects,
+        error: match response.success {
+            true => None,
+            false => error_text(response),
+        },
+        error_value: response.error_value.as_ref(),
+        output: &response.output,
+    };
+    serde_json::to_string(&line).expect("responses serialize")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EvalOptions;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_levels() {
+        let mut session = Session::builder()
+            .output_level(OutputLevel::Standard)
+            .build();
+        let resp = session.eval("return {1}".to_string()).await;
+        assert!(resp.objects.is_empty());
+        assert!(render(&resp, OutputLevel::Standard).starts_with("table: "));
+        let resp = session.eval("return 'a' .. 1".to_string()).await;
+        assert_eq!(render(&resp, OutputLevel::Minimal), "\"a1\"");
+        let resp = session.eval("error('x')".to_string()).await;
+        assert_eq!(
+            render(&resp, OutputLevel::Minimal),
+            "error: [string \"?\"]:1: x"
+        );
+
+        let full = EvalOptions {
+            output_level: Some(OutputLevel::Full),
+            ..Default::default()
+        };
+        let resp = session.eval_with("return {1}".to_string(), full).await;
+        assert_eq!(resp.objects.len(), 1);
+        assert_eq!(render(&resp, OutputLevel::Full), "{ 1 }");
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let mut session = Session::builder().capture_output(true).build();
+        let resp = session
+            .eval("print('hi') return { 'a', n = 1.5 }".to_string())
+            .await;
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let line: serde_json::Value = serde_json::from_str(&json(&resp)).unwrap();
+        assert_eq!(line["success"], true);
+        assert_eq!(line["value"]["object_ref"], id.as_str());
+        let members = line["objects"][id]["members"].as_array().unwrap();
+        assert!(members.contains(&serde_json::json!([{ "integer": 1 }, { "string": "a" }])));
+        assert!(members.contains(&serde_json::json!([{ "string": "n" }, { "number": 1.5 }])));
+        assert_eq!(line["error"], serde_json::Value::Null);
+        assert_eq!(line["output"], serde_json::json!(["hi"]));
+
+        let line = json(&session.eval("error('x', 0)".to_string()).await);
+        assert!(!line.contains('\n'));
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["success"], false);
+        assert_eq!(line["value"], "nil");
+        assert_eq!(line["error"], "x");
+    }
+
+    #[tokio::test]
+    async fn test_minimal_silences_print() {
+        let mut session = Session::builder()
+            .output_level(OutputLevel::Minimal)
+            .build();
+        let standard = || EvalOptions {
+            output_level: Some(OutputLevel::Standard),
+            ..Default::default()
+        };
+        session
+            .eval_with("original = print".to_string(), standard())
+            .await;
+        let resp = session.eval("return print == original".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Boolean(false));
+        let resp = session
+            .eval_with("return print == original".to_string(), standard())
+            .await;
+        assert_eq!(resp.value, LuaValue::Boolean(true));
+
+        // Redefinitions made by the eval outlive it.
+        session
+            .eval("function print() end; mine = print".to_string())
+            .await;
+        let resp = session
+            .eval_with("return print == mine".to_string(), standard())
+            .await;
+        assert_eq!(resp.value, LuaValue::Boolean(true));
+    }
+}
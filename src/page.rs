@@ -0,0 +1,130 @@
+//! Paging through large tables with `Session::expand_with`, so that clients
+//! can show tables with hundreds of thousands of entries a screen at a time
+//! and search their keys without receiving all of them.
+//!
+//! Pages follow Lua's traversal order, which is stable as long as the table
+//! is not modified between requests.
+
+use crate::EvalResponse;
+use rlua::Value;
+
+/// Which entries of the expanded table to serialize. Only the table itself
+/// is paged; the tables its entries reference are not.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpandOptions {
+    /// How many matching entries to skip.
+    pub offset: usize,
+    /// How many matching entries to serialize at most; all if `None`.
+    pub limit: Option<usize>,
+    /// Only entries whose string or number key contains this.
+    pub key_filter: Option<String>,
+}
+
+impl ExpandOptions {
+    pub(crate) fn matches(&self, key: &Value) -> bool {
+        let filter = match &self.key_filter {
+            Some(filter) => filter,
+            None => return true,
+        };
+        match key {
+            Value::String(key) => key.to_str().is_ok_and(|key| key.contains(filter.as_str())),
+            Value::Integer(key) => key.to_string().contains(filter.as_str()),
+            Value::Number(key) => key.to_string().contains(filter.as_str()),
+            _ => false,
+        }
+    }
+
+    /// Whether the `index`th matching entry, counting from 0, is on the page.
+    pub(crate) fn includes(&self, index: usize) -> bool {
+        index >= self.offset && self.limit.is_none_or(|limit| index - self.offset < limit)
+    }
+}
+
+/// One page of an expanded table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    pub response: EvalResponse,
+    /// How many entries match the filter, on this page or not.
+    pub total: usize,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hardening::Hardening;
+    use crate::LuaValue;
+    use crate::Session;
+
+    async fn big_table() -> (Session, String) {
+        let mut session = Session::builder()
+            .hardening(Hardening {
+                max_depth: 1,
+                ..Default::default()
+            })
+            .build();
+        let resp = session
+            .eval(
+                "local t = {} for i = 1, 1000 do t['player' .. i] = { hp = i } end \
+                 return { players = t }"
+                    .to_string(),
+            )
+            .await;
+        let LuaValue::ObjectRef(root) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let Some(LuaValue::ObjectRef(players)) = resp.objects[root].get("players").cloned() else {
+            panic!("expected the players in {:?}", resp);
+        };
+        (session, players)
+    }
+
+    fn page_ids(page: &Page) -> Vec<String> {
+        let LuaValue::ObjectRef(root) = &page.response.value else {
+            panic!("expected a table, got {:?}", page.response.value);
+        };
+        page.response.objects[root]
+            .members
+            .iter()
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pages() {
+        let (mut session, players) = big_table().await;
+        let options = |offset| ExpandOptions {
+            offset,
+            limit: Some(300),
+            ..Default::default()
+        };
+        let mut seen = vec![];
+        for offset in [0, 300, 600, 900] {
+            let page = session
+                .expand_with(players.clone(), options(offset))
+                .await
+                .unwrap();
+            assert_eq!(page.total, 1000);
+            seen.extend(page_ids(&page));
+        }
+        assert_eq!(seen.len(), 1000);
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_key_filter() {
+        let (mut session, players) = big_table().await;
+        let filtered = ExpandOptions {
+            key_filter: Some("player99".to_string()),
+            limit: Some(5),
+            ..Default::default()
+        };
+        let page = session.expand_with(players, filtered).await.unwrap();
+        // player99 and player990 through player999.
+        assert_eq!(page.total, 11);
+        let ids = page_ids(&page);
+        assert_eq!(ids.len(), 5);
+        assert!(ids.iter().all(|id| id.contains("player99")));
+    }
+}
@@ -0,0 +1,469 @@
+//! Dotted/indexed paths into the global object graph, such as
+//! `config.servers[3].host` or `t["some key"]`, resolved without generating
+//! Lua source. A path may also start with an index, as `["end"]` does for
+//! a global that names cannot reach.
+
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Table;
+use rlua::Value;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    /// A string key, written `.name` or `["name"]`.
+    Key(String),
+    Index(i64),
+    /// A float key, written `[1.5]`; Lua stores integral ones as integers.
+    Number(f64),
+    Boolean(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathError {
+    /// The path could not be parsed; `position` is a byte offset into it.
+    Syntax {
+        position: usize,
+        message: String,
+    },
+    /// Nothing is stored at `path`, a prefix of the requested path.
+    NotFound {
+        path: String,
+    },
+    /// `path` holds a value of type `type_name` that cannot be indexed.
+    NotATable {
+        path: String,
+        type_name: String,
+    },
+    /// The value cannot be stored into Lua (e.g. an `ObjectRef`).
+    Unsupported(String),
+    Lua(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Syntax { position, message } => {
+                write!(f, "invalid path at offset {}: {}", position, message)
+            }
+            PathError::NotFound { path } => write!(f, "{} is nil", path),
+            PathError::NotATable { path, type_name } => {
+                write!(f, "{} is a {}, not a table", path, type_name)
+            }
+            PathError::Unsupported(message) => write!(f, "{}", message),
+            PathError::Lua(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<rlua::Error> for PathError {
+    fn from(e: rlua::Error) -> Self {
+        PathError::Lua(e.to_string())
+    }
+}
+
+/// Returns the path of `key` inside a table reachable at `parent`.
+pub(crate) fn child_path(parent: &str, key: &Value) -> String {
+    match key {
+        Value::String(s) => match s.to_str() {
+            Ok(name) if is_identifier(name) && parent.is_empty() => name.to_string(),
+            Ok(name) if is_identifier(name) => format!("{}.{}", parent, name),
+            Ok(name) => format!("{}[{}]", parent, quote(name)),
+            Err(_) => format!("{}[?]", parent),
+        },
+        Value::Integer(i) => format!("{}[{}]", parent, i),
+        // Rust has no literal for infinity that Lua or `parse_key` read.
+        Value::Number(n) if n.is_infinite() => {
+            format!("{}[{}1e999]", parent, if *n < 0.0 { "-" } else { "" })
+        }
+        Value::Number(n) => format!("{}[{}]", parent, n),
+        Value::Boolean(b) => format!("{}[{}]", parent, b),
+        other => format!("{}[<{}>]", parent, other.type_name()),
+    }
+}
+
+pub(crate) const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Whether `name` can be written after a `.`: a Lua name that is not a
+/// keyword.
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+/// `text` as a Lua string literal, escaped the way `string.format("%q")`
+/// does, so that `parse_path` reads it back.
+pub(crate) fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\\' | '\n' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_ascii_control() => {
+                // Padded when a digit follows, which would otherwise be
+                // read as part of the escape.
+                if chars.peek().is_some_and(char::is_ascii_digit) {
+                    quoted.push_str(&format!("\\{:03}", c as u32));
+                } else {
+                    quoted.push_str(&format!("\\{}", c as u32));
+                }
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn parse_path(path: &str) -> Result<Vec<PathSegment>, PathError> {
+    let bytes = path.as_bytes();
+    let syntax = |position: usize, message: &str| PathError::Syntax {
+        position,
+        message: message.to_string(),
+    };
+    let name_at = |start: usize| -> Result<(String, usize), PathError> {
+        let end = path[start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(path.len(), |len| start + len);
+        if is_identifier(&path[start..end]) {
+            Ok((path[start..end].to_string(), end))
+        } else {
+            Err(syntax(start, "expected a name"))
+        }
+    };
+
+    let mut segments = vec![];
+    let mut pos = 0;
+    if !path.starts_with('[') {
+        let (name, end) = name_at(0)?;
+        segments.push(PathSegment::Key(name));
+        pos = end;
+    }
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'.' => {
+                let (name, end) = name_at(pos + 1)?;
+                segments.push(PathSegment::Key(name));
+                pos = end;
+            }
+            b'[' => {
+                let (segment, end) = parse_key(path, pos + 1)?;
+                if bytes.get(end) != Some(&b']') {
+                    return Err(syntax(end, "expected ]"));
+                }
+                segments.push(segment);
+                pos = end + 1;
+            }
+            _ => return Err(syntax(pos, "expected . or [")),
+        }
+    }
+    Ok(segments)
+}
+
+/// Parses the key of a `[...]` segment starting at `start`, returning it and
+/// the offset just past it.
+fn parse_key(path: &str, start: usize) -> Result<(PathSegment, usize), PathError> {
+    let rest = &path[start..];
+    let syntax = |position: usize, message: &str| PathError::Syntax {
+        position,
+        message: message.to_string(),
+    };
+
+    if let Some((key, len)) =
+        parse_string(rest).map_err(|position| syntax(start + position, "unterminated string"))?
+    {
+        return Ok((PathSegment::Key(key), start + len));
+    }
+
+    let end = rest.find(']').unwrap_or(rest.len());
+    let token = rest[..end].trim();
+    let invalid = || syntax(start, "expected a number, boolean or string key");
+    let segment = match token {
+        "true" => PathSegment::Boolean(true),
+        "false" => PathSegment::Boolean(false),
+        // Rust would also accept `inf` and `NaN`, which Lua does not.
+        _ if token.contains(|c: char| c.is_ascii_alphabetic() && !"eE".contains(c)) => {
+            return Err(invalid())
+        }
+        _ => match token.parse() {
+            Ok(i) => PathSegment::Index(i),
+            Err(_) => PathSegment::Number(token.parse().map_err(|_| invalid())?),
+        },
+    };
+    Ok((segment, start + end))
+}
+
+/// Parses the quoted string at the start of `text`, returning it and its
+/// length with the quotes, or `None` if `text` does not start with a quote.
+/// Fails with the offset of the opening quote if the string is unterminated.
+fn parse_string(text: &str) -> Result<Option<(String, usize)>, usize> {
+    let Some(quote) = text.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+        return Ok(None);
+    };
+    let mut string = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok(Some((string, i + 1))),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => string.push('\n'),
+                Some((_, 'r')) => string.push('\r'),
+                Some((_, 't')) => string.push('\t'),
+                Some((_, digit)) if digit.is_ascii_digit() => {
+                    // Up to three decimal digits, as in `\0` or `\010`.
+                    let mut code = digit.to_digit(10).unwrap();
+                    for _ in 0..2 {
+                        match chars.peek().and_then(|&(_, c)| c.to_digit(10)) {
+                            Some(d) => {
+                                code = code * 10 + d;
+                                chars.next();
+                            }
+                            None => break,
+                        }
+                    }
+                    string.extend(char::from_u32(code));
+                }
+                Some((_, c)) => string.push(c),
+                None => break,
+            },
+            c => string.push(c),
+        }
+    }
+    Err(0)
+}
+
+/// Parses a literal such as `100` (an integer), `-0.5`, `"text"`, `true` or `nil`, the
+/// values `:set` accepts. Like paths, literals never run as Lua.
+pub fn parse_literal(text: &str) -> Result<LuaValue, PathError> {
+    let text = text.trim();
+    let syntax = |position: usize, message: &str| PathError::Syntax {
+        position,
+        message: message.to_string(),
+    };
+    match parse_string(text).map_err(|position| syntax(position, "unterminated string"))? {
+        Some((string, len)) if len == text.len() => return Ok(LuaValue::String(string)),
+        Some((_, len)) => return Err(syntax(len, "unexpected text after the string")),
+        None => {}
+    }
+    match text {
+        "nil" => Ok(LuaValue::Nil),
+        "true" => Ok(LuaValue::Boolean(true)),
+        "false" => Ok(LuaValue::Boolean(false)),
+        // Rust would also accept `inf` and `NaN`, which Lua does not.
+        _ if text.contains(|c: char| c.is_ascii_alphabetic() && !"eE".contains(c)) => {
+            Err(syntax(0, "expected a number, string, boolean or nil"))
+        }
+        _ => match text.parse() {
+            Ok(i) => Ok(LuaValue::Integer(i)),
+            Err(_) => text.parse().map(LuaValue::Number),
+        }
+        .map_err(|_| syntax(0, "expected a number, string, boolean or nil")),
+    }
+}
+
+pub(crate) fn segment_value<'l>(
+    ctx: Context<'l>,
+    segment: &PathSegment,
+) -> Result<Value<'l>, PathError> {
+    Ok(match segment {
+        PathSegment::Key(key) => Value::String(ctx.create_string(key)?),
+        PathSegment::Index(i) => Value::Integer(*i),
+        PathSegment::Number(n) => Value::Number(*n),
+        PathSegment::Boolean(b) => Value::Boolean(*b),
+    })
+}
+
+/// Walks `segments` from `_G`, returning the value at the end of the path.
+pub(crate) fn resolve<'l>(
+    ctx: Context<'l>,
+    segments: &[PathSegment],
+) -> Result<Value<'l>, PathError> {
+    let mut current = Value::Table(ctx.globals());
+    let mut path = String::new();
+    for segment in segments {
+        let table = as_table(current, &path)?;
+        let key = segment_value(ctx, segment)?;
+        path = child_path(&path, &key);
+        current = table.get(key)?;
+    }
+    match current {
+        Value::Nil => Err(PathError::NotFound { path }),
+        value => Ok(value),
+    }
+}
+
+/// Stores `value` at the end of the path; every table along it must exist.
+pub(crate) fn assign<'l>(
+    ctx: Context<'l>,
+    segments: &[PathSegment],
+    value: Value<'l>,
+) -> Result<(), PathError> {
+    let (last, parents) = segments
+        .split_last()
+        .ok_or_else(|| PathError::Unsupported("empty path".to_string()))?;
+    let parent = if parents.is_empty() {
+        Value::Table(ctx.globals())
+    } else {
+        resolve(ctx, parents)?
+    };
+    let parent_path = parents.iter().try_fold(String::new(), |path, segment| {
+        Ok::<_, PathError>(child_path(&path, &segment_value(ctx, segment)?))
+    })?;
+    as_table(parent, &parent_path)?.set(segment_value(ctx, last)?, value)?;
+    Ok(())
+}
+
+fn as_table<'l>(value: Value<'l>, path: &str) -> Result<Table<'l>, PathError> {
+    match value {
+        Value::Table(table) => Ok(table),
+        Value::Nil => Err(PathError::NotFound {
+            path: path.to_string(),
+        }),
+        other => Err(PathError::NotATable {
+            path: path.to_string(),
+            type_name: other.type_name().to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("config.servers[3][\"a ]b\"][true]"),
+            Ok(vec![
+                PathSegment::Key("config".to_string()),
+                PathSegment::Key("servers".to_string()),
+                PathSegment::Index(3),
+                PathSegment::Key("a ]b".to_string()),
+                PathSegment::Boolean(true),
+            ])
+        );
+        assert!(parse_path("").is_err());
+        assert!(parse_path("a..b").is_err());
+        assert!(parse_path("a[x]").is_err());
+        assert!(parse_path("a[inf]").is_err());
+        assert!(parse_path("[").is_err());
+        assert!(parse_path("a['x'").is_err());
+        assert!(parse_path("t.end").is_err());
+    }
+
+    #[test]
+    fn test_child_path() {
+        let lua = rlua::Lua::new();
+        lua.context(|ctx| {
+            let key = |s: &str| Value::String(ctx.create_string(s).unwrap());
+            assert_eq!(child_path("t", &key("name")), "t.name");
+            assert_eq!(child_path("t", &key("end")), "t[\"end\"]");
+            // Whatever the key, its path parses back to it.
+            for (key, segment) in [
+                (key("end"), PathSegment::Key("end".to_string())),
+                (key("my key"), PathSegment::Key("my key".to_string())),
+                (Value::Integer(-3), PathSegment::Index(-3)),
+                (Value::Number(1.5), PathSegment::Number(1.5)),
+                (Value::Number(-1e-7), PathSegment::Number(-1e-7)),
+                (
+                    Value::Number(f64::INFINITY),
+                    PathSegment::Number(f64::INFINITY),
+                ),
+                (Value::Boolean(false), PathSegment::Boolean(false)),
+            ] {
+                let path = child_path("", &key);
+                assert_eq!(parse_path(&path), Ok(vec![segment.clone()]), "{}", path);
+                let path = child_path("t", &key);
+                assert_eq!(
+                    parse_path(&path),
+                    Ok(vec![PathSegment::Key("t".to_string()), segment]),
+                    "{}",
+                    path
+                );
+            }
+            let odd = "a\"b\\\n\r\0\u{1}2";
+            let path = child_path("t", &key(odd));
+            assert_eq!(
+                path,
+                r#"t["a\"b\\\
+\r\0\0012"]"#
+            );
+            assert_eq!(
+                parse_path(&path).unwrap()[1],
+                PathSegment::Key(odd.to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_parse_literal() {
+        assert_eq!(parse_literal(" 100 "), Ok(LuaValue::Integer(100)));
+        assert_eq!(parse_literal("-2.5e1"), Ok(LuaValue::Number(-25.0)));
+        assert_eq!(
+            parse_literal(r#""a \"b\"""#),
+            Ok(LuaValue::String("a \"b\"".to_string()))
+        );
+        assert_eq!(parse_literal("'x'"), Ok(LuaValue::String("x".to_string())));
+        assert_eq!(parse_literal("false"), Ok(LuaValue::Boolean(false)));
+        assert_eq!(parse_literal("nil"), Ok(LuaValue::Nil));
+        for bad in ["", "inf", "os.exit()", "'a' .. 'b'", "'open"] {
+            assert!(parse_literal(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_and_set_path() {
+        let mut session = Session::new();
+        session
+            .eval("config = { servers = { {host = 'a'}, 'b' } }".to_string())
+            .await;
+
+        let resp = session
+            .get_path("config.servers[1].host".to_string())
+            .await
+            .unwrap();
+        assert_eq!(resp.value, LuaValue::String("a".to_string()));
+
+        session
+            .set_path(
+                "config.servers[1].host".to_string(),
+                LuaValue::String("'); os.exit() --".to_string()),
+            )
+            .await
+            .unwrap();
+        let resp = session
+            .eval("return config.servers[1].host".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::String("'); os.exit() --".to_string()));
+
+        assert_eq!(
+            session.get_path("config.missing.x".to_string()).await,
+            Err(PathError::NotFound {
+                path: "config.missing".to_string()
+            })
+        );
+        assert_eq!(
+            session
+                .set_path("config.servers[2].x".to_string(), LuaValue::Nil)
+                .await,
+            Err(PathError::NotATable {
+                path: "config.servers[2]".to_string(),
+                type_name: "string".to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Objects kept alive between evals so that hosts can come back to them, and
+//! diagnostics for finding pins that are never released.
+
+use crate::path;
+use crate::path::PathError;
+use rlua::Context;
+use rlua::RegistryKey;
+use rlua::Value;
+use std::collections::HashMap;
+use std::panic::Location;
+
+/// A value held in the registry of a session's Lua state until it is
+/// unpinned or the state is reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Pin(u64);
+
+/// Pins created at `site` and still held.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinLeak {
+    /// `file:line` of the `Session::pin` call.
+    pub site: String,
+    pub count: usize,
+}
+
+/// The interpreter's side: the registry keys of one Lua state.
+#[derive(Default)]
+pub(crate) struct Pins {
+    keys: HashMap<Pin, RegistryKey>,
+    next: u64,
+}
+
+impl Pins {
+    pub fn pin(&mut self, ctx: Context, path: &str) -> Result<Pin, PathError> {
+        let value = path::parse_path(path).and_then(|segments| path::resolve(ctx, &segments))?;
+        let key = ctx.create_registry_value(value)?;
+        let pin = Pin(self.next);
+        self.next += 1;
+        self.keys.insert(pin, key);
+        Ok(pin)
+    }
+
+    pub fn get<'l>(&self, ctx: Context<'l>, pin: Pin) -> Option<Value<'l>> {
+        ctx.registry_value(self.keys.get(&pin)?).ok()
+    }
+
+    pub fn unpin(&mut self, ctx: Context, pin: Pin) -> bool {
+        match self.keys.remove(&pin) {
+            Some(key) => ctx.remove_registry_value(key).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// The session's side: where each live pin was created, in leak diagnostics
+/// mode.
+#[derive(Debug, Default)]
+pub(crate) struct PinSites(HashMap<Pin, &'static Location<'static>>);
+
+impl PinSites {
+    pub fn created(&mut self, pin: Pin, site: &'static Location<'static>) {
+        self.0.insert(pin, site);
+    }
+
+    pub fn released(&mut self, pin: Pin) {
+        self.0.remove(&pin);
+    }
+
+    /// Forgets every pin, after the Lua state holding them went away.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Live pins by creation site, most first.
+    pub fn leaks(&self) -> Vec<PinLeak> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for site in self.0.values() {
+            *counts
+                .entry(format!("{}:{}", site.file(), site.line()))
+                .or_default() += 1;
+        }
+        let mut leaks: Vec<PinLeak> = counts
+            .into_iter()
+            .map(|(site, count)| PinLeak { site, count })
+            .collect();
+        leaks.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.site.cmp(&b.site)));
+        leaks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_pins() {
+        let mut session = Session::new();
+        session.eval("t = { x = 1 }".to_string()).await;
+        let pin = session.pin("t".to_string()).await.unwrap();
+        session.eval("t = nil; collectgarbage()".to_string()).await;
+
+        let resp = session.pinned(pin).await.unwrap();
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        assert_eq!(resp.objects[id].get("x"), Some(&LuaValue::Integer(1)));
+        assert!(session.unpin(pin).await);
+        assert!(!session.unpin(pin).await);
+        assert!(session.pinned(pin).await.is_none());
+        assert!(session.pin("missing".to_string()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_leaks() {
+        let mut session = Session::builder().leak_diagnostics(true).build();
+        session.eval("a, b = {}, {}".to_string()).await;
+        let mut pins = vec![];
+        for _ in 0..3 {
+            pins.push(session.pin("a".to_string()).await.unwrap());
+        }
+        let other = session.pin("b".to_string()).await.unwrap();
+        session.unpin(pins[0]).await;
+
+        let leaks = session.leaks();
+        assert_eq!(leaks.len(), 2);
+        assert_eq!(leaks[0].count, 2);
+        assert!(leaks[0].site.starts_with("src/pin.rs:"));
+        assert_eq!(leaks[1].count, 1);
+
+        session.unpin(other).await;
+        session.reset().await;
+        assert!(session.leaks().is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! A bounded set of interpreter threads shared by many sessions, for servers
+//! that would otherwise spawn one OS thread per session.
+
+use crate::host::AsyncCall;
+use crate::Command;
+use crate::Interpreter;
+use crate::SessionConfig;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+
+enum Message {
+    Attach(
+        u64,
+        SessionConfig,
+        Arc<AtomicU64>,
+        UnboundedSender<AsyncCall>,
+    ),
+    Command(u64, Command),
+    Detach(u64),
+}
+
+/// Sessions are assigned to threads round-robin and keep their own Lua
+/// state. Commands of sessions sharing a thread run one after another, so a
+/// long eval delays its neighbours, including their `Session::ping`s.
+#[derive(Debug)]
+pub struct WorkerPool {
+    workers: Vec<mpsc::Sender<Message>>,
+    next_worker: AtomicUsize,
+    next_key: AtomicU64,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel();
+                thread::spawn(move || run_worker(receiver));
+                sender
+            })
+            .collect();
+        Self {
+            workers,
+            next_worker: AtomicUsize::new(0),
+            next_key: AtomicU64::new(0),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Starts a session's interpreter on one of the pool's threads.
+    pub(crate) fn attach(
+        &self,
+        mut config: SessionConfig,
+        heartbeat: Arc<AtomicU64>,
+        calls: UnboundedSender<AsyncCall>,
+    ) -> (UnboundedSender<Command>, JoinHandle<()>) {
+        let index = self.next_worker.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        let worker = self.workers[index].clone();
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        // Workers must not keep the pool, and with it themselves, alive.
+        config.pool = None;
+        let _ = worker.send(Message::Attach(key, config, heartbeat, calls));
+
+        let (command_sender, mut command_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            while let Some(command) = command_receiver.recv().await {
+                let _ = worker.send(Message::Command(key, command));
+            }
+            let _ = worker.send(Message::Detach(key));
+        });
+        (command_sender, task)
+    }
+}
+
+fn run_worker(messages: mpsc::Receiver<Message>) {
+    let mut interpreters: HashMap<u64, Interpreter> = HashMap::new();
+    loop {
+        let timeout = interpreters
+            .values()
+            .filter_map(Interpreter::idle_timeout)
+            .min();
+        let message = match timeout {
+            Some(timeout) => match messages.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    for interpreter in interpreters.values_mut() {
+                        interpreter.collect_idle();
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match messages.recv() {
+                Ok(message) => message,
+                Err(_) => return,
+            },
+        };
+        match message {
+            Message::Attach(key, config, heartbeat, calls) => {
+                interpreters.insert(key, Interpreter::new(config, heartbeat, calls));
+            }
+            Message::Command(key, command) => {
+                let interpreter = match interpreters.get_mut(&key) {
+                    Some(interpreter) => interpreter,
+                    // Dropping the command tells the session its interpreter died.
+                    None => continue,
+                };
+                let handled =
+                    std::panic::catch_unwind(AssertUnwindSafe(|| interpreter.handle(command)));
+                if handled.is_err() {
+                    interpreters.remove(&key);
+                }
+            }
+            Message::Detach(key) => {
+                interpreters.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::recovery::Recovery;
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_pooled_sessions() {
+        let pool = Arc::new(WorkerPool::new(2));
+        let mut sessions: Vec<Session> = (0..20)
+            .map(|_| {
+                Session::builder()
+                    .pool(pool.clone())
+                    .recovery(Recovery::Restart)
+                    .build()
+            })
+            .collect();
+        for (i, session) in sessions.iter_mut().enumerate() {
+            session.eval(format!("x = {}", i)).await;
+        }
+
+        // A crash only takes down the state of the session that caused it.
+        let _ = sessions[3].command_sender.send(Command::Crash);
+        assert!(!sessions[3].eval("return x".to_string()).await.success);
+        assert_eq!(
+            sessions[3].eval("return x".to_string()).await.value,
+            LuaValue::Nil
+        );
+
+        for (i, session) in sessions.iter_mut().enumerate().filter(|(i, _)| *i != 3) {
+            let resp = session.eval("return x".to_string()).await;
+            assert_eq!(resp.value, LuaValue::Integer(i as i64));
+        }
+        for session in sessions {
+            session.close().await;
+        }
+    }
+}
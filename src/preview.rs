@@ -0,0 +1,97 @@
+//! One-line summaries of tables, shown by UIs in place of collapsed objects.
+
+use crate::path::is_identifier;
+use rlua::Table;
+use rlua::Value;
+
+const MAX_ENTRIES: usize = 3;
+const MAX_STRING_LEN: usize = 16;
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::Nil => "nil".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => crate::float_text(*n),
+        Value::String(s) => {
+            let s = s.to_str().unwrap_or("?");
+            match s.char_indices().nth(MAX_STRING_LEN) {
+                Some((end, _)) => format!("{:?}", format!("{}…", &s[..end])),
+                None => format!("{:?}", s),
+            }
+        }
+        Value::Table(_) => "{…}".to_string(),
+        other => other.type_name().to_string(),
+    }
+}
+
+/// Renders up to a few entries of `table` Lua-style, e.g. `{1, 2, n=2, …}`.
+/// Leading sequence entries are shown without their keys.
+pub(crate) fn table_preview(table: Table) -> String {
+    let mut parts = vec![];
+    let mut next_index = 1;
+    let mut truncated = false;
+
+    for (key, value) in table.pairs::<Value, Value>().filter_map(Result::ok) {
+        if parts.len() == MAX_ENTRIES {
+            truncated = true;
+            break;
+        }
+        let part = match &key {
+            Value::Integer(i) if *i == next_index => {
+                next_index += 1;
+                scalar(&value)
+            }
+            Value::String(s) if s.to_str().is_ok_and(is_identifier) => {
+                format!("{}={}", s.to_str().unwrap(), scalar(&value))
+            }
+            _ => format!("[{}]={}", scalar(&key), scalar(&value)),
+        };
+        parts.push(part);
+    }
+
+    if truncated {
+        parts.push("…".to_string());
+    }
+    format!("{{{}}}", parts.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+    use rlua::Lua;
+
+    #[test]
+    fn test_table_preview() {
+        Lua::new().context(|ctx| {
+            let preview = |source: &str| table_preview(ctx.load(source).eval().unwrap());
+            assert_eq!(preview("{}"), "{}");
+            assert_eq!(preview("{1, 'a', {}}"), "{1, \"a\", {…}}");
+            assert_eq!(preview("{1, 2, 3, 4}"), "{1, 2, 3, …}");
+            assert_eq!(
+                preview("{name = 'a very long name indeed'}"),
+                "{name=\"a very long name…\"}"
+            );
+            assert_eq!(preview("{[true] = 1}"), "{[true]=1}");
+        });
+    }
+
+    #[tokio::test]
+    async fn test_child_previews() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("return { child = { n = 3 } }".to_string())
+            .await;
+        let root = match &resp.value {
+            LuaValue::ObjectRef(id) => &resp.objects[id],
+            other => panic!("Expected an object ref got {:?}!", other),
+        };
+        let child = match root.get("child") {
+            Some(LuaValue::ObjectRef(id)) => id,
+            other => panic!("Expected an object ref got {:?}!", other),
+        };
+        assert_eq!(root.child_previews[child], "{n=3}");
+    }
+}
@@ -0,0 +1,139 @@
+//! Status reports from long computations: Lua code calls `progress(pct, msg)`
+//! and the handler set with `SessionBuilder::on_progress` receives them while
+//! the eval is still running. The CLI draws them as a bar on stderr.
+
+use rlua::Context;
+use rlua::Error;
+use std::fmt;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Width of the CLI's progress bar, in characters between the brackets.
+const BAR_WIDTH: usize = 30;
+
+/// Whether the CLI's progress bar is on screen, leaving the cursor after it.
+static BAR_DRAWN: AtomicBool = AtomicBool::new(false);
+
+/// One call of `progress`, with `percent` clamped to 0..=100.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub percent: f64,
+    pub message: Option<String>,
+}
+
+/// Receives progress reports on the interpreter thread, so it should return
+/// quickly and hand them off if it has more to do.
+#[derive(Clone)]
+pub struct ProgressHandler(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl ProgressHandler {
+    pub fn new(handler: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(handler))
+    }
+}
+
+impl fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressHandler")
+    }
+}
+
+/// Defines the global `progress`, which does nothing without a handler.
+pub(crate) fn install(ctx: Context, handler: Option<&ProgressHandler>) -> Result<(), Error> {
+    let handler = handler.cloned();
+    let progress = ctx.create_function(move |_, (percent, message): (f64, Option<String>)| {
+        if let Some(handler) = &handler {
+            (handler.0)(&Progress {
+                percent: percent.clamp(0.0, 100.0),
+                message,
+            });
+        }
+        Ok(())
+    })?;
+    ctx.globals().set("progress", progress)
+}
+
+/// Renders `progress` like `[#######       ]  50% loading`.
+pub fn bar(progress: &Progress) -> String {
+    let filled = (progress.percent / 100.0 * BAR_WIDTH as f64).round() as usize;
+    let mut bar = format!(
+        "[{}{}] {:3.0}%",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        progress.percent
+    );
+    if let Some(message) = &progress.message {
+        bar.push(' ');
+        bar.push_str(message);
+    }
+    bar
+}
+
+/// The CLI's handler, redrawing a bar on stderr if it is a terminal.
+pub fn terminal_bar() -> ProgressHandler {
+    ProgressHandler::new(|progress| {
+        let mut stderr = std::io::stderr();
+        if !stderr.is_terminal() {
+            return;
+        }
+        let _ = write!(stderr, "\r\x1b[2K{}", bar(progress));
+        let _ = stderr.flush();
+        BAR_DRAWN.store(true, Ordering::Relaxed);
+    })
+}
+
+/// Removes the CLI's bar once the eval drawing it has finished.
+pub fn clear_terminal_bar() {
+    if BAR_DRAWN.swap(false, Ordering::Relaxed) {
+        eprint!("\r\x1b[2K");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_progress_reports() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let mut session = {
+            let reports = reports.clone();
+            Session::builder()
+                .on_progress(ProgressHandler::new(move |progress| {
+                    reports.lock().unwrap().push(progress.clone())
+                }))
+                .build()
+        };
+        let resp = session
+            .eval("for i = 1, 2 do progress(i * 75, 'step ' .. i) end; progress(0)".to_string())
+            .await;
+        assert!(resp.success);
+        let reports = reports.lock().unwrap().clone();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].percent, 75.0);
+        assert_eq!(reports[1].percent, 100.0);
+        assert_eq!(reports[1].message.as_deref(), Some("step 2"));
+        assert_eq!(reports[2].message, None);
+
+        let mut session = Session::new();
+        assert!(session.eval("progress(50)".to_string()).await.success);
+        assert!(!session.eval("progress('half')".to_string()).await.success);
+    }
+
+    #[test]
+    fn test_bar() {
+        let progress = Progress {
+            percent: 50.0,
+            message: Some("loading".to_string()),
+        };
+        assert_eq!(
+            bar(&progress),
+            format!("[{}{}]  50% loading", "#".repeat(15), " ".repeat(15))
+        );
+    }
+}
@@ -0,0 +1,727 @@
+//! The messages `server::serve` exchanges with its clients. Each message is
+//! one JSON frame naming the session it is about, so a single connection
+//! can drive several sessions, e.g. an editor's scratchpad, debugger and
+//! watch panel over one subprocess:
+//!
+//! ```json
+//! {"session":1,"op":"open"}
+//! {"session":1,"op":"eval","source":"return 6 * 7"}
+//! {"session":1,"op":"close"}
+//! ```
+//!
+//! Clients choose the session ids, which only need to be unique within
+//! their connection. The server answers every request with one reply for
+//! the same session:
+//!
+//! ```json
+//! {"session":1,"op":"opened"}
+//! {"session":1,"op":"result","success":true,"output":"42","error":null,"error_kind":null,"traceback":[],"printed":[],"stderr":[]}
+//! {"session":1,"op":"closed"}
+//! ```
+//!
+//! A failed eval's `error_kind` says why, as a `kind` of `"runtime"`,
+//! `"memory"`, `"callback"` or `"timeout"`, or for code that does not
+//! compile, whether more input could complete it. Frames keep this shape,
+//! which they have always had, even though `EvalResponse` serializes
+//! `ErrorKind` differently:
+//!
+//! ```json
+//! {"session":1,"op":"eval","source":"function f("}
+//! {"session":1,"op":"result","success":false,"output":"error: ...","error":"...","error_kind":{"kind":"syntax","incomplete":true},"traceback":[],"printed":[],"stderr":[]}
+//! ```
+//!
+//! An eval may carry an `id` of the client's choosing, which its reply
+//! repeats, so that clients can send several evals without waiting for each
+//! reply: `{"session":1,"op":"eval","source":"return 1","id":7}`.
+//!
+//! An editor sending part of a buffer can say where it is from, so that
+//! errors and tracebacks point back to it, e.g. `foo.lua:42:` rather than
+//! `[string "..."]:1:`, with `file`, or `chunk_name` for buffers without
+//! one, and the `line` the source starts at:
+//! `{"session":1,"op":"eval","source":"f()","file":"foo.lua","line":42}`.
+//!
+//! An eval's `output_level`, `minimal`, `standard` or `full`, overrides the
+//! server's for that eval, e.g. for a watch panel wanting one line:
+//! `{"session":1,"op":"eval","source":"return hp","output_level":"minimal"}`.
+//!
+//! Editors complete what is typed with `complete`, which answers with every
+//! way the prefix can go on, each the whole prefix with the name at its end
+//! completed:
+//!
+//! ```json
+//! {"session":1,"op":"complete","prefix":"x = string.fo"}
+//! {"session":1,"op":"completions","candidates":["x = string.format"]}
+//! ```
+//!
+//! A client can first send `hello` with the protocol version it speaks; the
+//! reply tells the server's, see `SessionInfo::check`. It is about the
+//! connection rather than a session, so its session id need not be open:
+//!
+//! ```json
+//! {"session":0,"op":"hello","protocol":1}
+//! {"session":0,"op":"hello","version":"0.1.0","lua_version":"Lua 5.4","protocol":1,"features":["capture_output","workspace"]}
+//! ```
+//!
+//! A server started with a token answers nothing until the client sends it
+//! with `hello`, e.g. `{"session":0,"op":"hello","protocol":1,"token":"..."}`;
+//! until then, every request gets an `error` reply.
+//!
+//! A client that lists the codecs it can decode with `hello` is answered
+//! with the one the server chose, see `compression`. Every message after
+//! that reply, both ways, starts with a byte saying how the rest of it is
+//! encoded; without a codec in common, or a list, messages stay plain JSON:
+//!
+//! ```json
+//! {"session":0,"op":"hello","protocol":1,"compression":["gzip","zstd"]}
+//! {"session":0,"op":"hello","version":"0.1.0",...,"compression":"zstd"}
+//! ```
+//!
+//! Replies to evals come in the format the client asks for, so integrations
+//! written against an older one keep working as responses gain fields. `v1`,
+//! the default, is the `result` reply above; `v2` is a `response` reply with
+//! every returned value and the tables they reference:
+//!
+//! ```json
+//! {"session":1,"op":"eval","source":"return 1, {2}","format":"v2"}
+//! {"session":1,"op":"response","success":true,"output":"1, { 2 }","values":[{"integer":1},{"object_ref":"table: 0x5581c0a3e2f0"}],"objects":{"table: 0x5581c0a3e2f0":{"members":[[{"integer":1},{"integer":2}]],"display":null,"child_previews":{},"type_name":null,"references":1}},"error":null,"printed":[],"stderr":[],"warnings":[]}
+//! ```
+//!
+//! A format given with `hello` applies to every eval of the connection that
+//! does not choose its own. So does `"delta":true`, with which `response`
+//! replies carry `deltas` against the objects the connection was sent before
+//! instead of `objects`, for clients polling the same large tables, see
+//! `delta`.
+//!
+//! An eval with `iterate` set to a limit keeps the iterator its chunk
+//! returns, see `EvalOptions::iterate`. After the eval's reply, the server
+//! streams an `item` reply for each value the iterator produces, up to the
+//! limit, and then an `exhausted` reply with how many there were:
+//!
+//! ```json
+//! {"session":1,"op":"eval","source":"return ipairs({'a'})","iterate":100}
+//! {"session":1,"op":"result","success":true,...}
+//! {"session":1,"op":"item","index":0,"success":true,"output":"1, \"a\"","values":[{"integer":1},{"string":"a"}],"error":null}
+//! {"session":1,"op":"exhausted","count":1}
+//! ```
+//!
+//! Every client has its own sessions, but any of them can list the sessions
+//! open on the server, each with the number of the client it belongs to, and
+//! kill one, e.g. to stop a runaway eval of another client. Like `hello`,
+//! these requests are about the server rather than the session they name:
+//!
+//! ```json
+//! {"session":0,"op":"list"}
+//! {"session":0,"op":"sessions","sessions":[{"client":1,"session":1,"peer":"127.0.0.1:50312","evals":3,"own":false}]}
+//! {"session":0,"op":"kill","client":1,"id":1}
+//! {"session":0,"op":"killed"}
+//! ```
+//!
+//! A killed session's running eval is cancelled, and requests its client
+//! sends for it are answered with an error.
+//!
+//! On a shared server, see `server::serve_shared`, the session a client
+//! opens is the one every client works in. Each eval a client runs there is
+//! also sent to the other attached clients, framed with the session id they
+//! opened, as a `broadcast` naming the client that ran it, with the reply in
+//! their own format:
+//!
+//! ```json
+//! {"session":1,"op":"broadcast","client":2,"peer":"127.0.0.1:50318","source":"return score","reply":{"op":"result","success":true,"output":"42",...}}
+//! ```
+//!
+//! While an eval runs, each call it makes of Lua's `progress` is sent to
+//! the client as a `progress` reply with the eval's `id`, ahead of its
+//! result, for a progress bar:
+//!
+//! ```json
+//! {"session":1,"op":"eval","source":"load_world()","id":3}
+//! {"session":1,"op":"progress","id":3,"percent":50.0,"message":"terrain"}
+//! {"session":1,"op":"result","id":3,"success":true,...}
+//! ```
+//!
+//! `help` and `apropos` look up the embedded reference behind the REPL's
+//! `:help` and `:apropos`, by name or by text, see `help`. Like `hello`,
+//! they need no open session; both are answered with `topics`:
+//!
+//! ```json
+//! {"session":0,"op":"help","name":"string.rep"}
+//! {"session":0,"op":"topics","topics":[{"name":"string.rep","signature":"string.rep(s, n [, sep]) -> string","description":"..."}]}
+//! {"session":0,"op":"apropos","pattern":"metatable"}
+//! ```
+//!
+//! A client can subscribe to a key path of a session with `watch_path`.
+//! After each request for the session that changed the value, the reply is
+//! followed by a `path_changed` reply with the new value:
+//!
+//! ```json
+//! {"session":1,"op":"watch_path","path":"world.players[7].hp"}
+//! {"session":1,"op":"watching","watch":0}
+//! {"session":1,"op":"eval","source":"world.players[7].hp = 9"}
+//! {"session":1,"op":"result","success":true,...}
+//! {"session":1,"op":"path_changed","watch":0,"path":"world.players[7].hp","output":"9","value":{"integer":9},"error":null}
+//! ```
+//!
+//! Files move between the client and the session's workspace in chunks of
+//! at most `MAX_CHUNK` bytes, base64-encoded. An upload is a series of
+//! `upload` requests with consecutive offsets, the last of which carries the
+//! CRC-32 of the whole file; a download is a series of `download` requests,
+//! and the reply whose chunk reaches the end of the file carries its CRC-32.
+
+use crate::delta::ObjectDelta;
+use crate::help::HelpTopic;
+use crate::info::SessionInfo;
+use crate::manager::SessionId;
+use crate::output;
+use crate::output::OutputLevel;
+use crate::progress::Progress;
+use crate::subscription::PathChange;
+use crate::traceback::StackFrame;
+use crate::ErrorKind;
+use crate::EvalResponse;
+use crate::LuaObject;
+use crate::LuaValue;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Bumped whenever a change to the frames would confuse existing clients.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest chunk of a file one upload request or download reply carries.
+pub const MAX_CHUNK: usize = 1024 * 1024;
+
+/// The shape of eval replies, see the module documentation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// `Reply::Result`: the rendered output and the error, if any.
+    #[default]
+    V1,
+    /// `Reply::Response`: also every value and object, with a structured
+    /// error.
+    V2,
+}
+
+/// A request or reply together with the session it is for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame<T> {
+    pub session: SessionId,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T: Serialize> Frame<T> {
+    pub fn new(session: SessionId, body: T) -> Self {
+        Self { session, body }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("frames serialize")
+    }
+}
+
+impl<T: for<'de> Deserialize<'de>> Frame<T> {
+    pub fn decode(message: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// Asks what the server runs. `protocol` is the version the client
+    /// speaks; the server replies with its own even if they differ.
+    Hello {
+        protocol: u32,
+        /// The format of the connection's eval replies from now on.
+        #[serde(default)]
+        format: Option<ResponseFormat>,
+        /// The server's token, if it requires one, see `auth`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// The codecs the client can decode, by name, see `compression`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        compression: Vec<String>,
+        /// Whether `v2` replies from now on send objects as deltas against
+        /// those the connection was sent before, see `delta`.
+        #[serde(default)]
+        delta: bool,
+    },
+    /// Starts a session with the server's configuration under this id.
+    Open,
+    /// Ends the session and frees its Lua state.
+    Close,
+    Eval {
+        source: String,
+        /// Echoed in the reply, so that clients sending several evals at
+        /// once can match up the replies.
+        #[serde(default)]
+        id: Option<u64>,
+        /// Overrides the connection's format for this reply.
+        #[serde(default)]
+        format: Option<ResponseFormat>,
+        /// Streams up to this many items of an iterator result.
+        #[serde(default)]
+        iterate: Option<usize>,
+        /// Where `source` came from, for errors and tracebacks to point there,
+        /// see `EvalOptions::located`: the path of its file, or else a name
+        /// for the chunk, and the line of the file it starts at.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk_name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        line: Option<u32>,
+        /// Overrides the server's output level for this eval and its reply,
+        /// see `EvalOptions::output_level`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        output_level: Option<OutputLevel>,
+    },
+    /// Asks for the ways `prefix` can go on, see `Session::complete`.
+    Complete { prefix: String },
+    /// Asks for the reference entry named `name`, see `help::lookup`.
+    Help { name: String },
+    /// Asks for the reference entries mentioning `pattern`, see
+    /// `help::apropos`.
+    Apropos { pattern: String },
+    /// Subscribes the connection to the value at `path`, see
+    /// `Session::watch_path`.
+    WatchPath { path: String },
+    /// Writes `data` at `offset` into the workspace file at `path`; an
+    /// upload starting at offset 0 replaces the file. `checksum` marks the
+    /// last chunk.
+    Upload {
+        path: String,
+        offset: u64,
+        #[serde(with = "base64_data")]
+        data: Vec<u8>,
+        checksum: Option<u32>,
+    },
+    /// Reads up to `MAX_CHUNK` bytes at `offset` from the workspace file at
+    /// `path`.
+    Download { path: String, offset: u64 },
+    /// Lists the sessions open on the server, of every client.
+    List,
+    /// Closes session `id` of client `client`, as listed.
+    Kill { client: u64, id: SessionId },
+}
+
+/// An entry of the embedded reference, as `Reply::Topics` carries it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelpEntry {
+    pub name: String,
+    pub signature: String,
+    pub description: String,
+}
+
+impl From<&HelpTopic> for HelpEntry {
+    fn from(topic: &HelpTopic) -> Self {
+        Self {
+            name: topic.name.to_string(),
+            signature: topic.signature.to_string(),
+            description: topic.description.to_string(),
+        }
+    }
+}
+
+/// A session open on the server, as `Request::List` lists it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    /// Numbers the server's clients in the order they connected, from 1.
+    pub client: u64,
+    /// The id the client chose for the session.
+    pub session: SessionId,
+    /// Where the client connected from.
+    pub peer: String,
+    /// How many evals the session ran.
+    pub evals: u64,
+    /// Whether the session belongs to the client that asked.
+    pub own: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Reply {
+    /// What the server runs, with the features sessions it opens have.
+    Hello {
+        #[serde(flatten)]
+        info: SessionInfo,
+        /// The codec the messages after this reply are encoded with, when
+        /// the client listed codecs and the server shares one of them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<String>,
+    },
+    Opened,
+    Closed,
+    /// The outcome of an eval; `output` is the response rendered at the
+    /// server's output level, `printed` and `stderr` the lines the eval
+    /// wrote to stdout and stderr.
+    Result {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        success: bool,
+        output: String,
+        error: Option<String>,
+        #[serde(with = "tagged_kind")]
+        error_kind: Option<ErrorKind>,
+        traceback: Vec<StackFrame>,
+        printed: Vec<String>,
+        stderr: Vec<String>,
+    },
+    /// The outcome of an eval in `ResponseFormat::V2`. `values` reference
+    /// `objects` as in `EvalResponse`; objects are only sent at the server's
+    /// full output level. A connection that asked for deltas at `hello` is
+    /// sent them in `deltas` instead, see `ClientVersions::apply_objects`.
+    Response {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        success: bool,
+        output: String,
+        values: Vec<LuaValue>,
+        objects: HashMap<String, LuaObject>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        deltas: HashMap<String, ObjectDelta>,
+        error: Option<ErrorPayload>,
+        printed: Vec<String>,
+        stderr: Vec<String>,
+        warnings: Vec<String>,
+    },
+    /// One value produced by the iterator an eval returned, counting from 0.
+    /// `values` are the values of one iteration, e.g. a key and a value.
+    Item {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        index: u64,
+        success: bool,
+        output: String,
+        values: Vec<LuaValue>,
+        error: Option<String>,
+    },
+    /// The iterator an eval returned produced its last item, or there was
+    /// none.
+    Exhausted {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        count: u64,
+    },
+    /// A report of the running eval, see `progress`; `percent` is in
+    /// 0..=100.
+    Progress {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        percent: f64,
+        message: Option<String>,
+    },
+    /// The completions of a `Complete` prefix, sorted.
+    Completions {
+        candidates: Vec<String>,
+    },
+    /// The reference entries a `Help` or `Apropos` asked for.
+    Topics {
+        topics: Vec<HelpEntry>,
+    },
+    /// The subscription a `WatchPath` registered.
+    Watching {
+        watch: u64,
+    },
+    /// A subscribed path has a new value, rendered in `output`, or `error`
+    /// says why it no longer resolves.
+    PathChanged {
+        watch: u64,
+        path: String,
+        output: Option<String>,
+        value: Option<LuaValue>,
+        error: Option<String>,
+    },
+    /// The sessions open on the server, ordered by client and session.
+    Sessions {
+        sessions: Vec<SessionSummary>,
+    },
+    Killed,
+    /// An eval another client ran in the session of a shared server, with
+    /// the reply it got.
+    Broadcast {
+        client: u64,
+        peer: String,
+        source: String,
+        reply: Box<Reply>,
+    },
+    /// A chunk of an upload was written; the file now has `size` bytes.
+    Received {
+        size: u64,
+    },
+    /// The last chunk of an upload was written and the file matched its
+    /// checksum. `path` is where it is on the server, for scripts to open.
+    Uploaded {
+        path: String,
+        size: u64,
+    },
+    /// A chunk of a download. `checksum` is set once the chunk reaches the
+    /// end of the file.
+    Data {
+        offset: u64,
+        size: u64,
+        #[serde(with = "base64_data")]
+        data: Vec<u8>,
+        checksum: Option<u32>,
+    },
+    /// The request could not be carried out, e.g. because it named a session
+    /// that is not open.
+    Error {
+        message: String,
+    },
+}
+
+/// Why an eval failed, in `ResponseFormat::V2`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorPayload {
+    /// The error message, or the limit that was exceeded.
+    pub message: Option<String>,
+    #[serde(with = "tagged_kind")]
+    pub kind: Option<ErrorKind>,
+    pub traceback: Vec<StackFrame>,
+    /// What the eval raised if it was not a string, e.g. a table of
+    /// details, referencing `objects`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Box<LuaValue>>,
+}
+
+impl Reply {
+    /// The reply to an eval in `format`, with the output rendered at `level`.
+    pub fn eval_result(response: EvalResponse, format: ResponseFormat, level: OutputLevel) -> Self {
+        let output = output::render(&response, level);
+        match format {
+            ResponseFormat::V1 => Reply::Result {
+                id: response.request_id,
+                success: response.success,
+                output,
+                error: response.error,
+                error_kind: response.error_kind,
+                traceback: response.traceback,
+                printed: response.output,
+                stderr: response.stderr,
+            },
+            ResponseFormat::V2 => Reply::Response {
+                id: response.request_id,
+                error: match response.success {
+                    true => None,
+                    false => Some(ErrorPayload {
+                        message: output::error_text(&response),
+                        kind: response.error_kind,
+                        traceback: response.traceback,
+                        value: response.error_value.map(Box::new),
+                    }),
+                },
+                success: response.success,
+                output,
+                values: response.values,
+                objects: response.objects,
+                deltas: HashMap::new(),
+                printed: response.output,
+                stderr: response.stderr,
+                warnings: response.warnings,
+            },
+        }
+    }
+
+    /// The reply for `change`, with the value rendered at `level`.
+    pub fn progress(id: Option<u64>, progress: Progress) -> Self {
+        Reply::Progress {
+            id,
+            percent: progress.percent,
+            message: progress.message,
+        }
+    }
+
+    pub fn path_changed(change: PathChange, level: OutputLevel) -> Self {
+        let watch = change.watch.id();
+        let path = change.path;
+        match change.value {
+            Ok(response) => Reply::PathChanged {
+                watch,
+                path,
+                output: Some(output::render(&response, level)),
+                value: Some(response.value),
+                error: None,
+            },
+            Err(e) => Reply::PathChanged {
+                watch,
+                path,
+                output: None,
+                value: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+
+    /// The `index`th item of an iterator in the reply to eval `id`.
+    pub fn item(id: Option<u64>, index: u64, response: EvalResponse, level: OutputLevel) -> Self {
+        Reply::Item {
+            id,
+            index,
+            success: response.success,
+            output: output::render(&response, level),
+            error: output::error_text(&response),
+            values: response.values,
+        }
+    }
+}
+
+mod base64_data {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(D::Error::custom)
+    }
+}
+
+/// `ErrorKind` as frames carry it, tagged with `kind`, e.g.
+/// `{"kind":"runtime"}`, see the module documentation.
+mod tagged_kind {
+    use crate::ErrorKind;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serialize;
+    use serde::Serializer;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(tag = "kind", rename_all = "snake_case")]
+    enum Tagged {
+        Syntax { incomplete: bool },
+        Runtime,
+        Memory,
+        Callback,
+        Timeout,
+    }
+
+    pub fn serialize<S: Serializer>(
+        kind: &Option<ErrorKind>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        kind.map(|kind| match kind {
+            ErrorKind::Syntax { incomplete } => Tagged::Syntax { incomplete },
+            ErrorKind::Runtime => Tagged::Runtime,
+            ErrorKind::Memory => Tagged::Memory,
+            ErrorKind::Callback => Tagged::Callback,
+            ErrorKind::Timeout => Tagged::Timeout,
+        })
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<ErrorKind>, D::Error> {
+        Ok(
+            Option::<Tagged>::deserialize(deserializer)?.map(|kind| match kind {
+                Tagged::Syntax { incomplete } => ErrorKind::Syntax { incomplete },
+                Tagged::Runtime => ErrorKind::Runtime,
+                Tagged::Memory => ErrorKind::Memory,
+                Tagged::Callback => ErrorKind::Callback,
+                Tagged::Timeout => ErrorKind::Timeout,
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let eval =
+            Frame::<Request>::decode(br#"{"session":3,"op":"eval","source":"x = 1"}"#).unwrap();
+        assert_eq!(
+            eval,
+            Frame::new(
+                3,
+                Request::Eval {
+                    source: "x = 1".to_string(),
+                    id: None,
+                    format: None,
+                    iterate: None,
+                    file: None,
+                    chunk_name: None,
+                    line: None,
+                    output_level: None,
+                }
+            )
+        );
+        let located = Frame::<Request>::decode(
+            br#"{"session":3,"op":"eval","source":"f()","file":"foo.lua","line":42}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            located.body,
+            Request::Eval {
+                file: Some(file),
+                chunk_name: None,
+                line: Some(42),
+                ..
+            } if file == "foo.lua"
+        ));
+        let opened = Frame::new(3, Reply::Opened).encode();
+        assert_eq!(opened, br#"{"session":3,"op":"opened"}"#);
+        assert!(Frame::<Request>::decode(br#"{"op":"open"}"#).is_err());
+        assert!(Frame::<Request>::decode(br#"{"session":3,"op":"launch"}"#).is_err());
+    }
+
+    #[test]
+    fn test_binary_data() {
+        let upload = Frame::<Request>::decode(
+            br#"{"session":0,"op":"upload","path":"a.bin","offset":0,"data":"AP8K"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            upload.body,
+            Request::Upload {
+                path: "a.bin".to_string(),
+                offset: 0,
+                data: vec![0, 255, 10],
+                checksum: None,
+            }
+        );
+        let data = Frame::new(
+            0,
+            Reply::Data {
+                offset: 0,
+                size: 3,
+                data: vec![0, 255, 10],
+                checksum: Some(1),
+            },
+        );
+        assert_eq!(Frame::decode(&data.encode()).unwrap(), data);
+        let bad = br#"{"session":0,"op":"upload","path":"a","offset":0,"data":"!"}"#;
+        assert!(Frame::<Request>::decode(bad).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_error_kind() {
+        let mut session = crate::Session::new();
+        let response = session.eval("function f(".to_string()).await;
+        for format in [ResponseFormat::V1, ResponseFormat::V2] {
+            let reply = Reply::eval_result(response.clone(), format, OutputLevel::Standard);
+            let frame = Frame::new(1, reply);
+            let json: serde_json::Value = serde_json::from_slice(&frame.encode()).unwrap();
+            let kind = match format {
+                ResponseFormat::V1 => &json["error_kind"],
+                ResponseFormat::V2 => &json["error"]["kind"],
+            };
+            assert_eq!(
+                kind,
+                &serde_json::json!({ "kind": "syntax", "incomplete": true })
+            );
+            assert_eq!(Frame::decode(&frame.encode()).unwrap(), frame);
+        }
+    }
+}
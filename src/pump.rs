@@ -0,0 +1,128 @@
+//! Cooperative sessions, whose interpreter runs on the host's own thread and
+//! only when the host asks for it, for embedders with a frame loop such as
+//! game engines; see `SessionBuilder::cooperative`.
+//!
+//! Work queued through a `SessionHandle` (events and evals) waits for
+//! `Session::pump`, which runs it in slices of one command each until the
+//! frame's time budget is used up. A slice is not preempted, so a long eval
+//! or event handler can overrun the budget; `Limits::instructions` bounds
+//! how far. Evals awaited through the `Session` itself run immediately,
+//! after the work queued before them.
+
+use crate::host::AsyncCall;
+use crate::Command;
+use crate::Interpreter;
+use crate::SessionConfig;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// An interpreter owned by its session instead of a thread.
+pub(crate) struct Cooperative {
+    /// `None` once the interpreter panicked, after which queued commands are
+    /// dropped so that their senders see it as dead.
+    interpreter: Option<Interpreter>,
+    commands: UnboundedReceiver<Command>,
+}
+
+impl fmt::Debug for Cooperative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cooperative")
+            .field("alive", &self.interpreter.is_some())
+            .finish()
+    }
+}
+
+impl Cooperative {
+    pub fn new(
+        config: SessionConfig,
+        heartbeat: Arc<AtomicU64>,
+        calls: UnboundedSender<AsyncCall>,
+    ) -> (UnboundedSender<Command>, Self) {
+        let (command_sender, commands) = tokio::sync::mpsc::unbounded_channel();
+        let interpreter = Interpreter::new(config, heartbeat, calls);
+        let cooperative = Self {
+            interpreter: Some(interpreter),
+            commands,
+        };
+        (command_sender, cooperative)
+    }
+
+    /// Runs the next queued command, returning false if there was none.
+    fn step(&mut self) -> bool {
+        let command = match self.commands.try_recv() {
+            Ok(command) => command,
+            Err(_) => return false,
+        };
+        if let Some(interpreter) = &mut self.interpreter {
+            let handled =
+                std::panic::catch_unwind(AssertUnwindSafe(|| interpreter.handle(command)));
+            if handled.is_err() {
+                self.interpreter = None;
+            }
+        }
+        true
+    }
+
+    /// Runs everything queued so far.
+    pub fn run_queued(&mut self) {
+        while self.step() {}
+    }
+
+    /// Runs queued commands until `budget` has passed, returning how many ran.
+    /// Spare time goes to idle garbage collection, see `idle_gc`.
+    pub fn pump(&mut self, budget: Duration) -> usize {
+        let deadline = Instant::now() + budget;
+        let mut ran = 0;
+        while Instant::now() < deadline && self.step() {
+            ran += 1;
+        }
+        if let Some(interpreter) = &mut self.interpreter {
+            while Instant::now() < deadline && interpreter.collect_idle() {}
+        }
+        ran
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_pump() {
+        let mut session = Session::builder().cooperative(true).build();
+        session
+            .eval("seen = {}; events.on('frame', function(n) seen[#seen + 1] = n end)".to_string())
+            .await;
+        let handle = session.handle();
+        handle.emit("frame", LuaValue::String("a".to_string()));
+        let mut response = handle.eval("return #seen".to_string());
+        assert_eq!(session.pump(Duration::ZERO), 0);
+        assert!(response.try_recv().is_err());
+
+        assert_eq!(session.pump(Duration::from_secs(1)), 2);
+        let response = response.try_recv().unwrap();
+        assert_eq!(response.value, LuaValue::Integer(1));
+        assert_eq!(session.pump(Duration::from_secs(1)), 0);
+    }
+
+    #[tokio::test]
+    async fn test_awaited_evals_run_queued_work() {
+        let mut session = Session::builder().cooperative(true).build();
+        session
+            .eval("events.on('tick', function(s) last = s end)".to_string())
+            .await;
+        session
+            .handle()
+            .emit("tick", LuaValue::String("first".to_string()));
+        let resp = session.eval("return last".to_string()).await;
+        assert_eq!(resp.value, LuaValue::String("first".to_string()));
+    }
+}
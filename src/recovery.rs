@@ -0,0 +1,138 @@
+//! Restarting sessions whose interpreter thread died, optionally replaying
+//! what had been run in them.
+
+use crate::Command;
+use crate::EvalOptions;
+use crate::LuaValue;
+use crate::Session;
+use tokio::sync::oneshot;
+
+/// What a session does when its interpreter thread dies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Recovery {
+    /// Leave the session dead; every further request fails.
+    #[default]
+    None,
+    /// Start a new interpreter from the session image.
+    Restart,
+    /// Start a new interpreter and replay every input that succeeded since
+    /// the session was created or last reset. Inputs that failed are not
+    /// replayed, even if they changed the state before failing.
+    Replay,
+}
+
+/// A state-changing request that succeeded, kept for `Recovery::Replay`.
+#[derive(Debug, Clone)]
+pub(crate) enum JournalEntry {
+    Eval(String),
+    Template {
+        source: String,
+        prologue: String,
+        params: Vec<LuaValue>,
+    },
+    SetPath(String, LuaValue),
+}
+
+impl Session {
+    pub(crate) fn journal(&mut self, entry: impl FnOnce() -> JournalEntry) {
+        if self.config.recovery == Recovery::Replay {
+            self.journal.push(entry());
+        }
+    }
+
+    /// Called once a request found the interpreter dead.
+    pub(crate) async fn recover(&mut self) {
+        if self.config.recovery == Recovery::None {
+            return;
+        }
+        self.recoveries += 1;
+        self.respawn();
+
+        let journal = std::mem::take(&mut self.journal);
+        for entry in &journal {
+            if !self.replay(entry.clone()).await {
+                // The journal itself kills the interpreter, so fall back to
+                // a plain restart rather than crashing over and over.
+                self.respawn();
+                return;
+            }
+        }
+        self.journal = journal;
+    }
+
+    /// Re-runs `entry`, returning whether the interpreter survived it.
+    async fn replay(&mut self, entry: JournalEntry) -> bool {
+        match entry {
+            JournalEntry::Eval(expr) => self.run_eval(expr, EvalOptions::default()).await.is_some(),
+            JournalEntry::Template {
+                source,
+                prologue,
+                params,
+            } => {
+                let (reply, response) = oneshot::channel();
+                self.send(Command::EvalTemplate {
+                    source,
+                    prologue,
+                    params,
+                    reply,
+                });
+                response.await.is_ok()
+            }
+            JournalEntry::SetPath(path, value) => {
+                let (reply, response) = oneshot::channel();
+                self.send(Command::SetPath(path, value, reply));
+                response.await.is_ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn crash(session: &mut Session) {
+        let _ = session.command_sender.send(Command::Crash);
+        let resp = session.eval("return 1".to_string()).await;
+        assert!(!resp.success);
+        assert_eq!(resp.error.as_deref(), Some("the interpreter thread died"));
+        assert_eq!(resp.error_kind, Some(crate::ErrorKind::Runtime));
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let mut session = Session::builder().recovery(Recovery::Replay).build();
+        session.eval("x = 1".to_string()).await;
+        session
+            .set_path("y".to_string(), LuaValue::Number(2.0))
+            .await
+            .unwrap();
+        session
+            .eval("x = x + y; error('not replayed')".to_string())
+            .await;
+        session
+            .eval_template("z = $1".to_string(), vec![LuaValue::Number(3.0)])
+            .await
+            .unwrap();
+
+        crash(&mut session).await;
+        let resp = session.eval("return x + y + z".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Number(6.0));
+        assert_eq!(session.recoveries(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let mut session = Session::builder().recovery(Recovery::Restart).build();
+        session.eval("x = 1".to_string()).await;
+
+        crash(&mut session).await;
+        let resp = session.eval("return x".to_string()).await;
+        assert!(resp.success);
+        assert_eq!(resp.value, LuaValue::Nil);
+
+        let mut session = Session::new();
+        crash(&mut session).await;
+        assert!(!session.eval("return 1".to_string()).await.success);
+    }
+}
@@ -0,0 +1,346 @@
+//! Responses as people read them, for `OutputLevel::Full`: values written
+//! the way Lua source would write them, with the tables they reference
+//! expanded inline.
+//!
+//! Tables that fit on one line stay on one line. A table reached more than
+//! once is labelled `<1>` where it is first shown and written as `<1>`
+//! wherever it appears again, which is also how cycles show. Tables a
+//! response left out, e.g. for being nested too deeply, are written as their
+//! object id.
+
+use crate::limits::LimitViolation;
+use crate::path::is_identifier;
+use crate::schema::SchemaErrorKind;
+use crate::EvalResponse;
+use crate::LuaValue;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Tables longer than this on one line are spread over several.
+const MAX_WIDTH: usize = 72;
+
+const INDENT: usize = 2;
+
+enum Node {
+    Text(String),
+    Table {
+        label: Option<usize>,
+        type_name: Option<String>,
+        /// Positional entries have no key.
+        entries: Vec<(Option<String>, Node)>,
+    },
+}
+
+struct Builder<'a> {
+    response: &'a EvalResponse,
+    references: HashMap<&'a str, usize>,
+    /// Tables already shown, and their labels if they have one.
+    shown: HashMap<&'a str, Option<usize>>,
+    labels: usize,
+}
+
+impl<'a> Builder<'a> {
+    fn new(response: &'a EvalResponse) -> Self {
+        let mut builder = Self {
+            response,
+            references: HashMap::new(),
+            shown: HashMap::new(),
+            labels: 0,
+        };
+        // `value` is the first of `values`, unless there are none.
+        let values = match response.values.is_empty() {
+            true => std::slice::from_ref(&response.value),
+            false => &response.values[..],
+        };
+        let assigned = response.assignments.iter().map(|(_, value)| value);
+        for value in values.iter().chain(assigned) {
+            builder.count(value);
+        }
+        builder
+    }
+
+    fn count(&mut self, value: &'a LuaValue) {
+        let LuaValue::ObjectRef(id) = value else {
+            return;
+        };
+        let count = self.references.entry(id.as_str()).or_insert(0);
+        *count += 1;
+        if *count > 1 {
+            return;
+        }
+        if let Some(object) = self.response.objects.get(id) {
+            for (key, value) in &object.members {
+                self.count(key);
+                self.count(value);
+            }
+        }
+    }
+
+    fn build(&mut self, value: &'a LuaValue) -> Node {
+        let id = match value {
+            LuaValue::ObjectRef(id) => id.as_str(),
+            other => return Node::Text(other.to_string()),
+        };
+        if let Some(label) = self.shown.get(id) {
+            return Node::Text(match label {
+                Some(label) => format!("<{}>", label),
+                None => id.to_string(),
+            });
+        }
+        let object = match self.response.objects.get(id) {
+            Some(object) => object,
+            None => return Node::Text(id.to_string()),
+        };
+        if let Some(display) = &object.display {
+            return Node::Text(display.clone());
+        }
+        let label = (self.references.get(id).copied().unwrap_or(0) > 1).then(|| {
+            self.labels += 1;
+            self.labels
+        });
+        self.shown.insert(id, label);
+
+        let mut members: Vec<_> = object.members.iter().collect();
+        members.sort_by(|(a, _), (b, _)| {
+            sort_key(a)
+                .partial_cmp(&sort_key(b))
+                .unwrap_or(Ordering::Equal)
+        });
+        let mut entries = vec![];
+        let mut next_index = 1;
+        for (key, value) in members {
+            let key = match key {
+                LuaValue::Integer(i) if *i == next_index => {
+                    next_index += 1;
+                    None
+                }
+                LuaValue::String(name) if is_identifier(name) => Some(name.clone()),
+                key => Some(format!("[{}]", inline(&self.build(key)))),
+            };
+            entries.push((key, self.build(value)));
+        }
+        Node::Table {
+            label,
+            type_name: object.type_name.clone(),
+            entries,
+        }
+    }
+}
+
+/// Orders the sequence first, then other numbers, strings and the rest.
+fn sort_key(key: &LuaValue) -> (u8, f64, String) {
+    match key {
+        LuaValue::Integer(i) => (0, *i as f64, String::new()),
+        LuaValue::Number(n) => (0, *n, String::new()),
+        LuaValue::String(s) => (1, 0.0, s.clone()),
+        other => (2, 0.0, other.to_string()),
+    }
+}
+
+fn prefix(label: Option<usize>, type_name: &Option<String>) -> String {
+    let mut prefix = String::new();
+    if let Some(label) = label {
+        prefix.push_str(&format!("<{}>", label));
+    }
+    if let Some(type_name) = type_name {
+        prefix.push_str(type_name);
+        prefix.push(' ');
+    }
+    prefix
+}
+
+fn entry(key: &Option<String>, value: String) -> String {
+    match key {
+        Some(key) => format!("{} = {}", key, value),
+        None => value,
+    }
+}
+
+fn inline(node: &Node) -> String {
+    match node {
+        Node::Text(text) => text.clone(),
+        Node::Table {
+            label,
+            type_name,
+            entries,
+        } if entries.is_empty() => format!("{}{{}}", prefix(*label, type_name)),
+        Node::Table {
+            label,
+            type_name,
+            entries,
+        } => {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(key, value)| entry(key, inline(value)))
+                .collect();
+            format!("{}{{ {} }}", prefix(*label, type_name), entries.join(", "))
+        }
+    }
+}
+
+fn layout(node: &Node, indent: usize) -> String {
+    let one_line = inline(node);
+    let Node::Table {
+        label,
+        type_name,
+        entries,
+    } = node
+    else {
+        return one_line;
+    };
+    if indent + one_line.len() <= MAX_WIDTH {
+        return one_line;
+    }
+    let pad = " ".repeat(indent + INDENT);
+    let mut text = format!("{}{{\n", prefix(*label, type_name));
+    for (i, (key, value)) in entries.iter().enumerate() {
+        text.push_str(&pad);
+        text.push_str(&entry(key, layout(value, indent + INDENT)));
+        if i + 1 < entries.len() {
+            text.push(',');
+        }
+        text.push('\n');
+    }
+    text.push_str(&" ".repeat(indent));
+    text.push('}');
+    text
+}
+
+/// `value`, which must be `response.value`, one of `response.values` or
+/// `response.error_value`, with the tables it references expanded.
+pub fn value(response: &EvalResponse, value: &LuaValue) -> String {
+    let node = Builder::new(response).build(value);
+    layout(&node, 0)
+}
+
+impl fmt::Display for EvalResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.success {
+            // One builder, so that tables shared between values are
+            // labelled consistently.
+            let mut builder = Builder::new(self);
+            if !self.assignments.is_empty() {
+                for (i, (name, value)) in self.assignments.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{} = {}", name, layout(&builder.build(value), 0))?;
+                }
+            } else if self.values.len() > 1 {
+                let values: Vec<_> = self
+                    .values
+                    .iter()
+                    .map(|value| layout(&builder.build(value), 0))
+                    .collect();
+                let one_line = values.join(", ");
+                if one_line.len() <= MAX_WIDTH && !one_line.contains('\n') {
+                    write!(f, "{}", one_line)?;
+                } else {
+                    write!(f, "{}", values.join(",\n"))?;
+                }
+            } else {
+                write!(f, "{}", layout(&builder.build(&self.value), 0))?;
+            }
+            for error in &self.schema_errors {
+                let path = if error.path.is_empty() {
+                    "result"
+                } else {
+                    &error.path
+                };
+                match &error.kind {
+                    SchemaErrorKind::Missing => write!(f, "\nschema: {} is missing", path)?,
+                    SchemaErrorKind::WrongType { expected, found } => write!(
+                        f,
+                        "\nschema: {} should be {}, not {}",
+                        path, expected, found
+                    )?,
+                }
+            }
+        } else {
+            let error_value = self.error_value.as_ref();
+            match (
+                &self.limit_violation,
+                &self.nil_access,
+                error_value,
+                &self.error,
+            ) {
+                (Some(LimitViolation::Memory), ..) => write!(f, "error: memory limit exceeded")?,
+                (Some(LimitViolation::Instructions), ..) => {
+                    write!(f, "error: instruction limit exceeded")?
+                }
+                (None, Some(access), ..) => write!(f, "error: {}", access)?,
+                // Tables and the like are shown rather than their address.
+                (None, None, Some(raised), _) => write!(f, "error: {}", value(self, raised))?,
+                (None, None, None, Some(message)) => write!(f, "error: {}", message)?,
+                (None, None, None, None) => write!(f, "error")?,
+            }
+            for frame in &self.traceback {
+                write!(f, "\n  at {}", frame.source)?;
+                if let Some(line) = frame.line {
+                    write!(f, ":{}", line)?;
+                }
+                if let Some(function) = &frame.function {
+                    write!(f, " in {}", function)?;
+                }
+            }
+        }
+        if let Some(explanation) = &self.explanation {
+            write!(f, "\n{}", explanation.to_string().trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_tables() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("return { 'a', 'b', n = 2, [10] = true, nested = { x = 1.5 } }".to_string())
+            .await;
+        assert_eq!(
+            resp.to_string(),
+            r#"{ "a", "b", [10] = true, n = 2, nested = { x = 1.5 } }"#
+        );
+
+        let resp = session
+            .eval("local t = { name = 'loop' } t.self = t return { t, t }".to_string())
+            .await;
+        assert_eq!(
+            resp.to_string(),
+            r#"{ <1>{ name = "loop", self = <1> }, <1> }"#
+        );
+
+        let resp = session
+            .eval(
+                "return { first = string.rep('x', 40), second = string.rep('y', 40) }".to_string(),
+            )
+            .await;
+        assert_eq!(
+            resp.to_string(),
+            format!(
+                "{{\n  first = \"{}\",\n  second = \"{}\"\n}}",
+                "x".repeat(40),
+                "y".repeat(40)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_and_values() {
+        let mut session = Session::new();
+        let resp = session
+            .eval("local function f() error('boom') end\nf()".to_string())
+            .await;
+        assert_eq!(
+            resp.to_string(),
+            "error: [string \"?\"]:1: boom\n  at [C] in error\n  at [string \"?\"]:1 in f\n  at [string \"?\"]:2"
+        );
+        let resp = session.eval("return 1, 'two', {}".to_string()).await;
+        assert_eq!(resp.to_string(), "1, \"two\", {}");
+    }
+}
@@ -0,0 +1,165 @@
+//! Host-side resources opened on behalf of a session, such as sockets, files
+//! or database connections, closed deterministically when the session resets
+//! or closes instead of whenever Lua's garbage collector gets to them.
+//!
+//! Host functions and userdata methods record what they open with `track`
+//! while the interpreter runs them. Async host functions run outside the
+//! interpreter and cannot track resources.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// A tracked resource, for releasing it early with `release`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Resource(u64);
+
+type Cleanup = Box<dyn FnOnce() + Send>;
+
+/// The resources of one Lua state, closed newest first.
+#[derive(Default)]
+pub(crate) struct Resources {
+    open: Vec<(Resource, String, Cleanup)>,
+    next: u64,
+}
+
+impl fmt::Debug for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.open.iter().map(|(_, name, _)| name))
+            .finish()
+    }
+}
+
+impl Resources {
+    pub fn names(&self) -> Vec<String> {
+        self.open.iter().map(|(_, name, _)| name.clone()).collect()
+    }
+
+    /// Runs the cleanup of every resource. A cleanup that panics does not
+    /// keep the others from running.
+    pub fn close_all(&mut self) {
+        for (_, _, cleanup) in std::mem::take(&mut self.open).into_iter().rev() {
+            let _ = std::panic::catch_unwind(AssertUnwindSafe(cleanup));
+        }
+    }
+}
+
+impl Drop for Resources {
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
+
+thread_local! {
+    /// The resources of the interpreter handling a command on this thread.
+    static CURRENT: RefCell<Option<Arc<Mutex<Resources>>>> = const { RefCell::new(None) };
+}
+
+/// Makes `resources` the target of `track` and `release` while `f` runs.
+pub(crate) fn with_current<R>(resources: &Arc<Mutex<Resources>>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Arc<Mutex<Resources>>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+    let previous = CURRENT.with(|current| current.borrow_mut().replace(resources.clone()));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Records a resource of the session whose host function is running, to be
+/// closed with `cleanup` when the session resets or closes. Returns `None`
+/// when called from anywhere else.
+pub fn track(name: &str, cleanup: impl FnOnce() + Send + 'static) -> Option<Resource> {
+    let resources = CURRENT.with(|current| current.borrow().clone())?;
+    let mut resources = resources.lock().unwrap_or_else(|e| e.into_inner());
+    let resource = Resource(resources.next);
+    resources.next += 1;
+    resources
+        .open
+        .push((resource, name.to_string(), Box::new(cleanup)));
+    Some(resource)
+}
+
+/// Closes `resource` now, e.g. because Lua code closed it explicitly.
+/// Returns false if it was already closed.
+pub fn release(resource: Resource) -> bool {
+    let resources = match CURRENT.with(|current| current.borrow().clone()) {
+        Some(resources) => resources,
+        None => return false,
+    };
+    let cleanup = {
+        let mut resources = resources.lock().unwrap_or_else(|e| e.into_inner());
+        let index = resources.open.iter().position(|(r, _, _)| *r == resource);
+        index.map(|index| resources.open.remove(index).2)
+    };
+    match cleanup {
+        Some(cleanup) => {
+            cleanup();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+
+    fn session(log: &Arc<Mutex<Vec<String>>>) -> Session {
+        let log = log.clone();
+        Session::builder()
+            .register_function("open", move |args| {
+                let name = match &args[..] {
+                    [LuaValue::String(name)] => name.clone(),
+                    _ => return Err("expected a name".into()),
+                };
+                let (log, closed) = (log.clone(), name.clone());
+                let resource = track(&name, move || log.lock().unwrap().push(closed));
+                Ok(LuaValue::Number(resource.map_or(-1.0, |r| r.0 as f64)))
+            })
+            .register_function("close", |args| match args[..] {
+                [LuaValue::Number(id)] => Ok(LuaValue::Boolean(release(Resource(id as u64)))),
+                _ => Err("expected a resource".into()),
+            })
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_on_reset_and_close() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut session = session(&log);
+        session
+            .eval("open('a'); open('b'); open('c')".to_string())
+            .await;
+        assert_eq!(session.open_resources().await, ["a", "b", "c"]);
+        session.reset().await;
+        assert_eq!(*log.lock().unwrap(), ["c", "b", "a"]);
+        assert!(session.open_resources().await.is_empty());
+
+        session.eval("open('d')".to_string()).await;
+        session.close().await;
+        assert_eq!(log.lock().unwrap().last().map(String::as_str), Some("d"));
+        assert!(track("outside", || {}).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut session = session(&log);
+        let resp = session
+            .eval(
+                "local f = open('f'); return tostring(close(f)) .. tostring(close(f))".to_string(),
+            )
+            .await;
+        assert_eq!(resp.value, LuaValue::String("truefalse".to_string()));
+        assert_eq!(*log.lock().unwrap(), ["f"]);
+    }
+}
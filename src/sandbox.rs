@@ -0,0 +1,341 @@
+//! Expression-only evals against a read-only view of the globals.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::MultiValue;
+use rlua::Table;
+use rlua::Value;
+
+const FACTORY_KEY: &str = "luarepl.readonly_env";
+
+/// Returns a function building a read-only proxy of the globals, plus a
+/// function mapping proxies back to the tables they wrap. Nested tables are
+/// wrapped lazily as they are read, and proxies cannot be written to or have
+/// their metatable changed. Functions reached through the proxy still run
+/// with whatever environment they were defined in, apart from the builtins
+/// that would hand out the real globals or write to them: however they are
+/// reached, `load`, `loadstring`, `loadfile` and `dofile` run chunks against
+/// the proxy, `getmetatable` returns a read-only view, `io.open` only opens
+/// files for reading, and `require`, `rawset`, `setmetatable`, the `debug`
+/// library, the package loaders and the `os` and `io` functions with side
+/// effects, such as `os.exit`, `os.remove` and `io.popen`, refuse.
+///
+/// If a tracker is passed along with the globals, its `index`, `len` and
+/// `pairs` functions are told about every read, with the path of keys from
+/// the globals to the table read.
+const FACTORY_SOURCE: &str = r#"
+local proxies = setmetatable({}, { __mode = "k" })
+local unpack, type, error, tostring, assert, pairs, ipairs =
+    table.unpack, type, error, tostring, assert, pairs, ipairs
+local setmetatable, getmetatable, rawset = setmetatable, getmetatable, rawset
+local load, loadfile, dofile, require, debug, package =
+    load, loadfile, dofile, require, debug, package
+local os, io = os, io
+
+local function deny()
+    error("attempt to modify a read-only environment", 2)
+end
+
+local function refuse(name)
+    return function()
+        error(name .. " is not available in a read-only environment", 2)
+    end
+end
+
+local function wrap(t, track, path, replacements)
+    local replaced = replacements[t]
+    if replaced ~= nil then
+        return replaced
+    end
+    if type(t) ~= "table" then
+        return t
+    end
+    local proxy = setmetatable({}, {
+        __index = function(_, k)
+            local value = t[k]
+            local child = { unpack(path) }
+            child[#child + 1] = k
+            if track then track.index(child, value) end
+            return wrap(value, track, child, replacements)
+        end,
+        __newindex = deny,
+        __len = function()
+            local n = #t
+            if track then track.len(path, n) end
+            return n
+        end,
+        __pairs = function()
+            if track then track.pairs(path) end
+            return function(_, k)
+                local key, value = next(t, k)
+                local child = { unpack(path) }
+                child[#child + 1] = key
+                return key, wrap(value, track, child, replacements)
+            end, nil, nil
+        end,
+        __metatable = false,
+    })
+    proxies[proxy] = t
+    return proxy
+end
+
+local function unwrap(v)
+    return proxies[v] or v
+end
+
+return function(globals, track)
+    local replacements = {}
+    local env = wrap(globals, track, {}, replacements)
+    local function replace(real, safe)
+        if real ~= nil then
+            replacements[real] = safe
+            -- Returned from the expression as the function it stands for.
+            proxies[safe] = real
+        end
+    end
+    replace(load, function(chunk, name)
+        return load(chunk, name, "t", env)
+    end)
+    replace(globals.loadstring, function(chunk, name)
+        return load(chunk, name, "t", env)
+    end)
+    replace(loadfile, function(file)
+        return loadfile(file, "t", env)
+    end)
+    replace(dofile, function(file)
+        return assert(loadfile(file, "t", env))()
+    end)
+    replace(getmetatable, function(v)
+        return wrap(getmetatable(v), nil, {}, replacements)
+    end)
+    replace(require, refuse("require"))
+    replace(rawset, deny)
+    replace(setmetatable, deny)
+    replace(debug, setmetatable({}, {
+        __index = function(_, k)
+            return refuse("debug." .. tostring(k))
+        end,
+        __newindex = deny,
+        __metatable = false,
+    }))
+    if type(package) == "table" then
+        replace(package.loadlib, refuse("package.loadlib"))
+        for _, searcher in pairs(package.searchers or {}) do
+            replace(searcher, refuse("package.searchers"))
+        end
+    end
+    if type(os) == "table" then
+        for _, name in ipairs({ "exit", "remove", "rename", "execute", "tmpname", "setlocale" }) do
+            replace(os[name], refuse("os." .. name))
+        end
+    end
+    if type(io) == "table" then
+        for _, name in ipairs({ "popen", "tmpfile", "input", "output", "close" }) do
+            replace(io[name], refuse("io." .. name))
+        end
+        local open = io.open
+        replace(open, function(file, mode)
+            if mode ~= nil and mode ~= "r" and mode ~= "rb" then
+                error("io.open for writing is not available in a read-only environment", 2)
+            end
+            return open(file, mode)
+        end)
+    end
+    return env, unwrap
+end
+"#;
+
+/// Evaluates `source` as a single expression against a read-only view of
+/// `globals`.
+pub(crate) fn eval_expression<'l>(
+    ctx: Context<'l>,
+    source: &str,
+    globals: Table<'l>,
+) -> Result<Value<'l>, Error> {
+    eval_against(ctx, source, globals, Value::Nil)
+}
+
+/// Like `eval_expression`, reporting reads to `track`, see `FACTORY_SOURCE`.
+pub(crate) fn eval_tracked<'l>(
+    ctx: Context<'l>,
+    source: &str,
+    track: Value<'l>,
+) -> Result<Value<'l>, Error> {
+    eval_against(ctx, source, ctx.globals(), track)
+}
+
+fn eval_against<'l>(
+    ctx: Context<'l>,
+    source: &str,
+    globals: Table<'l>,
+    track: Value<'l>,
+) -> Result<Value<'l>, Error> {
+    let factory: Function = match ctx.named_registry_value(FACTORY_KEY)? {
+        Value::Function(factory) => factory,
+        _ => {
+            let factory: Function = ctx.load(FACTORY_SOURCE).set_name("=readonly")?.eval()?;
+            ctx.set_named_registry_value(FACTORY_KEY, factory.clone())?;
+            factory
+        }
+    };
+    let (env, unwrap): (Table, Function) = factory.call((globals, track))?;
+
+    // The parentheses reject statements; the newline keeps a trailing
+    // comment from swallowing the closing one. Input closing them early, as
+    // `1), (2` does, returns more than one value.
+    let values: MultiValue = ctx
+        .load(&format!("return ({}\n)", source))
+        .set_environment(env)?
+        .call(())?;
+    let mut values = values.into_iter();
+    match (values.next(), values.next()) {
+        (Some(value), None) => unwrap.call(value),
+        _ => Err(Error::RuntimeError(
+            "expected a single expression".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::EvalOptions;
+    use crate::LuaValue;
+    use crate::Session;
+
+    fn expression_only() -> EvalOptions {
+        EvalOptions {
+            expression_only: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expression_only() {
+        let mut session = Session::new();
+        session
+            .eval("price = 10; cfg = { tax = { rate = 2 } }".to_string())
+            .await;
+
+        let resp = session
+            .eval_with(
+                "price * cfg.tax.rate -- total".to_string(),
+                expression_only(),
+            )
+            .await;
+        assert_eq!(resp.value, LuaValue::Integer(20));
+
+        for source in ["price = 1", "1, 2", "x = 1; return x", "1), (2"] {
+            let resp = session
+                .eval_with(source.to_string(), expression_only())
+                .await;
+            assert!(!resp.success, "{} should be rejected", source);
+        }
+        for source in [
+            "(function() cfg.tax.rate = 5 end)()",
+            "table.insert(cfg, 1)",
+            "rawset(_G, 'price', 1) and nil",
+            "setmetatable(cfg, nil)",
+        ] {
+            session
+                .eval_with(source.to_string(), expression_only())
+                .await;
+        }
+
+        let resp = session
+            .eval("return price + cfg.tax.rate".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Integer(12));
+    }
+
+    #[tokio::test]
+    async fn test_builtins_cannot_escape() {
+        let mut session = Session::new();
+        session.eval("price = 10".to_string()).await;
+
+        let resp = session
+            .eval_with("load('return price * 2')()".to_string(), expression_only())
+            .await;
+        assert_eq!(resp.value, LuaValue::Integer(20));
+        let resp = session
+            .eval_with(
+                "getmetatable('').__index.upper('a')".to_string(),
+                expression_only(),
+            )
+            .await;
+        assert_eq!(resp.value, LuaValue::String("A".to_string()));
+        for source in [
+            "load('price = 1')()",
+            "_G.load('price = 2')()",
+            "package.loaded._G.load('price = 3')()",
+            "rawset(_G, 'price', 4)",
+            "rawset(debug.getregistry()[2], 'price', 5)",
+            "(function() getmetatable('').__index.price = 6 end)()",
+            "require('string')",
+        ] {
+            let resp = session
+                .eval_with(source.to_string(), expression_only())
+                .await;
+            assert!(!resp.success, "{} should be rejected", source);
+        }
+
+        let resp = session.eval("return price, string.price".to_string()).await;
+        assert_eq!(resp.values, [LuaValue::Integer(10), LuaValue::Nil]);
+    }
+
+    #[tokio::test]
+    async fn test_os_and_io_refuse() {
+        let dir = std::env::temp_dir().join(format!("luarepl-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kept = dir.join("kept.txt");
+        std::fs::write(&kept, "data").unwrap();
+        let mut session = Session::new();
+        session
+            .eval(format!(
+                "kept, moved = {:?}, {:?}",
+                kept,
+                dir.join("moved.txt")
+            ))
+            .await;
+
+        for (source, refused) in [
+            ("os.exit(1)", "os.exit"),
+            ("os.remove(kept)", "os.remove"),
+            ("os.rename(kept, moved)", "os.rename"),
+            ("os.execute('true')", "os.execute"),
+            ("io.popen('true')", "io.popen"),
+            ("io.output(kept)", "io.output"),
+            ("io.open(kept, 'w')", "io.open for writing"),
+            ("io.open(kept, 'a+')", "io.open for writing"),
+        ] {
+            let resp = session
+                .eval_with(source.to_string(), expression_only())
+                .await;
+            let error = resp.error.unwrap_or_default();
+            assert!(error.contains(refused), "{}: {}", source, error);
+        }
+        assert_eq!(std::fs::read_to_string(&kept).unwrap(), "data");
+
+        // Reading is not a side effect.
+        let resp = session
+            .eval_with(
+                "io.open(kept):read('a') .. os.time() - os.time()".to_string(),
+                expression_only(),
+            )
+            .await;
+        assert_eq!(resp.value, LuaValue::String("data0".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expression_returns_real_tables() {
+        let mut session = Session::new();
+        session.eval("cfg = { a = 1 }".to_string()).await;
+
+        let resp = session
+            .eval_with("cfg".to_string(), expression_only())
+            .await;
+        assert_eq!(resp.objects.len(), 1);
+        assert_eq!(resp.objects.values().next().unwrap().members.len(), 1);
+    }
+}
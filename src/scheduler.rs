@@ -0,0 +1,319 @@
+//! Fair scheduling of evals from the tenants of one server, with per-tenant
+//! CPU budgets measured in instruction hook ticks. `server::serve` and
+//! `jsonrpc::serve` run every eval through the `Scheduler` of their
+//! `ServeOptions::quota`, with each connection a tenant named after the host
+//! of its peer, see `tenant`, so that reconnecting does not start a new
+//! budget.
+//!
+//! A tenant that has used up its budget for the window is either refused,
+//! or only let in while no tenant within its budget is running an eval.
+//! Tenants whose window is over are forgotten.
+
+use crate::Session;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// What happens to work from a tenant that has used up its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverQuota {
+    /// Fail the eval without running it.
+    Reject,
+    /// Run it only when no tenant within its budget has an eval running.
+    Deprioritize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// CPU budget per tenant and window, in `Session::cpu_ticks`.
+    pub ticks: u64,
+    /// How often budgets are replenished.
+    pub window: Duration,
+    pub over_quota: OverQuota,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchedulerError {
+    OverQuota {
+        tenant: String,
+        used: u64,
+        budget: u64,
+    },
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchedulerError::OverQuota {
+                tenant,
+                used,
+                budget,
+            } => write!(
+                f,
+                "tenant {} used {} of {} ticks this window",
+                tenant, used, budget
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+/// The tenant a connection from `peer` belongs to: its host, without the
+/// port or the `ws://` of WebSocket peers, such as `127.0.0.1` for
+/// `ws://127.0.0.1:50312`. Peers without a port, such as `unix:` ones, are
+/// tenants as they are.
+pub fn tenant(peer: &str) -> &str {
+    let address = peer.strip_prefix("ws://").unwrap_or(peer);
+    let host = match address.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty() && !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            host
+        }
+        _ => return peer,
+    };
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+#[derive(Debug)]
+struct Tenant {
+    used: u64,
+    window_start: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    tenants: HashMap<String, Tenant>,
+    /// Evals of tenants within their budget that are running.
+    running: usize,
+}
+
+/// The budgets of a server's tenants, shared by its connections.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    quota: Quota,
+    state: Arc<Mutex<State>>,
+    /// Notified when the last eval within budget is done.
+    idle: Arc<Notify>,
+}
+
+impl Scheduler {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            state: Default::default(),
+            idle: Default::default(),
+        }
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Ticks `tenant` has used in the current window.
+    pub fn usage(&self, tenant: &str) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.tenants.get(tenant).map(|tenant| tenant.used)
+    }
+
+    /// Runs `run`, an eval in `session` for `tenant`, once the tenant is
+    /// let in, and charges the tenant the ticks it took.
+    pub async fn run<T>(
+        &self,
+        tenant: &str,
+        session: &mut Session,
+        run: impl AsyncFnOnce(&mut Session) -> T,
+    ) -> Result<T, SchedulerError> {
+        let admission = self.admit(tenant).await?;
+        let before = session.cpu_ticks();
+        let result = run(session).await;
+        admission.charge(session.cpu_ticks().saturating_sub(before));
+        Ok(result)
+    }
+
+    /// Waits until `tenant` may run an eval.
+    async fn admit(&self, tenant: &str) -> Result<Admission<'_>, SchedulerError> {
+        loop {
+            // Created before looking, so that it is woken by an eval
+            // finishing in between.
+            let idle = self.idle.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                let quota = self.quota;
+                state
+                    .tenants
+                    .retain(|_, tenant| tenant.window_start.elapsed() < quota.window);
+                let entry = state
+                    .tenants
+                    .entry(tenant.to_string())
+                    .or_insert_with(|| Tenant {
+                        used: 0,
+                        window_start: Instant::now(),
+                    });
+                let used = entry.used;
+                let admission = |within| Admission {
+                    scheduler: self,
+                    tenant: tenant.to_string(),
+                    within,
+                };
+                if used < quota.ticks {
+                    state.running += 1;
+                    return Ok(admission(true));
+                }
+                match quota.over_quota {
+                    OverQuota::Reject => {
+                        return Err(SchedulerError::OverQuota {
+                            tenant: tenant.to_string(),
+                            used,
+                            budget: quota.ticks,
+                        })
+                    }
+                    OverQuota::Deprioritize if state.running == 0 => return Ok(admission(false)),
+                    OverQuota::Deprioritize => {}
+                }
+            }
+            idle.await;
+        }
+    }
+}
+
+/// A tenant's leave to run one eval.
+struct Admission<'a> {
+    scheduler: &'a Scheduler,
+    tenant: String,
+    /// Whether the tenant was within its budget, and counts as running.
+    within: bool,
+}
+
+impl Admission<'_> {
+    fn charge(&self, ticks: u64) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        // The tenant is gone if its window ended during the eval, which
+        // then counts towards the next one.
+        let tenant = state
+            .tenants
+            .entry(self.tenant.clone())
+            .or_insert_with(|| Tenant {
+                used: 0,
+                window_start: Instant::now(),
+            });
+        tenant.used += ticks;
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if !self.within {
+            return;
+        }
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.running -= 1;
+        if state.running == 0 {
+            self.scheduler.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HEAVY: &str = "for i = 1, 200000 do end";
+
+    fn scheduler(over_quota: OverQuota) -> Scheduler {
+        Scheduler::new(Quota {
+            ticks: 50,
+            window: Duration::from_secs(3600),
+            over_quota,
+        })
+    }
+
+    async fn eval(scheduler: &Scheduler, tenant: &str, session: &mut Session, source: &str) {
+        let source = source.to_string();
+        let response = scheduler
+            .run(tenant, session, async |session: &mut Session| {
+                session.eval(source).await
+            })
+            .await
+            .unwrap();
+        assert!(response.success);
+    }
+
+    #[tokio::test]
+    async fn test_deprioritize() {
+        let scheduler = scheduler(OverQuota::Deprioritize);
+        let (mut a, mut b) = (Session::new(), Session::new());
+        eval(&scheduler, "a", &mut a, HEAVY).await;
+        assert!(scheduler.usage("a").unwrap() >= 50);
+
+        // While b runs an eval, a has to wait for it.
+        let (started, running) = tokio::sync::oneshot::channel();
+        let (finish, finished) = tokio::sync::oneshot::channel::<()>();
+        let b_scheduler = scheduler.clone();
+        let b_eval = tokio::spawn(async move {
+            b_scheduler
+                .run("b", &mut b, async |_: &mut Session| {
+                    started.send(()).unwrap();
+                    finished.await.unwrap();
+                })
+                .await
+        });
+        running.await.unwrap();
+        let a_eval = eval(&scheduler, "a", &mut a, "return 1");
+        tokio::pin!(a_eval);
+        let waited = tokio::time::timeout(Duration::from_millis(50), &mut a_eval).await;
+        assert!(waited.is_err());
+        finish.send(()).unwrap();
+        b_eval.await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), a_eval)
+            .await
+            .unwrap();
+        assert_eq!(scheduler.usage("b"), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let scheduler = scheduler(OverQuota::Reject);
+        let mut session = Session::new();
+        eval(&scheduler, "a", &mut session, HEAVY).await;
+        let refused = scheduler
+            .run("a", &mut session, async |_: &mut Session| ())
+            .await;
+        assert!(matches!(
+            refused,
+            Err(SchedulerError::OverQuota { budget: 50, .. })
+        ));
+        // Other tenants have budgets of their own.
+        eval(&scheduler, "b", &mut session, "return 1").await;
+    }
+
+    #[tokio::test]
+    async fn test_expired_tenants() {
+        let scheduler = Scheduler::new(Quota {
+            ticks: 50,
+            window: Duration::from_millis(20),
+            over_quota: OverQuota::Reject,
+        });
+        let mut session = Session::new();
+        eval(&scheduler, "a", &mut session, HEAVY).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        eval(&scheduler, "b", &mut session, "return 1").await;
+        assert_eq!(scheduler.usage("a"), None);
+    }
+
+    #[test]
+    fn test_tenant() {
+        assert_eq!(tenant("127.0.0.1:50312"), "127.0.0.1");
+        assert_eq!(tenant("ws://127.0.0.1:50312"), "127.0.0.1");
+        assert_eq!(tenant("[::1]:50312"), "::1");
+        assert_eq!(tenant("unix:/tmp/luarepl.sock"), "unix:/tmp/luarepl.sock");
+        assert_eq!(tenant("stdio"), "stdio");
+    }
+}
@@ -0,0 +1,218 @@
+//! Expected shapes of eval results, checked against the serialized object
+//! graph of the response.
+
+use crate::EvalResponse;
+use crate::LuaObject;
+use crate::LuaValue;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schema {
+    Any,
+    Boolean,
+    Number,
+    String,
+    /// A table with these string-keyed fields. Fields not listed are allowed.
+    Object(Vec<(String, Schema)>),
+    /// A table whose integer-keyed entries all match the element schema.
+    Array(Box<Schema>),
+    /// Either nil (or missing, as a field) or a value matching the schema.
+    Optional(Box<Schema>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaError {
+    /// Path from the result to the offending value, e.g. `servers[2].port`;
+    /// empty for the result itself.
+    pub path: String,
+    pub kind: SchemaErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaErrorKind {
+    Missing,
+    WrongType { expected: String, found: String },
+}
+
+fn type_name(value: &LuaValue) -> &'static str {
+    match value {
+        LuaValue::Nil => "nil",
+        LuaValue::Boolean(_) => "boolean",
+        LuaValue::Number(_) | LuaValue::Integer(_) => "number",
+        LuaValue::String(_) => "string",
+        LuaValue::ObjectRef(_) => "table",
+        LuaValue::FunctionRef(_) => "function",
+        LuaValue::Coroutine(_) => "thread",
+        LuaValue::UserData(_) | LuaValue::LightUserData(_) => "userdata",
+    }
+}
+
+impl Schema {
+    fn expected(&self) -> &'static str {
+        match self {
+            Schema::Any => "any",
+            Schema::Boolean => "boolean",
+            Schema::Number => "number",
+            Schema::String => "string",
+            Schema::Object(_) | Schema::Array(_) => "table",
+            Schema::Optional(inner) => inner.expected(),
+        }
+    }
+
+    /// Checks the value of `response`, returning every mismatch found.
+    pub fn validate(&self, response: &EvalResponse) -> Vec<SchemaError> {
+        let mut errors = vec![];
+        self.check(&response.value, &response.objects, "", &mut errors);
+        errors
+    }
+
+    fn check(
+        &self,
+        value: &LuaValue,
+        objects: &HashMap<String, LuaObject>,
+        path: &str,
+        errors: &mut Vec<SchemaError>,
+    ) {
+        let object = match value {
+            LuaValue::ObjectRef(id) => objects.get(id),
+            _ => None,
+        };
+        let matches = match (self, value) {
+            (Schema::Any, _) => true,
+            (Schema::Optional(_), LuaValue::Nil) => true,
+            (Schema::Optional(inner), _) => return inner.check(value, objects, path, errors),
+            (_, LuaValue::Nil) => {
+                errors.push(SchemaError {
+                    path: path.to_string(),
+                    kind: SchemaErrorKind::Missing,
+                });
+                return;
+            }
+            (Schema::Boolean, LuaValue::Boolean(_)) => true,
+            (Schema::Number, LuaValue::Number(_) | LuaValue::Integer(_)) => true,
+            (Schema::String, LuaValue::String(_)) => true,
+            (Schema::Object(_), LuaValue::ObjectRef(_)) => true,
+            (Schema::Array(_), LuaValue::ObjectRef(_)) => true,
+            _ => false,
+        };
+        if !matches {
+            errors.push(SchemaError {
+                path: path.to_string(),
+                kind: SchemaErrorKind::WrongType {
+                    expected: self.expected().to_string(),
+                    found: type_name(value).to_string(),
+                },
+            });
+            return;
+        }
+
+        let members = object.map_or(&[][..], |object| object.members.as_slice());
+        match self {
+            Schema::Object(fields) => {
+                for (name, field) in fields {
+                    let value = members
+                        .iter()
+                        .find(|(key, _)| matches!(key, LuaValue::String(k) if k == name))
+                        .map_or(&LuaValue::Nil, |(_, value)| value);
+                    let field_path = if path.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", path, name)
+                    };
+                    field.check(value, objects, &field_path, errors);
+                }
+            }
+            Schema::Array(element) => {
+                let mut items: Vec<_> = members
+                    .iter()
+                    .filter_map(|(key, value)| match key {
+                        LuaValue::Integer(i) => Some((*i, value)),
+                        _ => None,
+                    })
+                    .collect();
+                items.sort_by_key(|(index, _)| *index);
+                for (index, value) in items {
+                    element.check(value, objects, &format!("{}[{}]", path, index), errors);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EvalOptions;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_schema_validation() {
+        let schema = Schema::Object(vec![
+            ("name".to_string(), Schema::String),
+            (
+                "servers".to_string(),
+                Schema::Array(Box::new(Schema::Object(vec![
+                    ("host".to_string(), Schema::String),
+                    (
+                        "port".to_string(),
+                        Schema::Optional(Box::new(Schema::Number)),
+                    ),
+                ]))),
+            ),
+        ]);
+        let mut session = Session::new();
+        let resp = session
+            .eval_with(
+                "return { servers = { {host = 'a', port = 1}, {port = 'x'} } }".to_string(),
+                EvalOptions {
+                    schema: Some(schema),
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        assert_eq!(
+            resp.schema_errors,
+            vec![
+                SchemaError {
+                    path: "name".to_string(),
+                    kind: SchemaErrorKind::Missing,
+                },
+                SchemaError {
+                    path: "servers[2].host".to_string(),
+                    kind: SchemaErrorKind::Missing,
+                },
+                SchemaError {
+                    path: "servers[2].port".to_string(),
+                    kind: SchemaErrorKind::WrongType {
+                        expected: "number".to_string(),
+                        found: "string".to_string(),
+                    },
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_schema_on_scalar() {
+        let mut session = Session::new();
+        let resp = session.eval("return 'x'".to_string()).await;
+        assert_eq!(
+            Schema::Number.validate(&resp),
+            vec![SchemaError {
+                path: String::new(),
+                kind: SchemaErrorKind::WrongType {
+                    expected: "number".to_string(),
+                    found: "string".to_string(),
+                },
+            }]
+        );
+        assert!(Schema::Optional(Box::new(Schema::String))
+            .validate(&resp)
+            .is_empty());
+    }
+}
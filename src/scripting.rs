@@ -0,0 +1,172 @@
+//! The `repl` library, through which trusted Lua code drives the REPL
+//! itself, see `SessionBuilder::trust_repl_api`:
+//!
+//! - `repl.eval_in(name, source)` runs `source` in the peer session `name`,
+//!   see `SessionBuilder::peer`, and returns its value; tables and other
+//!   objects come back rendered as strings, as they live in the peer's Lua
+//!   state.
+//! - `repl.history()` returns the sources of the last evals, oldest first,
+//!   including the one calling it.
+//! - `repl.watch(expr)` adds a watch expression, as `Session::watch` does,
+//!   and returns its id.
+//!
+//! `eval_in` and `watch` are completed by the session like async host
+//! functions, so they cannot be called from inside `pcall`.
+
+use crate::events::SessionHandle;
+use crate::host;
+use crate::host::AsyncCall;
+use crate::host::HostError;
+use crate::output;
+use crate::render;
+use crate::watch::WatchList;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+
+const RECORD_KEY: &str = "luarepl.repl_record";
+
+/// How many sources `repl.history` returns at most.
+const INPUTS_KEPT: usize = 100;
+
+/// Defines `repl` and returns the function recording the sources of evals.
+const INSTALL_SOURCE: &str = r#"
+local eval_in, watch, kept = ...
+local unpack, remove = table.unpack, table.remove
+local inputs = {}
+
+repl = { eval_in = eval_in, watch = watch }
+
+function repl.history()
+    return { unpack(inputs) }
+end
+
+return function(source)
+    if #inputs == kept then
+        remove(inputs, 1)
+    end
+    inputs[#inputs + 1] = source
+end
+"#;
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let record: Function = ctx.load(INSTALL_SOURCE).set_name("=repl")?.call((
+        host::async_function(ctx, "repl.eval_in")?,
+        host::async_function(ctx, "repl.watch")?,
+        INPUTS_KEPT,
+    ))?;
+    ctx.set_named_registry_value(RECORD_KEY, record)
+}
+
+/// Adds `source` to what `repl.history` returns.
+pub(crate) fn record(ctx: Context, source: &str) -> Result<(), Error> {
+    let record: Function = ctx.named_registry_value(RECORD_KEY)?;
+    record.call(source)
+}
+
+/// Whether the session completes `call` here rather than with its host
+/// functions.
+pub(crate) fn handles(call: &AsyncCall) -> bool {
+    call.function.starts_with("repl.")
+}
+
+/// Runs `call` on the session's side.
+pub(crate) async fn complete(
+    call: &AsyncCall,
+    peers: &[(String, SessionHandle)],
+    watches: &mut WatchList,
+) -> Result<LuaValue, HostError> {
+    let fail = |message: String| {
+        let e: Box<dyn std::error::Error + Send + Sync> = message.into();
+        host::failure(&call.function, e.as_ref())
+    };
+    let argument = |i: usize| match call.args.get(i) {
+        Some(LuaValue::String(s)) => Ok(s.clone()),
+        _ => Err(fail(format!("argument #{} must be a string", i + 1))),
+    };
+    match call.function.as_str() {
+        "repl.eval_in" => {
+            let name = argument(0)?;
+            let source = argument(1)?;
+            let peer = peers
+                .iter()
+                .find(|(peer, _)| *peer == name)
+                .map(|(_, handle)| handle)
+                .ok_or_else(|| fail(format!("no session named {}", name)))?;
+            let response = peer
+                .eval(source)
+                .await
+                .map_err(|_| fail(format!("session {} is gone", name)))?;
+            if !response.success {
+                let error = output::error_text(&response).unwrap_or_else(|| "error".to_string());
+                return Err(fail(format!("{}: {}", name, error)));
+            }
+            Ok(match &response.value {
+                LuaValue::ObjectRef(_)
+                | LuaValue::FunctionRef(_)
+                | LuaValue::Coroutine(_)
+                | LuaValue::UserData(_) => {
+                    LuaValue::String(render::value(&response, &response.value))
+                }
+                value => value.clone(),
+            })
+        }
+        "repl.watch" => {
+            let watch = watches.add(argument(0)?);
+            Ok(LuaValue::Integer(watch.id() as i64))
+        }
+        other => Err(fail(format!("no repl function named {}", other))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_repl_api() {
+        let mut other = Session::new();
+        other.eval("score = 41".to_string()).await;
+        let mut session = Session::builder()
+            .trust_repl_api(true)
+            .peer("other", other.handle())
+            .build();
+
+        let resp = session
+            .eval("return repl.eval_in('other', 'return score + 1')".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::Integer(42));
+        let resp = session
+            .eval("return repl.eval_in('other', 'return { 1, 2 }')".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::String("{ 1, 2 }".to_string()));
+        let resp = session
+            .eval("return repl.eval_in('nobody', 'return 1')".to_string())
+            .await;
+        assert!(resp.error.unwrap().contains("no session named nobody"));
+
+        let resp = session
+            .eval("local h = repl.history() return #h, h[1], h[#h]".to_string())
+            .await;
+        assert_eq!(
+            resp.values,
+            vec![
+                LuaValue::Integer(4),
+                LuaValue::String("return repl.eval_in('other', 'return score + 1')".to_string()),
+                LuaValue::String("local h = repl.history() return #h, h[1], h[#h]".to_string()),
+            ]
+        );
+
+        let resp = session.eval("return repl.watch('score')".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Integer(0));
+        session.eval("score = 7".to_string()).await;
+        let watches = session.refresh_watches().await;
+        assert_eq!(watches.len(), 1);
+        assert_eq!(watches[0].1.value, LuaValue::Integer(7));
+
+        let untrusted = Session::new().eval("return repl".to_string()).await;
+        assert_eq!(untrusted.value, LuaValue::Nil);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::path;
+use crate::path::child_path;
+use crate::path::PathError;
+use crate::table_id;
+use crate::value_id;
+use crate::LuaValue;
+use rlua::Context;
+use rlua::Table;
+use rlua::Value;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchOptions {
+    /// How many tables deep below `_G` to look.
+    pub max_depth: usize,
+    pub max_results: usize,
+    /// Upper bound on table entries inspected, to keep huge graphs cheap.
+    pub max_entries: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 6,
+            max_results: 100,
+            max_entries: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    /// Where the match was found, e.g. `config.servers[3].host`.
+    pub path: String,
+    pub value: LuaValue,
+}
+
+fn preview(value: &Value) -> LuaValue {
+    match value {
+        Value::Nil => LuaValue::Nil,
+        Value::Boolean(b) => LuaValue::Boolean(*b),
+        Value::Integer(i) => LuaValue::Integer(*i),
+        Value::Number(n) => LuaValue::Number(*n),
+        Value::String(s) => LuaValue::String(s.to_str().unwrap_or_default().to_string()),
+        other => LuaValue::ObjectRef(other.type_name().to_string()),
+    }
+}
+
+fn contains(value: &Value, pattern: &str) -> bool {
+    matches!(value, Value::String(s) if s.to_str().is_ok_and(|s| s.contains(pattern)))
+}
+
+/// Breadth-first search of the global object graph for keys and string
+/// values containing `pattern`.
+pub(crate) fn search_globals(
+    ctx: Context,
+    pattern: &str,
+    opts: &SearchOptions,
+) -> Vec<SearchMatch> {
+    let mut matches = vec![];
+    let mut seen = HashSet::new();
+    let mut queue: VecDeque<(Table, String, usize)> = VecDeque::new();
+    let mut entries = 0;
+
+    queue.push_back((ctx.globals(), String::new(), 0));
+    'search: while let Some((table, path, depth)) = queue.pop_front() {
+        if !seen.insert(table_id(ctx, &table)) {
+            continue;
+        }
+        for (key, value) in table.pairs::<Value, Value>().filter_map(Result::ok) {
+            entries += 1;
+            if entries > opts.max_entries || matches.len() >= opts.max_results {
+                break 'search;
+            }
+
+            let value_path = child_path(&path, &key);
+            if contains(&key, pattern) || contains(&value, pattern) {
+                matches.push(SearchMatch {
+                    path: value_path.clone(),
+                    value: preview(&value),
+                });
+            }
+            if let Value::Table(child) = value {
+                if depth < opts.max_depth {
+                    queue.push_back((child, value_path, depth + 1));
+                }
+            }
+        }
+    }
+
+    matches.sort_by(|a, b| a.path.cmp(&b.path));
+    matches
+}
+
+/// Every path from `_G` through which the table, function, coroutine or
+/// userdata `target` can be reached, for `:refs`. `target` is an object id
+/// such as `table: 0x5581c0a3e2f0`, or a path to the object.
+///
+/// Each table is only walked once, so a reference is listed under the
+/// shortest path to the table holding it; more than one path means the
+/// object is shared, e.g. aliased by two fields or part of a cycle.
+pub(crate) fn references(
+    ctx: Context,
+    target: &str,
+    opts: &SearchOptions,
+) -> Result<Vec<String>, PathError> {
+    let id = match path::parse_path(target) {
+        Ok(segments) => match path::resolve(ctx, &segments)? {
+            value @ (Value::Table(_)
+            | Value::Function(_)
+            | Value::Thread(_)
+            | Value::UserData(_)) => value_id(ctx, value),
+            value => {
+                return Err(PathError::NotATable {
+                    path: target.to_string(),
+                    type_name: value.type_name().to_string(),
+                })
+            }
+        },
+        Err(_) => target.to_string(),
+    };
+    let mut paths = vec![];
+    let mut seen = HashSet::new();
+    let mut queue: VecDeque<(Table, String, usize)> = VecDeque::new();
+    let mut entries = 0;
+
+    queue.push_back((ctx.globals(), String::new(), 0));
+    'search: while let Some((table, path, depth)) = queue.pop_front() {
+        if !seen.insert(table_id(ctx, &table)) {
+            continue;
+        }
+        for (key, value) in table.pairs::<Value, Value>().filter_map(Result::ok) {
+            entries += 1;
+            if entries > opts.max_entries || paths.len() >= opts.max_results {
+                break 'search;
+            }
+
+            let value_path = child_path(&path, &key);
+            if matches!(
+                value,
+                Value::Table(_) | Value::Function(_) | Value::Thread(_) | Value::UserData(_)
+            ) && value_id(ctx, value.clone()) == id
+            {
+                paths.push(value_path.clone());
+            }
+            if let Value::Table(child) = value {
+                if depth < opts.max_depth {
+                    queue.push_back((child, value_path, depth + 1));
+                }
+            }
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_search() {
+        let mut session = Session::new();
+        session
+            .eval(
+                "config = { servers = { {host = 'a'}, {host = 'b'}, {host = 'needle.example'} } }"
+                    .to_string(),
+            )
+            .await;
+        session.eval("needle_count = 3".to_string()).await;
+
+        let matches = session
+            .search("needle".to_string(), SearchOptions::default())
+            .await;
+        assert_eq!(
+            matches,
+            vec![
+                SearchMatch {
+                    path: "config.servers[3].host".to_string(),
+                    value: LuaValue::String("needle.example".to_string()),
+                },
+                SearchMatch {
+                    path: "needle_count".to_string(),
+                    value: LuaValue::Integer(3),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_depth_limit() {
+        let mut session = Session::new();
+        session
+            .eval("deep = { a = { b = { c = 'needle' } } }".to_string())
+            .await;
+
+        let opts = SearchOptions {
+            max_depth: 2,
+            ..Default::default()
+        };
+        assert!(session.search("needle".to_string(), opts).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_references() {
+        let mut session = Session::new();
+        session
+            .eval(
+                "shared = { hp = 10 }\n\
+                 party = { leader = shared, members = { shared, { hp = 5 } } }\n\
+                 shared.self = shared"
+                    .to_string(),
+            )
+            .await;
+        let paths = session
+            .references("party.leader".to_string(), SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            paths,
+            ["party.leader", "party.members[1]", "shared", "shared.self"]
+        );
+
+        let id = session.eval("return shared".to_string()).await.value;
+        let LuaValue::ObjectRef(id) = id else {
+            panic!("{:?}", id);
+        };
+        let by_id = session
+            .references(id, SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(by_id, paths);
+        assert!(session
+            .references("shared.hp".to_string(), SearchOptions::default())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,2082 @@
+//! Serves sessions over any `Transport`, speaking the frames of `protocol`.
+//! Each connection opens and closes sessions of its own, all built from the
+//! same config with a `Workspace` for file transfers added; those still open
+//! when the client goes away are closed with it. Sessions capture their
+//! output, which would otherwise end up among the frames of a stdio
+//! transport. Requests are handled one at a time, in the order they arrive.
+//!
+//! The connections share a registry of the sessions open on the server, so
+//! that any client can list them and kill one; the connection owning a
+//! killed session closes it when its client next addresses it.
+//!
+//! `serve_shared` instead has every client work in one session, see there.
+//! With `ServeOptions::token` set, clients must present the token with
+//! `hello` before anything else, see `auth`. Settings sent on
+//! `ServeOptions::reloads` apply to the sessions and connections opened
+//! after them. With `ServeOptions::quota` set, evals are run through a
+//! `Scheduler`, with a CPU budget for each host connecting, see
+//! `scheduler::tenant`.
+//! Reports of Lua's `progress` during an eval in a session of the
+//! connection's own are sent to its client as they are made, as `progress`
+//! frames ahead of the eval's reply.
+//! The changes to paths a client watches with `watch_path` are sent to it
+//! as `path_changed` frames after the reply to each eval.
+
+use crate::audit::AuditLog;
+use crate::audit::AuditStatus;
+use crate::auth;
+use crate::auth::Token;
+use crate::cancel::CancelToken;
+use crate::compression::Compressed;
+use crate::compression::Compression;
+use crate::compression::Framing;
+use crate::delta::ClientVersions;
+use crate::environment::Environment;
+use crate::help;
+use crate::info::SessionInfo;
+use crate::manager::SessionId;
+use crate::output::OutputLevel;
+use crate::progress::Progress;
+use crate::progress::ProgressHandler;
+use crate::protocol::Frame;
+use crate::protocol::Reply;
+use crate::protocol::Request;
+use crate::protocol::ResponseFormat;
+use crate::protocol::SessionSummary;
+use crate::protocol::MAX_CHUNK;
+use crate::scheduler;
+use crate::scheduler::Quota;
+use crate::scheduler::Scheduler;
+use crate::subscription::PathChange;
+use crate::transport::Connection;
+use crate::transport::Transport;
+use crate::workspace::Workspace;
+use crate::EvalOptions;
+use crate::EvalRequest;
+use crate::EvalResponse;
+use crate::Session;
+use crate::SessionConfig;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+/// The sessions open on the server, by client number and session id.
+#[derive(Debug, Clone, Default)]
+struct Registry(Arc<Mutex<BTreeMap<(u64, SessionId), Registered>>>);
+
+#[derive(Debug)]
+struct Registered {
+    peer: String,
+    evals: u64,
+    cancel: CancelToken,
+}
+
+impl Registry {
+    fn add(&self, client: u64, id: SessionId, peer: String, cancel: CancelToken) {
+        let registered = Registered {
+            peer,
+            evals: 0,
+            cancel,
+        };
+        self.0.lock().unwrap().insert((client, id), registered);
+    }
+
+    fn remove(&self, client: u64, id: SessionId) -> Option<CancelToken> {
+        let registered = self.0.lock().unwrap().remove(&(client, id))?;
+        Some(registered.cancel)
+    }
+
+    fn remove_client(&self, client: u64) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(owner, _), _| *owner != client);
+    }
+
+    fn contains(&self, client: u64, id: SessionId) -> bool {
+        self.0.lock().unwrap().contains_key(&(client, id))
+    }
+
+    fn count_eval(&self, client: u64, id: SessionId) {
+        if let Some(registered) = self.0.lock().unwrap().get_mut(&(client, id)) {
+            registered.evals += 1;
+        }
+    }
+
+    /// The sessions as `client` is shown them.
+    fn list(&self, client: u64) -> Vec<SessionSummary> {
+        let registry = self.0.lock().unwrap();
+        registry
+            .iter()
+            .map(|(&(owner, session), registered)| SessionSummary {
+                client: owner,
+                session,
+                peer: registered.peer.clone(),
+                evals: registered.evals,
+                own: owner == client,
+            })
+            .collect()
+    }
+}
+
+/// A connection's place in the registry, and the scheduler and audit log
+/// its evals go through, if any.
+#[derive(Debug, Clone)]
+struct Client {
+    number: u64,
+    peer: String,
+    registry: Registry,
+    scheduler: Option<Scheduler>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    /// The namespace the client's evals in a shared session run in.
+    namespace: Option<String>,
+}
+
+/// The session of `serve_shared`, and the clients attached to it.
+#[derive(Debug, Clone)]
+struct Shared {
+    session: Arc<tokio::sync::Mutex<Session>>,
+    /// Kept apart, so that killing the session need not wait for its eval.
+    cancel: CancelToken,
+    attached: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Broadcast>>>>,
+}
+
+/// An eval one client of `serve_shared` ran, for the others.
+#[derive(Debug, Clone)]
+struct Broadcast {
+    client: u64,
+    peer: String,
+    source: String,
+    response: EvalResponse,
+}
+
+impl Shared {
+    fn new(config: &SessionConfig) -> Self {
+        let session = Session::with_config(config.clone());
+        Self {
+            cancel: session.cancel_token(),
+            session: Arc::new(tokio::sync::Mutex::new(session)),
+            attached: Default::default(),
+        }
+    }
+
+    fn detach(&self, client: u64) {
+        self.attached.lock().unwrap().remove(&client);
+    }
+
+    fn broadcast(&self, broadcast: Broadcast) {
+        for (client, sender) in self.attached.lock().unwrap().iter() {
+            if *client != broadcast.client {
+                let _ = sender.send(broadcast.clone());
+            }
+        }
+    }
+}
+
+/// A client's hold on the session of `serve_shared`.
+#[derive(Debug)]
+struct Attachment {
+    shared: Shared,
+    /// The session id the client attached under, once it has.
+    id: Option<SessionId>,
+    broadcasts: mpsc::UnboundedSender<Broadcast>,
+}
+
+/// The sessions a connection addresses.
+#[derive(Debug)]
+enum Sessions {
+    Own(HashMap<SessionId, Session>),
+    Shared(Attachment),
+}
+
+/// How `serve_with` serves its clients.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    /// See `serve_shared`.
+    pub shared: bool,
+    /// With `shared`, runs each client's evals in a namespace named after
+    /// its number, see `Environment::Namespace`: the globals the session
+    /// had are there for all, but those a client assigns are its own.
+    pub namespaced: bool,
+    /// The token clients must present with `hello` before anything else.
+    pub token: Option<Token>,
+    /// Settings replacing `config` and `token`, e.g. after the settings
+    /// file changed. Sessions already open keep the config they were built
+    /// from, the session of `serve_shared` included.
+    pub reloads: Option<watch::Receiver<Reload>>,
+    /// The codecs offered to clients that list theirs with `hello`.
+    pub compression: Compression,
+    /// The CPU budget of each connection, with evals run through a
+    /// `Scheduler`, see there.
+    pub quota: Option<Quota>,
+    /// Where every eval is recorded, with the connection's peer as client.
+    pub audit: Option<Arc<Mutex<AuditLog>>>,
+}
+
+/// What a server serves with from when it is sent it.
+#[derive(Debug, Clone)]
+pub struct Reload {
+    pub config: SessionConfig,
+    pub token: Option<Token>,
+}
+
+/// `config` as served sessions are built from it.
+pub(crate) fn served(config: SessionConfig) -> SessionConfig {
+    SessionConfig {
+        workspace: true,
+        capture_output: true,
+        ..config
+    }
+}
+
+/// The settings sent on `reloads` since it was last asked, if any.
+pub(crate) fn reloaded(reloads: &mut Option<watch::Receiver<Reload>>) -> Option<Reload> {
+    let reloads = reloads.as_mut()?;
+    match reloads.has_changed() {
+        Ok(true) => Some(reloads.borrow_and_update().clone()),
+        _ => None,
+    }
+}
+
+/// Accepts clients until `transport` runs out of them, then waits for the
+/// connected ones to disconnect.
+pub async fn serve<T: Transport>(transport: T, config: SessionConfig) -> io::Result<()> {
+    serve_with(transport, config, ServeOptions::default()).await
+}
+
+/// Like `serve`, but the session every client opens is the same one, for
+/// pair-debugging a live environment: evals of every client run in one Lua
+/// state, one at a time, and the other clients are sent each eval's reply
+/// in a `broadcast` naming the client that ran it. Killing the session
+/// detaches the client that opened it under that id, and cancels whichever
+/// eval is running. See `ServeOptions::namespaced` for keeping the clients'
+/// globals apart.
+pub async fn serve_shared<T: Transport>(transport: T, config: SessionConfig) -> io::Result<()> {
+    let options = ServeOptions {
+        shared: true,
+        ..Default::default()
+    };
+    serve_with(transport, config, options).await
+}
+
+/// `serve` or `serve_shared`, as `options` say.
+pub async fn serve_with<T: Transport>(
+    mut transport: T,
+    config: SessionConfig,
+    mut options: ServeOptions,
+) -> io::Result<()> {
+    let mut config = served(config);
+    let shared = options.shared.then(|| Shared::new(&config));
+    // Every connection task holds a sender, so the channel closes once the
+    // last of them is done.
+    let (connected, mut done) = mpsc::channel::<()>(1);
+    let registry = Registry::default();
+    let scheduler = options.quota.map(Scheduler::new);
+    let mut clients = 0;
+    while let Some(connection) = transport.accept().await? {
+        if let Some(reload) = reloaded(&mut options.reloads) {
+            config = served(reload.config);
+            options.token = reload.token;
+        }
+        let connected = connected.clone();
+        let config = config.clone();
+        clients += 1;
+        let client = Client {
+            number: clients,
+            peer: connection.peer(),
+            registry: registry.clone(),
+            scheduler: scheduler.clone(),
+            audit: options.audit.clone(),
+            namespace: options.namespaced.then(|| clients.to_string()),
+        };
+        let shared = shared.clone();
+        let token = options.token.clone();
+        let reloads = options.reloads.clone();
+        let compression = options.compression.clone();
+        tokio::spawn(async move {
+            let connection = Compressed::new(connection);
+            handle(
+                connection,
+                config,
+                client,
+                shared,
+                token,
+                reloads,
+                compression,
+            )
+            .await;
+            drop(connected);
+        });
+    }
+    drop(connected);
+    done.recv().await;
+    // The connections dropped their handles before they were done.
+    if let Some(session) = shared.and_then(|shared| Arc::try_unwrap(shared.session).ok()) {
+        session.into_inner().close().await;
+    }
+    Ok(())
+}
+
+async fn handle<C: Connection>(
+    mut connection: Compressed<C>,
+    mut config: SessionConfig,
+    client: Client,
+    shared: Option<Shared>,
+    // Until the client presents it.
+    mut token: Option<Token>,
+    mut reloads: Option<watch::Receiver<Reload>>,
+    compression: Compression,
+) {
+    let (broadcasts, mut received) = mpsc::unbounded_channel();
+    let mut sessions = match shared {
+        Some(shared) => Sessions::Shared(Attachment {
+            shared,
+            id: None,
+            broadcasts,
+        }),
+        None => Sessions::Own(HashMap::new()),
+    };
+    // Set by `hello`, for evals that do not choose a format.
+    let mut format = ResponseFormat::default();
+    // The objects the client was sent, once it asks for deltas.
+    let mut versions: Option<ClientVersions> = None;
+    // The wrong tokens the client presented.
+    let mut rejections = 0;
+    // The changes to the paths the client watches, by session.
+    let mut watched: HashMap<SessionId, mpsc::UnboundedReceiver<PathChange>> = HashMap::new();
+    // The sessions the connection opens report the progress of their evals
+    // here, to be sent to the client while the eval runs.
+    let (progress, mut reports) = mpsc::unbounded_channel();
+    let progress = ProgressHandler::new(move |report: &Progress| {
+        let _ = progress.send(report.clone());
+    });
+    config.progress = Some(progress.clone());
+    loop {
+        let message = tokio::select! {
+            message = connection.recv() => message,
+            // Only attached clients are sent broadcasts, so by now the
+            // client has sent a frame, and e.g. a WebSocket handshake is
+            // no longer in progress.
+            Some(broadcast) = received.recv() => {
+                let Sessions::Shared(Attachment { id: Some(id), .. }) = &sessions else {
+                    continue;
+                };
+                let mut reply = Reply::Broadcast {
+                    client: broadcast.client,
+                    peer: broadcast.peer,
+                    source: broadcast.source,
+                    reply: Box::new(Reply::eval_result(
+                        broadcast.response,
+                        format,
+                        config.output_level,
+                    )),
+                };
+                if let Some(versions) = &mut versions {
+                    as_deltas(&mut reply, versions);
+                }
+                if let Err(e) = connection.send(&Frame::new(*id, reply).encode()).await {
+                    eprintln!("{}: {}", connection.peer(), e);
+                    break;
+                }
+                continue;
+            }
+        };
+        let message = match message {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("{}: {}", connection.peer(), e);
+                break;
+            }
+        };
+        // Without a session id there is nothing to address an error reply
+        // to, so a malformed frame ends the connection.
+        let frame = match Frame::<Request>::decode(&message) {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("{}: bad frame: {}", connection.peer(), e);
+                break;
+            }
+        };
+        if let Some(reload) = reloaded(&mut reloads) {
+            config = SessionConfig {
+                progress: Some(progress.clone()),
+                ..served(reload.config)
+            };
+            // A client that has presented the old token stays in.
+            if token.is_some() {
+                token = reload.token;
+            }
+        }
+        if let Some(expected) = &token {
+            let refused = match &frame.body {
+                Request::Hello { token, .. } if expected.accepts(token.as_deref()) => None,
+                Request::Hello { .. } => Some(auth::REJECTED),
+                _ => Some(auth::REQUIRED),
+            };
+            if let Some(message) = refused {
+                if message == auth::REJECTED {
+                    rejections += 1;
+                }
+                let reply = Reply::Error {
+                    message: message.to_string(),
+                };
+                if let Err(e) = connection
+                    .send(&Frame::new(frame.session, reply).encode())
+                    .await
+                {
+                    eprintln!("{}: {}", connection.peer(), e);
+                    break;
+                }
+                if rejections == auth::MAX_REJECTIONS {
+                    eprintln!("{}: too many wrong tokens", connection.peer());
+                    break;
+                }
+                continue;
+            }
+            token = None;
+        }
+        // The items of an iterator result follow the eval's reply.
+        let streamed = match &frame.body {
+            Request::Eval {
+                id,
+                iterate: Some(_),
+                ..
+            } => Some(*id),
+            _ => None,
+        };
+        let (request_id, output_level) = match &frame.body {
+            Request::Eval {
+                id, output_level, ..
+            } => (*id, *output_level),
+            _ => (None, None),
+        };
+        // The codecs a `hello` lists, which the server picks among.
+        let offered = match &frame.body {
+            Request::Hello { compression, .. } if !compression.is_empty() => {
+                Some(compression.clone())
+            }
+            _ => None,
+        };
+        if let Request::Hello { delta, .. } = &frame.body {
+            versions = match delta {
+                true => Some(versions.take().unwrap_or_default()),
+                false => None,
+            };
+        }
+        let (id, body) = (frame.session, frame.body);
+        let mut reply = {
+            let dispatched = async {
+                match &mut sessions {
+                    Sessions::Own(sessions) => {
+                        dispatch(sessions, &mut format, &config, &client, id, body).await
+                    }
+                    Sessions::Shared(attachment) => {
+                        dispatch_shared(attachment, &mut format, &config, &client, id, body).await
+                    }
+                }
+            };
+            tokio::pin!(dispatched);
+            // Until sending one fails, after which the reply will too.
+            let mut reporting = true;
+            loop {
+                tokio::select! {
+                    reply = &mut dispatched => break reply,
+                    Some(report) = reports.recv(), if reporting => {
+                        let report = Frame::new(id, Reply::progress(request_id, report));
+                        reporting = connection.send(&report.encode()).await.is_ok();
+                    }
+                }
+            }
+        };
+        if let Err(e) =
+            send_progress(&mut connection, frame.session, request_id, &mut reports).await
+        {
+            eprintln!("{}: {}", connection.peer(), e);
+            break;
+        }
+        if let Some(versions) = &mut versions {
+            as_deltas(&mut reply, versions);
+        }
+        match (&reply, &mut sessions) {
+            // A session opened under the id of a closed one starts afresh.
+            (Reply::Opened, _) => {
+                watched.remove(&frame.session);
+            }
+            (Reply::Watching { .. }, Sessions::Own(sessions)) => {
+                if let Some(session) = sessions.get_mut(&frame.session) {
+                    watched
+                        .entry(frame.session)
+                        .or_insert_with(|| session.path_changes());
+                }
+            }
+            _ => {}
+        }
+        let mut framing = None;
+        if let (
+            Some(offered),
+            Reply::Hello {
+                compression: chosen,
+                ..
+            },
+        ) = (offered, &mut reply)
+        {
+            let codec = compression.negotiate(offered.iter().map(String::as_str));
+            *chosen = codec.map(|codec| codec.name().to_string());
+            framing = codec.map(|codec| Framing::new(&compression, Some(codec)));
+        }
+        let reply = Frame::new(frame.session, reply);
+        if let Err(e) = connection.send(&reply.encode()).await {
+            eprintln!("{}: {}", connection.peer(), e);
+            break;
+        }
+        // The reply itself still goes out as the client last knew.
+        if let Some(framing) = framing {
+            connection.set_framing(framing);
+        }
+        let level = output_level.unwrap_or(config.output_level);
+        let sent = match (streamed, &mut sessions) {
+            (None, _) => Ok(()),
+            (Some(request_id), Sessions::Own(sessions)) => match sessions.get_mut(&frame.session) {
+                Some(session) => {
+                    send_items(&mut connection, frame.session, request_id, session, level).await
+                }
+                None => Ok(()),
+            },
+            (Some(request_id), Sessions::Shared(attachment))
+                if attachment.id == Some(frame.session) =>
+            {
+                let mut session = attachment.shared.session.lock().await;
+                send_items(
+                    &mut connection,
+                    frame.session,
+                    request_id,
+                    &mut session,
+                    level,
+                )
+                .await
+            }
+            (Some(_), Sessions::Shared(_)) => Ok(()),
+        };
+        // Iterating can report progress too.
+        let sent = match sent {
+            Ok(()) if streamed.is_some() => {
+                send_progress(&mut connection, frame.session, request_id, &mut reports).await
+            }
+            sent => sent,
+        };
+        if let Err(e) = sent {
+            eprintln!("{}: {}", connection.peer(), e);
+            break;
+        }
+        if let Some(changes) = watched.get_mut(&frame.session) {
+            if let Err(e) = send_changes(&mut connection, frame.session, changes, level).await {
+                eprintln!("{}: {}", connection.peer(), e);
+                break;
+            }
+        }
+    }
+    client.registry.remove_client(client.number);
+    match sessions {
+        Sessions::Own(sessions) => {
+            for (_, session) in sessions {
+                session.close().await;
+            }
+        }
+        Sessions::Shared(attachment) => attachment.shared.detach(client.number),
+    }
+}
+
+/// Sends the items of the iterator the session's last eval returned, one
+/// frame each as they are produced, then how many there were.
+async fn send_items<C: Connection>(
+    connection: &mut C,
+    id: SessionId,
+    request_id: Option<u64>,
+    session: &mut Session,
+    level: OutputLevel,
+) -> io::Result<()> {
+    let mut count = 0;
+    while let Some(item) = session.next_item().await {
+        let reply = Reply::item(request_id, count, item, level);
+        connection.send(&Frame::new(id, reply).encode()).await?;
+        count += 1;
+    }
+    let reply = Reply::Exhausted {
+        id: request_id,
+        count,
+    };
+    connection.send(&Frame::new(id, reply).encode()).await
+}
+
+/// Sends the progress reports made so far of the eval `request_id`, one
+/// frame each.
+async fn send_progress<C: Connection>(
+    connection: &mut C,
+    id: SessionId,
+    request_id: Option<u64>,
+    reports: &mut mpsc::UnboundedReceiver<Progress>,
+) -> io::Result<()> {
+    while let Ok(report) = reports.try_recv() {
+        let reply = Reply::progress(request_id, report);
+        connection.send(&Frame::new(id, reply).encode()).await?;
+    }
+    Ok(())
+}
+
+/// Sends the changes to the watched paths of session `id` so far, one frame
+/// each.
+async fn send_changes<C: Connection>(
+    connection: &mut C,
+    id: SessionId,
+    changes: &mut mpsc::UnboundedReceiver<PathChange>,
+    level: OutputLevel,
+) -> io::Result<()> {
+    while let Ok(change) = changes.try_recv() {
+        let reply = Reply::path_changed(change, level);
+        connection.send(&Frame::new(id, reply).encode()).await?;
+    }
+    Ok(())
+}
+
+/// `reply` with the objects of a `v2` response, its own or broadcast, as
+/// deltas against those the connection was sent before.
+fn as_deltas(reply: &mut Reply, versions: &mut ClientVersions) {
+    match reply {
+        Reply::Response {
+            objects, deltas, ..
+        } => *deltas = versions.delta_objects(std::mem::take(objects)),
+        Reply::Broadcast { reply, .. } => as_deltas(reply, versions),
+        _ => {}
+    }
+}
+
+/// Whether `request` is about the session it names, rather than the
+/// connection or the server.
+fn about_session(request: &Request) -> bool {
+    !matches!(
+        request,
+        Request::Hello { .. }
+            | Request::Help { .. }
+            | Request::Apropos { .. }
+            | Request::List
+            | Request::Kill { .. }
+    )
+}
+
+// Clients decide what to do about a mismatch, see `SessionInfo::check`.
+fn hello(
+    format: &mut ResponseFormat,
+    requested: Option<ResponseFormat>,
+    config: &SessionConfig,
+) -> Reply {
+    if let Some(requested) = requested {
+        *format = requested;
+    }
+    Reply::Hello {
+        info: SessionInfo::new(config),
+        compression: None,
+    }
+}
+
+fn help(name: &str) -> Reply {
+    match help::lookup(name) {
+        Some(topic) => Reply::Topics {
+            topics: vec![topic.into()],
+        },
+        None => Reply::Error {
+            message: format!("no help for {}, try apropos", name),
+        },
+    }
+}
+
+fn apropos(pattern: &str) -> Reply {
+    Reply::Topics {
+        topics: help::apropos(pattern).into_iter().map(Into::into).collect(),
+    }
+}
+
+fn kill(client: &Client, owner: u64, id: SessionId) -> Reply {
+    match client.registry.remove(owner, id) {
+        Some(cancel) => {
+            // The owner's running eval, if any, stops at once; the session
+            // itself goes when the owner next addresses it.
+            cancel.cancel();
+            Reply::Killed
+        }
+        None => Reply::Error {
+            message: format!("client {} has no session {}", owner, id),
+        },
+    }
+}
+
+/// Runs an eval in session `id` for `client`, through its scheduler and
+/// into its audit log if it has them.
+async fn eval(
+    client: &Client,
+    id: SessionId,
+    session: &mut Session,
+    source: String,
+    request_id: Option<u64>,
+    options: EvalOptions,
+) -> Result<EvalResponse, Reply> {
+    let audited = client.audit.as_ref().map(|audit| (audit, source.clone()));
+    let started = Instant::now();
+    let run = async |session: &mut Session| match request_id {
+        Some(request_id) => {
+            session
+                .eval_request(EvalRequest {
+                    options,
+                    ..EvalRequest::new(request_id, source)
+                })
+                .await
+        }
+        None => session.eval_with(source, options).await,
+    };
+    let response = match &client.scheduler {
+        Some(scheduler) => scheduler
+            .run(scheduler::tenant(&client.peer), session, run)
+            .await
+            .map_err(|e| Reply::Error {
+                message: e.to_string(),
+            })?,
+        None => run(session).await,
+    };
+    if let Some((audit, source)) = audited {
+        let status = AuditStatus::of(&response);
+        let recorded = audit.lock().unwrap().record(
+            id,
+            Some(&client.peer),
+            &source,
+            status,
+            started.elapsed(),
+        );
+        if let Err(e) = recorded {
+            eprintln!("{}: cannot write the audit log: {}", client.peer, e);
+        }
+    }
+    Ok(response)
+}
+
+async fn dispatch(
+    sessions: &mut HashMap<SessionId, Session>,
+    format: &mut ResponseFormat,
+    config: &SessionConfig,
+    client: &Client,
+    id: SessionId,
+    request: Request,
+) -> Reply {
+    let not_open = || Reply::Error {
+        message: format!("session {} is not open", id),
+    };
+    if about_session(&request)
+        && sessions.contains_key(&id)
+        && !client.registry.contains(client.number, id)
+    {
+        if let Some(session) = sessions.remove(&id) {
+            session.close().await;
+        }
+        if request != Request::Open {
+            return Reply::Error {
+                message: format!("session {} was killed", id),
+            };
+        }
+    }
+    match request {
+        Request::Hello {
+            format: requested, ..
+        } => hello(format, requested, config),
+        Request::Open if sessions.contains_key(&id) => Reply::Error {
+            message: format!("session {} is already open", id),
+        },
+        Request::Open => {
+            let session = Session::with_config(config.clone());
+            let peer = client.peer.clone();
+            client
+                .registry
+                .add(client.number, id, peer, session.cancel_token());
+            sessions.insert(id, session);
+            Reply::Opened
+        }
+        Request::Close => match sessions.remove(&id) {
+            Some(session) => {
+                client.registry.remove(client.number, id);
+                session.close().await;
+                Reply::Closed
+            }
+            None => not_open(),
+        },
+        Request::Eval {
+            source,
+            id: request_id,
+            format: requested,
+            iterate,
+            file,
+            chunk_name,
+            line,
+            output_level,
+        } => match sessions.get_mut(&id) {
+            Some(session) => {
+                let options = EvalOptions {
+                    iterate,
+                    output_level,
+                    ..Default::default()
+                }
+                .located(file, chunk_name, line);
+                let response = match eval(client, id, session, source, request_id, options).await {
+                    Ok(response) => response,
+                    Err(reply) => return reply,
+                };
+                client.registry.count_eval(client.number, id);
+                let format = requested.unwrap_or(*format);
+                let level = output_level.unwrap_or(config.output_level);
+                Reply::eval_result(response, format, level)
+            }
+            None => not_open(),
+        },
+        Request::Complete { prefix } => match sessions.get_mut(&id) {
+            Some(session) => Reply::Completions {
+                candidates: session.complete(&prefix).await,
+            },
+            None => not_open(),
+        },
+        Request::WatchPath { path } => match sessions.get_mut(&id) {
+            Some(session) => match session.watch_path(path).await {
+                Ok(watch) => Reply::Watching { watch: watch.id() },
+                Err(e) => Reply::Error {
+                    message: e.to_string(),
+                },
+            },
+            None => not_open(),
+        },
+        Request::Upload {
+            path,
+            offset,
+            data,
+            checksum,
+        } => match workspace(sessions.get_mut(&id), id).await {
+            Ok(workspace) => upload(&workspace, &path, offset, &data, checksum),
+            Err(reply) => reply,
+        },
+        Request::Download { path, offset } => match workspace(sessions.get_mut(&id), id).await {
+            Ok(workspace) => download(&workspace, &path, offset),
+            Err(reply) => reply,
+        },
+        Request::Help { name } => help(&name),
+        Request::Apropos { pattern } => apropos(&pattern),
+        Request::List => Reply::Sessions {
+            sessions: client.registry.list(client.number),
+        },
+        Request::Kill { client: owner, id } => {
+            let reply = kill(client, owner, id);
+            if reply == Reply::Killed && owner == client.number {
+                if let Some(session) = sessions.remove(&id) {
+                    session.close().await;
+                }
+            }
+            reply
+        }
+    }
+}
+
+/// `dispatch` for a client of `serve_shared`, which can only open the
+/// shared session, under one id at a time.
+async fn dispatch_shared(
+    attachment: &mut Attachment,
+    format: &mut ResponseFormat,
+    config: &SessionConfig,
+    client: &Client,
+    id: SessionId,
+    request: Request,
+) -> Reply {
+    let not_open = || Reply::Error {
+        message: format!("session {} is not open", id),
+    };
+    if let Some(attached) = attachment.id {
+        if about_session(&request) && !client.registry.contains(client.number, attached) {
+            attachment.shared.detach(client.number);
+            attachment.id = None;
+            if request != Request::Open {
+                return Reply::Error {
+                    message: format!("session {} was killed", attached),
+                };
+            }
+        }
+    }
+    let shared = &attachment.shared;
+    match request {
+        Request::Hello {
+            format: requested, ..
+        } => hello(format, requested, config),
+        Request::Open => match attachment.id {
+            Some(attached) if attached == id => Reply::Error {
+                message: format!("session {} is already open", id),
+            },
+            Some(attached) => Reply::Error {
+                message: format!("already attached as session {}", attached),
+            },
+            None => {
+                let peer = client.peer.clone();
+                client
+                    .registry
+                    .add(client.number, id, peer, shared.cancel.clone());
+                let broadcasts = attachment.broadcasts.clone();
+                shared
+                    .attached
+                    .lock()
+                    .unwrap()
+                    .insert(client.number, broadcasts);
+                attachment.id = Some(id);
+                Reply::Opened
+            }
+        },
+        Request::Help { name } => help(&name),
+        Request::Apropos { pattern } => apropos(&pattern),
+        Request::List => Reply::Sessions {
+            sessions: client.registry.list(client.number),
+        },
+        Request::Kill { client: owner, id } => {
+            let reply = kill(client, owner, id);
+            if reply == Reply::Killed {
+                shared.detach(owner);
+            }
+            reply
+        }
+        _ if attachment.id != Some(id) => not_open(),
+        Request::Close => {
+            client.registry.remove(client.number, id);
+            shared.detach(client.number);
+            attachment.id = None;
+            Reply::Closed
+        }
+        Request::Eval {
+            source,
+            id: request_id,
+            format: requested,
+            iterate,
+            file,
+            chunk_name,
+            line,
+            output_level,
+        } => {
+            let options = EvalOptions {
+                iterate,
+                output_level,
+                environment: client.namespace.clone().map(Environment::Namespace),
+                ..Default::default()
+            }
+            .located(file, chunk_name, line);
+            let mut session = shared.session.lock().await;
+            let response = eval(
+                client,
+                id,
+                &mut session,
+                source.clone(),
+                request_id,
+                options,
+            )
+            .await;
+            drop(session);
+            let response = match response {
+                Ok(response) => response,
+                Err(reply) => return reply,
+            };
+            client.registry.count_eval(client.number, id);
+            shared.broadcast(Broadcast {
+                client: client.number,
+                peer: client.peer.clone(),
+                source,
+                response: response.clone(),
+            });
+            let format = requested.unwrap_or(*format);
+            Reply::eval_result(
+                response,
+                format,
+                output_level.unwrap_or(config.output_level),
+            )
+        }
+        Request::Complete { prefix } => Reply::Completions {
+            candidates: shared.session.lock().await.complete(&prefix).await,
+        },
+        // Only one receiver gets a session's changes, so they could not go
+        // to every client.
+        Request::WatchPath { .. } => Reply::Error {
+            message: "the paths of a shared session cannot be watched".to_string(),
+        },
+        Request::Upload {
+            path,
+            offset,
+            data,
+            checksum,
+        } => match workspace(Some(&mut *shared.session.lock().await), id).await {
+            Ok(workspace) => upload(&workspace, &path, offset, &data, checksum),
+            Err(reply) => reply,
+        },
+        Request::Download { path, offset } => {
+            match workspace(Some(&mut *shared.session.lock().await), id).await {
+                Ok(workspace) => download(&workspace, &path, offset),
+                Err(reply) => reply,
+            }
+        }
+    }
+}
+
+async fn workspace(session: Option<&mut Session>, id: SessionId) -> Result<Workspace, Reply> {
+    let session = session.ok_or_else(|| Reply::Error {
+        message: format!("session {} is not open", id),
+    })?;
+    session.workspace().await.ok_or_else(|| Reply::Error {
+        message: format!("session {} has no workspace", id),
+    })
+}
+
+fn upload(
+    workspace: &Workspace,
+    path: &str,
+    offset: u64,
+    data: &[u8],
+    checksum: Option<u32>,
+) -> Reply {
+    let failed = |e: io::Error| Reply::Error {
+        message: format!("cannot upload {}: {}", path, e),
+    };
+    if data.len() > MAX_CHUNK {
+        return Reply::Error {
+            message: format!("chunks are at most {} bytes", MAX_CHUNK),
+        };
+    }
+    let size = match workspace.write_chunk(path, offset, data) {
+        Ok(size) => size,
+        Err(e) => return failed(e),
+    };
+    let Some(expected) = checksum else {
+        return Reply::Received { size };
+    };
+    match workspace.checksum(path) {
+        Ok(actual) if actual == expected => Reply::Uploaded {
+            path: workspace.root().join(path).display().to_string(),
+            size,
+        },
+        Ok(actual) => {
+            // Keep scripts from reading a corrupt file.
+            let _ = std::fs::remove_file(workspace.root().join(path));
+            Reply::Error {
+                message: format!(
+                    "{} arrived with checksum {:08x}, expected {:08x}",
+                    path, actual, expected
+                ),
+            }
+        }
+        Err(e) => failed(e),
+    }
+}
+
+fn download(workspace: &Workspace, path: &str, offset: u64) -> Reply {
+    let failed = |e: io::Error| Reply::Error {
+        message: format!("cannot download {}: {}", path, e),
+    };
+    let (data, size) = match workspace.read_chunk(path, offset, MAX_CHUNK) {
+        Ok(chunk) => chunk,
+        Err(e) => return failed(e),
+    };
+    let checksum = if offset + data.len() as u64 >= size {
+        match workspace.checksum(path) {
+            Ok(checksum) => Some(checksum),
+            Err(e) => return failed(e),
+        }
+    } else {
+        None
+    };
+    Reply::Data {
+        offset,
+        size,
+        data,
+        checksum,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression::Codec;
+    use crate::delta::ObjectDelta;
+    use crate::protocol::PROTOCOL_VERSION;
+    use crate::transport::Framed;
+    use crate::transport::TcpTransport;
+    use crate::workspace::checksum;
+    use crate::LuaValue;
+    use tokio::io::DuplexStream;
+    use tokio::io::ReadHalf;
+    use tokio::io::WriteHalf;
+    use tokio::sync::mpsc;
+
+    type Pipe = Framed<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    fn pipe(name: &str) -> (Pipe, Pipe) {
+        let (a, b) = tokio::io::duplex(1024);
+        let (a_reader, a_writer) = tokio::io::split(a);
+        let (b_reader, b_writer) = tokio::io::split(b);
+        (
+            Framed::new(a_reader, a_writer, name.to_string()),
+            Framed::new(b_reader, b_writer, name.to_string()),
+        )
+    }
+
+    /// A transport defined outside the transport module, handing out
+    /// in-memory pipes.
+    struct Pipes(mpsc::UnboundedReceiver<Pipe>);
+
+    impl Transport for Pipes {
+        type Connection = Pipe;
+
+        async fn accept(&mut self) -> io::Result<Option<Pipe>> {
+            Ok(self.0.recv().await)
+        }
+    }
+
+    fn config() -> SessionConfig {
+        Session::builder()
+            .output_level(OutputLevel::Minimal)
+            .config()
+    }
+
+    async fn request(client: &mut impl Connection, session: SessionId, request: Request) -> Reply {
+        client
+            .send(&Frame::new(session, request).encode())
+            .await
+            .unwrap();
+        let reply = Frame::<Reply>::decode(&client.recv().await.unwrap().unwrap()).unwrap();
+        assert_eq!(reply.session, session);
+        reply.body
+    }
+
+    async fn eval(client: &mut impl Connection, session: SessionId, source: &str) -> String {
+        let source = source.to_string();
+        match request(
+            client,
+            session,
+            Request::Eval {
+                source,
+                id: None,
+                format: None,
+                iterate: None,
+                file: None,
+                chunk_name: None,
+                line: None,
+                output_level: None,
+            },
+        )
+        .await
+        {
+            Reply::Result { output, .. } => output,
+            reply => panic!("expected a result, got {:?}", reply),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_sessions() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut a, server_a) = pipe("a");
+        let (mut b, server_b) = pipe("b");
+        connect.send(server_a).unwrap();
+        connect.send(server_b).unwrap();
+
+        // `hello` needs no open session.
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            format: None,
+            token: None,
+            compression: vec![],
+            delta: false,
+        };
+        let Reply::Hello { info, .. } = request(&mut a, 0, hello).await else {
+            panic!("expected hello");
+        };
+        assert!(info.check().is_ok());
+        assert!(info.features.contains(&"workspace".to_string()));
+
+        assert_eq!(request(&mut a, 1, Request::Open).await, Reply::Opened);
+        assert_eq!(request(&mut a, 2, Request::Open).await, Reply::Opened);
+        assert!(matches!(
+            request(&mut a, 2, Request::Open).await,
+            Reply::Error { .. }
+        ));
+        // Session ids are per connection.
+        assert_eq!(request(&mut b, 1, Request::Open).await, Reply::Opened);
+
+        assert_eq!(eval(&mut a, 1, "x = 'a'").await, "nil");
+        assert_eq!(eval(&mut a, 2, "return x").await, "nil");
+        assert_eq!(eval(&mut b, 1, "return x").await, "nil");
+        assert_eq!(eval(&mut a, 1, "return x").await, "\"a\"");
+        let complete = |prefix: &str| Request::Complete {
+            prefix: prefix.to_string(),
+        };
+        assert_eq!(
+            request(&mut a, 1, complete("string.fo")).await,
+            Reply::Completions {
+                candidates: vec!["string.format".to_string()]
+            }
+        );
+
+        // Like `hello`, the reference needs no open session.
+        let help = Request::Help {
+            name: "string.rep".to_string(),
+        };
+        match request(&mut a, 9, help).await {
+            Reply::Topics { topics } => {
+                assert_eq!(topics.len(), 1);
+                assert!(topics[0].signature.starts_with("string.rep("));
+            }
+            reply => panic!("expected topics, got {:?}", reply),
+        }
+        let help = Request::Help {
+            name: "string.nope".to_string(),
+        };
+        assert!(matches!(
+            request(&mut a, 9, help).await,
+            Reply::Error { .. }
+        ));
+        let apropos = Request::Apropos {
+            pattern: "METATABLE".to_string(),
+        };
+        match request(&mut a, 9, apropos).await {
+            Reply::Topics { topics } => {
+                assert!(topics.iter().any(|topic| topic.name == "setmetatable"))
+            }
+            reply => panic!("expected topics, got {:?}", reply),
+        }
+
+        assert_eq!(request(&mut a, 1, Request::Close).await, Reply::Closed);
+        assert!(matches!(
+            request(&mut a, 1, complete("x")).await,
+            Reply::Error { .. }
+        ));
+        let source = "return x".to_string();
+        assert!(matches!(
+            request(
+                &mut a,
+                1,
+                Request::Eval {
+                    source,
+                    id: None,
+                    format: None,
+                    iterate: None,
+                    file: None,
+                    chunk_name: None,
+                    line: None,
+                    output_level: None,
+                }
+            )
+            .await,
+            Reply::Error { .. }
+        ));
+
+        drop((a, b, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_and_kill() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut a, server_a) = pipe("a");
+        let (mut b, server_b) = pipe("b");
+        connect.send(server_a).unwrap();
+        connect.send(server_b).unwrap();
+        assert_eq!(request(&mut a, 1, Request::Open).await, Reply::Opened);
+        assert_eq!(request(&mut b, 1, Request::Open).await, Reply::Opened);
+        eval(&mut a, 1, "x = 1").await;
+
+        let Reply::Sessions { sessions } = request(&mut b, 0, Request::List).await else {
+            panic!("expected sessions");
+        };
+        let listed: Vec<_> = sessions
+            .iter()
+            .map(|s| (s.client, s.session, s.peer.as_str(), s.evals, s.own))
+            .collect();
+        assert_eq!(listed, vec![(1, 1, "a", 1, false), (2, 1, "b", 0, true)]);
+
+        // Killing another client's session stops its running eval.
+        let runaway = tokio::spawn(async move {
+            let output = eval(&mut b, 1, "while true do end").await;
+            (b, output)
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let kill = Request::Kill { client: 2, id: 1 };
+        assert_eq!(request(&mut a, 0, kill.clone()).await, Reply::Killed);
+        assert!(matches!(
+            request(&mut a, 0, kill).await,
+            Reply::Error { .. }
+        ));
+        let (mut b, output) = runaway.await.unwrap();
+        assert!(output.contains(crate::cancel::CANCELLED), "{}", output);
+        let source = "return 1".to_string();
+        let reply = request(
+            &mut b,
+            1,
+            Request::Eval {
+                source,
+                id: None,
+                format: None,
+                iterate: None,
+                file: None,
+                chunk_name: None,
+                line: None,
+                output_level: None,
+            },
+        )
+        .await;
+        assert_eq!(
+            reply,
+            Reply::Error {
+                message: "session 1 was killed".to_string()
+            }
+        );
+        assert_eq!(request(&mut b, 1, Request::Open).await, Reply::Opened);
+
+        drop((a, b, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_session() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve_shared(Pipes(pipes), config()));
+        let (mut a, server_a) = pipe("a");
+        let (mut b, server_b) = pipe("b");
+        connect.send(server_a).unwrap();
+        connect.send(server_b).unwrap();
+        assert_eq!(request(&mut a, 1, Request::Open).await, Reply::Opened);
+        assert_eq!(request(&mut b, 5, Request::Open).await, Reply::Opened);
+        assert!(matches!(
+            request(&mut b, 6, Request::Open).await,
+            Reply::Error { .. }
+        ));
+
+        // The other client is sent each eval, under the id it attached with.
+        assert_eq!(eval(&mut a, 1, "score = 41").await, "nil");
+        let broadcast = Frame::<Reply>::decode(&b.recv().await.unwrap().unwrap()).unwrap();
+        assert_eq!(broadcast.session, 5);
+        assert_eq!(
+            eval(&mut b, 5, "score = score + 1 return score").await,
+            "42"
+        );
+        let Reply::Broadcast {
+            client,
+            peer,
+            source,
+            reply,
+        } = Frame::<Reply>::decode(&a.recv().await.unwrap().unwrap())
+            .unwrap()
+            .body
+        else {
+            panic!("expected a broadcast");
+        };
+        assert_eq!((client, peer.as_str()), (2, "b"));
+        assert_eq!(source, "score = score + 1 return score");
+        assert!(matches!(*reply, Reply::Result { output, .. } if output == "42"));
+
+        drop((a, b, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_namespaced() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let options = ServeOptions {
+            shared: true,
+            namespaced: true,
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_with(Pipes(pipes), config(), options));
+        let (mut a, server_a) = pipe("a");
+        let (mut b, server_b) = pipe("b");
+        connect.send(server_a).unwrap();
+        connect.send(server_b).unwrap();
+        assert_eq!(request(&mut a, 1, Request::Open).await, Reply::Opened);
+        assert_eq!(request(&mut b, 1, Request::Open).await, Reply::Opened);
+
+        assert_eq!(eval(&mut a, 1, "x = 'a' return x").await, "\"a\"");
+        b.recv().await.unwrap().unwrap();
+        assert_eq!(
+            eval(&mut b, 1, "return x, type(string)").await,
+            "nil, \"table\""
+        );
+        a.recv().await.unwrap().unwrap();
+        assert_eq!(eval(&mut a, 1, "return x").await, "\"a\"");
+
+        drop((a, b, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let options = ServeOptions {
+            token: Some(Token::new("s3cret")),
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_with(Pipes(pipes), config(), options));
+        let (mut client, server_end) = pipe("client");
+        connect.send(server_end).unwrap();
+        let refused = |message: &str| Reply::Error {
+            message: message.to_string(),
+        };
+        assert_eq!(
+            request(&mut client, 1, Request::Open).await,
+            refused(auth::REQUIRED)
+        );
+        let hello = |token: &str| Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            format: None,
+            token: Some(token.to_string()),
+            compression: vec![],
+            delta: false,
+        };
+        assert_eq!(
+            request(&mut client, 0, hello("guess")).await,
+            refused(auth::REJECTED)
+        );
+        assert!(matches!(
+            request(&mut client, 0, hello("s3cret")).await,
+            Reply::Hello { .. }
+        ));
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+        assert_eq!(eval(&mut client, 1, "return 6 * 7").await, "42");
+
+        // A client that keeps guessing is disconnected.
+        let (mut guesser, server_end) = pipe("guesser");
+        connect.send(server_end).unwrap();
+        for _ in 0..auth::MAX_REJECTIONS {
+            assert_eq!(
+                request(&mut guesser, 0, hello("guess")).await,
+                refused(auth::REJECTED)
+            );
+        }
+        assert!(matches!(guesser.recv().await, Ok(None) | Err(_)));
+
+        drop((client, guesser, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let (reload, reloads) = watch::channel(Reload {
+            config: config(),
+            token: None,
+        });
+        let options = ServeOptions {
+            reloads: Some(reloads),
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_with(Pipes(pipes), config(), options));
+        let (mut a, server_a) = pipe("a");
+        connect.send(server_a).unwrap();
+        assert_eq!(request(&mut a, 1, Request::Open).await, Reply::Opened);
+
+        let limited = Session::builder()
+            .output_level(OutputLevel::Minimal)
+            .limits(crate::limits::Limits {
+                memory: None,
+                instructions: Some(10_000),
+            })
+            .config();
+        reload
+            .send(Reload {
+                config: limited,
+                token: Some(Token::new("s3cret")),
+            })
+            .unwrap();
+        let spin = "for i = 1, 1e7 do end return 'done'";
+        // The open session keeps its config, a new one gets the reloaded
+        // one, and the connection stays in without the new token.
+        assert_eq!(eval(&mut a, 1, spin).await, "\"done\"");
+        assert_eq!(request(&mut a, 2, Request::Open).await, Reply::Opened);
+        assert_ne!(eval(&mut a, 2, spin).await, "\"done\"");
+
+        let (mut b, server_b) = pipe("b");
+        connect.send(server_b).unwrap();
+        assert_eq!(
+            request(&mut b, 1, Request::Open).await,
+            Reply::Error {
+                message: auth::REQUIRED.to_string()
+            }
+        );
+
+        drop((a, b, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_response_formats() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut client, server_end) = pipe("client");
+        connect.send(server_end).unwrap();
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+        let eval = |format| Request::Eval {
+            source: "return 1, 'two'".to_string(),
+            id: None,
+            format,
+            iterate: None,
+            file: None,
+            chunk_name: None,
+            line: None,
+            output_level: None,
+        };
+
+        assert!(matches!(
+            request(&mut client, 1, eval(None)).await,
+            Reply::Result { .. }
+        ));
+        let Reply::Response { values, error, .. } =
+            request(&mut client, 1, eval(Some(ResponseFormat::V2))).await
+        else {
+            panic!("expected a v2 response");
+        };
+        assert_eq!(
+            values,
+            vec![LuaValue::Integer(1), LuaValue::String("two".to_string())]
+        );
+        assert_eq!(error, None);
+
+        // `hello` changes the default, which evals can still override.
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            format: Some(ResponseFormat::V2),
+            token: None,
+            compression: vec![],
+            delta: false,
+        };
+        request(&mut client, 0, hello).await;
+        let source = "error('x', 0)".to_string();
+        let Reply::Response { error, .. } = request(
+            &mut client,
+            1,
+            Request::Eval {
+                source,
+                id: None,
+                format: None,
+                iterate: None,
+                file: None,
+                chunk_name: None,
+                line: None,
+                output_level: None,
+            },
+        )
+        .await
+        else {
+            panic!("expected a v2 response");
+        };
+        assert_eq!(error.unwrap().message.as_deref(), Some("x"));
+        assert!(matches!(
+            request(&mut client, 1, eval(Some(ResponseFormat::V1))).await,
+            Reply::Result { .. }
+        ));
+
+        drop((client, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let options = ServeOptions {
+            quota: Some(Quota {
+                ticks: 50,
+                window: std::time::Duration::from_secs(3600),
+                over_quota: crate::scheduler::OverQuota::Reject,
+            }),
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_with(Pipes(pipes), config(), options));
+        let (mut a, server_a) = pipe("a:50312");
+        let (mut b, server_b) = pipe("b:50313");
+        connect.send(server_a).unwrap();
+        connect.send(server_b).unwrap();
+        assert_eq!(request(&mut a, 1, Request::Open).await, Reply::Opened);
+        assert_eq!(request(&mut b, 1, Request::Open).await, Reply::Opened);
+        eval(&mut a, 1, "for i = 1, 200000 do end").await;
+        drop(a);
+
+        // Reconnecting from the same host does not replenish the budget.
+        let (mut a, server_a) = pipe("a:50314");
+        connect.send(server_a).unwrap();
+        assert_eq!(request(&mut a, 1, Request::Open).await, Reply::Opened);
+        let source = "return 1".to_string();
+        let reply = request(
+            &mut a,
+            1,
+            Request::Eval {
+                source,
+                id: None,
+                format: None,
+                iterate: None,
+                file: None,
+                chunk_name: None,
+                line: None,
+                output_level: None,
+            },
+        )
+        .await;
+        let Reply::Error { message } = reply else {
+            panic!("expected the eval to be refused, got {:?}", reply);
+        };
+        assert!(message.starts_with("tenant a used"), "{}", message);
+        // Other hosts have budgets of their own.
+        assert_eq!(eval(&mut b, 1, "return 1").await, "1");
+
+        drop((a, b, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let path = std::env::temp_dir().join(format!("luarepl-serve-{}.jsonl", std::process::id()));
+        let log = AuditLog::open(crate::audit::AuditConfig {
+            path: path.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let options = ServeOptions {
+            audit: Some(Arc::new(Mutex::new(log))),
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_with(Pipes(pipes), config(), options));
+        let (mut client, server_end) = pipe("127.0.0.1:50312");
+        connect.send(server_end).unwrap();
+        assert_eq!(request(&mut client, 3, Request::Open).await, Reply::Opened);
+        eval(&mut client, 3, "return 6 * 7").await;
+        drop((client, connect));
+        server.await.unwrap().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(log.trim()).unwrap();
+        assert_eq!(record["client"], "127.0.0.1:50312");
+        assert_eq!(record["session"], 3);
+        assert_eq!(record["source"], "return 6 * 7");
+    }
+
+    #[tokio::test]
+    async fn test_watch_path() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut client, server_end) = pipe("client");
+        connect.send(server_end).unwrap();
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+        let watch = Request::WatchPath {
+            path: "player.hp".to_string(),
+        };
+        assert_eq!(
+            request(&mut client, 1, watch).await,
+            Reply::Watching { watch: 0 }
+        );
+
+        assert_eq!(eval(&mut client, 1, "player = { hp = 10 }").await, "nil");
+        let change = Frame::<Reply>::decode(&client.recv().await.unwrap().unwrap()).unwrap();
+        assert_eq!(
+            change,
+            Frame::new(
+                1,
+                Reply::PathChanged {
+                    watch: 0,
+                    path: "player.hp".to_string(),
+                    output: Some("10".to_string()),
+                    value: Some(LuaValue::Integer(10)),
+                    error: None,
+                }
+            )
+        );
+        // Nothing follows an eval that leaves the path as it was.
+        assert_eq!(eval(&mut client, 1, "x = 1").await, "nil");
+        assert_eq!(eval(&mut client, 1, "player = 1").await, "nil");
+        let change = Frame::<Reply>::decode(&client.recv().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(
+            change.body,
+            Reply::PathChanged { error: Some(_), .. }
+        ));
+
+        drop((client, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deltas() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let config = Session::builder().output_level(OutputLevel::Full).config();
+        let server = tokio::spawn(serve(Pipes(pipes), config));
+        let (mut client, server_end) = pipe("client");
+        connect.send(server_end).unwrap();
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            format: Some(ResponseFormat::V2),
+            token: None,
+            compression: vec![],
+            delta: true,
+        };
+        request(&mut client, 0, hello).await;
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+        let mut versions = ClientVersions::default();
+        let mut poll = async |source: &str| {
+            let eval = Request::Eval {
+                source: source.to_string(),
+                id: None,
+                format: None,
+                iterate: None,
+                file: None,
+                chunk_name: None,
+                line: None,
+                output_level: None,
+            };
+            let Reply::Response {
+                values,
+                objects,
+                deltas,
+                ..
+            } = request(&mut client, 1, eval).await
+            else {
+                panic!("expected a response");
+            };
+            assert!(objects.is_empty());
+            (values, deltas)
+        };
+
+        let (_, deltas) = poll("state = { tick = 1, player = { hp = 10 } } return state").await;
+        assert!(deltas
+            .values()
+            .all(|delta| matches!(delta, ObjectDelta::Full { .. })));
+        versions.apply_objects(deltas).unwrap();
+        let (values, deltas) = poll("state.tick = 2 return state").await;
+        let LuaValue::ObjectRef(root) = &values[0] else {
+            panic!("expected a table, got {:?}", values);
+        };
+        assert!(matches!(deltas[root], ObjectDelta::Changed { base: 0, .. }));
+        assert!(deltas
+            .values()
+            .any(|delta| matches!(delta, ObjectDelta::Unchanged { .. })));
+        let objects = versions.apply_objects(deltas).unwrap();
+        assert!(objects[root]
+            .members
+            .contains(&(LuaValue::String("tick".to_string()), LuaValue::Integer(2))));
+
+        drop((client, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_ids() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut client, server_end) = pipe("client");
+        connect.send(server_end).unwrap();
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+
+        // Both evals are sent before either reply is read.
+        for (id, source) in [(7, "return 'first'"), (8, "return 'second'")] {
+            let eval = Request::Eval {
+                source: source.to_string(),
+                id: Some(id),
+                format: None,
+                iterate: None,
+                file: None,
+                chunk_name: None,
+                line: None,
+                output_level: None,
+            };
+            client.send(&Frame::new(1, eval).encode()).await.unwrap();
+        }
+        let mut replies = vec![];
+        for _ in 0..2 {
+            let message = client.recv().await.unwrap().unwrap();
+            match Frame::<Reply>::decode(&message).unwrap().body {
+                Reply::Result { id, output, .. } => replies.push((id, output)),
+                reply => panic!("expected a result, got {:?}", reply),
+            }
+        }
+        replies.sort();
+        assert_eq!(
+            replies,
+            vec![
+                (Some(7), "\"first\"".to_string()),
+                (Some(8), "\"second\"".to_string()),
+            ]
+        );
+
+        // An eval can ask for more than the server's minimal output.
+        let eval = Request::Eval {
+            source: "return { 1 }".to_string(),
+            id: Some(9),
+            format: None,
+            iterate: None,
+            file: None,
+            chunk_name: None,
+            line: None,
+            output_level: Some(OutputLevel::Full),
+        };
+        match request(&mut client, 1, eval).await {
+            Reply::Result { output, .. } => assert_eq!(output, "{ 1 }"),
+            reply => panic!("expected a result, got {:?}", reply),
+        }
+
+        // Replies to evals without an id leave it out.
+        let eval = Request::Eval {
+            source: "return 1".to_string(),
+            id: None,
+            format: None,
+            iterate: None,
+            file: None,
+            chunk_name: None,
+            line: None,
+            output_level: None,
+        };
+        client.send(&Frame::new(1, eval).encode()).await.unwrap();
+        let message = client.recv().await.unwrap().unwrap();
+        assert!(!String::from_utf8(message).unwrap().contains("\"id\""));
+
+        drop((client, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut client, server_end) = pipe("client");
+        connect.send(server_end).unwrap();
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+
+        let eval = Request::Eval {
+            source: "progress(50, 'half') progress(150)".to_string(),
+            id: Some(3),
+            format: None,
+            iterate: None,
+            file: None,
+            chunk_name: None,
+            line: None,
+            output_level: None,
+        };
+        assert_eq!(
+            request(&mut client, 1, eval).await,
+            Reply::Progress {
+                id: Some(3),
+                percent: 50.0,
+                message: Some("half".to_string()),
+            }
+        );
+        let report = Frame::<Reply>::decode(&client.recv().await.unwrap().unwrap()).unwrap();
+        assert_eq!(
+            report.body,
+            Reply::Progress {
+                id: Some(3),
+                percent: 100.0,
+                message: None,
+            }
+        );
+        let result = Frame::<Reply>::decode(&client.recv().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(
+            result.body,
+            Reply::Result {
+                id: Some(3),
+                success: true,
+                ..
+            }
+        ));
+
+        drop((client, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_iterator_items() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        let server = tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut client, server_end) = pipe("client");
+        connect.send(server_end).unwrap();
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+
+        let eval = Request::Eval {
+            source: "return ipairs({ 'a', 'b', 'c' })".to_string(),
+            id: Some(3),
+            format: None,
+            iterate: Some(2),
+            file: None,
+            chunk_name: None,
+            line: None,
+            output_level: None,
+        };
+        let reply = request(&mut client, 1, eval).await;
+        assert!(matches!(reply, Reply::Result { success: true, .. }));
+        let mut replies = vec![];
+        for _ in 0..3 {
+            let message = client.recv().await.unwrap().unwrap();
+            replies.push(Frame::<Reply>::decode(&message).unwrap().body);
+        }
+        assert!(matches!(
+            &replies[1],
+            Reply::Item { id: Some(3), index: 1, values, .. }
+                if values == &[LuaValue::Integer(2), LuaValue::String("b".to_string())]
+        ));
+        assert_eq!(
+            replies[2],
+            Reply::Exhausted {
+                id: Some(3),
+                count: 2
+            }
+        );
+
+        drop((client, connect));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_transfer() {
+        let (connect, pipes) = mpsc::unbounded_channel();
+        tokio::spawn(serve(Pipes(pipes), config()));
+        let (mut client, server) = pipe("client");
+        connect.send(server).unwrap();
+        assert_eq!(request(&mut client, 0, Request::Open).await, Reply::Opened);
+
+        let script = b"return select(1, ...) * 2";
+        let chunk = |offset: usize, end: usize, checksum| Request::Upload {
+            path: "lib/double.lua".to_string(),
+            offset: offset as u64,
+            data: script[offset..end].to_vec(),
+            checksum,
+        };
+        assert_eq!(
+            request(&mut client, 0, chunk(0, 10, None)).await,
+            Reply::Received { size: 10 }
+        );
+        let end = script.len();
+        let path = match request(&mut client, 0, chunk(10, end, Some(checksum(script)))).await {
+            Reply::Uploaded { path, size } if size == end as u64 => path,
+            reply => panic!("expected the upload to finish, got {:?}", reply),
+        };
+        let source = format!("return loadfile({:?})(21)", path);
+        assert_eq!(eval(&mut client, 0, &source).await, "42");
+        assert!(matches!(
+            request(&mut client, 0, chunk(0, end, Some(0))).await,
+            Reply::Error { .. }
+        ));
+
+        let out = path.replace("lib/double.lua", "out.bin");
+        let source = format!(
+            "local f = io.open({:?}, 'wb') f:write(string.rep('\\0\\255', 10)) f:close()",
+            out
+        );
+        assert_eq!(eval(&mut client, 0, &source).await, "nil");
+        let download = Request::Download {
+            path: "out.bin".to_string(),
+            offset: 0,
+        };
+        let expected = [0, 255].repeat(10);
+        assert_eq!(
+            request(&mut client, 0, download).await,
+            Reply::Data {
+                offset: 0,
+                size: 20,
+                checksum: Some(checksum(&expected)),
+                data: expected,
+            }
+        );
+        let escape = Request::Download {
+            path: "../out.bin".to_string(),
+            offset: 0,
+        };
+        assert!(matches!(
+            request(&mut client, 0, escape).await,
+            Reply::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        let transport = TcpTransport::bind("127.0.0.1:0").await.unwrap();
+        let addr = transport.local_addr().unwrap();
+        tokio::spawn(serve(transport, config()));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut client = Framed::new(reader, writer, addr.to_string());
+        assert_eq!(request(&mut client, 0, Request::Open).await, Reply::Opened);
+        let source = "error('boom')".to_string();
+        let Reply::Result { success, error, .. } = request(
+            &mut client,
+            0,
+            Request::Eval {
+                source,
+                id: None,
+                format: None,
+                iterate: None,
+                file: None,
+                chunk_name: None,
+                line: None,
+                output_level: None,
+            },
+        )
+        .await
+        else {
+            panic!("expected a result");
+        };
+        assert!(!success);
+        assert_eq!(error.as_deref(), Some("[string \"?\"]:1: boom"));
+        assert_eq!(eval(&mut client, 0, "return 6 * 7").await, "42");
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let transport = TcpTransport::bind("127.0.0.1:0").await.unwrap();
+        let addr = transport.local_addr().unwrap();
+        tokio::spawn(serve(transport, config()));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut client = Framed::new(reader, writer, addr.to_string());
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            format: None,
+            token: None,
+            compression: vec!["br".to_string(), "gzip".to_string()],
+            delta: false,
+        };
+        let Reply::Hello { compression, .. } = request(&mut client, 0, hello).await else {
+            panic!("expected hello");
+        };
+        assert_eq!(compression.as_deref(), Some("gzip"));
+        let framing = Framing::new(&Compression::default(), Some(Codec::Gzip));
+        let mut client = Compressed::new(client);
+        client.set_framing(framing);
+        assert_eq!(request(&mut client, 1, Request::Open).await, Reply::Opened);
+
+        // Large replies come compressed.
+        let eval = Request::Eval {
+            source: "return string.rep('ab', 5000)".to_string(),
+            id: None,
+            format: None,
+            iterate: None,
+            file: None,
+            chunk_name: None,
+            line: None,
+            output_level: None,
+        };
+        let mut client = client.into_inner();
+        client
+            .send(&framing.encode(&Frame::new(1, eval).encode()))
+            .await
+            .unwrap();
+        let message = client.recv().await.unwrap().unwrap();
+        assert!(message.len() < 1000, "{} bytes", message.len());
+        let reply = Frame::<Reply>::decode(&framing.decode(&message).unwrap()).unwrap();
+        let Reply::Result { output, .. } = reply.body else {
+            panic!("expected a result");
+        };
+        assert_eq!(output.len(), 10_002);
+    }
+}
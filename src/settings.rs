@@ -0,0 +1,264 @@
+//! `luarepl.toml`, the settings file that can be reloaded while running.
+//!
+//! ```toml
+//! hardened = true
+//!
+//! [limits]
+//! memory = 67108864
+//! instructions = 1000000
+//!
+//! [output]
+//! max_string_len = 200
+//! ```
+//!
+//! Every key is also tunable from the REPL with `:config set`, and
+//! `:config save` writes the values in effect back to the file.
+
+use crate::elide::Truncation;
+use crate::limits::Limits;
+use crate::SessionConfig;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+pub const DEFAULT_PATH: &str = "luarepl.toml";
+
+/// A key the settings file accepts, for generated documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SettingKey {
+    /// The key with its table, e.g. `limits.memory`.
+    pub key: &'static str,
+    /// The TOML type of the value.
+    pub kind: &'static str,
+    pub description: &'static str,
+}
+
+/// Every key of `Settings`.
+pub const KEYS: &[SettingKey] = &[
+    SettingKey {
+        key: "hardened",
+        kind: "boolean",
+        description: "Reject oversized, NUL-containing or non-UTF-8 input and cap result nesting, like --hardened.",
+    },
+    SettingKey {
+        key: "limits.memory",
+        kind: "integer",
+        description: "Maximum bytes the Lua state may allocate.",
+    },
+    SettingKey {
+        key: "limits.instructions",
+        kind: "integer",
+        description: "Maximum VM instructions a single eval may execute.",
+    },
+    SettingKey {
+        key: "output.max_string_len",
+        kind: "integer",
+        description: "Longest string shown in full before it is truncated, like --max-string-len.",
+    },
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// See `--hardened`.
+    pub hardened: bool,
+    pub limits: LimitSettings,
+    pub output: OutputSettings,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSettings {
+    /// See `--max-string-len`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_string_len: Option<usize>,
+}
+
+impl From<LimitSettings> for Limits {
+    fn from(settings: LimitSettings) -> Self {
+        Limits {
+            memory: settings.memory,
+            instructions: settings.instructions,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::Io(e) => write!(f, "{}", e),
+            SettingsError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl Settings {
+    pub fn parse(source: &str) -> Result<Self, SettingsError> {
+        toml::from_str(source).map_err(SettingsError::Parse)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SettingsError> {
+        Self::parse(&std::fs::read_to_string(path).map_err(SettingsError::Io)?)
+    }
+
+    /// The settings a session running with `config`, and output truncated
+    /// with `truncation`, is in effect using, wherever they came from.
+    pub fn effective(config: &SessionConfig, truncation: &Truncation) -> Self {
+        Self {
+            hardened: config.hardening.is_some(),
+            limits: LimitSettings {
+                memory: config.limits.memory,
+                instructions: config.limits.instructions,
+            },
+            output: OutputSettings {
+                max_string_len: Some(truncation.max_string_len),
+            },
+        }
+    }
+
+    /// `config` with these settings applied; the rest of it is kept.
+    pub fn apply(&self, config: &SessionConfig) -> SessionConfig {
+        SessionConfig {
+            limits: self.limits.into(),
+            hardening: match self.hardened {
+                true => Some(config.hardening.unwrap_or_default()),
+                false => None,
+            },
+            ..config.clone()
+        }
+    }
+
+    /// The value of `key` as `:config` shows it, `none` for one left unset.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+        Some(match key {
+            "hardened" => self.hardened.to_string(),
+            "limits.memory" => optional(self.limits.memory.map(|n| n.to_string())),
+            "limits.instructions" => optional(self.limits.instructions.map(|n| n.to_string())),
+            "output.max_string_len" => optional(self.output.max_string_len.map(|n| n.to_string())),
+            _ => return None,
+        })
+    }
+
+    /// Sets `key` from `value` as typed after `:config set`; `none` unsets
+    /// an optional key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<Option<T>, String> {
+            match value {
+                "none" => Ok(None),
+                value => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("{} must be an integer or none", key)),
+            }
+        }
+        match key {
+            "hardened" => {
+                self.hardened = value
+                    .parse()
+                    .map_err(|_| format!("{} must be true or false", key))?
+            }
+            "limits.memory" => self.limits.memory = parse(key, value)?,
+            "limits.instructions" => self.limits.instructions = parse(key, value)?,
+            "output.max_string_len" => self.output.max_string_len = parse(key, value)?,
+            key => return Err(format!("no setting named {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Writes the settings to `path` as TOML, replacing what it held.
+    pub fn save(&self, path: &Path) -> Result<(), SettingsError> {
+        let source = toml::to_string(self).expect("settings serialize");
+        std::fs::write(path, source).map_err(SettingsError::Io)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hardening::Hardening;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Settings::parse("").unwrap(), Settings::default());
+        assert_eq!(
+            Settings::parse("hardened = true\n[limits]\ninstructions = 5").unwrap(),
+            Settings {
+                hardened: true,
+                limits: LimitSettings {
+                    memory: None,
+                    instructions: Some(5),
+                },
+                output: OutputSettings::default(),
+            }
+        );
+        assert!(Settings::parse("[limits]\nmemory = 'lots'").is_err());
+        assert!(Settings::parse("tokens = []").is_err());
+    }
+
+    #[test]
+    fn test_keys() {
+        // Setting every documented key sets every field.
+        let mut source = String::new();
+        for key in KEYS {
+            let value = match key.kind {
+                "boolean" => "true",
+                _ => "1",
+            };
+            // TOML reads `limits.memory = 1` as a key of `[limits]`.
+            source.push_str(&format!("{} = {}\n", key.key, value));
+        }
+        let settings = Settings::parse(&source).unwrap();
+        assert!(settings.hardened);
+        assert_eq!(settings.limits.memory, Some(1));
+        assert_eq!(settings.limits.instructions, Some(1));
+        assert_eq!(settings.output.max_string_len, Some(1));
+        // And every one of them can be shown and set.
+        for key in KEYS {
+            assert!(settings.get(key.key).is_some(), "{}", key.key);
+        }
+    }
+
+    #[test]
+    fn test_set() {
+        let mut settings = Settings::default();
+        settings.set("limits.instructions", "1000").unwrap();
+        settings.set("hardened", "true").unwrap();
+        settings.set("output.max_string_len", "80").unwrap();
+        settings.set("limits.instructions", "none").unwrap();
+        assert_eq!(settings.get("limits.instructions").unwrap(), "none");
+        assert!(settings.set("limits.memory", "lots").is_err());
+        assert!(settings.set("hardened", "1").is_err());
+        assert!(settings.set("tokens", "1").is_err());
+
+        let path = std::env::temp_dir().join(format!("luarepl-{}.toml", std::process::id()));
+        settings.save(&path).unwrap();
+        assert_eq!(Settings::load(&path).unwrap(), settings);
+        std::fs::remove_file(&path).unwrap();
+
+        let config = settings.apply(&SessionConfig::default());
+        assert_eq!(config.hardening, Some(Hardening::default()));
+        assert_eq!(
+            Settings::effective(&config, &Truncation::default()).limits,
+            settings.limits
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Subscriptions to key paths such as `world.players[7].hp`, for live
+//! dashboards over state exposed through luarepl: after every eval,
+//! `set_path` and reset, the session reads each subscribed path and sends a
+//! `PathChange` for those whose value differs from the one it last saw.
+//!
+//! Unlike watch expressions, subscribed paths are read after every change
+//! whether or not anything on them changed, so each costs a path lookup and
+//! a serialization per eval.
+
+use crate::path::PathError;
+use crate::EvalResponse;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A path subscription registered with `Session::watch_path`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PathWatch(u64);
+
+impl PathWatch {
+    /// The number of the subscription within its session, for clients.
+    pub fn id(self) -> u64 {
+        self.0
+    }
+}
+
+/// The new value of a subscribed path; `Err` if it no longer resolves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathChange {
+    pub watch: PathWatch,
+    pub path: String,
+    pub value: Result<EvalResponse, PathError>,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    watch: PathWatch,
+    path: String,
+    last: Result<EvalResponse, PathError>,
+}
+
+/// The subscriptions of one session and where their changes go.
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    subscriptions: Vec<Subscription>,
+    next: u64,
+    sender: Option<UnboundedSender<PathChange>>,
+}
+
+impl Subscriptions {
+    /// Subscribes to `path`, whose value is `current` now.
+    pub fn add(&mut self, path: String, current: Result<EvalResponse, PathError>) -> PathWatch {
+        let watch = PathWatch(self.next);
+        self.next += 1;
+        self.subscriptions.push(Subscription {
+            watch,
+            path,
+            last: current,
+        });
+        watch
+    }
+
+    pub fn remove(&mut self, watch: PathWatch) -> bool {
+        let before = self.subscriptions.len();
+        self.subscriptions.retain(|s| s.watch != watch);
+        self.subscriptions.len() != before
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+    }
+
+    /// The subscribed paths, in the order `update` expects their values.
+    pub fn paths(&self) -> Vec<String> {
+        self.subscriptions.iter().map(|s| s.path.clone()).collect()
+    }
+
+    /// Takes the current `values` of `paths()` and sends the changed ones.
+    pub fn update(&mut self, values: Vec<Result<EvalResponse, PathError>>) {
+        for (subscription, value) in self.subscriptions.iter_mut().zip(values) {
+            if subscription.last == value {
+                continue;
+            }
+            subscription.last = value.clone();
+            if let Some(sender) = &self.sender {
+                let _ = sender.send(PathChange {
+                    watch: subscription.watch,
+                    path: subscription.path.clone(),
+                    value,
+                });
+            }
+        }
+    }
+
+    /// A receiver for all changes from now on, replacing the previous one.
+    pub fn changes(&mut self) -> UnboundedReceiver<PathChange> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.sender = Some(sender);
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_path_changes() {
+        let mut session = Session::new();
+        session
+            .eval("world = { players = { [7] = { hp = 10 } } }".to_string())
+            .await;
+        let mut changes = session.path_changes();
+        let hp = session
+            .watch_path("world.players[7].hp".to_string())
+            .await
+            .unwrap();
+        session.eval("world.tick = 1".to_string()).await;
+        assert!(changes.try_recv().is_err());
+
+        session.eval("world.players[7].hp = 9".to_string()).await;
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.watch, hp);
+        assert_eq!(change.path, "world.players[7].hp");
+        assert_eq!(change.value.unwrap().value, LuaValue::Integer(9));
+        assert!(changes.try_recv().is_err());
+
+        session
+            .set_path("world.players[7].hp".to_string(), LuaValue::Number(8.0))
+            .await
+            .unwrap();
+        assert!(changes.try_recv().unwrap().value.is_ok());
+        session.reset().await;
+        assert!(matches!(
+            changes.try_recv().unwrap().value,
+            Err(PathError::NotFound { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unwatch_path() {
+        let mut session = Session::new();
+        let mut changes = session.path_changes();
+        assert!(session.watch_path("a..b".to_string()).await.is_err());
+        let x = session.watch_path("x".to_string()).await.unwrap();
+        session.eval("x = {}".to_string()).await;
+        assert!(changes.try_recv().is_ok());
+        session.eval("x[1] = true".to_string()).await;
+        assert!(changes.try_recv().is_ok());
+        assert!(session.unwatch_path(x));
+        assert!(!session.unwatch_path(x));
+        session.eval("x = 2".to_string()).await;
+        assert!(changes.try_recv().is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Eval sources with `$1`, `$2`, ... placeholders whose values are passed to
+//! the chunk as locals instead of being spliced into the source.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError(pub String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+fn param_name(index: usize) -> String {
+    format!("__repl_param{}", index)
+}
+
+/// Replaces placeholders outside of string literals and comments with local
+/// names. Returns the rewritten source and the prologue declaring the locals
+/// from the chunk's varargs; the prologue has no newline so line numbers in
+/// errors still match the template.
+pub(crate) fn bind(template: &str, param_count: usize) -> Result<(String, String), TemplateError> {
+    let bytes = template.as_bytes();
+    let mut out = String::with_capacity(template.len());
+    let mut copied = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = match long_bracket_level(bytes, i + 2) {
+                    Some(level) => skip_long_bracket(bytes, i + 2, level),
+                    None => template[i..].find('\n').map_or(bytes.len(), |n| i + n),
+                };
+            }
+            b'[' if long_bracket_level(bytes, i).is_some() => {
+                i = skip_long_bracket(bytes, i, long_bracket_level(bytes, i).unwrap());
+            }
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b'$' => {
+                let digits = bytes[i + 1..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+                let index: usize = template[i + 1..i + 1 + digits].parse().map_err(|_| {
+                    TemplateError(format!("expected a parameter number at offset {}", i))
+                })?;
+                if index == 0 || index > param_count {
+                    return Err(TemplateError(format!(
+                        "${} used but {} parameters given",
+                        index, param_count
+                    )));
+                }
+                out.push_str(&template[copied..i]);
+                out.push_str(&param_name(index));
+                i += 1 + digits;
+                copied = i;
+            }
+            _ => i += 1,
+        }
+    }
+    out.push_str(&template[copied..]);
+
+    let prologue = if param_count == 0 {
+        String::new()
+    } else {
+        let names: Vec<_> = (1..=param_count).map(param_name).collect();
+        format!("local {} = ... ", names.join(", "))
+    };
+    Ok((out, prologue))
+}
+
+/// Returns the level of a long bracket (`[[`, `[==[`) opening at `start`.
+fn long_bracket_level(bytes: &[u8], start: usize) -> Option<usize> {
+    if bytes.get(start) != Some(&b'[') {
+        return None;
+    }
+    let level = bytes[start + 1..]
+        .iter()
+        .take_while(|b| **b == b'=')
+        .count();
+    (bytes.get(start + 1 + level) == Some(&b'[')).then_some(level)
+}
+
+fn skip_long_bracket(bytes: &[u8], start: usize, level: usize) -> usize {
+    let close: Vec<u8> = std::iter::once(b']')
+        .chain(std::iter::repeat_n(b'=', level))
+        .chain(std::iter::once(b']'))
+        .collect();
+    bytes[start..]
+        .windows(close.len())
+        .position(|window| window == close.as_slice())
+        .map_or(bytes.len(), |end| start + end + close.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[test]
+    fn test_bind_skips_strings_and_comments() {
+        let (source, prologue) = bind("f($1, '$2', [[$2]], $2) -- $3", 2).unwrap();
+        assert_eq!(
+            source,
+            "f(__repl_param1, '$2', [[$2]], __repl_param2) -- $3"
+        );
+        assert_eq!(prologue, "local __repl_param1, __repl_param2 = ... ");
+        assert!(bind("f($3)", 2).is_err());
+        assert!(bind("f($)", 2).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eval_template() {
+        let mut session = Session::new();
+        let resp = session
+            .eval_template(
+                "$1 .. $2".to_string(),
+                vec![
+                    LuaValue::String("'); os.exit() --".to_string()),
+                    LuaValue::Number(1.0),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.value,
+            LuaValue::String("'); os.exit() --1.0".to_string())
+        );
+
+        let resp = session
+            .eval_template("x = $1".to_string(), vec![LuaValue::Boolean(true)])
+            .await
+            .unwrap();
+        assert!(resp.success);
+        let resp = session.eval("return x".to_string()).await;
+        assert_eq!(resp.value, LuaValue::Boolean(true));
+    }
+}
@@ -0,0 +1,198 @@
+//! The terminal as full-screen views such as `ui` use it: raw mode on the
+//! alternate screen, with keys read as they are pressed.
+//!
+//! Raw mode is set with termios and the screen drawn with ANSI escapes, so
+//! this only works on Unix. It turns Ctrl-C into a key rather than a
+//! signal; dropping the `Terminal`, which also happens on errors and
+//! panics, puts the terminal back as it was.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Backspace,
+    Esc,
+    Char(char),
+    /// Ctrl-C, which raw mode delivers as a key.
+    Interrupt,
+    /// Ctrl-D.
+    Eof,
+}
+
+/// The keys in what one read from the terminal returned. An escape
+/// sequence is assumed to arrive whole, so a lone ESC is the Esc key.
+pub fn parse_keys(input: &[u8]) -> Vec<Key> {
+    let mut keys = vec![];
+    let text = String::from_utf8_lossy(input);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        keys.push(match c {
+            '\x1b' if chars.peek() == Some(&'[') || chars.peek() == Some(&'O') => {
+                chars.next();
+                let mut sequence = String::new();
+                while let Some(&c) = chars.peek() {
+                    chars.next();
+                    if c.is_ascii_alphabetic() || c == '~' {
+                        sequence.push(c);
+                        break;
+                    }
+                    sequence.push(c);
+                }
+                match sequence.as_str() {
+                    "A" => Key::Up,
+                    "B" => Key::Down,
+                    "C" => Key::Right,
+                    "D" => Key::Left,
+                    "H" | "1~" | "7~" => Key::Home,
+                    "F" | "4~" | "8~" => Key::End,
+                    "5~" => Key::PageUp,
+                    "6~" => Key::PageDown,
+                    _ => continue,
+                }
+            }
+            '\x1b' => Key::Esc,
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x03' => Key::Interrupt,
+            '\x04' => Key::Eof,
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        });
+    }
+    keys
+}
+
+pub(crate) use imp::Terminal;
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::io::Write;
+
+    /// The terminal in raw mode on the alternate screen, until dropped.
+    pub(crate) struct Terminal {
+        original: libc::termios,
+    }
+
+    impl Terminal {
+        pub(crate) fn enter() -> io::Result<Self> {
+            if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "needs a terminal",
+                ));
+            }
+            let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            unsafe { libc::cfmakeraw(&mut raw) };
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let terminal = Self { original };
+            // Alternate screen, hidden cursor.
+            terminal.draw("\x1b[?1049h\x1b[?25l")?;
+            Ok(terminal)
+        }
+
+        /// Columns and rows.
+        pub(crate) fn size(&self) -> (usize, usize) {
+            let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+            match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+                0 if size.ws_col > 0 && size.ws_row > 0 => {
+                    (size.ws_col as usize, size.ws_row as usize)
+                }
+                _ => (80, 24),
+            }
+        }
+
+        pub(crate) fn draw(&self, screen: &str) -> io::Result<()> {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(screen.as_bytes())?;
+            stdout.flush()
+        }
+
+        /// What the next read from the terminal returns, at least a byte.
+        /// It reads the descriptor itself, as the REPL holds the lock on
+        /// `io::stdin` while it runs a command.
+        pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+            let mut buffer = [0u8; 64];
+            let read =
+                unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr().cast(), buffer.len()) };
+            match read {
+                n if n < 0 => Err(io::Error::last_os_error()),
+                // End of input is taken as Ctrl-D.
+                0 => Ok(vec![4]),
+                n => Ok(buffer[..n as usize].to_vec()),
+            }
+        }
+    }
+
+    impl Drop for Terminal {
+        fn drop(&mut self) {
+            let _ = self.draw("\x1b[?25h\x1b[?1049l");
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::io;
+
+    pub(crate) struct Terminal;
+
+    impl Terminal {
+        pub(crate) fn enter() -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "needs a Unix terminal",
+            ))
+        }
+
+        pub(crate) fn size(&self) -> (usize, usize) {
+            (80, 24)
+        }
+
+        pub(crate) fn draw(&self, _screen: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+            Ok(vec![4])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(
+            parse_keys(b"\x1b[A\x1b[6~j/na\x7f\r\x1b\x03"),
+            vec![
+                Key::Up,
+                Key::PageDown,
+                Key::Char('j'),
+                Key::Char('/'),
+                Key::Char('n'),
+                Key::Char('a'),
+                Key::Backspace,
+                Key::Enter,
+                Key::Esc,
+                Key::Interrupt,
+            ]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Helpers for testing code that embeds a `Session`, enabled with the
+//! `test-util` feature.
+//!
+//! Object refs are addresses that change from run to run, so responses are
+//! compared as snapshots: the value is rendered inline with table keys
+//! sorted, and tables reached more than once are labelled `#1`, `#2`, ... in
+//! the order they are first visited.
+//!
+//! ```ignore
+//! let mut session = Session::new();
+//! assert_eval!(session, "return {1, x = 'a'}", r#"{[1] = 1, x = "a"}"#);
+//! assert_eval!(session, "local t = {}; t.t = t; return t", "#1={t = #1}");
+//! assert_eval_err!(session, "error('boom')");
+//! ```
+
+use crate::path::is_identifier;
+use crate::EvalResponse;
+use crate::LuaValue;
+use std::collections::HashMap;
+
+/// Renders the value of `response` as a stable, single-line snapshot.
+pub fn snapshot(response: &EvalResponse) -> String {
+    if !response.success {
+        return "<error>".to_string();
+    }
+    let mut references = HashMap::new();
+    count_references(response, &response.value, &mut references);
+    let mut labels = HashMap::new();
+    let mut out = String::new();
+    render(
+        response,
+        &response.value,
+        &references,
+        &mut labels,
+        &mut out,
+    );
+    out
+}
+
+fn count_references<'a>(
+    response: &'a EvalResponse,
+    value: &'a LuaValue,
+    references: &mut HashMap<&'a str, usize>,
+) {
+    if let LuaValue::ObjectRef(id) = value {
+        let count = references.entry(id.as_str()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            for (key, value) in response.objects.get(id).map_or(&[][..], |o| &o.members) {
+                count_references(response, key, references);
+                count_references(response, value, references);
+            }
+        }
+    }
+}
+
+fn render<'a>(
+    response: &'a EvalResponse,
+    value: &'a LuaValue,
+    references: &HashMap<&'a str, usize>,
+    labels: &mut HashMap<&'a str, usize>,
+    out: &mut String,
+) {
+    let id = match value {
+        LuaValue::ObjectRef(id) => id.as_str(),
+        other => {
+            out.push_str(&other.to_string());
+            return;
+        }
+    };
+    if let Some(label) = labels.get(id) {
+        out.push_str(&format!("#{}", label));
+        return;
+    }
+    let label = labels.len() + 1;
+    labels.insert(id, label);
+    if references.get(id).copied().unwrap_or(0) > 1 {
+        out.push_str(&format!("#{}=", label));
+    }
+
+    let members = response.objects.get(id).map_or(&[][..], |o| &o.members);
+    let mut entries: Vec<_> = members.iter().collect();
+    entries.sort_by_key(|(key, _)| sort_key(key));
+    out.push('{');
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        match key {
+            LuaValue::String(name) if is_identifier(name) => out.push_str(name),
+            key => {
+                out.push('[');
+                render(response, key, references, labels, out);
+                out.push(']');
+            }
+        }
+        out.push_str(" = ");
+        render(response, value, references, labels, out);
+    }
+    out.push('}');
+}
+
+/// Orders numeric keys numerically and before everything else.
+fn sort_key(key: &LuaValue) -> (u8, i64, String) {
+    match key {
+        LuaValue::Number(n) => (0, *n as i64, n.to_string()),
+        LuaValue::Integer(i) => (0, *i, i.to_string()),
+        LuaValue::String(s) => (1, 0, s.clone()),
+        other => (2, 0, other.to_string()),
+    }
+}
+
+/// Evaluates `$source` in `$session` and asserts that it succeeds with a
+/// value whose `snapshot` is `$expected`. Must be used in an async context.
+#[macro_export]
+macro_rules! assert_eval {
+    ($session:expr, $source:expr, $expected:expr) => {{
+        let source = $source.to_string();
+        let response = $session.eval(source.clone()).await;
+        assert!(
+            response.success,
+            "eval of {:?} failed: {:?}",
+            source, response
+        );
+        assert_eq!(
+            $crate::test_util::snapshot(&response),
+            $expected,
+            "unexpected result for {:?}",
+            source
+        );
+        response
+    }};
+}
+
+/// Evaluates `$source` in `$session` and asserts that it fails.
+#[macro_export]
+macro_rules! assert_eval_err {
+    ($session:expr, $source:expr) => {{
+        let source = $source.to_string();
+        let response = $session.eval(source.clone()).await;
+        assert!(
+            !response.success,
+            "eval of {:?} succeeded: {:?}",
+            source, response
+        );
+        response
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let mut session = Session::new();
+        assert_eval!(session, "x = 1", "nil");
+        assert_eval!(session, "return x", "1");
+        assert_eval!(
+            session,
+            "return {2, 1, x = 'a', [true] = {}}",
+            r#"{[1] = 2, [2] = 1, x = "a", [true] = {}}"#
+        );
+        assert_eval!(
+            session,
+            "local t = {}; t.t = t; return {a = t, b = t}",
+            "{a = #2={t = #2}, b = #2}"
+        );
+        assert_eval_err!(session, "syntax error");
+    }
+}
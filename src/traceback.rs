@@ -0,0 +1,123 @@
+//! The Lua stack at the point an eval failed, as frames rather than the text
+//! `debug.traceback` renders, see `EvalResponse::traceback`.
+//!
+//! rlua appends that text to runtime error messages and records it for
+//! errors raised by callbacks, so the frames are read back from it.
+
+use crate::error_value;
+use rlua::Error;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StackFrame {
+    /// The chunk the function was defined in, e.g. `[string "?"]`, or `[C]`
+    /// for C functions.
+    pub source: String,
+    /// The line that was running; `None` for C functions.
+    pub line: Option<u32>,
+    /// The name the function was called by; `None` for the main chunk and
+    /// anonymous functions.
+    pub function: Option<String>,
+}
+
+/// The frames of the traceback rlua recorded for `error`, innermost first.
+pub(crate) fn frames(error: &Error) -> Vec<StackFrame> {
+    match error {
+        Error::RuntimeError(message) => match message.split_once("\nstack traceback:") {
+            Some((_, traceback)) => parse(traceback),
+            None => vec![],
+        },
+        Error::CallbackError { traceback, cause } => match frames(cause) {
+            // The cause's own traceback is the deeper one.
+            frames if !frames.is_empty() => frames,
+            _ => parse(traceback.trim_start_matches("stack traceback:")),
+        },
+        _ => vec![],
+    }
+}
+
+/// Parses the lines after `stack traceback:`, such as
+/// `[string "?"]:3: in local 'f'`.
+fn parse(traceback: &str) -> Vec<StackFrame> {
+    traceback
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            let (location, called) = line.split_once(": in ")?;
+            let (source, line) = match location.rsplit_once(':') {
+                Some((source, line)) => match line.parse() {
+                    Ok(line) => (source, Some(line)),
+                    Err(_) => (location, None),
+                },
+                None => (location, None),
+            };
+            // rlua's own handler, which has no name, and the Lua half of
+            // `error` and `assert`.
+            if source == "[C]" && called == "?" || source == error_value::SOURCE {
+                return None;
+            }
+            Some(StackFrame {
+                source: source.to_string(),
+                line,
+                function: function_name(called),
+            })
+        })
+        .collect()
+}
+
+/// The name in `function 'f'`, `local 'f'`, `method 'f'` and the like.
+fn function_name(called: &str) -> Option<String> {
+    let (_, quoted) = called.split_once('\'')?;
+    quoted.strip_suffix('\'').map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_nested_calls() {
+        let mut session = Session::new();
+        let resp = session
+            .eval(
+                "local function inner() error('boom') end\n\
+                 local function outer() inner() end\n\
+                 outer()"
+                    .to_string(),
+            )
+            .await;
+        let frames: Vec<_> = resp
+            .traceback
+            .iter()
+            .map(|frame| (frame.source.as_str(), frame.line, frame.function.as_deref()))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                ("[C]", None, Some("error")),
+                ("[string \"?\"]", Some(1), Some("inner")),
+                ("[string \"?\"]", Some(2), Some("outer")),
+                ("[string \"?\"]", Some(3), None),
+            ]
+        );
+        let resp = session.eval("syntax error".to_string()).await;
+        assert!(resp.traceback.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_callback_error() {
+        let mut session = Session::builder()
+            .register_function("fail", |_| Err("unavailable".into()))
+            .build();
+        let resp = session
+            .eval("local function f() fail() end\nf()".to_string())
+            .await;
+        let names: Vec<_> = resp
+            .traceback
+            .iter()
+            .filter_map(|frame| frame.function.as_deref())
+            .collect();
+        assert!(names.contains(&"f"), "{:?}", resp.traceback);
+    }
+}
@@ -0,0 +1,342 @@
+//! `:export-transcript`: the inputs of a REPL session with what they
+//! printed, returned and raised, written as a Markdown or HTML report.
+//!
+//! Output is only part of the transcript if the session captures it, see
+//! `SessionBuilder::capture_output`; otherwise it went straight to the
+//! terminal.
+
+use crate::output;
+use crate::output::OutputLevel;
+use crate::path::KEYWORDS;
+use crate::EvalResponse;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// One input and what came of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub input: String,
+    /// The lines the eval printed, then those it wrote to stderr.
+    pub output: Vec<String>,
+    /// The values as the REPL printed them, on success.
+    pub result: Option<String>,
+    /// Why the eval failed, otherwise.
+    pub error: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Html,
+}
+
+impl Format {
+    /// HTML for `.html` and `.htm` files, Markdown for anything else.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension)
+                if extension.eq_ignore_ascii_case("html")
+                    || extension.eq_ignore_ascii_case("htm") =>
+            {
+                Format::Html
+            }
+            _ => Format::Markdown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    entries: Vec<Entry>,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Adds `input` and its response, rendered at `level`.
+    pub fn record(&mut self, input: &str, response: &EvalResponse, level: OutputLevel) {
+        let rendered = output::render(response, level);
+        let (result, error) = if response.success {
+            (Some(rendered), None)
+        } else {
+            let error = match level {
+                OutputLevel::Full => rendered,
+                _ => output::error_text(response).unwrap_or(rendered),
+            };
+            (None, Some(error))
+        };
+        self.entries.push(Entry {
+            input: input.trim_end().to_string(),
+            output: response
+                .output
+                .iter()
+                .chain(&response.stderr)
+                .cloned()
+                .collect(),
+            result,
+            error,
+            warnings: response.warnings.clone(),
+        });
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Markdown => self.markdown(),
+            Format::Html => self.html(),
+        }
+    }
+
+    /// Writes the transcript to `path`, in the format its extension names.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.render(Format::of(path)))
+    }
+
+    fn markdown(&self) -> String {
+        let mut text = format!("# luarepl session\n\n{} inputs.\n", self.entries.len());
+        for (index, entry) in self.entries.iter().enumerate() {
+            text.push_str(&format!("\n## In [{}]\n\n", index + 1));
+            text.push_str(&fenced("lua", &entry.input));
+            if !entry.output.is_empty() {
+                text.push_str("\nOutput:\n\n");
+                text.push_str(&fenced("text", &entry.output.join("\n")));
+            }
+            if let Some(result) = &entry.result {
+                text.push_str("\nResult:\n\n");
+                text.push_str(&fenced("text", result));
+            }
+            if let Some(error) = &entry.error {
+                text.push_str("\n**Error:**\n\n");
+                text.push_str(&fenced("text", error));
+            }
+            for warning in &entry.warnings {
+                text.push_str(&format!("\n> warning: {}\n", warning));
+            }
+        }
+        text
+    }
+
+    fn html(&self) -> String {
+        let mut text = String::from(HTML_HEAD);
+        text.push_str(&format!(
+            "<h1>luarepl session</h1>\n<p>{} inputs.</p>\n",
+            self.entries.len()
+        ));
+        for (index, entry) in self.entries.iter().enumerate() {
+            text.push_str(&format!(
+                "<section>\n<h2>In [{}]</h2>\n<pre class=\"input\"><code>{}</code></pre>\n",
+                index + 1,
+                highlight(&entry.input)
+            ));
+            if !entry.output.is_empty() {
+                text.push_str(&format!(
+                    "<pre class=\"output\">{}</pre>\n",
+                    escape(&entry.output.join("\n"))
+                ));
+            }
+            if let Some(result) = &entry.result {
+                text.push_str(&format!("<pre class=\"result\">{}</pre>\n", escape(result)));
+            }
+            if let Some(error) = &entry.error {
+                text.push_str(&format!("<pre class=\"error\">{}</pre>\n", escape(error)));
+            }
+            for warning in &entry.warnings {
+                text.push_str(&format!(
+                    "<p class=\"warning\">warning: {}</p>\n",
+                    escape(warning)
+                ));
+            }
+            text.push_str("</section>\n");
+        }
+        text.push_str("</body>\n</html>\n");
+        text
+    }
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>luarepl session</title>
+<style>
+body { font-family: sans-serif; max-width: 60em; margin: 2em auto; color: #222; }
+pre { padding: 0.5em 1em; background: #f6f8fa; overflow-x: auto; }
+pre.output { background: #fff; border-left: 3px solid #ccc; }
+pre.error { background: #fdecea; color: #a00; }
+.warning { color: #8a6d00; }
+.keyword { color: #a626a4; font-weight: bold; }
+.string { color: #50a14f; }
+.number { color: #986801; }
+.comment { color: #a0a1a7; font-style: italic; }
+</style>
+</head>
+<body>
+"#;
+
+/// `text` in a fenced code block, with a fence longer than any run of
+/// backticks in it.
+fn fenced(language: &str, text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{}{}\n{}\n{}\n", fence, language, text, fence)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Lua `source` as escaped HTML, with keywords, strings, numbers and
+/// comments in spans of those classes. It only needs to look right, so
+/// source that does not lex is passed through as it is.
+fn highlight(source: &str) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let mut html = String::new();
+    let mut i = 0;
+    let span = |html: &mut String, class: &str, text: &[char]| {
+        let text: String = text.iter().collect();
+        html.push_str(&format!(
+            "<span class=\"{}\">{}</span>",
+            class,
+            escape(&text)
+        ));
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c == '-' && chars.get(i + 1) == Some(&'-') {
+            i = match long_bracket(&chars, i + 2) {
+                Some(end) => end,
+                None => chars[i..]
+                    .iter()
+                    .position(|&c| c == '\n')
+                    .map_or(chars.len(), |n| i + n),
+            };
+            span(&mut html, "comment", &chars[start..i]);
+        } else if c == '[' && long_bracket(&chars, i).is_some() {
+            i = long_bracket(&chars, i).unwrap();
+            span(&mut html, "string", &chars[start..i]);
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(chars.len());
+            span(&mut html, "string", &chars[start..i]);
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    || (matches!(chars[i], '+' | '-')
+                        && matches!(chars[i - 1], 'e' | 'E' | 'p' | 'P')))
+            {
+                i += 1;
+            }
+            span(&mut html, "number", &chars[start..i]);
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if KEYWORDS.contains(&word.as_str()) {
+                span(&mut html, "keyword", &chars[start..i]);
+            } else {
+                html.push_str(&word);
+            }
+        } else {
+            html.push_str(&escape(&c.to_string()));
+            i += 1;
+        }
+    }
+    html
+}
+
+/// Where the long bracket opening at `start`, such as `[==[`, is closed,
+/// or `None` if there is none there. An unclosed one runs to the end.
+fn long_bracket(chars: &[char], start: usize) -> Option<usize> {
+    if chars.get(start) != Some(&'[') {
+        return None;
+    }
+    let level = chars[start + 1..].iter().take_while(|&&c| c == '=').count();
+    if chars.get(start + 1 + level) != Some(&'[') {
+        return None;
+    }
+    let close: Vec<char> = format!("]{}]", "=".repeat(level)).chars().collect();
+    let body = start + level + 2;
+    Some(
+        (body..chars.len())
+            .find(|&i| chars[i..].starts_with(&close))
+            .map_or(chars.len(), |i| i + close.len()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_transcript() {
+        let mut session = Session::builder().capture_output(true).build();
+        let mut transcript = Transcript::new();
+        for input in ["print('hi <b>') return 1 + 1 -- sum", "error('boom', 0)"] {
+            let response = session.eval(input.to_string()).await;
+            transcript.record(input, &response, OutputLevel::Standard);
+        }
+        let entries = transcript.entries();
+        assert_eq!(entries[0].output, ["hi <b>"]);
+        assert_eq!(entries[0].result.as_deref(), Some("2"));
+        assert_eq!(entries[1].error.as_deref(), Some("boom"));
+
+        let markdown = transcript.render(Format::Markdown);
+        assert!(markdown.contains("## In [2]\n\n```lua\nerror('boom', 0)\n```\n"));
+        assert!(
+            markdown.contains("Result:\n\n```text\n2\n```"),
+            "{}",
+            markdown
+        );
+
+        let html = transcript.render(Format::Html);
+        assert!(
+            html.contains("<pre class=\"output\">hi &lt;b&gt;</pre>"),
+            "{}",
+            html
+        );
+        assert!(html.contains(
+            "<span class=\"keyword\">return</span> <span class=\"number\">1</span> + \
+             <span class=\"number\">1</span> <span class=\"comment\">-- sum</span>"
+        ));
+        assert!(html.contains("<span class=\"string\">'boom'</span>"));
+        assert!(html.contains("<pre class=\"error\">boom</pre>"));
+
+        assert_eq!(Format::of(Path::new("report.HTML")), Format::Html);
+        assert_eq!(Format::of(Path::new("report.md")), Format::Markdown);
+        assert_eq!(
+            highlight("s = [==[a]]b]==] x"),
+            "s = <span class=\"string\">[==[a]]b]==]</span> x"
+        );
+    }
+}
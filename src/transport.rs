@@ -0,0 +1,290 @@
+//! How the server reaches its clients: a `Transport` accepts connections and
+//! a `Connection` carries whole messages both ways. TCP, Unix domain sockets,
+//! stdio and WebSockets (see `websocket`) are built in; other transports, such as named pipes or QUIC, implement the two
+//! traits and are passed to `server::serve` like the built-in ones.
+//!
+//! The built-in transports frame messages with `Framed`: a 4-byte big-endian
+//! length followed by that many bytes, so messages may contain newlines and
+//! binary data.
+
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpListener;
+use tokio::net::ToSocketAddrs;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// Longest message `Framed` accepts, so that a corrupt length cannot make it
+/// allocate without bound.
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// A source of client connections.
+pub trait Transport: Send + 'static {
+    type Connection: Connection;
+
+    /// Waits for the next client. `None` means no more will come, and the
+    /// server stops once the connected ones are done.
+    fn accept(&mut self) -> impl Future<Output = io::Result<Option<Self::Connection>>> + Send;
+}
+
+/// One client, exchanging whole messages.
+pub trait Connection: Send + 'static {
+    /// The next message, or `None` once the client closed the connection.
+    /// Dropping the future before it resolves must not lose part of a
+    /// message, since `server::serve_shared` races it against broadcasts.
+    fn recv(&mut self) -> impl Future<Output = io::Result<Option<Vec<u8>>>> + Send;
+
+    fn send(&mut self, message: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Who is on the other end, for logs.
+    fn peer(&self) -> String;
+}
+
+/// Length-prefixed messages over a byte stream.
+#[derive(Debug)]
+pub struct Framed<R, W> {
+    reader: R,
+    writer: W,
+    peer: String,
+    /// What has been read of the next message, kept across cancelled reads.
+    buffer: Vec<u8>,
+}
+
+impl<R, W> Framed<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(reader: R, writer: W, peer: String) -> Self {
+        Self {
+            reader,
+            writer,
+            peer,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<R, W> Connection for Framed<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    async fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if self.buffer.len() >= 4 {
+                let len = u32::from_be_bytes(self.buffer[..4].try_into().unwrap()) as usize;
+                if len > MAX_MESSAGE_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("message of {} bytes is too long", len),
+                    ));
+                }
+                if self.buffer.len() >= 4 + len {
+                    let message = self.buffer[4..4 + len].to_vec();
+                    self.buffer.drain(..4 + len);
+                    return Ok(Some(message));
+                }
+            }
+            // `read_buf` only appends what it read, so it can be cancelled.
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return match self.buffer.len() {
+                    0..=3 => Ok(None),
+                    _ => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+        }
+    }
+
+    async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(message.len())
+            .ok()
+            .filter(|len| *len as usize <= MAX_MESSAGE_LEN)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+        self.writer.write_all(&len.to_be_bytes()).await?;
+        self.writer.write_all(message).await?;
+        self.writer.flush().await
+    }
+
+    fn peer(&self) -> String {
+        self.peer.clone()
+    }
+}
+
+/// Clients connecting over TCP.
+#[derive(Debug)]
+pub struct TcpTransport {
+    listener: TcpListener,
+}
+
+impl TcpTransport {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Transport for TcpTransport {
+    type Connection = Framed<OwnedReadHalf, OwnedWriteHalf>;
+
+    async fn accept(&mut self) -> io::Result<Option<Self::Connection>> {
+        let (stream, peer) = self.listener.accept().await?;
+        let (reader, writer) = stream.into_split();
+        Ok(Some(Framed::new(reader, writer, peer.to_string())))
+    }
+}
+
+/// Clients connecting to a Unix domain socket, for local editor plugins
+/// that should not need a TCP port. The socket file is removed when the
+/// transport is dropped.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixTransport {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    /// Listens at `path`, replacing a socket file left behind by a server
+    /// that is no longer running.
+    pub async fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let listener = match UnixListener::bind(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if UnixStream::connect(&path).await.is_ok() {
+                    return Err(e);
+                }
+                std::fs::remove_file(&path)?;
+                UnixListener::bind(&path)?
+            }
+            listener => listener?,
+        };
+        Ok(Self { listener, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    type Connection = Framed<tokio::net::unix::OwnedReadHalf, tokio::net::unix::OwnedWriteHalf>;
+
+    async fn accept(&mut self) -> io::Result<Option<Self::Connection>> {
+        let (stream, _) = self.listener.accept().await?;
+        let (reader, writer) = stream.into_split();
+        Ok(Some(Framed::new(
+            reader,
+            writer,
+            format!("unix:{}", self.path.display()),
+        )))
+    }
+}
+
+/// The process's stdin and stdout as the one client, for editors that run
+/// luarepl as a subprocess.
+#[derive(Debug, Default)]
+pub struct StdioTransport {
+    accepted: bool,
+}
+
+impl Transport for StdioTransport {
+    type Connection = Framed<tokio::io::Stdin, tokio::io::Stdout>;
+
+    async fn accept(&mut self) -> io::Result<Option<Self::Connection>> {
+        if std::mem::replace(&mut self.accepted, true) {
+            return Ok(None);
+        }
+        Ok(Some(Framed::new(
+            tokio::io::stdin(),
+            tokio::io::stdout(),
+            "stdio".to_string(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_framing() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let mut client = Framed::new(client_reader, client_writer, "client".to_string());
+        let mut server = Framed::new(server_reader, server_writer, "server".to_string());
+        let message = b"x = 1\nreturn x\0".repeat(20);
+        client.send(&message).await.unwrap();
+        client.send(b"").await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), Some(message));
+        assert_eq!(server.recv().await.unwrap(), Some(vec![]));
+        drop(client);
+        assert_eq!(server.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message() {
+        let (client, server) = tokio::io::duplex(64);
+        let (_, mut client_writer) = tokio::io::split(client);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let mut server = Framed::new(server_reader, server_writer, "server".to_string());
+        client_writer
+            .write_all(&u32::MAX.to_be_bytes())
+            .await
+            .unwrap();
+        let error = server.recv().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("luarepl-{}.sock", std::process::id()));
+        // A socket file nobody listens on any more is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path));
+        let mut transport = UnixTransport::bind(&path).await.unwrap();
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut client = Framed::new(reader, writer, "client".to_string());
+        let mut server = transport.accept().await.unwrap().unwrap();
+        client.send(b"return 1").await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), Some(b"return 1".to_vec()));
+        assert!(server.peer().starts_with("unix:"));
+        // One that is still served is not.
+        assert!(UnixTransport::bind(&path).await.is_err());
+
+        drop(transport);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,392 @@
+//! `luarepl --tui`: the REPL as a full-screen dashboard, for long debugging
+//! sessions. The scrollback of inputs and responses and the input line sit
+//! on the left; on the right are the values of the watch expressions, the
+//! globals defined since the dashboard started, and the session's stats.
+//!
+//! Input goes through the `SessionManager` like the line-based REPL's, so
+//! the audit log, the limits and what happens on violations are the same,
+//! and responses are rendered the same way. Enter runs the input, Up and
+//! Down recall earlier inputs, Page Up and Page Down scroll, Esc clears the
+//! input, and Ctrl-C clears it or, once empty, leaves, as does Ctrl-D.
+//! `:watch <expr>` adds a watch, `:unwatch <n>` removes one, and `:quit`
+//! leaves; the other meta-commands are for the line-based REPL.
+//!
+//! Sessions should capture their output, see `SessionBuilder::capture_output`,
+//! so that what evals print ends up in the scrollback rather than across
+//! the screen.
+
+use crate::manager::SessionId;
+use crate::manager::SessionManager;
+use crate::output;
+use crate::output::OutputLevel;
+use crate::terminal::parse_keys;
+use crate::terminal::Key;
+use crate::terminal::Terminal;
+use crate::ui;
+use crate::ui::Entry;
+use crate::watch::Watch;
+use std::collections::HashSet;
+use std::io;
+use std::time::Instant;
+
+/// How many lines of scrollback are kept.
+const SCROLLBACK_KEPT: usize = 10_000;
+
+/// What the dashboard needs done after a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Redraw,
+    /// Run this input.
+    Submit(String),
+    Quit,
+}
+
+/// The dashboard's state, apart from the terminal and the session.
+#[derive(Debug, Clone, Default)]
+pub struct Dashboard {
+    scrollback: Vec<String>,
+    /// How many lines the scrollback is scrolled up by.
+    scrolled: usize,
+    input: String,
+    history: Vec<String>,
+    /// The entry of `history` recalled into the input, if any.
+    recalled: Option<usize>,
+    /// The watches, with their sources and latest values.
+    watches: Vec<(Watch, String, String)>,
+    globals: Vec<Entry>,
+    stats: Vec<(&'static str, String)>,
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `text` to the scrollback, scrolling back down.
+    pub fn push(&mut self, text: &str) {
+        self.scrollback.extend(text.lines().map(str::to_string));
+        let excess = self.scrollback.len().saturating_sub(SCROLLBACK_KEPT);
+        self.scrollback.drain(..excess);
+        self.scrolled = 0;
+    }
+
+    pub fn key(&mut self, key: Key, page: usize) -> Action {
+        match key {
+            Key::Char(c) => self.input.push(c),
+            Key::Backspace => {
+                self.input.pop();
+            }
+            Key::Enter if self.input.trim().is_empty() => {}
+            Key::Enter => {
+                let input = std::mem::take(&mut self.input);
+                self.push(&format!("> {}", input));
+                self.history.push(input.clone());
+                self.recalled = None;
+                return Action::Submit(input);
+            }
+            Key::Up if !self.history.is_empty() => {
+                let recalled = self
+                    .recalled
+                    .map_or(self.history.len() - 1, |i| i.saturating_sub(1));
+                self.input = self.history[recalled].clone();
+                self.recalled = Some(recalled);
+            }
+            Key::Down => match self.recalled {
+                Some(i) if i + 1 < self.history.len() => {
+                    self.input = self.history[i + 1].clone();
+                    self.recalled = Some(i + 1);
+                }
+                Some(_) => {
+                    self.input.clear();
+                    self.recalled = None;
+                }
+                None => {}
+            },
+            Key::PageUp => {
+                let most = self.scrollback.len().saturating_sub(page);
+                self.scrolled = (self.scrolled + page).min(most);
+            }
+            Key::PageDown => self.scrolled = self.scrolled.saturating_sub(page),
+            Key::Esc => self.input.clear(),
+            Key::Interrupt if !self.input.is_empty() => self.input.clear(),
+            Key::Interrupt | Key::Eof => return Action::Quit,
+            _ => {}
+        }
+        Action::Redraw
+    }
+
+    /// The screen, `width` columns by `height` rows.
+    pub fn render(&self, width: usize, height: usize) -> String {
+        let width = width.max(40);
+        let rows = height.max(6) - 2;
+        let left = width * 3 / 5;
+        let right = width - left - 1;
+
+        let end = self.scrollback.len() - self.scrolled;
+        let start = end.saturating_sub(rows);
+        let scrollback = &self.scrollback[start..end];
+
+        let stats: Vec<_> = self
+            .stats
+            .iter()
+            .map(|(name, value)| format!("{:<16} {}", name, value))
+            .collect();
+        let watches: Vec<_> = self
+            .watches
+            .iter()
+            .map(|(watch, source, value)| format!("{} {} = {}", watch.id(), source, value))
+            .collect();
+        let globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|entry| format!("{} = {}", entry.key, entry.value))
+            .collect();
+        // Stats always fit; watches get up to half of what is left.
+        let stats_rows = (stats.len() + 1).min(rows);
+        let rest = rows - stats_rows;
+        let watch_rows = (watches.len() + 1).min(rest / 2).max(rest.min(1));
+        let global_rows = rest - watch_rows;
+        let mut panes = vec![];
+        for (title, lines, count) in [
+            ("watches", &watches, watch_rows),
+            ("globals", &globals, global_rows),
+            ("stats", &stats, stats_rows),
+        ] {
+            if count == 0 {
+                continue;
+            }
+            panes.push(format!("\x1b[1m{}\x1b[0m", title));
+            panes.extend(lines.iter().take(count - 1).map(|line| clip(line, right)));
+            panes.extend(std::iter::repeat_n(
+                String::new(),
+                (count - 1).saturating_sub(lines.len()),
+            ));
+        }
+
+        let mut screen = String::from("\x1b[H\x1b[2J");
+        for row in 0..rows {
+            let line = scrollback
+                .get(row)
+                .map_or(String::new(), |line| clip(line, left));
+            let pane = panes.get(row).map_or("", String::as_str);
+            screen.push_str(&format!("{:<left$}│{}\r\n", line, pane, left = left));
+        }
+        let status = match self.scrolled {
+            0 => "enter: run  pgup/pgdn: scroll  :watch <expr>  ctrl-d: quit".to_string(),
+            n => format!("scrolled up {} lines", n),
+        };
+        screen.push_str(&format!("\x1b[2m{}\x1b[0m\r\n", clip(&status, width)));
+        // The end of long input stays in view, with the cursor after it.
+        let prompt = format!("> {}", self.input);
+        let shown = prompt.chars().count().saturating_sub(width - 1);
+        let prompt: String = prompt.chars().skip(shown).collect();
+        screen.push_str(&prompt);
+        screen.push_str(&format!(
+            "\x1b[{};{}H\x1b[?25h",
+            rows + 2,
+            prompt.chars().count() + 1
+        ));
+        screen
+    }
+}
+
+/// `text` cut to `width` characters.
+fn clip(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+/// Runs `input` in session `id`: a meta-command of the dashboard, or a
+/// chunk whose response goes to the scrollback after it. Returns false once
+/// the dashboard should be left.
+pub async fn submit(
+    manager: &mut SessionManager,
+    id: SessionId,
+    dashboard: &mut Dashboard,
+    input: String,
+) -> bool {
+    let Some(session) = manager.session_mut(id) else {
+        return false;
+    };
+    let command = input.trim();
+    if command == ":quit" {
+        return false;
+    }
+    if let Some(source) = command.strip_prefix(":watch ") {
+        let watch = session.watch(source.trim().to_string());
+        dashboard
+            .watches
+            .push((watch, source.trim().to_string(), "…".to_string()));
+    } else if let Some(number) = command.strip_prefix(":unwatch ") {
+        let found = dashboard
+            .watches
+            .iter()
+            .position(|(watch, _, _)| number.trim().parse() == Ok(watch.id()));
+        match found {
+            Some(index) => {
+                let (watch, _, _) = dashboard.watches.remove(index);
+                session.unwatch(watch);
+            }
+            None => dashboard.push(&format!("no watch {}", number.trim())),
+        }
+    } else if command.starts_with(':') {
+        dashboard.push("only :watch, :unwatch and :quit work in the dashboard");
+    } else {
+        let level = manager.config().output_level;
+        let started = Instant::now();
+        let Some(response) = manager.eval_as(id, Some("tui"), input).await else {
+            return false;
+        };
+        let elapsed = started.elapsed();
+        for line in response.output.iter().chain(&response.stderr) {
+            dashboard.push(line);
+        }
+        dashboard.push(&output::render(&response, level));
+        for warning in &response.warnings {
+            dashboard.push(&format!("warning: {}", warning));
+        }
+        dashboard.stats.retain(|(name, _)| *name != "last eval");
+        dashboard
+            .stats
+            .push(("last eval", format!("{:?}", elapsed)));
+    }
+    manager.contains(id)
+}
+
+/// Re-reads the watches, globals and stats of session `id`. Globals in
+/// `baseline` are left out.
+pub async fn refresh(
+    manager: &mut SessionManager,
+    id: SessionId,
+    dashboard: &mut Dashboard,
+    baseline: &HashSet<String>,
+) {
+    let session_stats = manager.session_stats(id).unwrap_or_default();
+    let Some(session) = manager.session_mut(id) else {
+        return;
+    };
+    for (watch, response) in session.refresh_watches().await {
+        let value = output::render(&response, OutputLevel::Minimal);
+        if let Some(entry) = dashboard.watches.iter_mut().find(|(w, _, _)| *w == watch) {
+            entry.2 = value.lines().next().unwrap_or_default().to_string();
+        }
+    }
+    if let Ok(response) = session.get_path(ui::ROOT.to_string()).await {
+        dashboard.globals = ui::entries(&response, ui::ROOT)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| !baseline.contains(&entry.key))
+            .collect();
+    }
+    let last = dashboard
+        .stats
+        .iter()
+        .find(|(name, _)| *name == "last eval")
+        .cloned();
+    dashboard.stats = vec![
+        ("evals", session_stats.evals.to_string()),
+        (
+            "limit violations",
+            session_stats.limit_violations.to_string(),
+        ),
+        ("cpu ticks", session.cpu_ticks().to_string()),
+        ("recoveries", session.recoveries().to_string()),
+    ];
+    dashboard.stats.extend(last);
+}
+
+/// The globals of session `id` now, which the dashboard does not list.
+async fn globals(manager: &mut SessionManager, id: SessionId) -> HashSet<String> {
+    let Some(session) = manager.session_mut(id) else {
+        return HashSet::new();
+    };
+    match session.get_path(ui::ROOT.to_string()).await {
+        Ok(response) => ui::entries(&response, ui::ROOT)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect(),
+        Err(_) => HashSet::new(),
+    }
+}
+
+/// Runs the dashboard on session `id` of `manager` until it is left or the
+/// session goes away.
+pub async fn run(manager: &mut SessionManager, id: SessionId) -> io::Result<()> {
+    let baseline = globals(manager, id).await;
+    let mut dashboard = Dashboard::new();
+    refresh(manager, id, &mut dashboard, &baseline).await;
+    let terminal = Terminal::enter()?;
+    loop {
+        let (width, height) = terminal.size();
+        terminal.draw(&dashboard.render(width, height))?;
+        let input = terminal.read()?;
+        for key in parse_keys(&input) {
+            match dashboard.key(key, height.saturating_sub(2).max(1)) {
+                Action::Redraw => {}
+                Action::Submit(input) => {
+                    // Drawn first, so that a long eval shows what it runs.
+                    terminal.draw(&dashboard.render(width, height))?;
+                    if !submit(manager, id, &mut dashboard, input).await {
+                        return Ok(());
+                    }
+                    refresh(manager, id, &mut dashboard, &baseline).await;
+                }
+                Action::Quit => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manager::LimitPolicy;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_dashboard() {
+        let config = Session::builder().capture_output(true).config();
+        let mut manager = SessionManager::new(config, LimitPolicy::ErrorAndContinue, 3);
+        let id = manager.open();
+        let baseline = globals(&mut manager, id).await;
+        let mut dashboard = Dashboard::new();
+
+        for c in "score = 4".chars() {
+            dashboard.key(Key::Char(c), 10);
+        }
+        let Action::Submit(input) = dashboard.key(Key::Enter, 10) else {
+            panic!("expected a submission");
+        };
+        assert!(submit(&mut manager, id, &mut dashboard, input).await);
+        assert!(
+            submit(
+                &mut manager,
+                id,
+                &mut dashboard,
+                ":watch score * 2".to_string()
+            )
+            .await
+        );
+        let input = "print('hi') score = score + 1 return score".to_string();
+        assert!(submit(&mut manager, id, &mut dashboard, input).await);
+        refresh(&mut manager, id, &mut dashboard, &baseline).await;
+
+        assert_eq!(
+            dashboard.scrollback[dashboard.scrollback.len() - 2..],
+            ["hi", "5"]
+        );
+        assert_eq!(dashboard.watches[0].2, "10");
+        let globals: Vec<_> = dashboard.globals.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(globals, ["score"]);
+        let screen = dashboard.render(80, 12);
+        assert!(screen.contains("0 score * 2 = 10"), "{}", screen);
+        assert!(screen.contains("evals            2"), "{}", screen);
+
+        // Up recalls the last input typed; Ctrl-C clears it, then leaves.
+        dashboard.key(Key::Up, 10);
+        assert_eq!(dashboard.input, "score = 4");
+        assert_eq!(dashboard.key(Key::Interrupt, 10), Action::Redraw);
+        assert_eq!(dashboard.key(Key::Interrupt, 10), Action::Quit);
+        assert!(!submit(&mut manager, id, &mut dashboard, ":quit".to_string()).await);
+    }
+}
@@ -0,0 +1,374 @@
+//! `:ui`: a full-screen explorer of the session's tables on the terminal's
+//! alternate screen, starting at the globals or at a path.
+//!
+//! Up and Down (or `k` and `j`), Page Up and Page Down, Home and End move
+//! through the entries of a table; Enter or Right opens the table selected,
+//! Left or Backspace goes back to the one it is in. `/` filters the entries
+//! by key as it is typed, until Enter; Esc clears the filter. `q`, Esc
+//! without a filter, Ctrl-C and Ctrl-D leave.
+//!
+//! The terminal is taken over with `terminal`, only available on Unix, and
+//! restored however the explorer is left, including by Ctrl-C, an error or
+//! a panic, so the line-based REPL carries on as before.
+
+use crate::path;
+use crate::terminal::parse_keys;
+use crate::terminal::Key;
+use crate::terminal::Terminal;
+use crate::EvalResponse;
+use crate::LuaValue;
+use crate::Session;
+use std::io;
+
+/// The table `:ui` opens without a path.
+pub const ROOT: &str = "_G";
+
+/// One entry of an opened table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    /// A one-line rendering of the value.
+    pub value: String,
+    /// The path of the value if it is a table the explorer can open.
+    pub child: Option<String>,
+}
+
+/// The entries of the table `response` holds, which was fetched from
+/// `path`, sorted by key; `None` if it holds something else.
+pub fn entries(response: &EvalResponse, path: &str) -> Option<Vec<Entry>> {
+    let LuaValue::ObjectRef(id) = &response.value else {
+        return None;
+    };
+    let object = response.objects.get(id)?;
+    let mut entries: Vec<_> = object
+        .members
+        .iter()
+        .map(|(key, value)| {
+            let child = match value {
+                LuaValue::ObjectRef(_) => child_path(path, key),
+                _ => None,
+            };
+            let value = match value {
+                LuaValue::ObjectRef(id) => object
+                    .child_previews
+                    .get(id)
+                    .cloned()
+                    .unwrap_or_else(|| id.clone()),
+                value => value.to_string(),
+            };
+            let key = match key {
+                LuaValue::String(name) if path::is_identifier(name) => name.clone(),
+                key => format!("[{}]", key),
+            };
+            Entry { key, value, child }
+        })
+        .collect();
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    Some(entries)
+}
+
+/// The path of `key` in the table at `parent`, if paths can name it.
+fn child_path(parent: &str, key: &LuaValue) -> Option<String> {
+    Some(match key {
+        LuaValue::String(name) if path::is_identifier(name) && parent == ROOT => name.clone(),
+        LuaValue::String(name) if path::is_identifier(name) => format!("{}.{}", parent, name),
+        LuaValue::String(name) => format!("{}[{}]", parent, path::quote(name)),
+        LuaValue::Integer(i) => format!("{}[{}]", parent, i),
+        LuaValue::Boolean(b) => format!("{}[{}]", parent, b),
+        _ => return None,
+    })
+}
+
+/// What the explorer needs done after a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Redraw,
+    /// Fetch the table at this path and `open` it.
+    Open(String),
+    Quit,
+}
+
+#[derive(Debug, Clone)]
+struct Level {
+    path: String,
+    entries: Vec<Entry>,
+    selected: usize,
+}
+
+/// The explorer's state, apart from the terminal.
+#[derive(Debug, Clone, Default)]
+pub struct Explorer {
+    /// The tables opened, from the first down to the one shown.
+    levels: Vec<Level>,
+    filter: String,
+    /// Whether keys are typed into the filter.
+    searching: bool,
+    /// Shown in the status line until the next key.
+    message: Option<String>,
+}
+
+impl Explorer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shows the table at `path` below the current one.
+    pub fn open(&mut self, path: String, entries: Vec<Entry>) {
+        self.levels.push(Level {
+            path,
+            entries,
+            selected: 0,
+        });
+        self.filter.clear();
+        self.searching = false;
+    }
+
+    /// Reports something that went wrong, e.g. a table that could not be
+    /// fetched.
+    pub fn message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    /// The entries of the shown table that match the filter.
+    fn visible(&self) -> Vec<&Entry> {
+        let filter = self.filter.to_lowercase();
+        self.levels.last().map_or(vec![], |level| {
+            level
+                .entries
+                .iter()
+                .filter(|entry| entry.key.to_lowercase().contains(&filter))
+                .collect()
+        })
+    }
+
+    /// The entry selected, if any.
+    pub fn selected(&self) -> Option<&Entry> {
+        let selected = self.levels.last()?.selected;
+        self.visible().get(selected).copied()
+    }
+
+    fn select(&mut self, selected: usize) {
+        let count = self.visible().len();
+        if let Some(level) = self.levels.last_mut() {
+            level.selected = selected.min(count.saturating_sub(1));
+        }
+    }
+
+    pub fn key(&mut self, key: Key, page: usize) -> Action {
+        self.message = None;
+        if self.searching {
+            match key {
+                Key::Char(c) => self.filter.push(c),
+                Key::Backspace => {
+                    self.filter.pop();
+                }
+                Key::Enter => self.searching = false,
+                Key::Esc => {
+                    self.filter.clear();
+                    self.searching = false;
+                }
+                Key::Interrupt | Key::Eof => return Action::Quit,
+                _ => return self.navigate(key, page),
+            }
+            self.select(0);
+            return Action::Redraw;
+        }
+        match key {
+            Key::Char('q') | Key::Interrupt | Key::Eof => Action::Quit,
+            Key::Esc if self.filter.is_empty() => Action::Quit,
+            Key::Esc => {
+                self.filter.clear();
+                self.select(0);
+                Action::Redraw
+            }
+            Key::Char('/') => {
+                self.searching = true;
+                Action::Redraw
+            }
+            Key::Char('j') => self.navigate(Key::Down, page),
+            Key::Char('k') => self.navigate(Key::Up, page),
+            Key::Char('l') => self.navigate(Key::Right, page),
+            Key::Char('h') => self.navigate(Key::Left, page),
+            key => self.navigate(key, page),
+        }
+    }
+
+    fn navigate(&mut self, key: Key, page: usize) -> Action {
+        let selected = self.levels.last().map_or(0, |level| level.selected);
+        match key {
+            Key::Up => self.select(selected.saturating_sub(1)),
+            Key::Down => self.select(selected + 1),
+            Key::PageUp => self.select(selected.saturating_sub(page)),
+            Key::PageDown => self.select(selected + page),
+            Key::Home => self.select(0),
+            Key::End => self.select(usize::MAX),
+            Key::Enter | Key::Right => match self.selected() {
+                Some(Entry {
+                    child: Some(path), ..
+                }) => return Action::Open(path.clone()),
+                Some(entry) => self.message = Some(format!("{} is not a table", entry.key)),
+                None => {}
+            },
+            Key::Left | Key::Backspace if self.levels.len() > 1 => {
+                self.levels.pop();
+                self.filter.clear();
+            }
+            _ => {}
+        }
+        Action::Redraw
+    }
+
+    /// The screen, `width` columns by `height` rows: the path, the entries
+    /// around the selected one, and a status line.
+    pub fn render(&self, width: usize, height: usize) -> String {
+        let width = width.max(20);
+        let rows = height.saturating_sub(2).max(1);
+        let path = self.levels.last().map_or(ROOT, |level| level.path.as_str());
+        let mut screen = String::from("\x1b[H\x1b[2J");
+        screen.push_str(&format!("\x1b[1m{}\x1b[0m\r\n", clip(path, width)));
+
+        let visible = self.visible();
+        let selected = self.levels.last().map_or(0, |level| level.selected);
+        let first = (selected + 1).saturating_sub(rows);
+        let key_width = visible
+            .iter()
+            .map(|entry| entry.key.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(width / 3);
+        for (index, entry) in visible.iter().enumerate().skip(first).take(rows) {
+            let marker = if entry.child.is_some() { '+' } else { ' ' };
+            let line = format!(
+                "{} {:<key_width$}  {}",
+                marker,
+                clip(&entry.key, key_width),
+                entry.value,
+                key_width = key_width
+            );
+            let line = clip(&line, width);
+            if index == selected {
+                screen.push_str(&format!("\x1b[7m{}\x1b[0m\r\n", line));
+            } else {
+                screen.push_str(&format!("{}\r\n", line));
+            }
+        }
+        for _ in visible.len().saturating_sub(first).min(rows)..rows {
+            screen.push_str("\r\n");
+        }
+
+        let status = match (&self.message, self.searching) {
+            (Some(message), _) => message.clone(),
+            (None, true) => format!("/{}", self.filter),
+            (None, false) if !self.filter.is_empty() => {
+                format!(
+                    "{} of {} match /{}",
+                    visible.len(),
+                    self.count(),
+                    self.filter
+                )
+            }
+            (None, false) => format!(
+                "{} entries  enter: open  left: back  /: filter  q: quit",
+                self.count()
+            ),
+        };
+        screen.push_str(&clip(&status, width));
+        screen
+    }
+
+    fn count(&self) -> usize {
+        self.levels.last().map_or(0, |level| level.entries.len())
+    }
+}
+
+/// `text` cut to `width` characters.
+fn clip(text: &str, width: usize) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    if line.chars().count() <= width {
+        return line.to_string();
+    }
+    let mut clipped: String = line.chars().take(width.saturating_sub(1)).collect();
+    clipped.push('…');
+    clipped
+}
+
+/// Fetches the table at `path` for the explorer.
+async fn fetch(session: &mut Session, path: &str) -> Result<Vec<Entry>, String> {
+    let response = session
+        .get_path(path.to_string())
+        .await
+        .map_err(|e| e.to_string())?;
+    entries(&response, path).ok_or_else(|| format!("{} is not a table", path))
+}
+
+/// Runs the explorer on the terminal until it is left, starting at `path`
+/// or the globals.
+pub async fn run(session: &mut Session, path: Option<String>) -> io::Result<()> {
+    let path = path.unwrap_or_else(|| ROOT.to_string());
+    let entries = fetch(session, &path)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut explorer = Explorer::new();
+    explorer.open(path, entries);
+    let terminal = Terminal::enter()?;
+    loop {
+        let (width, height) = terminal.size();
+        terminal.draw(&explorer.render(width, height))?;
+        let input = terminal.read()?;
+        for key in parse_keys(&input) {
+            match explorer.key(key, height.saturating_sub(2).max(1)) {
+                Action::Redraw => {}
+                Action::Open(path) => match fetch(session, &path).await {
+                    Ok(entries) => explorer.open(path, entries),
+                    Err(e) => explorer.message(e),
+                },
+                Action::Quit => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_explorer() {
+        let mut session = Session::new();
+        session
+            .eval("player = { name = 'Ada', stats = { hp = 10 }, [1] = true }".to_string())
+            .await;
+        let mut explorer = Explorer::new();
+        explorer.open(
+            "player".to_string(),
+            fetch(&mut session, "player").await.unwrap(),
+        );
+        let keys: Vec<_> = explorer
+            .visible()
+            .iter()
+            .map(|entry| entry.key.clone())
+            .collect();
+        assert_eq!(keys, ["[1]", "name", "stats"]);
+
+        for key in parse_keys(b"/st\r") {
+            assert_eq!(explorer.key(key, 10), Action::Redraw);
+        }
+        assert_eq!(explorer.selected().unwrap().key, "stats");
+        assert_eq!(
+            explorer.key(Key::Enter, 10),
+            Action::Open("player.stats".to_string())
+        );
+        explorer.open(
+            "player.stats".to_string(),
+            fetch(&mut session, "player.stats").await.unwrap(),
+        );
+        let screen = explorer.render(40, 5);
+        assert!(screen.contains("player.stats"), "{}", screen);
+        assert!(screen.contains("\x1b[7m  hp  10"), "{}", screen);
+
+        assert_eq!(explorer.key(Key::Left, 10), Action::Redraw);
+        assert_eq!(explorer.visible().len(), 3);
+        assert_eq!(explorer.key(Key::Char('q'), 10), Action::Quit);
+        assert!(fetch(&mut session, "player.name").await.is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Userdata that is not bridged, such as files or values of C modules, in
+//! responses, see `LuaValue::UserData`. Only what its metatable shows is
+//! known about it.
+
+use crate::value_id;
+use rlua::AnyUserData;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+
+const INSPECT_KEY: &str = "luarepl.userdata_inspect";
+
+/// Returns a function describing a userdata value with the library
+/// functions of the fresh state, so scripts replacing them do not matter.
+const INSTALL_SOURCE: &str = r#"
+local getmetatable, tostring, pcall, type, next, rawget, sub, sort =
+    getmetatable, tostring, pcall, type, next, rawget, string.sub, table.sort
+local function names(t, metamethods, fields)
+    for key in next, t do
+        if type(key) == "string" then
+            local list = sub(key, 1, 2) == "__" and metamethods or fields
+            list[#list + 1] = key
+        end
+    end
+end
+return function(u)
+    local ok, text = pcall(tostring, u)
+    local mt = getmetatable(u)
+    local metamethods, fields, name = {}, {}, nil
+    if type(mt) == "table" then
+        names(mt, metamethods, fields)
+        local index = rawget(mt, "__index")
+        if type(index) == "table" then
+            names(index, {}, fields)
+        end
+        name = rawget(mt, "__name")
+    end
+    sort(metamethods)
+    sort(fields)
+    return ok and type(text) == "string" and text or nil,
+        type(name) == "string" and name or nil, metamethods, fields
+end
+"#;
+
+/// What the function of `INSTALL_SOURCE` returns: the `tostring` text,
+/// `__name`, metamethods and fields.
+type Inspected = (Option<String>, Option<String>, Vec<String>, Vec<String>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserDataRef {
+    /// Identifies the value like the ids of `LuaValue::ObjectRef`, e.g.
+    /// `userdata: 0x5581c0a3e2f0`.
+    pub id: String,
+    /// What `tostring` returns for it, e.g. `file (0x5581c0a3e2f0)`; `None`
+    /// if `__tostring` failed.
+    pub display: Option<String>,
+    /// The `__name` of its metatable, e.g. `FILE*`.
+    pub type_name: Option<String>,
+    /// Keys of its metatable starting with `__`, sorted.
+    pub metamethods: Vec<String>,
+    /// Other string keys of its metatable and of a table `__index`, which
+    /// are usually its methods, sorted.
+    pub fields: Vec<String>,
+}
+
+impl UserDataRef {
+    pub(crate) fn new<'l>(ctx: Context<'l>, userdata: AnyUserData<'l>) -> Self {
+        let id = value_id(ctx, Value::UserData(userdata.clone()));
+        let inspected: Result<Inspected, Error> = ctx
+            .named_registry_value::<_, Function>(INSPECT_KEY)
+            .and_then(|inspect| inspect.call(userdata));
+        match inspected {
+            Ok((display, type_name, metamethods, fields)) => Self {
+                id,
+                display,
+                type_name,
+                metamethods,
+                fields,
+            },
+            Err(_) => Self {
+                id,
+                display: None,
+                type_name: None,
+                metamethods: vec![],
+                fields: vec![],
+            },
+        }
+    }
+}
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let inspect: Function = ctx.load(INSTALL_SOURCE).set_name("=userdata")?.eval()?;
+    ctx.set_named_registry_value(INSPECT_KEY, inspect)
+}
+
+impl fmt::Display for UserDataRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.display {
+            Some(display) => write!(f, "{}", display),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_file_handle() {
+        let mut session = Session::new();
+        let resp = session.eval("return io.stdout".to_string()).await;
+        assert!(resp.success, "{:?}", resp.error);
+        let LuaValue::UserData(userdata) = &resp.value else {
+            panic!("expected userdata, got {:?}", resp.value);
+        };
+        assert_eq!(userdata.type_name.as_deref(), Some("FILE*"));
+        assert!(userdata.display.as_deref().unwrap().starts_with("file ("));
+        assert!(userdata.metamethods.contains(&"__gc".to_string()));
+        for method in ["close", "lines", "read", "write"] {
+            assert!(
+                userdata.fields.contains(&method.to_string()),
+                "{:?}",
+                userdata
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failing_tostring() {
+        let mut session = Session::new();
+        let resp = session
+            .eval(
+                "local mt = getmetatable(io.stdout)\n\
+                 mt.__tostring = function() error('no') end\n\
+                 tostring = nil\n\
+                 return { f = io.stdout }"
+                    .to_string(),
+            )
+            .await;
+        let LuaValue::ObjectRef(id) = &resp.value else {
+            panic!("expected a table, got {:?}", resp.value);
+        };
+        let Some(LuaValue::UserData(userdata)) = resp.objects[id].get("f") else {
+            panic!("expected userdata in {:?}", resp.objects[id]);
+        };
+        assert_eq!(userdata.display, None);
+        assert_eq!(userdata.to_string(), userdata.id);
+        assert!(userdata.id.starts_with("userdata: "));
+    }
+}
@@ -0,0 +1,144 @@
+//! Lua 5.4's warning system: `warn` collects its messages into
+//! `EvalResponse::warnings` instead of dropping them, as rlua sets no
+//! warning function. Warnings are on until a script turns them off with
+//! `warn("@off")`. Warnings Lua raises itself, such as errors in `__gc`
+//! metamethods, do not reach `warn` and are still dropped.
+
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+
+const TAKE_KEY: &str = "luarepl.warnings";
+
+/// How many warnings one eval reports; the rest are counted.
+const MAX_WARNINGS: usize = 100;
+
+/// Replaces `warn` and returns a function taking the warnings collected
+/// since it was last called, and how many were left out.
+const INSTALL_SOURCE: &str = r##"
+local max = ...
+local select, type, tostring, error, concat, sub, format =
+    select, type, tostring, error, table.concat, string.sub, string.format
+local messages, dropped, on = {}, 0, true
+
+function warn(...)
+    local n = select("#", ...)
+    local parts = { ... }
+    if n == 0 then
+        error("bad argument #1 to 'warn' (string expected, got no value)", 2)
+    end
+    for i = 1, n do
+        local kind = type(parts[i])
+        if kind == "number" then
+            parts[i] = tostring(parts[i])
+        elseif kind ~= "string" then
+            error(format("bad argument #%d to 'warn' (string expected, got %s)", i, kind), 2)
+        end
+    end
+    local message = concat(parts, "", 1, n)
+    if n == 1 and sub(message, 1, 1) == "@" then
+        -- Control messages; unknown ones are ignored, as by lua.c.
+        if message == "@on" then
+            on = true
+        elseif message == "@off" then
+            on = false
+        end
+    elseif on and #messages < max then
+        messages[#messages + 1] = message
+    elseif on then
+        dropped = dropped + 1
+    end
+end
+
+return function()
+    local taken, left_out = messages, dropped
+    messages, dropped = {}, 0
+    return taken, left_out
+end
+"##;
+
+pub(crate) fn install(ctx: Context) -> Result<(), Error> {
+    let take: Function = ctx
+        .load(INSTALL_SOURCE)
+        .set_name("=warnings")?
+        .call(MAX_WARNINGS)?;
+    ctx.set_named_registry_value(TAKE_KEY, take)
+}
+
+/// The warnings issued since the last call.
+pub(crate) fn take(ctx: Context) -> Vec<String> {
+    let taken: Result<(Vec<String>, usize), Error> = ctx
+        .named_registry_value::<_, Function>(TAKE_KEY)
+        .and_then(|take| take.call(()));
+    let Ok((mut warnings, dropped)) = taken else {
+        return vec![];
+    };
+    if dropped > 0 {
+        warnings.push(format!("({} more warnings)", dropped));
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ErrorKind;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_warn() {
+        let mut session = Session::new();
+        let resp = session
+            .eval(
+                "warn('disk ', 'almost ', 'full') warn('@off') warn('hidden') warn('@on') warn(1)"
+                    .to_string(),
+            )
+            .await;
+        assert!(resp.success);
+        assert_eq!(resp.warnings, ["disk almost full", "1"]);
+        let resp = session.eval("return 1".to_string()).await;
+        assert!(resp.warnings.is_empty());
+        let resp = session.eval("warn({}) return 1".to_string()).await;
+        assert_eq!(
+            resp.error.as_deref(),
+            Some("[string \"?\"]:1: bad argument #1 to 'warn' (string expected, got table)")
+        );
+        let resp = session
+            .eval("for i = 1, 150 do warn('w', i) end".to_string())
+            .await;
+        assert_eq!(resp.warnings.len(), 101);
+        assert_eq!(resp.warnings[100], "(50 more warnings)");
+    }
+
+    #[tokio::test]
+    async fn test_to_be_closed() {
+        let mut session = Session::new();
+        let resp = session.eval("local x <close> = 42".to_string()).await;
+        assert_eq!(resp.error_kind, Some(ErrorKind::Runtime));
+        assert_eq!(
+            resp.error.as_deref(),
+            Some("[string \"?\"]:1: variable 'x' got a non-closable value")
+        );
+
+        let resp = session
+            .eval(
+                "do\n\
+                   local a <close> = setmetatable({}, { __close = function() warn('closed a') end })\n\
+                   local b <close> = setmetatable({}, { __close = function() error('b failed') end })\n\
+                 end"
+                    .to_string(),
+            )
+            .await;
+        assert_eq!(resp.error.as_deref(), Some("[string \"?\"]:3: b failed"));
+        assert_eq!(resp.error_kind, Some(ErrorKind::Runtime));
+        assert_eq!(resp.traceback[1].function.as_deref(), Some("close"));
+        assert_eq!(resp.traceback[1].line, Some(3));
+        // Closing continues past a failing closing method.
+        assert_eq!(resp.warnings, ["closed a"]);
+
+        let resp = session.eval("local c <const> = 1; c = 2".to_string()).await;
+        assert_eq!(
+            resp.error_kind,
+            Some(ErrorKind::Syntax { incomplete: false })
+        );
+    }
+}
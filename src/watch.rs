@@ -0,0 +1,244 @@
+//! Watch expressions that are only re-evaluated when something they read
+//! changed, so that frontends can keep dozens of them on screen cheaply.
+//!
+//! A watch is evaluated like an expression-only eval, with every read through
+//! the globals recorded as a path of keys and the value found there. After
+//! later evals, a watch is stale if any of those paths now leads to another
+//! value. Reads that cannot be tracked this way, such as calls to functions
+//! defined by scripts (which read through their own environment) or `pairs`
+//! loops, make a watch re-evaluate every time.
+
+use crate::sandbox;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use rlua::RegistryKey;
+use rlua::Table;
+use rlua::Value;
+use std::collections::HashMap;
+
+const TRACKER_KEY: &str = "luarepl.watch_tracker";
+
+/// Returns a function creating a tracker for `sandbox::eval_tracked`, and a
+/// function telling whether anything it saw has changed since.
+const TRACKER_SOURCE: &str = r#"
+local globals = ...
+local pure = {}
+for _, lib in ipairs({ string, table, math, utf8 }) do
+    for _, f in pairs(lib) do
+        pure[f] = true
+    end
+end
+for _, f in ipairs({ tostring, tonumber, type, select, ipairs, next, rawget, rawlen, rawequal }) do
+    pure[f] = true
+end
+pure[math.random] = nil
+pure[math.randomseed] = nil
+
+local function resolve(path)
+    local value = globals
+    for _, key in ipairs(path) do
+        if type(value) ~= "table" then
+            return nil
+        end
+        value = value[key]
+    end
+    return value
+end
+
+return function()
+    local reads, volatile = {}, false
+    local track = {}
+    function track.index(path, value)
+        local kind = type(value)
+        if (kind == "function" and not pure[value]) or kind == "userdata" or kind == "thread" then
+            volatile = true
+        end
+        reads[#reads + 1] = { path = path, value = value }
+    end
+    function track.len(path, n)
+        reads[#reads + 1] = { path = path, value = n, len = true }
+    end
+    function track.pairs()
+        volatile = true
+    end
+    local function changed()
+        if volatile then
+            return true
+        end
+        for _, read in ipairs(reads) do
+            local value = resolve(read.path)
+            if read.len then
+                value = type(value) == "table" and #value or nil
+            end
+            if not rawequal(value, read.value) then
+                return true
+            end
+        end
+        return false
+    end
+    return track, changed
+end
+"#;
+
+/// A watch expression registered with `Session::watch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Watch(u64);
+
+impl Watch {
+    /// The number the session gave the watch, counting from 0.
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The session's side: the watched expressions, which outlive Lua states.
+#[derive(Debug, Default)]
+pub(crate) struct WatchList {
+    sources: Vec<(Watch, String)>,
+    next: u64,
+}
+
+impl WatchList {
+    pub fn add(&mut self, source: String) -> Watch {
+        let watch = Watch(self.next);
+        self.next += 1;
+        self.sources.push((watch, source));
+        watch
+    }
+
+    pub fn remove(&mut self, watch: Watch) -> bool {
+        let before = self.sources.len();
+        self.sources.retain(|(w, _)| *w != watch);
+        self.sources.len() != before
+    }
+
+    pub fn sources(&self) -> Vec<(Watch, String)> {
+        self.sources.clone()
+    }
+}
+
+/// The interpreter's side: what each watch read when it last ran.
+#[derive(Default)]
+pub(crate) struct Dependencies(HashMap<Watch, RegistryKey>);
+
+impl Dependencies {
+    /// Whether `watch` needs evaluating, because it never ran or something
+    /// it read changed since.
+    pub fn stale(&self, ctx: Context, watch: Watch) -> bool {
+        let changed = self
+            .0
+            .get(&watch)
+            .and_then(|key| ctx.registry_value::<Function>(key).ok());
+        match changed {
+            Some(changed) => changed.call(()).unwrap_or(true),
+            None => true,
+        }
+    }
+
+    /// Evaluates `source`, remembering what it read for `watch`.
+    pub fn eval<'l>(
+        &mut self,
+        ctx: Context<'l>,
+        watch: Watch,
+        source: &str,
+    ) -> Result<Value<'l>, Error> {
+        let (track, changed): (Table, Function) = tracker(ctx)?.call(())?;
+        let result = sandbox::eval_tracked(ctx, source, Value::Table(track));
+        let key = ctx.create_registry_value(changed)?;
+        if let Some(old) = self.0.insert(watch, key) {
+            let _ = ctx.remove_registry_value(old);
+        }
+        result
+    }
+
+    /// Forgets watches the session no longer has.
+    pub fn retain(&mut self, ctx: Context, watches: &[(Watch, String)]) {
+        let gone: Vec<Watch> = self
+            .0
+            .keys()
+            .filter(|watch| !watches.iter().any(|(w, _)| w == *watch))
+            .copied()
+            .collect();
+        for watch in gone {
+            if let Some(key) = self.0.remove(&watch) {
+                let _ = ctx.remove_registry_value(key);
+            }
+        }
+    }
+}
+
+fn tracker(ctx: Context) -> Result<Function, Error> {
+    match ctx.named_registry_value(TRACKER_KEY)? {
+        Value::Function(tracker) => Ok(tracker),
+        _ => {
+            let tracker: Function = ctx
+                .load(TRACKER_SOURCE)
+                .set_name("=watch")?
+                .call(ctx.globals())?;
+            ctx.set_named_registry_value(TRACKER_KEY, tracker.clone())?;
+            Ok(tracker)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[tokio::test]
+    async fn test_only_stale_watches_run() {
+        let mut session = Session::new();
+        session
+            .eval("player = { hp = 10, name = 'a' }; items = { 'x' }; score = 0".to_string())
+            .await;
+        let hp = session.watch("player.hp".to_string());
+        let count = session.watch("#items".to_string());
+        let label = session.watch("string.upper(player.name)".to_string());
+        assert_eq!(session.refresh_watches().await.len(), 3);
+        assert!(session.refresh_watches().await.is_empty());
+
+        session
+            .eval("score = 1; player.name = 'b'".to_string())
+            .await;
+        let changed = session.refresh_watches().await;
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, label);
+        assert_eq!(changed[0].1.value, LuaValue::String("B".to_string()));
+
+        session.eval("items[2] = 'y'".to_string()).await;
+        let changed = session.refresh_watches().await;
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, count);
+
+        session.eval("player = { hp = 5 }".to_string()).await;
+        let changed = session.refresh_watches().await;
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0].0, hp);
+        assert_eq!(changed[0].1.value, LuaValue::Integer(5));
+    }
+
+    #[tokio::test]
+    async fn test_untrackable_watches() {
+        let mut session = Session::new();
+        session
+            .eval("x = 1; function get() return x end".to_string())
+            .await;
+        let get = session.watch("get()".to_string());
+        let missing = session.watch("later.value".to_string());
+        assert_eq!(session.refresh_watches().await.len(), 2);
+
+        let changed = session.refresh_watches().await;
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, get);
+
+        session.eval("later = { value = 1 }".to_string()).await;
+        let changed = session.refresh_watches().await;
+        assert_eq!(changed.len(), 2);
+        assert!(changed[1].1.success);
+        assert!(session.unwatch(missing));
+        assert!(!session.unwatch(missing));
+        assert_eq!(session.refresh_watches().await.len(), 1);
+    }
+}
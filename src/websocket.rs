@@ -0,0 +1,208 @@
+//! WebSocket clients for `server::serve`, so that browser-based frontends
+//! can drive sessions: every text or binary message is one frame of
+//! `protocol`, and replies are sent as text messages.
+//!
+//! The handshake happens on the connection's own task, so a slow client
+//! cannot hold up the others. While a client is quiet, it is pinged every
+//! ping interval; one that has not answered, or sent anything else, by the
+//! next ping is disconnected, which closes its sessions like any other
+//! disconnect.
+
+use crate::transport::Connection;
+use crate::transport::Transport;
+use futures_util::SinkExt;
+use futures_util::StreamExt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// How long a quiet client goes unpinged by default.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a client may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clients connecting over WebSockets.
+#[derive(Debug)]
+pub struct WsTransport {
+    listener: TcpListener,
+    ping_interval: Duration,
+}
+
+impl WsTransport {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            ping_interval: PING_INTERVAL,
+        })
+    }
+
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Transport for WsTransport {
+    type Connection = WsConnection;
+
+    async fn accept(&mut self) -> io::Result<Option<Self::Connection>> {
+        let (stream, peer) = self.listener.accept().await?;
+        Ok(Some(WsConnection {
+            stream: Some(stream),
+            socket: None,
+            peer,
+            ping_interval: self.ping_interval,
+            awaiting_pong: false,
+        }))
+    }
+}
+
+/// One WebSocket client.
+#[derive(Debug)]
+pub struct WsConnection {
+    /// Until the handshake is done.
+    stream: Option<TcpStream>,
+    socket: Option<WebSocketStream<TcpStream>>,
+    peer: SocketAddr,
+    ping_interval: Duration,
+    /// Whether the client was pinged and has not been heard from since.
+    awaiting_pong: bool,
+}
+
+fn io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl WsConnection {
+    async fn socket(&mut self) -> io::Result<&mut WebSocketStream<TcpStream>> {
+        if let Some(stream) = self.stream.take() {
+            let handshake = tokio_tungstenite::accept_async(stream);
+            let socket = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+                .map_err(io_error)?;
+            self.socket = Some(socket);
+        }
+        self.socket
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "handshake failed"))
+    }
+}
+
+impl Connection for WsConnection {
+    async fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let ping_interval = self.ping_interval;
+        loop {
+            // Borrowed from the field, to leave `awaiting_pong` free.
+            self.socket().await?;
+            let Some(socket) = self.socket.as_mut() else {
+                return Ok(None);
+            };
+            let message = tokio::select! {
+                message = socket.next() => message,
+                _ = tokio::time::sleep(ping_interval) => {
+                    if std::mem::replace(&mut self.awaiting_pong, true) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "client stopped answering pings",
+                        ));
+                    }
+                    socket.send(Message::Ping(vec![])).await.map_err(io_error)?;
+                    continue;
+                }
+            };
+            self.awaiting_pong = false;
+            match message {
+                None
+                | Some(Ok(Message::Close(_)))
+                | Some(Err(tungstenite::Error::ConnectionClosed)) => return Ok(None),
+                Some(Ok(Message::Text(text))) => return Ok(Some(text.into_bytes())),
+                Some(Ok(Message::Binary(data))) => return Ok(Some(data)),
+                // Pings are answered by the socket itself.
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(io_error(e)),
+            }
+        }
+    }
+
+    async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let text = String::from_utf8_lossy(message).into_owned();
+        let socket = self.socket().await?;
+        socket.send(Message::Text(text)).await.map_err(io_error)
+    }
+
+    fn peer(&self) -> String {
+        format!("ws://{}", self.peer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::Frame;
+    use crate::protocol::Reply;
+    use crate::server::serve;
+    use crate::SessionConfig;
+
+    #[tokio::test]
+    async fn test_websocket() {
+        let transport = WsTransport::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .ping_interval(Duration::from_millis(50));
+        let addr = transport.local_addr().unwrap();
+        tokio::spawn(serve(transport, SessionConfig::default()));
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        for request in [
+            r#"{"session":1,"op":"open"}"#,
+            r#"{"session":1,"op":"eval","source":"return 6 * 7"}"#,
+        ] {
+            client
+                .send(Message::Text(request.to_string()))
+                .await
+                .unwrap();
+        }
+        let mut replies = vec![];
+        while replies.len() < 2 {
+            match client.next().await.unwrap().unwrap() {
+                Message::Text(text) => {
+                    replies.push(Frame::<Reply>::decode(text.as_bytes()).unwrap().body)
+                }
+                Message::Ping(_) => {}
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+        assert_eq!(replies[0], Reply::Opened);
+        assert!(matches!(&replies[1], Reply::Result { output, .. } if output == "42"));
+
+        // A client that reads, and so answers pings, stays connected.
+        for _ in 0..3 {
+            let ping = client.next().await.unwrap().unwrap();
+            assert!(matches!(ping, Message::Ping(_)));
+        }
+
+        // One that stops is dropped, ending the stream.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let drained = async { while let Some(Ok(Message::Ping(_))) = client.next().await {} };
+        assert!(tokio::time::timeout(Duration::from_secs(5), drained)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,251 @@
+//! A scratch directory for one session, see `SessionBuilder::workspace`.
+//! Scripts find it in the `WORKSPACE` global, and in hardened sessions
+//! relative paths given to `io.open`, `io.lines`, `loadfile` and `dofile`
+//! resolve against it, as if it were the working directory. Remote clients
+//! hand files to it and fetch what scripts wrote through the server, without
+//! a shared filesystem. Paths into the workspace are relative and cannot
+//! leave it.
+
+use flate2::Crc;
+use rlua::Context;
+use rlua::Error;
+use rlua::Function;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+const INSTALL_SOURCE: &str = r#"
+local root, hardened = ...
+WORKSPACE = root
+if not hardened then
+    return
+end
+local sub, open, lines, load_file, do_file = string.sub, io.open, io.lines, loadfile, dofile
+local function inside(path)
+    if type(path) == "string" and sub(path, 1, 1) ~= "/" then
+        return root .. "/" .. path
+    end
+    return path
+end
+function io.open(path, ...)
+    return open(inside(path), ...)
+end
+function io.lines(path, ...)
+    if path == nil then
+        return lines()
+    end
+    return lines(inside(path), ...)
+end
+function loadfile(path, ...)
+    if path == nil then
+        return load_file()
+    end
+    return load_file(inside(path), ...)
+end
+function dofile(path)
+    if path == nil then
+        return do_file()
+    end
+    return do_file(inside(path))
+end
+"#;
+
+/// A temporary directory, removed with everything in it when dropped.
+#[derive(Debug)]
+pub struct Workspace {
+    root: PathBuf,
+    owned: bool,
+}
+
+impl Workspace {
+    /// Creates an empty directory under the system's temporary directory.
+    pub fn create() -> io::Result<Self> {
+        let name = format!(
+            "luarepl-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let root = std::env::temp_dir().join(name);
+        std::fs::create_dir(&root)?;
+        Ok(Self { root, owned: true })
+    }
+
+    /// The workspace at `root`, created by someone else, e.g. a session's
+    /// interpreter. It is left in place when dropped.
+    pub fn at(root: PathBuf) -> Self {
+        Self { root, owned: false }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where `path` is inside the workspace. Absolute paths and `..` are
+    /// rejected rather than resolved.
+    pub fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(path);
+        let inside = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if path.is_empty() || !inside {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a relative path inside the workspace", path),
+            ));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Writes `data` at `offset` into the file at `path`, creating it and its
+    /// directories as needed. Writing at offset 0 replaces the file. Returns
+    /// the file's size afterwards.
+    pub fn write_chunk(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<u64> {
+        let path = self.resolve(path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(offset == 0)
+            .open(&path)?;
+        if offset > file.metadata()?.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("chunk at {} would leave a gap", offset),
+            ));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        file.metadata().map(|metadata| metadata.len())
+    }
+
+    /// Reads up to `len` bytes at `offset` from the file at `path`, returning
+    /// them with the file's size.
+    pub fn read_chunk(&self, path: &str, offset: u64, len: usize) -> io::Result<(Vec<u8>, u64)> {
+        let mut file = File::open(self.resolve(path)?)?;
+        let size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset.min(size)))?;
+        let mut data = vec![];
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok((data, size))
+    }
+
+    /// The CRC-32 of the whole file at `path`.
+    pub fn checksum(&self, path: &str) -> io::Result<u32> {
+        let mut file = File::open(self.resolve(path)?)?;
+        let mut crc = Crc::new();
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            match file.read(&mut buffer)? {
+                0 => return Ok(crc.sum()),
+                n => crc.update(&buffer[..n]),
+            }
+        }
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if self.owned {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+}
+
+/// Sets `WORKSPACE` to `root`, which also becomes the working directory for
+/// file access if the session is hardened.
+pub(crate) fn install(ctx: Context, root: &Path, hardened: bool) -> Result<(), Error> {
+    let install: Function = ctx
+        .load(INSTALL_SOURCE)
+        .set_name("=workspace")?
+        .into_function()?;
+    install.call((root.display().to_string(), hardened))
+}
+
+/// The CRC-32 of `data`, as `Workspace::checksum` computes it for files.
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hardening::Hardening;
+    use crate::LuaValue;
+    use crate::Session;
+
+    #[test]
+    fn test_chunks() {
+        let workspace = Workspace::create().unwrap();
+        assert_eq!(
+            workspace.write_chunk("data/in.txt", 0, b"hello ").unwrap(),
+            6
+        );
+        assert_eq!(
+            workspace.write_chunk("data/in.txt", 6, b"world").unwrap(),
+            11
+        );
+        assert!(workspace.write_chunk("data/in.txt", 20, b"!").is_err());
+        assert_eq!(
+            workspace.checksum("data/in.txt").unwrap(),
+            checksum(b"hello world")
+        );
+        let (data, size) = workspace.read_chunk("data/in.txt", 6, 3).unwrap();
+        assert_eq!((data.as_slice(), size), (&b"wor"[..], 11));
+        let (data, _) = workspace.read_chunk("data/in.txt", 20, 3).unwrap();
+        assert!(data.is_empty());
+
+        let root = workspace.root().to_path_buf();
+        assert!(root.join("data/in.txt").exists());
+        drop(workspace);
+        assert!(!root.exists());
+    }
+
+    #[tokio::test]
+    async fn test_session_workspace() {
+        let mut session = Session::builder()
+            .workspace(true)
+            .hardening(Hardening::default())
+            .build();
+        let root = session.workspace().await.unwrap().root().to_path_buf();
+        let resp = session.eval("return WORKSPACE".to_string()).await;
+        assert_eq!(resp.value, LuaValue::String(root.display().to_string()));
+        session
+            .eval("local f = io.open('notes.txt', 'w') f:write('hi') f:close()".to_string())
+            .await;
+        assert_eq!(std::fs::read(root.join("notes.txt")).unwrap(), b"hi");
+        session.reset().await;
+        let resp = session
+            .eval("return io.lines('notes.txt')()".to_string())
+            .await;
+        assert_eq!(resp.value, LuaValue::String("hi".to_string()));
+        session.close().await;
+        assert!(!root.exists());
+
+        let mut plain = Session::new();
+        assert!(plain.workspace().await.is_none());
+    }
+
+    #[test]
+    fn test_confined() {
+        let workspace = Workspace::create().unwrap();
+        assert!(workspace.resolve("a/./b.lua").is_ok());
+        for path in ["", "../x", "a/../../x", "/etc/passwd"] {
+            assert!(workspace.resolve(path).is_err(), "{:?}", path);
+        }
+    }
+}